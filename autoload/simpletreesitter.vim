@@ -167,6 +167,15 @@ var s_fold_exprs: dict<list<string>> = {}
 const s_foldexpr = 'simpletreesitter#FoldExpr(v:lnum)'
 # winid -> {method: string, expr: string} 应用折叠前的窗口设置
 var s_fold_windows: dict<dict<string>> = {}
+# =============== 缩进（g:simpletreesitter_indentexpr） ===============
+const s_indentexpr = 'simpletreesitter#IndentExpr(v:lnum)'
+# buf -> 装上我们之前的 'indentexpr'：daemon 答不上来时退回它，关闭时还原它。
+var s_indent_fallback: dict<string> = {}
+# buf -> 正在等的 indent 请求 {lnum, revision}；回包到了补上 indents 或 failed。
+var s_indent_wait: dict<dict<any>> = {}
+# IndentFallback() 在 :legacy 里求值时的进出参数。
+var s_indent_fallback_expr: string = ''
+var s_indent_fallback_result: any = -1
 # =============== 符号 location list 请求 ===============
 var s_loclist_pending: dict<bool> = {}
 # 当前 symbols 请求的用途：partial 只覆盖视口，full 服务 loclist/导航。
@@ -236,6 +245,8 @@ const s_language_by_filetype = {
   toml: 'toml',
   lua: 'lua',
   html: 'html',
+  vue: 'html',
+  svelte: 'html',
   css: 'css',
   markdown: 'markdown',
   julia: 'julia',
@@ -1049,6 +1060,14 @@ def OnDaemonEvent(ev: dict<any>)
     if retry
       ScheduleFolds(buf)
     endif
+  elseif ev.type ==# 'indent'
+    var buf = get(ev, 'buf', 0)
+    var wait = get(s_indent_wait, buf, {})
+    # IndentExpr() 等超时之后才到的回包已经没人要了；只认正在等的那一行那一版。
+    if empty(wait) || get(ev, 'lnum', 0) != wait.lnum || get(ev, 'revision', -1) != wait.revision
+      return
+    endif
+    wait.indents = get(ev, 'indents', [])
   elseif ev.type ==# 'ok'
     var buf = get(ev, 'buf', 0)
     var op  = get(ev, 'op', '')
@@ -1139,11 +1158,12 @@ def OnDaemonEvent(ev: dict<any>)
       echohl None
     endif
   elseif ev.type ==# 'status'
-    echom printf('[ts-hl] daemon v%s protocol=%d | cache=%d/%d bytes evicted=%d | parse full=%d incremental=%d unchanged=%d | %s',
+    echom printf('[ts-hl] daemon v%s protocol=%d | cache=%d/%d bytes evicted=%d | parse full=%d incremental=%d unchanged=%d | injected full=%d incremental=%d | superseded=%d | %s',
       get(ev, 'version', '?'), get(ev, 'protocol_version', 0), get(ev, 'cached_buffers', 0),
       get(ev, 'cached_bytes', 0), get(ev, 'cache_evictions', 0), get(ev, 'full_parses', 0),
       get(ev, 'incremental_parses', 0), get(ev, 'unchanged_syncs', 0),
-      join(get(ev, 'languages', []), ', '))
+      get(ev, 'injected_full_parses', 0), get(ev, 'injected_incremental_parses', 0),
+      get(ev, 'superseded_requests', 0), join(get(ev, 'languages', []), ', '))
   elseif ev.type ==# 'error'
     var buf = get(ev, 'buf', 0)
     var message = get(ev, 'message', '')
@@ -1158,6 +1178,18 @@ def OnDaemonEvent(ev: dict<any>)
       endif
       return
     endif
+    # 同一 buffer 同类的更新请求已经在 daemon 手里，要等的是它的回包；在途标记保持不动。
+    if message ==# 'superseded'
+      return
+    endif
+    # IndentExpr() 已经退回原来的缩进；打字途中弹一条消息只会打断输入。
+    if op ==# 'indent'
+      if has_key(s_indent_wait, buf)
+        s_indent_wait[buf].failed = true
+      endif
+      Log('indent failed: ' .. message)
+      return
+    endif
     if buf > 0 && op ==# 'symbols' && !SymbolEventMatchesCurrent(ev, buf)
       return
     endif
@@ -1850,6 +1882,99 @@ export def FoldExpr(lnum: number): string
   return exprs[lnum - 1]
 enddef
 
+def IndentExprWanted(buf: number): bool
+  # Haskell 没有 indents 查询，layout 缩进交给 indent/haskell.vim。
+  return get(g:, 'simpletreesitter_indentexpr', 0) && IsSupportedLang(buf)
+    && DetectLang(buf) !=# 'haskell'
+enddef
+
+def EnsureIndentExpr(buf: number)
+  if !IndentExprWanted(buf) || getbufvar(buf, '&indentexpr') ==# s_indentexpr
+    return
+  endif
+  s_indent_fallback[buf] = getbufvar(buf, '&indentexpr')
+  setbufvar(buf, '&indentexpr', s_indentexpr)
+enddef
+
+def RestoreIndentExprs()
+  for [k, saved] in items(s_indent_fallback)
+    var buf = str2nr(k)
+    if bufexists(buf) && getbufvar(buf, '&indentexpr') ==# s_indentexpr
+      setbufvar(buf, '&indentexpr', saved)
+    endif
+  endfor
+  s_indent_fallback = {}
+  s_indent_wait = {}
+enddef
+
+# 原来的 'indentexpr'（通常来自 filetype 的缩进脚本）；没有就回 -1，交给 'autoindent'。
+def IndentFallback(buf: number): number
+  var expr = get(s_indent_fallback, buf, '')
+  if expr ==# '' || expr ==# s_indentexpr
+    return -1
+  endif
+  # 'indentexpr' 是旧式表达式，也得按旧式语法求值：Vim9 的 eval() 既不认 `.`
+  # 拼接，也找不到不带 g: 的全局函数。:legacy 看不见局部变量，只好借道脚本变量。
+  s_indent_fallback_expr = expr
+  try
+    legacy let s:s_indent_fallback_result = eval(s:s_indent_fallback_expr)
+    return type(s_indent_fallback_result) == v:t_number ? s_indent_fallback_result : -1
+  catch
+    Log('fallback indentexpr failed: ' .. v:exception)
+    return -1
+  endtry
+enddef
+
+# 'indentexpr' 要当场给出数字，daemon 的回包却只经 channel 回调送达。:sleep 在
+# 等待期间照常处理 channel 消息和定时器，所以这里发完请求就一毫秒一毫秒地等，
+# 等满 g:simpletreesitter_indent_timeout 仍没有回包就退回原来的缩进。daemon 按
+# 收到的顺序处理请求，同步快照又先于 indent 发出，回包算的一定是当前文本。
+export def IndentExpr(lnum: number): number
+  var buf = bufnr()
+  if !s_enabled || !get(s_daemon_capabilities, 'indent', false) || !IndentExprWanted(buf)
+    return IndentFallback(buf)
+  endif
+  var limit = get(g:, 'simpletreesitter_indent_timeout', 50)
+  var start = reltime()
+  # 上一次同步还在途时 SyncBufferNow() 什么也不发；先等它的 ACK。
+  while get(s_inflight_sync, buf, false) && reltimefloat(reltime(start)) * 1000 < limit
+    sleep 1m
+  endwhile
+  SyncBufferNow(buf)
+  var ct = GetChangedTick(buf)
+  if get(s_inflight_revision, buf, get(s_sent_changedtick, buf, -1)) != ct
+    # 同步没发出去（超出大小上限、daemon 不在），daemon 手里不是这份文本。
+    return IndentFallback(buf)
+  endif
+  var wait: dict<any> = {lnum: lnum, revision: ct}
+  s_indent_wait[buf] = wait
+  if !Send({
+    type: 'indent',
+    buf: buf,
+    lang: DetectLang(buf),
+    lnum: lnum,
+    shiftwidth: shiftwidth(),
+    tabstop: &l:tabstop,
+  })
+    remove(s_indent_wait, string(buf))
+    return IndentFallback(buf)
+  endif
+  while !has_key(wait, 'indents') && !has_key(wait, 'failed')
+      && reltimefloat(reltime(start)) * 1000 < limit
+    sleep 1m
+  endwhile
+  if get(s_indent_wait, buf, {}) is wait
+    remove(s_indent_wait, string(buf))
+  endif
+  var indents = get(wait, 'indents', [])
+  if empty(indents) || type(indents[0]) != v:t_number
+    Log(printf('indent for buffer %d line %d fell back (%s)', buf, lnum,
+      has_key(wait, 'failed') ? 'daemon error' : 'timed out'))
+    return IndentFallback(buf)
+  endif
+  return indents[0]
+enddef
+
 export def FoldsToggle()
   if FoldsEnabled()
     g:simpletreesitter_folds = 0
@@ -2545,6 +2670,7 @@ export def Disable()
   # 清理 listener 与折叠状态
   RemoveAllListeners()
   RestoreFoldSettings()
+  RestoreIndentExprs()
   # 清理缩进参考线
   DisableIndentGuides()
   # 清理面包屑
@@ -2654,6 +2780,7 @@ export def OnBufEvent(buf: number)
     endif
   endif
   ScheduleSync(buf)
+  EnsureIndentExpr(buf)
 
   if cursor_owned && s_outline_win != 0 && buf != s_outline_buf && getbufvar(buf, '&filetype') !=# 'simpletreesitter_outline'
     if IsSupportedLang(buf)
@@ -2781,7 +2908,7 @@ export def OnBufClose(buf: number)
   # 文件的“内容没变”结论。
   for state in [s_inflight_revision, s_sent_changedtick, s_skipped_changedtick,
       s_req_timers, s_sync_timers, s_symbol_jump_pending, s_pending_inspect,
-      s_symbols_digest, s_folds_digest]
+      s_symbols_digest, s_folds_digest, s_indent_fallback, s_indent_wait]
     if has_key(state, buf)
       remove(state, string(buf))
     endif
@@ -81,6 +81,12 @@ g:simpletreesitter_rainbow_brackets = get(g:, 'simpletreesitter_rainbow_brackets
 # =============== Tree-sitter 折叠 ===============
 g:simpletreesitter_folds = get(g:, 'simpletreesitter_folds', 0)
 
+# =============== Tree-sitter 缩进 ===============
+# 1: 给支持的 buffer（Haskell 除外）装上 'indentexpr'，由 daemon 的 indent 请求作答；
+# 超过 timeout 毫秒没有回包就退回原来的 'indentexpr'。
+g:simpletreesitter_indentexpr = get(g:, 'simpletreesitter_indentexpr', 0)
+g:simpletreesitter_indent_timeout = get(g:, 'simpletreesitter_indent_timeout', 50)
+
 # =============== :TsHlInspect ===============
 # 1: 光标处弹出 popup；0: 用底部的 ts-hl-inspect scratch split（可复制/搜索）。
 g:simpletreesitter_inspect_popup = get(g:, 'simpletreesitter_inspect_popup', 1)
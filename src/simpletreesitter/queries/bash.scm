@@ -16,7 +16,7 @@
 
 ; ----- variables -----
 (variable_name) @variable
-(special_variable_name) @variable.builtin
+((special_variable_name) @variable.builtin (#set! @variable.builtin priority "5"))
 (simple_expansion) @variable
 (expansion) @variable
 
@@ -45,8 +45,8 @@
 "unsetenv" @keyword
 
 ; ----- operators -----
-"=" @keyword.operator
-"+=" @keyword.operator
+("=" @keyword.operator (#set! @keyword.operator priority "9"))
+("+=" @keyword.operator (#set! @keyword.operator priority "9"))
 "=~" @operator
 "==" @operator
 "!=" @operator
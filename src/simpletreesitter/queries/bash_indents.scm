@@ -0,0 +1,27 @@
+[
+  (if_statement)
+  (do_group)
+  (compound_statement)
+  (subshell)
+  (case_statement)
+  (case_item)
+] @indent.begin
+
+[
+  (elif_clause)
+  (else_clause)
+] @indent.begin @indent.branch
+
+[
+  "fi"
+  "done"
+  "esac"
+  "}"
+  ")"
+] @indent.branch @indent.end
+
+[
+  (heredoc_body)
+  (raw_string)
+  (string)
+] @indent.ignore
@@ -12,7 +12,7 @@
 ; ----- strings / chars -----
 (string_literal) @string
 (char_literal) @string
-(escape_sequence) @string.escape
+((escape_sequence) @string.escape (#set! @string.escape priority "9"))
 
 ; ----- numbers -----
 (number_literal) @number
@@ -45,9 +45,9 @@
 "restrict" @keyword
 
 ; ----- types -----
-(primitive_type) @type.builtin
+((primitive_type) @type.builtin (#set! @type.builtin priority "9"))
 (type_identifier) @type
-(sized_type_specifier) @type.builtin
+((sized_type_specifier) @type.builtin (#set! @type.builtin priority "9"))
 
 ; ----- functions -----
 (function_declarator declarator: (identifier) @function)
@@ -55,8 +55,8 @@
 (call_expression function: (identifier) @function)
 
 ; ----- parameters -----
-(parameter_declaration declarator: (identifier) @variable.parameter)
-(parameter_declaration declarator: (pointer_declarator declarator: (identifier) @variable.parameter))
+(parameter_declaration declarator: (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
+(parameter_declaration declarator: (pointer_declarator declarator: (identifier) @variable.parameter) (#set! @variable.parameter priority "5"))
 
 ; ----- fields / members -----
 (field_identifier) @field
@@ -90,16 +90,16 @@
 "~" @operator
 "<<" @operator
 ">>" @operator
-"+=" @keyword.operator
-"-=" @keyword.operator
-"*=" @keyword.operator
-"/=" @keyword.operator
-"%=" @keyword.operator
-"&=" @keyword.operator
-"|=" @keyword.operator
-"^=" @keyword.operator
-"<<=" @keyword.operator
-">>=" @keyword.operator
+("+=" @keyword.operator (#set! @keyword.operator priority "9"))
+("-=" @keyword.operator (#set! @keyword.operator priority "9"))
+("*=" @keyword.operator (#set! @keyword.operator priority "9"))
+("/=" @keyword.operator (#set! @keyword.operator priority "9"))
+("%=" @keyword.operator (#set! @keyword.operator priority "9"))
+("&=" @keyword.operator (#set! @keyword.operator priority "9"))
+("|=" @keyword.operator (#set! @keyword.operator priority "9"))
+("^=" @keyword.operator (#set! @keyword.operator priority "9"))
+("<<=" @keyword.operator (#set! @keyword.operator priority "9"))
+(">>=" @keyword.operator (#set! @keyword.operator priority "9"))
 "++" @operator
 "--" @operator
 "->" @operator
@@ -0,0 +1,33 @@
+[
+  (compound_statement)
+  (field_declaration_list)
+  (enumerator_list)
+  (initializer_list)
+  (case_statement)
+  (if_statement)
+  (for_statement)
+  (while_statement)
+  (do_statement)
+] @indent.begin
+
+; `else` sits at the level of its `if` and indents a braceless body
+(else_clause) @indent.begin @indent.branch
+
+; a braceless body ends with its statement
+(if_statement consequence: (expression_statement) @indent.end)
+(else_clause (expression_statement) @indent.end)
+(for_statement body: (expression_statement) @indent.end)
+(while_statement body: (expression_statement) @indent.end)
+
+[
+  (argument_list)
+  (parameter_list)
+] @indent.align
+
+[
+  "}"
+  ")"
+  "]"
+] @indent.branch @indent.end
+
+(comment) @indent.ignore
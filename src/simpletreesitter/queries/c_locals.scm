@@ -0,0 +1,36 @@
+; scopes
+(translation_unit) @local.scope
+(function_definition) @local.scope
+(compound_statement) @local.scope
+(for_statement) @local.scope
+
+; functions: definitions are visible in the enclosing scope
+(function_definition
+  declarator: (function_declarator declarator: (identifier) @local.definition.function)
+  (#set! definition.function.scope "parent"))
+(function_definition
+  declarator: (pointer_declarator
+    declarator: (function_declarator declarator: (identifier) @local.definition.function))
+  (#set! definition.function.scope "parent"))
+(function_declarator declarator: (identifier) @local.definition.function)
+
+; variables and parameters
+(declaration declarator: (identifier) @local.definition.var)
+(init_declarator declarator: (identifier) @local.definition.var)
+(pointer_declarator declarator: (identifier) @local.definition.var)
+(array_declarator declarator: (identifier) @local.definition.var)
+(parameter_declaration declarator: (identifier) @local.definition.parameter)
+
+; types and constants
+(type_definition declarator: (type_identifier) @local.definition.type)
+(struct_specifier name: (type_identifier) @local.definition.type)
+(union_specifier name: (type_identifier) @local.definition.type)
+(enum_specifier name: (type_identifier) @local.definition.type)
+(enumerator name: (identifier) @local.definition.constant)
+(preproc_def name: (identifier) @local.definition.macro)
+(preproc_function_def name: (identifier) @local.definition.macro)
+(preproc_params (identifier) @local.definition.parameter)
+
+; references
+(identifier) @local.reference
+(type_identifier) @local.reference
@@ -29,7 +29,7 @@
 (system_lib_string) @string
 (char_literal) @string
 (raw_string_literal) @string
-(escape_sequence) @string.escape
+((escape_sequence) @string.escape (#set! @string.escape priority "9"))
 
 ; ----- Numbers -----
 (number_literal) @number
@@ -39,13 +39,13 @@
 (false) @boolean
 
 ; ----- Builtins -----
-(this) @variable.builtin
-"nullptr" @constant.builtin
+((this) @variable.builtin (#set! @variable.builtin priority "5"))
+("nullptr" @constant.builtin (#set! @constant.builtin priority "5"))
 
 ; ----- Type Specifiers -----
-(primitive_type) @type.builtin
+((primitive_type) @type.builtin (#set! @type.builtin priority "9"))
 (type_identifier) @type
-(placeholder_type_specifier) @type.builtin
+((placeholder_type_specifier) @type.builtin (#set! @type.builtin priority "9"))
 
 ; Qualified identifiers/types（不使用字段标签）
 (qualified_identifier (namespace_identifier) @namespace)
@@ -152,10 +152,10 @@
 (call_expression (field_expression (field_identifier) @method))
 
 ; ----- Parameters -----
-(parameter_declaration (identifier) @variable.parameter)
-(parameter_declaration (pointer_declarator (identifier) @variable.parameter))
-(parameter_declaration (reference_declarator (identifier) @variable.parameter))
-(optional_parameter_declaration (identifier) @variable.parameter)
+(parameter_declaration (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
+(parameter_declaration (pointer_declarator (identifier) @variable.parameter) (#set! @variable.parameter priority "5"))
+(parameter_declaration (reference_declarator (identifier) @variable.parameter) (#set! @variable.parameter priority "5"))
+(optional_parameter_declaration (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
 
 ; ----- Fields & Properties -----
 (field_identifier) @field
@@ -192,16 +192,16 @@
 ; ----- Operators -----
 
 "=" @operator
-"+=" @keyword.operator
-"-=" @keyword.operator
-"*=" @keyword.operator
-"/=" @keyword.operator
-"%=" @keyword.operator
-"&=" @keyword.operator
-"|=" @keyword.operator
-"^=" @keyword.operator
-"<<=" @keyword.operator
-">>=" @keyword.operator
+("+=" @keyword.operator (#set! @keyword.operator priority "9"))
+("-=" @keyword.operator (#set! @keyword.operator priority "9"))
+("*=" @keyword.operator (#set! @keyword.operator priority "9"))
+("/=" @keyword.operator (#set! @keyword.operator priority "9"))
+("%=" @keyword.operator (#set! @keyword.operator priority "9"))
+("&=" @keyword.operator (#set! @keyword.operator priority "9"))
+("|=" @keyword.operator (#set! @keyword.operator priority "9"))
+("^=" @keyword.operator (#set! @keyword.operator priority "9"))
+("<<=" @keyword.operator (#set! @keyword.operator priority "9"))
+(">>=" @keyword.operator (#set! @keyword.operator priority "9"))
 
 "==" @operator
 "!=" @operator
@@ -0,0 +1,7 @@
+
+; C++ additions on top of the C indents. Namespace bodies stay flush left,
+; access specifiers sit at the level of their class.
+(access_specifier) @indent.branch
+(template_argument_list) @indent.begin
+(lambda_expression) @indent.begin
+(raw_string_literal) @indent.ignore
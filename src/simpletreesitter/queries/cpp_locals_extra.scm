@@ -0,0 +1,16 @@
+
+; C++ additions on top of the C locals
+(namespace_definition) @local.scope
+(class_specifier) @local.scope
+(lambda_expression) @local.scope
+(for_range_loop) @local.scope
+
+(namespace_definition name: (namespace_identifier) @local.definition.namespace)
+(class_specifier name: (type_identifier) @local.definition.type)
+(type_parameter_declaration (type_identifier) @local.definition.type)
+(reference_declarator (identifier) @local.definition.var)
+(for_range_loop declarator: (identifier) @local.definition.var)
+(optional_parameter_declaration declarator: (identifier) @local.definition.parameter)
+(alias_declaration name: (type_identifier) @local.definition.type)
+
+(namespace_identifier) @local.reference
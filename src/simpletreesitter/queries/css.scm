@@ -22,10 +22,10 @@
 (keyword_query) @keyword
 
 (string_value) @string
-(color_value) @constant.builtin
+((color_value) @constant.builtin (#set! @constant.builtin priority "5"))
 (integer_value) @number
 (float_value) @number
-(unit) @type.builtin
+((unit) @type.builtin (#set! @type.builtin priority "9"))
 (important) @keyword
 (at_keyword) @keyword
 
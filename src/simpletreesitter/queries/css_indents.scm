@@ -0,0 +1,13 @@
+[
+  (block)
+  (keyframe_block_list)
+  (declaration)
+  (arguments)
+] @indent.begin
+
+[
+  "}"
+  ")"
+] @indent.branch @indent.end
+
+(comment) @indent.ignore
@@ -5,7 +5,7 @@
 (interpreted_string_literal) @string
 (raw_string_literal) @string
 (rune_literal) @string
-(escape_sequence) @string.escape
+((escape_sequence) @string.escape (#set! @string.escape priority "9"))
 
 ; ----- numbers / booleans / nil -----
 (int_literal) @number
@@ -74,19 +74,19 @@
 "<-" @operator
 "++" @operator
 "--" @operator
-"=" @keyword.operator
-":=" @keyword.operator
-"+=" @keyword.operator
-"-=" @keyword.operator
-"*=" @keyword.operator
-"/=" @keyword.operator
-"%=" @keyword.operator
-"&=" @keyword.operator
-"|=" @keyword.operator
-"^=" @keyword.operator
-"<<=" @keyword.operator
-">>=" @keyword.operator
-"&^=" @keyword.operator
+("=" @keyword.operator (#set! @keyword.operator priority "9"))
+(":=" @keyword.operator (#set! @keyword.operator priority "9"))
+("+=" @keyword.operator (#set! @keyword.operator priority "9"))
+("-=" @keyword.operator (#set! @keyword.operator priority "9"))
+("*=" @keyword.operator (#set! @keyword.operator priority "9"))
+("/=" @keyword.operator (#set! @keyword.operator priority "9"))
+("%=" @keyword.operator (#set! @keyword.operator priority "9"))
+("&=" @keyword.operator (#set! @keyword.operator priority "9"))
+("|=" @keyword.operator (#set! @keyword.operator priority "9"))
+("^=" @keyword.operator (#set! @keyword.operator priority "9"))
+("<<=" @keyword.operator (#set! @keyword.operator priority "9"))
+(">>=" @keyword.operator (#set! @keyword.operator priority "9"))
+("&^=" @keyword.operator (#set! @keyword.operator priority "9"))
 "..." @operator
 
 ; ----- punctuation -----
@@ -113,4 +113,4 @@
 
 ; ----- builtins -----
 ((identifier) @function.builtin
-  (#match? @function.builtin "^(append|cap|close|complex|copy|delete|imag|len|make|max|min|new|panic|print|println|real|recover|clear)$"))
+  (#match? @function.builtin "^(append|cap|close|complex|copy|delete|imag|len|make|max|min|new|panic|print|println|real|recover|clear)$") (#set! @function.builtin priority "9"))
@@ -0,0 +1,28 @@
+[
+  (block)
+  (literal_value)
+  (field_declaration_list)
+  (interface_type)
+  (argument_list)
+  (parameter_list)
+  (import_spec_list)
+  (const_declaration)
+  (var_declaration)
+  (type_declaration)
+] @indent.begin
+
+; gofmt puts `case` at the level of its `switch`; its body is indented once
+[
+  (expression_case)
+  (type_case)
+  (default_case)
+  (communication_case)
+] @indent.begin
+
+[
+  "}"
+  ")"
+  "]"
+] @indent.branch @indent.end
+
+(raw_string_literal) @indent.ignore
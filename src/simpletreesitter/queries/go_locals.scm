@@ -0,0 +1,32 @@
+; scopes
+(source_file) @local.scope
+(function_declaration) @local.scope
+(method_declaration) @local.scope
+(func_literal) @local.scope
+(block) @local.scope
+(if_statement) @local.scope
+(for_statement) @local.scope
+(expression_switch_statement) @local.scope
+(type_switch_statement) @local.scope
+(expression_case) @local.scope
+(type_case) @local.scope
+(communication_case) @local.scope
+
+; definitions
+(function_declaration name: (identifier) @local.definition.function
+  (#set! definition.function.scope "parent"))
+(type_spec name: (type_identifier) @local.definition.type)
+(type_parameter_declaration name: (identifier) @local.definition.type)
+(const_spec name: (identifier) @local.definition.constant)
+(var_spec name: (identifier) @local.definition.var)
+(short_var_declaration left: (expression_list (identifier) @local.definition.var))
+(range_clause left: (expression_list (identifier) @local.definition.var))
+(type_switch_statement alias: (expression_list (identifier) @local.definition.var))
+(parameter_declaration name: (identifier) @local.definition.parameter)
+(variadic_parameter_declaration name: (identifier) @local.definition.parameter)
+(import_spec name: (package_identifier) @local.definition.import)
+
+; references
+(identifier) @local.reference
+(type_identifier) @local.reference
+(package_identifier) @local.reference
@@ -8,15 +8,15 @@
 
 (decl/function
   patterns: (patterns
-    (_) @variable.parameter))
+    (_) @variable.parameter) (#set! @variable.parameter priority "5"))
 
 (expression/lambda
   (_)+ @variable.parameter
-  "->")
+  "->" (#set! @variable.parameter priority "5"))
 
 (decl/function
   (infix
-    (pattern) @variable.parameter))
+    (pattern) @variable.parameter) (#set! @variable.parameter priority "5"))
 
 ; ----------------------------------------------------------------------------
 ; Literals and comments
@@ -24,18 +24,18 @@
 
 (negation) @number
 
-(expression/literal
-  (float)) @number.float
+((expression/literal
+  (float)) @number.float (#set! @number.float priority "9"))
 
 (char) @character
 
 (string) @string
 
-(unit) @string.special.symbol ; unit, as in ()
+((unit) @string.special.symbol (#set! @string.special.symbol priority "9")) ; unit, as in ()
 
 (comment) @comment
 
-((haddock) @comment.documentation)
+((haddock) @comment.documentation (#set! @comment.documentation priority "9"))
 
 ; ----------------------------------------------------------------------------
 ; Punctuation
@@ -55,26 +55,26 @@
 
 ; ----------------------------------------------------------------------------
 ; Keywords, operators, includes
-[
+([
   "forall"
   ; "∀" ; utf-8 is not cross-platform safe
-] @keyword.repeat
+] @keyword.repeat (#set! @keyword.repeat priority "9"))
 
-(pragma) @keyword.directive
+((pragma) @keyword.directive (#set! @keyword.directive priority "9"))
 
-[
+([
   "if"
   "then"
   "else"
   "case"
   "of"
-] @keyword.conditional
+] @keyword.conditional (#set! @keyword.conditional priority "9"))
 
-[
+([
   "import"
   "qualified"
   "module"
-] @keyword.import
+] @keyword.import (#set! @keyword.import priority "9"))
 
 [
   (operator)
@@ -188,7 +188,7 @@
     (expression/variable) @function.call
     (expression/qualified
       (variable) @function.call)
-  ])
+  ] (#set! @function.call priority "9"))
 
 ; consider infix functions as operators
 (infix_id
@@ -208,7 +208,7 @@
         (variable) @function.call))
   ]
   .
-  (operator))
+  (operator) (#set! @function.call priority "9"))
 
 ; infix operators applied to variables
 ((expression/variable) @variable
@@ -231,7 +231,7 @@
   ]
   .
   (operator) @_op
-  (#any-of? @_op "$" "<$>" ">>=" "=<<"))
+  (#any-of? @_op "$" "<$>" ">>=" "=<<") (#set! @function.call priority "9"))
 
 ; right hand side of infix operator
 ((infix
@@ -247,7 +247,7 @@
   ])
   .
   (operator) @_op
-  (#any-of? @_op "$" "<$>" "=<<"))
+  (#any-of? @_op "$" "<$>" "=<<") (#set! @function.call priority "9"))
 
 ; decl/function composition, arrows, monadic composition (lhs)
 (
@@ -299,7 +299,7 @@
     (expression/variable) @function.call
     (expression/qualified
       (variable) @function.call)
-  ])
+  ] (#set! @function.call priority "9"))
 
 ; function compositions, in parentheses, applied
 ; lhs
@@ -313,7 +313,7 @@
           (variable) @function.call)
       ]
       .
-      (operator))))
+      (operator))) (#set! @function.call priority "9"))
 
 ; rhs
 (apply
@@ -326,7 +326,7 @@
         (variable) @function.call
         (qualified
           (variable) @function.call)
-      ])))
+      ])) (#set! @function.call priority "9"))
 
 ; variables being passed to a function call
 (apply
@@ -383,7 +383,7 @@
 
 ; ----------------------------------------------------------------------------
 ; Quasi-quotes
-(quoter) @function.call
+((quoter) @function.call (#set! @function.call priority "9"))
 
 (quasiquote
   [
@@ -405,7 +405,7 @@
   (_
     (module) @module
     .
-    (variable) @function.call))
+    (variable) @function.call) (#set! @function.call priority "9"))
 
 ; Highlighting of quasiquote_body for other languages is handled by injections.scm
 ; ----------------------------------------------------------------------------
@@ -415,7 +415,7 @@
     "error" "undefined" "try" "tryJust" "tryAny" "catch" "catches" "catchJust" "handle" "handleJust"
     "throw" "throwIO" "throwTo" "throwError" "ioError" "mask" "mask_" "uninterruptibleMask"
     "uninterruptibleMask_" "bracket" "bracket_" "bracketOnErrorSource" "finally" "fail"
-    "onException" "expectationFailure"))
+    "onException" "expectationFailure") (#set! @keyword.exception priority "9"))
 
 ; ----------------------------------------------------------------------------
 ; Debugging
@@ -423,19 +423,19 @@
   (#any-of? @keyword.debug
     "trace" "traceId" "traceShow" "traceShowId" "traceWith" "traceShowWith" "traceStack" "traceIO"
     "traceM" "traceShowM" "traceEvent" "traceEventWith" "traceEventIO" "flushEventLog" "traceMarker"
-    "traceMarkerIO"))
+    "traceMarkerIO") (#set! @keyword.debug priority "9"))
 
 ; ----------------------------------------------------------------------------
 ; Fields
 
 (field_name
-  (variable) @variable.member)
+  (variable) @variable.member (#set! @variable.member priority "9"))
 
 (import_name
   (name)
   .
   (children
-    (variable) @variable.member))
+    (variable) @variable.member) (#set! @variable.member priority "9"))
 
 
 ; ----------------------------------------------------------------------------
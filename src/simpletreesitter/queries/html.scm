@@ -12,7 +12,7 @@
 (quoted_attribute_value) @string
 
 ; ----- entities -----
-(entity) @string.escape
+((entity) @string.escape (#set! @string.escape priority "9"))
 
 ; ----- punctuation -----
 [
@@ -0,0 +1,19 @@
+[
+  (element)
+  (script_element)
+  (style_element)
+  (start_tag)
+  (self_closing_tag)
+] @indent.begin
+
+(end_tag) @indent.branch @indent.end
+
+; the end of a multi-line tag closes that tag, not the element
+">" @indent.branch
+
+"/>" @indent.branch @indent.end
+
+[
+  (comment)
+  (raw_text)
+] @indent.ignore
@@ -0,0 +1,26 @@
+((script_element
+  (raw_text) @injection.content)
+  (#set! injection.language "javascript"))
+
+((style_element
+  (raw_text) @injection.content)
+  (#set! injection.language "css"))
+
+; Vue/Svelte single-file components: <script lang="ts">, <style lang="css">.
+; Listed after the defaults above so they win for the same element; a `lang`
+; without a bundled grammar (scss, pug) falls back to the default.
+((script_element
+  (start_tag
+    (attribute
+      (attribute_name) @_attr
+      (quoted_attribute_value (attribute_value) @injection.language)))
+  (raw_text) @injection.content)
+  (#eq? @_attr "lang"))
+
+((style_element
+  (start_tag
+    (attribute
+      (attribute_name) @_attr
+      (quoted_attribute_value (attribute_value) @injection.language)))
+  (raw_text) @injection.content)
+  (#eq? @_attr "lang"))
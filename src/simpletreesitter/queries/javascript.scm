@@ -4,9 +4,9 @@
 ; ----- strings / regex / escapes -----
 (string) @string
 (template_string) @string
-(escape_sequence) @string.escape
-(regex) @string.regex
-(template_substitution) @string.special
+((escape_sequence) @string.escape (#set! @string.escape priority "9"))
+((regex) @string.regex (#set! @string.regex priority "9"))
+((template_substitution) @string.special (#set! @string.special priority "9"))
 
 ; ----- numbers / booleans / null -----
 (number) @number
@@ -16,7 +16,7 @@
 (undefined) @constant
 
 ; ----- this 表达式 -----
-(this) @variable.builtin
+((this) @variable.builtin (#set! @variable.builtin priority "5"))
 
 ; ----- keywords -----
 "var" @keyword
@@ -61,13 +61,13 @@
 "with" @keyword
 
 ; ----- operators -----
-"=" @keyword.operator
-"+=" @keyword.operator
-"-=" @keyword.operator
-"*=" @keyword.operator
-"/=" @keyword.operator
-"%=" @keyword.operator
-"**=" @keyword.operator
+("=" @keyword.operator (#set! @keyword.operator priority "9"))
+("+=" @keyword.operator (#set! @keyword.operator priority "9"))
+("-=" @keyword.operator (#set! @keyword.operator priority "9"))
+("*=" @keyword.operator (#set! @keyword.operator priority "9"))
+("/=" @keyword.operator (#set! @keyword.operator priority "9"))
+("%=" @keyword.operator (#set! @keyword.operator priority "9"))
+("**=" @keyword.operator (#set! @keyword.operator priority "9"))
 "==" @operator
 "===" @operator
 "!=" @operator
@@ -86,9 +86,9 @@
 "||" @operator
 "!" @operator
 "??" @operator
-"??=" @keyword.operator
-"&&=" @keyword.operator
-"||=" @keyword.operator
+("??=" @keyword.operator (#set! @keyword.operator priority "9"))
+("&&=" @keyword.operator (#set! @keyword.operator priority "9"))
+("||=" @keyword.operator (#set! @keyword.operator priority "9"))
 "=>" @operator
 
 ; ----- punctuation -----
@@ -127,9 +127,9 @@
 (call_expression function: (member_expression property: (property_identifier) @method))
 
 ; ----- parameters -----
-(formal_parameters (identifier) @variable.parameter)
-(formal_parameters (rest_pattern (identifier) @variable.parameter))
-(arrow_function parameter: (identifier) @variable.parameter)
+(formal_parameters (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
+(formal_parameters (rest_pattern (identifier) @variable.parameter) (#set! @variable.parameter priority "5"))
+(arrow_function parameter: (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
 
 ; ----- properties / fields -----
 (pair key: (property_identifier) @property)
@@ -138,10 +138,10 @@
 
 ; ----- builtins -----
 ((identifier) @variable.builtin
-  (#match? @variable.builtin "^(undefined|arguments|NaN|Infinity)$"))
+  (#match? @variable.builtin "^(undefined|arguments|NaN|Infinity)$") (#set! @variable.builtin priority "5"))
 
 ((identifier) @constant.builtin
-  (#match? @constant.builtin "^(console|JSON|Math|Date|Number|String|Boolean|Array|Object|RegExp|Error|Promise|Symbol|BigInt|Map|Set|WeakMap|WeakSet|Proxy|Reflect|globalThis|window|document)$"))
+  (#match? @constant.builtin "^(console|JSON|Math|Date|Number|String|Boolean|Array|Object|RegExp|Error|Promise|Symbol|BigInt|Map|Set|WeakMap|WeakSet|Proxy|Reflect|globalThis|window|document)$") (#set! @constant.builtin priority "5"))
 
 ; ----- fallback -----
 (identifier) @variable
@@ -0,0 +1,34 @@
+[
+  (statement_block)
+  (class_body)
+  (object)
+  (object_pattern)
+  (array)
+  (array_pattern)
+  (arguments)
+  (formal_parameters)
+  (named_imports)
+  (export_clause)
+  (switch_body)
+  (switch_case)
+  (switch_default)
+  (parenthesized_expression)
+] @indent.begin
+
+; continuation lines of a declaration or a method chain
+[
+  (lexical_declaration)
+  (variable_declaration)
+  (member_expression)
+] @indent.begin
+
+[
+  "}"
+  ")"
+  "]"
+] @indent.branch @indent.end
+
+[
+  (template_string)
+  (comment)
+] @indent.ignore
@@ -0,0 +1,16 @@
+; regex literals; no `regex` grammar is bundled, so this only takes effect
+; once one is registered with load_grammar
+((regex_pattern) @injection.content
+  (#set! injection.language "regex"))
+
+; tagged templates named after a language: html`...`, css`...`, sql`...`
+; only the literal fragments: `${...}` stays JavaScript, and the fragments of
+; one language are parsed together as a single document
+(call_expression
+  function: [
+    (identifier) @injection.language
+    (member_expression
+      property: (property_identifier) @injection.language)
+  ]
+  arguments: (template_string
+    (string_fragment) @injection.content))
@@ -6,7 +6,7 @@
 
 ; ----- values -----
 (string) @string
-(escape_sequence) @string.escape
+((escape_sequence) @string.escape (#set! @string.escape priority "9"))
 (number) @number
 (true) @boolean
 (false) @boolean
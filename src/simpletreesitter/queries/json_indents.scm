@@ -0,0 +1,9 @@
+[
+  (object)
+  (array)
+] @indent.begin
+
+[
+  "}"
+  "]"
+] @indent.branch @indent.end
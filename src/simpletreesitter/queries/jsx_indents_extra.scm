@@ -0,0 +1,13 @@
+
+; JSX: children of an element, attributes of a multi-line tag
+[
+  (jsx_element)
+  (jsx_opening_element)
+  (jsx_self_closing_element)
+] @indent.begin
+
+(jsx_closing_element) @indent.branch @indent.end
+
+; a closing `>` or `/>` on its own line goes back to the tag's level
+(jsx_opening_element ">" @indent.branch)
+(jsx_self_closing_element "/>" @indent.branch @indent.end)
@@ -1,8 +1,8 @@
 ; Identifiers
 (identifier) @variable
 
-(field_expression
-  (identifier) @variable.member .)
+((field_expression
+  (identifier) @variable.member .) (#set! @variable.member priority "9"))
 
 ; Symbols
 (quote_expression
@@ -10,31 +10,31 @@
   [
     (identifier)
     (operator)
-  ] @string.special.symbol)
+  ] @string.special.symbol (#set! @string.special.symbol priority "9"))
 
 ; Function calls
 (call_expression
-  (identifier) @function.call)
+  (identifier) @function.call (#set! @function.call priority "9"))
 
 (call_expression
   (field_expression
-    (identifier) @function.call .))
+    (identifier) @function.call .) (#set! @function.call priority "9"))
 
 (broadcast_call_expression
-  (identifier) @function.call)
+  (identifier) @function.call (#set! @function.call priority "9"))
 
 (broadcast_call_expression
   (field_expression
-    (identifier) @function.call .))
+    (identifier) @function.call .) (#set! @function.call priority "9"))
 
 ; Macros
-(macro_identifier) @function.macro
+((macro_identifier) @function.macro (#set! @function.macro priority "9"))
 
 (macro_definition
   (signature
     (call_expression
       .
-      (identifier) @function.macro)))
+      (identifier) @function.macro)) (#set! @function.macro priority "9"))
 
 ; Built-in functions
 ; filter(name -> Base.eval(Core, name) isa Core.Builtin, names(Core))
@@ -42,10 +42,10 @@
   (#any-of? @function.builtin
     "applicable" "fieldtype" "getfield" "getglobal" "invoke" "isa" "isdefined" "modifyfield!"
     "modifyglobal!" "nfields" "replacefield!" "replaceglobal!" "setfield!" "setfieldonce!"
-    "setglobal!" "setglobalonce!" "swapfield!" "swapglobal!" "throw" "tuple" "typeassert" "typeof"))
+    "setglobal!" "setglobalonce!" "swapfield!" "swapglobal!" "throw" "tuple" "typeassert" "typeof") (#set! @function.builtin priority "9"))
 
 ; Type definitions
-(type_head (_) @type.definition)
+(type_head (_) @type.definition (#set! @type.definition priority "9"))
 
 ; Type annotations
 (parametrized_type_expression
@@ -85,7 +85,7 @@
     "OverflowError" "Pair" "Ptr" "QuoteNode" "ReadOnlyMemoryError" "Real" "Ref" "SegmentationFault"
     "Signed" "StackOverflowError" "String" "Symbol" "Task" "Tuple" "Type" "TypeError" "TypeVar"
     "UInt" "UInt128" "UInt16" "UInt32" "UInt64" "UInt8" "UndefInitializer" "UndefKeywordError"
-    "UndefRefError" "UndefVarError" "Union" "UnionAll" "Unsigned" "VecElement" "WeakRef"))
+    "UndefRefError" "UndefVarError" "Union" "UnionAll" "Unsigned" "VecElement" "WeakRef") (#set! @type.builtin priority "9"))
 
 ; Keywords
 [
@@ -116,70 +116,70 @@
   [
     "if"
     "end"
-  ] @keyword.conditional)
+  ] @keyword.conditional (#set! @keyword.conditional priority "9"))
 
 (elseif_clause
-  "elseif" @keyword.conditional)
+  "elseif" @keyword.conditional (#set! @keyword.conditional priority "9"))
 
 (else_clause
-  "else" @keyword.conditional)
+  "else" @keyword.conditional (#set! @keyword.conditional priority "9"))
 
 (ternary_expression
   [
     "?"
     ":"
-  ] @keyword.conditional.ternary)
+  ] @keyword.conditional.ternary (#set! @keyword.conditional.ternary priority "9"))
 
 (try_statement
   [
     "try"
     "end"
-  ] @keyword.exception)
+  ] @keyword.exception (#set! @keyword.exception priority "9"))
 
 (catch_clause
-  "catch" @keyword.exception)
+  "catch" @keyword.exception (#set! @keyword.exception priority "9"))
 
 (finally_clause
-  "finally" @keyword.exception)
+  "finally" @keyword.exception (#set! @keyword.exception priority "9"))
 
 (for_statement
   [
     "for"
     "end"
-  ] @keyword.repeat)
+  ] @keyword.repeat (#set! @keyword.repeat priority "9"))
 
 (for_binding
-  "outer" @keyword.repeat)
+  "outer" @keyword.repeat (#set! @keyword.repeat priority "9"))
 
 ; comprehensions
 (for_clause
-  "for" @keyword.repeat)
+  "for" @keyword.repeat (#set! @keyword.repeat priority "9"))
 
 (if_clause
-  "if" @keyword.conditional)
+  "if" @keyword.conditional (#set! @keyword.conditional priority "9"))
 
 (while_statement
   [
     "while"
     "end"
-  ] @keyword.repeat)
+  ] @keyword.repeat (#set! @keyword.repeat priority "9"))
 
-[
+([
   (break_statement)
   (continue_statement)
-] @keyword.repeat
+] @keyword.repeat (#set! @keyword.repeat priority "9"))
 
 (function_definition
   [
     "function"
     "end"
-  ] @keyword.function)
+  ] @keyword.function (#set! @keyword.function priority "9"))
 
 (do_clause
   [
     "do"
     "end"
-  ] @keyword.function)
+  ] @keyword.function (#set! @keyword.function priority "9"))
 
 (macro_definition
   [
@@ -188,29 +188,29 @@
   ] @keyword)
 
 (return_statement
-  "return" @keyword.return)
+  "return" @keyword.return (#set! @keyword.return priority "9"))
 
 (module_definition
   [
     "module"
     "baremodule"
     "end"
-  ] @keyword.import)
+  ] @keyword.import (#set! @keyword.import priority "9"))
 
 (export_statement
-  "export" @keyword.import)
+  "export" @keyword.import (#set! @keyword.import priority "9"))
 
 (public_statement
-  "public" @keyword.import)
+  "public" @keyword.import (#set! @keyword.import priority "9"))
 
 (import_statement
-  "import" @keyword.import)
+  "import" @keyword.import (#set! @keyword.import priority "9"))
 
 (using_statement
-  "using" @keyword.import)
+  "using" @keyword.import (#set! @keyword.import priority "9"))
 
 (import_alias
-  "as" @keyword.import)
+  "as" @keyword.import (#set! @keyword.import priority "9"))
 
 (selected_import
   ":" @punctuation.delimiter)
@@ -220,21 +220,21 @@
     "mutable"
     "struct"
     "end"
-  ] @keyword.type)
+  ] @keyword.type (#set! @keyword.type priority "9"))
 
 (abstract_definition
   [
     "abstract"
     "type"
     "end"
-  ] @keyword.type)
+  ] @keyword.type (#set! @keyword.type priority "9"))
 
 (primitive_definition
   [
     "primitive"
     "type"
     "end"
-  ] @keyword.type)
+  ] @keyword.type (#set! @keyword.type priority "9"))
 
 ; Operators & Punctuation
 (operator) @operator
@@ -248,11 +248,11 @@
 (arrow_function_expression
   "->" @operator)
 
-[
+([
   "."
   "..."
   "::"
-] @punctuation.special
+] @punctuation.special (#set! @punctuation.special priority "9"))
 
 [
   ","
@@ -270,38 +270,38 @@
 
 ; Keyword operators
 ((operator) @keyword.operator
-  (#any-of? @keyword.operator "in" "isa"))
+  (#any-of? @keyword.operator "in" "isa") (#set! @keyword.operator priority "9"))
 
 (where_expression
-  "where" @keyword.operator)
+  "where" @keyword.operator (#set! @keyword.operator priority "9"))
 
 ; Built-in constants
 ((identifier) @constant.builtin
-  (#any-of? @constant.builtin "nothing" "missing"))
+  (#any-of? @constant.builtin "nothing" "missing") (#set! @constant.builtin priority "5"))
 
 ; Literals
 (boolean_literal) @boolean
 
 (integer_literal) @number
 
-(float_literal) @number.float
+((float_literal) @number.float (#set! @number.float priority "9"))
 
 ((identifier) @number.float
-  (#any-of? @number.float "NaN" "NaN16" "NaN32" "Inf" "Inf16" "Inf32"))
+  (#any-of? @number.float "NaN" "NaN16" "NaN32" "Inf" "Inf16" "Inf32") (#set! @number.float priority "9"))
 
 (character_literal) @character
 
-(escape_sequence) @string.escape
+((escape_sequence) @string.escape (#set! @string.escape priority "9"))
 
 (string_literal) @string
 
-(prefixed_string_literal
-  prefix: (identifier) @function.macro) @string
+((prefixed_string_literal
+  prefix: (identifier) @function.macro) @string (#set! @function.macro priority "9"))
 
-(command_literal) @string.special
+((command_literal) @string.special (#set! @string.special priority "9"))
 
-(prefixed_command_literal
-  prefix: (identifier) @function.macro) @string.special
+((prefixed_command_literal
+  prefix: (identifier) @function.macro) @string.special (#set! @function.macro priority "9") (#set! @string.special priority "9"))
 
 ((string_literal) @string.documentation
   .
@@ -313,7 +313,7 @@
     (macro_definition)
     (module_definition)
     (struct_definition)
-  ])
+  ] (#set! @string.documentation priority "9"))
 
 [
   (line_comment)
@@ -0,0 +1,32 @@
+; module bodies stay flush left by convention
+[
+  (function_definition)
+  (macro_definition)
+  (struct_definition)
+  (if_statement)
+  (for_statement)
+  (while_statement)
+  (try_statement)
+  (let_statement)
+  (do_clause)
+  (compound_statement)
+  (quote_statement)
+  (call_expression)
+] @indent.begin
+
+[
+  (elseif_clause)
+  (else_clause)
+  (catch_clause)
+  (finally_clause)
+] @indent.begin @indent.branch
+
+[
+  "end"
+  ")"
+] @indent.branch @indent.end
+
+[
+  (string_literal)
+  (block_comment)
+] @indent.ignore
@@ -4,14 +4,14 @@
 
 ; ----- strings -----
 (string) @string
-(escape_sequence) @string.escape
+((escape_sequence) @string.escape (#set! @string.escape priority "9"))
 
 ; ----- literals -----
 (number) @number
 (true) @boolean
 (false) @boolean
-(nil) @constant.builtin
-(vararg_expression) @constant.builtin
+((nil) @constant.builtin (#set! @constant.builtin priority "5"))
+((vararg_expression) @constant.builtin (#set! @constant.builtin priority "5"))
 
 ; ----- keywords -----
 [
@@ -34,11 +34,11 @@
 
 (break_statement) @keyword
 
-[
+([
   "and"
   "or"
   "not"
-] @keyword.operator
+] @keyword.operator (#set! @keyword.operator priority "9"))
 
 ; ----- operators -----
 (binary_expression operator: _ @operator)
@@ -78,17 +78,17 @@
   (#any-of? @function.builtin
     "assert" "error" "ipairs" "next" "pairs" "pcall" "print" "rawequal"
     "rawget" "rawlen" "rawset" "require" "select" "setmetatable"
-    "getmetatable" "tonumber" "tostring" "type" "unpack" "xpcall"))
+    "getmetatable" "tonumber" "tostring" "type" "unpack" "xpcall") (#set! @function.builtin priority "9"))
 
 ; ----- fields / properties -----
 (field name: (identifier) @field)
 (dot_index_expression field: (identifier) @property)
 
 ; ----- parameters and variables -----
-(parameters name: (identifier) @variable.parameter)
+(parameters name: (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
 
 ((identifier) @variable.builtin
-  (#eq? @variable.builtin "self"))
+  (#eq? @variable.builtin "self") (#set! @variable.builtin priority "5"))
 
 ((identifier) @constant
   (#match? @constant "^[A-Z][A-Z_0-9]*$"))
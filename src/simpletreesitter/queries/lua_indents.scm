@@ -0,0 +1,29 @@
+[
+  (function_declaration)
+  (function_definition)
+  (if_statement)
+  (for_statement)
+  (while_statement)
+  (repeat_statement)
+  (do_statement)
+  (table_constructor)
+  (arguments)
+  (parameters)
+] @indent.begin
+
+[
+  (elseif_statement)
+  (else_statement)
+] @indent.begin @indent.branch
+
+[
+  "end"
+  "until"
+  "}"
+  ")"
+] @indent.branch @indent.end
+
+[
+  (string)
+  (comment)
+] @indent.ignore
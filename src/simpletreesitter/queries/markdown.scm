@@ -1,12 +1,12 @@
 ; ----- headings -----
-(atx_heading) @text.title
-(setext_heading) @text.title
+((atx_heading) @text.title (#set! @text.title priority "9"))
+((setext_heading) @text.title (#set! @text.title priority "9"))
 
 ; ----- code blocks -----
 (fenced_code_block_delimiter) @punctuation.delimiter
 (info_string (language) @type)
-(code_fence_content) @text.literal
-(indented_code_block) @text.literal
+((code_fence_content) @text.literal (#set! @text.literal priority "9"))
+((indented_code_block) @text.literal (#set! @text.literal priority "9"))
 
 ; ----- block quotes / lists -----
 (block_quote_marker) @punctuation.delimiter
@@ -26,17 +26,17 @@
 ; ----- link reference definitions -----
 (link_reference_definition
   (link_label) @property
-  (link_destination) @text.uri)
+  (link_destination) @text.uri (#set! @text.uri priority "9"))
 (link_title) @string
 
 ; ----- tables (GFM) -----
-(pipe_table_header (pipe_table_cell) @text.strong)
+(pipe_table_header (pipe_table_cell) @text.strong (#set! @text.strong priority "9"))
 (pipe_table_delimiter_row) @punctuation.delimiter
 
 ; ----- escapes / entities -----
-(backslash_escape) @string.escape
-(entity_reference) @string.escape
-(numeric_character_reference) @string.escape
+((backslash_escape) @string.escape (#set! @string.escape priority "9"))
+((entity_reference) @string.escape (#set! @string.escape priority "9"))
+((numeric_character_reference) @string.escape (#set! @string.escape priority "9"))
 
 ; ----- frontmatter -----
 (minus_metadata) @comment
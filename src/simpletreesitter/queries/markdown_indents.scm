@@ -0,0 +1,7 @@
+; continuation lines of a list item line up with its text
+(list_item) @indent.align
+
+[
+  (fenced_code_block)
+  (indented_code_block)
+] @indent.ignore
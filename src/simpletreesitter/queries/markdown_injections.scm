@@ -0,0 +1,20 @@
+; fenced code: the info string names the language; an unlabelled or unknown
+; fence keeps the host's @text.literal
+(fenced_code_block
+  (info_string) @injection.language
+  (code_fence_content) @injection.content)
+
+; the block grammar leaves every span of prose as one opaque `inline` node;
+; without this, emphasis and links are invisible
+((inline) @injection.content
+  (#set! injection.language "markdown_inline"))
+
+((html_block) @injection.content
+  (#set! injection.language "html"))
+
+; front matter
+((minus_metadata) @injection.content
+  (#set! injection.language "yaml"))
+
+((plus_metadata) @injection.content
+  (#set! injection.language "toml"))
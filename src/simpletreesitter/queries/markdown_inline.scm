@@ -1,27 +1,27 @@
 ; ----- code spans -----
-(code_span) @text.literal
+((code_span) @text.literal (#set! @text.literal priority "9"))
 (code_span_delimiter) @punctuation.delimiter
 
 ; ----- emphasis -----
-(emphasis) @text.emphasis
-(strong_emphasis) @text.strong
+((emphasis) @text.emphasis (#set! @text.emphasis priority "9"))
+((strong_emphasis) @text.strong (#set! @text.strong priority "9"))
 (emphasis_delimiter) @punctuation.delimiter
-(strikethrough) @text.strike
+((strikethrough) @text.strike (#set! @text.strike priority "9"))
 
 ; ----- links -----
-(inline_link (link_text) @text.reference)
-(inline_link (link_destination) @text.uri)
-(image (image_description) @text.reference)
-(image (link_destination) @text.uri)
-(full_reference_link (link_text) @text.reference)
+(inline_link (link_text) @text.reference (#set! @text.reference priority "9"))
+(inline_link (link_destination) @text.uri (#set! @text.uri priority "9"))
+(image (image_description) @text.reference (#set! @text.reference priority "9"))
+(image (link_destination) @text.uri (#set! @text.uri priority "9"))
+(full_reference_link (link_text) @text.reference (#set! @text.reference priority "9"))
 (full_reference_link (link_label) @property)
-(collapsed_reference_link (link_text) @text.reference)
-(shortcut_link (link_text) @text.reference)
+(collapsed_reference_link (link_text) @text.reference (#set! @text.reference priority "9"))
+(shortcut_link (link_text) @text.reference (#set! @text.reference priority "9"))
 (link_title) @string
-(uri_autolink) @text.uri
-(email_autolink) @text.uri
+((uri_autolink) @text.uri (#set! @text.uri priority "9"))
+((email_autolink) @text.uri (#set! @text.uri priority "9"))
 
 ; ----- escapes / entities -----
-(backslash_escape) @string.escape
-(entity_reference) @string.escape
-(numeric_character_reference) @string.escape
+((backslash_escape) @string.escape (#set! @string.escape priority "9"))
+((entity_reference) @string.escape (#set! @string.escape priority "9"))
+((numeric_character_reference) @string.escape (#set! @string.escape priority "9"))
@@ -43,3 +43,60 @@ pub const JULIA_QUERY: &str = include_str!("julia.scm");
 pub const JULIA_SYM_QUERY: &str = include_str!("julia_symbols.scm");
 pub const HASKELL_QUERY: &str = include_str!("haskell.scm");
 pub const HASKELL_SYM_QUERY: &str = include_str!("haskell_symbols.scm");
+
+// locals：作用域 / 定义 / 引用，供 references 等按作用域解析标识符的请求使用。
+pub const RUST_LOCALS_QUERY: &str = include_str!("rust_locals.scm");
+pub const JS_LOCALS_QUERY: &str = include_str!("js_locals.scm");
+pub const TS_LOCALS_QUERY: &str = include_str!("typescript_locals.scm");
+pub const PYTHON_LOCALS_QUERY: &str = include_str!("python_locals.scm");
+pub const GO_LOCALS_QUERY: &str = include_str!("go_locals.scm");
+pub const C_LOCALS_QUERY: &str = include_str!("c_locals.scm");
+// C++ 语法沿用 C 的节点：共享 C 的 locals，再追加 C++ 专属模式。
+pub const CPP_LOCALS_QUERY: &str = concat!(
+    include_str!("c_locals.scm"),
+    include_str!("cpp_locals_extra.scm")
+);
+
+// indents：缩进查询（@indent.begin/end/branch/dedent/align/ignore），供 indent 请求计算行缩进。
+pub const RUST_INDENTS_QUERY: &str = include_str!("rust_indents.scm");
+pub const JS_INDENTS_QUERY: &str = concat!(
+    include_str!("js_indents.scm"),
+    include_str!("jsx_indents_extra.scm")
+);
+// TypeScript 没有 JSX 节点；TSX 两份追加都要。
+pub const TS_INDENTS_QUERY: &str = concat!(
+    include_str!("js_indents.scm"),
+    include_str!("typescript_indents_extra.scm")
+);
+pub const TSX_INDENTS_QUERY: &str = concat!(
+    include_str!("js_indents.scm"),
+    include_str!("typescript_indents_extra.scm"),
+    include_str!("jsx_indents_extra.scm")
+);
+pub const PYTHON_INDENTS_QUERY: &str = include_str!("python_indents.scm");
+pub const GO_INDENTS_QUERY: &str = include_str!("go_indents.scm");
+pub const C_INDENTS_QUERY: &str = include_str!("c_indents.scm");
+pub const CPP_INDENTS_QUERY: &str = concat!(
+    include_str!("c_indents.scm"),
+    include_str!("cpp_indents_extra.scm")
+);
+pub const BASH_INDENTS_QUERY: &str = include_str!("bash_indents.scm");
+pub const JSON_INDENTS_QUERY: &str = include_str!("json_indents.scm");
+pub const YAML_INDENTS_QUERY: &str = include_str!("yaml_indents.scm");
+pub const TOML_INDENTS_QUERY: &str = include_str!("toml_indents.scm");
+pub const HTML_INDENTS_QUERY: &str = include_str!("html_indents.scm");
+pub const CSS_INDENTS_QUERY: &str = include_str!("css_indents.scm");
+pub const LUA_INDENTS_QUERY: &str = include_str!("lua_indents.scm");
+pub const JULIA_INDENTS_QUERY: &str = include_str!("julia_indents.scm");
+pub const MD_INDENTS_QUERY: &str = include_str!("markdown_indents.scm");
+pub const VIM_INDENTS_QUERY: &str = include_str!("vim_indents.scm");
+
+// injections：@injection.content 交给 @injection.language（或 `#set! injection.language`）
+// 指定的语法解析，供注入高亮使用。
+pub const MD_INJECTIONS_QUERY: &str = include_str!("markdown_injections.scm");
+pub const HTML_INJECTIONS_QUERY: &str = include_str!("html_injections.scm");
+// TypeScript/TSX 沿用 JavaScript 的正则字面量与模板字符串节点。
+pub const JS_INJECTIONS_QUERY: &str = include_str!("js_injections.scm");
+pub const PYTHON_INJECTIONS_QUERY: &str = include_str!("python_injections.scm");
+pub const RUST_INJECTIONS_QUERY: &str = include_str!("rust_injections.scm");
+pub const YAML_INJECTIONS_QUERY: &str = include_str!("yaml_injections.scm");
@@ -4,9 +4,9 @@
 ; ----- strings -----
 (string) @string
 (concatenated_string) @string
-(escape_sequence) @string.escape
-(interpolation) @string.special
-(format_expression) @string.special
+((escape_sequence) @string.escape (#set! @string.escape priority "9"))
+((interpolation) @string.special (#set! @string.special priority "9"))
+((format_expression) @string.special (#set! @string.special priority "9"))
 
 ; ----- numbers / booleans / none -----
 (integer) @number
@@ -76,20 +76,20 @@
 ">=" @operator
 "==" @operator
 "!=" @operator
-"=" @keyword.operator
-"+=" @keyword.operator
-"-=" @keyword.operator
-"*=" @keyword.operator
-"/=" @keyword.operator
-"//=" @keyword.operator
-"%=" @keyword.operator
-"**=" @keyword.operator
-"|=" @keyword.operator
-"&=" @keyword.operator
-"^=" @keyword.operator
-"<<=" @keyword.operator
-">>=" @keyword.operator
-":=" @keyword.operator
+("=" @keyword.operator (#set! @keyword.operator priority "9"))
+("+=" @keyword.operator (#set! @keyword.operator priority "9"))
+("-=" @keyword.operator (#set! @keyword.operator priority "9"))
+("*=" @keyword.operator (#set! @keyword.operator priority "9"))
+("/=" @keyword.operator (#set! @keyword.operator priority "9"))
+("//=" @keyword.operator (#set! @keyword.operator priority "9"))
+("%=" @keyword.operator (#set! @keyword.operator priority "9"))
+("**=" @keyword.operator (#set! @keyword.operator priority "9"))
+("|=" @keyword.operator (#set! @keyword.operator priority "9"))
+("&=" @keyword.operator (#set! @keyword.operator priority "9"))
+("^=" @keyword.operator (#set! @keyword.operator priority "9"))
+("<<=" @keyword.operator (#set! @keyword.operator priority "9"))
+(">>=" @keyword.operator (#set! @keyword.operator priority "9"))
+(":=" @keyword.operator (#set! @keyword.operator priority "9"))
 "->" @operator
 
 ; ----- punctuation -----
@@ -115,21 +115,25 @@
 (call function: (attribute attribute: (identifier) @method))
 
 ; ----- parameters -----
-(parameters (identifier) @variable.parameter)
-(default_parameter name: (identifier) @variable.parameter)
-(typed_parameter (identifier) @variable.parameter)
-(typed_default_parameter name: (identifier) @variable.parameter)
-(lambda_parameters (identifier) @variable.parameter)
+(parameters (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
+(default_parameter name: (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
+(typed_parameter (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
+(typed_default_parameter name: (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
+(lambda_parameters (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
 
 ; ----- builtins -----
 ((identifier) @variable.builtin
-  (#match? @variable.builtin "^(self|cls)$"))
+  (#match? @variable.builtin "^(self|cls)$") (#set! @variable.builtin priority "5"))
 
 ((identifier) @function.builtin
-  (#match? @function.builtin "^(print|len|range|type|int|str|float|list|dict|set|tuple|bool|input|open|map|filter|zip|enumerate|sorted|reversed|sum|min|max|abs|round|any|all|isinstance|issubclass|hasattr|getattr|setattr|delattr|repr|id|hash|hex|oct|bin|chr|ord|super|property|staticmethod|classmethod)$"))
+  (#match? @function.builtin "^(print|len|range|type|int|str|float|list|dict|set|tuple|bool|input|open|map|filter|zip|enumerate|sorted|reversed|sum|min|max|abs|round|any|all|isinstance|issubclass|hasattr|getattr|setattr|delattr|repr|id|hash|hex|oct|bin|chr|ord|super|property|staticmethod|classmethod)$") (#set! @function.builtin priority "9"))
 
 ((identifier) @constant.builtin
-  (#match? @constant.builtin "^(NotImplemented|Ellipsis|__name__|__file__|__doc__|__debug__|__package__|__spec__|__loader__|__builtins__)$"))
+  (#match? @constant.builtin "^(NotImplemented|Ellipsis|__name__|__file__|__doc__|__debug__|__package__|__spec__|__loader__|__builtins__)$") (#set! @constant.builtin priority "5"))
+
+; module-level constants are SCREAMING_CASE by convention
+((identifier) @constant
+  (#lua-match? @constant "^[A-Z][A-Z%d_]*$"))
 
 ; ----- properties / attributes -----
 (attribute attribute: (identifier) @property)
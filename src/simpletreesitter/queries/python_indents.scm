@@ -0,0 +1,50 @@
+; compound statements: the body follows the header's colon, and the block may
+; still be empty while it is being typed, hence immediate
+([
+  (function_definition)
+  (class_definition)
+  (if_statement)
+  (for_statement)
+  (while_statement)
+  (with_statement)
+  (try_statement)
+  (match_statement)
+  (case_clause)
+  (elif_clause)
+  (else_clause)
+  (except_clause)
+  (finally_clause)
+] @indent.begin
+  (#set! indent.immediate "1"))
+
+; clauses sit at the level of the statement they continue
+[
+  (elif_clause)
+  (else_clause)
+  (except_clause)
+  (finally_clause)
+] @indent.branch
+
+; brackets align with what follows the opening delimiter, or hang one level
+[
+  (argument_list)
+  (parameters)
+  (list)
+  (tuple)
+  (dictionary)
+  (set)
+  (parenthesized_expression)
+  (list_comprehension)
+  (dictionary_comprehension)
+  (set_comprehension)
+  (generator_expression)
+  (import_from_statement)
+] @indent.align
+
+[
+  "}"
+  ")"
+  "]"
+] @indent.branch @indent.end
+
+(string) @indent.ignore
@@ -0,0 +1,9 @@
+; a comment naming a language tags the string assigned on the next line:
+;   # sql
+;   QUERY = "SELECT id FROM users"
+((comment) @injection.language
+  .
+  (expression_statement
+    (assignment
+      right: (string (string_content) @injection.content)))
+  (#lua-match? @injection.language "^#%s*[%w_+-]+%s*$"))
@@ -0,0 +1,42 @@
+; scopes
+(module) @local.scope
+(function_definition) @local.scope
+(class_definition) @local.scope
+(lambda) @local.scope
+(list_comprehension) @local.scope
+(set_comprehension) @local.scope
+(dictionary_comprehension) @local.scope
+(generator_expression) @local.scope
+
+; definitions
+(function_definition name: (identifier) @local.definition.function
+  (#set! definition.function.scope "parent"))
+(class_definition name: (identifier) @local.definition.type
+  (#set! definition.type.scope "parent"))
+
+; parameters
+(parameters (identifier) @local.definition.parameter)
+(lambda_parameters (identifier) @local.definition.parameter)
+(default_parameter name: (identifier) @local.definition.parameter)
+(typed_parameter (identifier) @local.definition.parameter)
+(typed_default_parameter name: (identifier) @local.definition.parameter)
+(list_splat_pattern (identifier) @local.definition.parameter)
+(dictionary_splat_pattern (identifier) @local.definition.parameter)
+
+; assignments and loop targets
+(assignment left: (identifier) @local.definition.var)
+(pattern_list (identifier) @local.definition.var)
+(tuple_pattern (identifier) @local.definition.var)
+(list_pattern (identifier) @local.definition.var)
+(for_statement left: (identifier) @local.definition.var)
+(for_in_clause left: (identifier) @local.definition.var)
+(as_pattern_target (identifier) @local.definition.var)
+(named_expression name: (identifier) @local.definition.var)
+
+; imports: `import a.b` binds `a`, aliases bind the alias
+(import_statement name: (dotted_name . (identifier) @local.definition.import))
+(aliased_import alias: (identifier) @local.definition.import)
+(import_from_statement name: (dotted_name (identifier) @local.definition.import))
+
+; references
+(identifier) @local.reference
@@ -23,7 +23,7 @@
 (crate) @keyword
 (mutable_specifier) @keyword
 (super) @keyword
-(self) @variable.builtin
+((self) @variable.builtin (#set! @variable.builtin priority "5"))
 
 ; ----- functions / methods / types -----
 (function_item name: (identifier) @function)
@@ -32,11 +32,11 @@
   function: (field_expression
               field: (field_identifier) @method))
 (type_identifier) @type
-(primitive_type) @type.builtin
+((primitive_type) @type.builtin (#set! @type.builtin priority "9"))
 
 ; ----- parameters -----
-(parameter pattern: (identifier) @variable.parameter)
-(closure_parameters (identifier) @variable.parameter)
+(parameter pattern: (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
+(closure_parameters (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
 
 ; ----- fields -----
 (field_identifier) @field
@@ -44,7 +44,7 @@
 ; ----- macros / attributes / lifetime -----
 (macro_invocation macro: (identifier) @macro)
 (attribute_item) @attribute
-(lifetime) @type.builtin
+((lifetime) @type.builtin (#set! @type.builtin priority "9"))
 
 ; ----- punctuation / operators -----
 "(" @punctuation.bracket
@@ -80,5 +80,9 @@
 "|" @operator
 "^" @operator
 
+; ----- SCREAMING_CASE consts and statics -----
+((identifier) @constant
+  (#lua-match? @constant "^[A-Z][A-Z%d_]*$"))
+
 ; ----- fallback variables -----
 (identifier) @variable
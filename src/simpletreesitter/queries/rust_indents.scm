@@ -0,0 +1,40 @@
+; blocks and bracketed lists indent their contents
+[
+  (block)
+  (declaration_list)
+  (field_declaration_list)
+  (enum_variant_list)
+  (field_initializer_list)
+  (match_block)
+  (arguments)
+  (parameters)
+  (type_arguments)
+  (type_parameters)
+  (token_tree)
+  (use_list)
+  (array_expression)
+  (tuple_expression)
+  (tuple_pattern)
+  (struct_pattern)
+] @indent.begin
+
+; continuation lines of a statement or expression
+[
+  (let_declaration)
+  (match_arm)
+  (where_clause)
+  (field_expression)
+] @indent.begin
+
+; closing delimiters line up with the line that opened them
+[
+  "}"
+  ")"
+  "]"
+] @indent.branch @indent.end
+
+[
+  (string_literal)
+  (raw_string_literal)
+  (block_comment)
+] @indent.ignore
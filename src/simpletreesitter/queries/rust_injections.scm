@@ -0,0 +1,8 @@
+; doc comments are markdown; their ```rust blocks are fences in it
+((line_comment
+  doc: (doc_comment) @injection.content)
+  (#set! injection.language "markdown"))
+
+((block_comment
+  doc: (doc_comment) @injection.content)
+  (#set! injection.language "markdown"))
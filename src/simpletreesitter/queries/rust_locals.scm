@@ -0,0 +1,68 @@
+; scopes
+(source_file) @local.scope
+(block) @local.scope
+(function_item) @local.scope
+(closure_expression) @local.scope
+(match_arm) @local.scope
+(if_expression) @local.scope
+(while_expression) @local.scope
+(for_expression) @local.scope
+(impl_item) @local.scope
+(trait_item) @local.scope
+(struct_item) @local.scope
+(enum_item) @local.scope
+(union_item) @local.scope
+(type_item) @local.scope
+(mod_item) @local.scope
+
+; items: the name belongs to the enclosing scope, the generics to the item
+(function_item name: (identifier) @local.definition.function
+  (#set! definition.function.scope "parent"))
+(struct_item name: (type_identifier) @local.definition.type
+  (#set! definition.type.scope "parent"))
+(enum_item name: (type_identifier) @local.definition.type
+  (#set! definition.type.scope "parent"))
+(union_item name: (type_identifier) @local.definition.type
+  (#set! definition.type.scope "parent"))
+(trait_item name: (type_identifier) @local.definition.type
+  (#set! definition.type.scope "parent"))
+(type_item name: (type_identifier) @local.definition.type
+  (#set! definition.type.scope "parent"))
+(mod_item name: (identifier) @local.definition.namespace
+  (#set! definition.namespace.scope "parent"))
+(const_item name: (identifier) @local.definition.constant)
+(static_item name: (identifier) @local.definition.constant)
+(macro_definition name: (identifier) @local.definition.macro)
+(type_parameter name: (type_identifier) @local.definition.type)
+
+; parameters
+(parameter pattern: (identifier) @local.definition.parameter)
+(closure_parameters (identifier) @local.definition.parameter)
+(self_parameter (self) @local.definition.parameter)
+
+; bindings introduced by patterns (let, if let, match, for, parameters)
+(let_declaration pattern: (identifier) @local.definition.var)
+(let_condition pattern: (identifier) @local.definition.var)
+(for_expression pattern: (identifier) @local.definition.var)
+(match_pattern (identifier) @local.definition.var)
+(tuple_pattern (identifier) @local.definition.var)
+(tuple_struct_pattern type: (_) (identifier) @local.definition.var)
+(slice_pattern (identifier) @local.definition.var)
+(ref_pattern (identifier) @local.definition.var)
+(mut_pattern (identifier) @local.definition.var)
+(captured_pattern (identifier) @local.definition.var)
+(or_pattern (identifier) @local.definition.var)
+(field_pattern name: (shorthand_field_identifier) @local.definition.var)
+(field_pattern pattern: (identifier) @local.definition.var)
+
+; imports
+(use_declaration argument: (identifier) @local.definition.import)
+(use_declaration argument: (scoped_identifier name: (identifier) @local.definition.import))
+(use_as_clause alias: (identifier) @local.definition.import)
+(use_list (identifier) @local.definition.import)
+(use_list (scoped_identifier name: (identifier) @local.definition.import))
+
+; references
+(identifier) @local.reference
+(type_identifier) @local.reference
+(self) @local.reference
@@ -17,17 +17,17 @@
 
 ; ----- values -----
 (string) @string
-(escape_sequence) @string.escape
+((escape_sequence) @string.escape (#set! @string.escape priority "9"))
 (integer) @number
 (float) @number
 (boolean) @boolean
-(offset_date_time) @string.special
-(local_date_time) @string.special
-(local_date) @string.special
-(local_time) @string.special
+((offset_date_time) @string.special (#set! @string.special priority "9"))
+((local_date_time) @string.special (#set! @string.special priority "9"))
+((local_date) @string.special (#set! @string.special priority "9"))
+((local_time) @string.special (#set! @string.special priority "9"))
 
 ; ----- punctuation -----
-"=" @keyword.operator
+("=" @keyword.operator (#set! @keyword.operator priority "9"))
 "{" @punctuation.bracket
 "}" @punctuation.bracket
 "[" @punctuation.bracket
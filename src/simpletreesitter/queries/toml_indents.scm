@@ -0,0 +1,13 @@
+[
+  (array)
+  (inline_table)
+] @indent.begin
+
+[
+  "}"
+  "]"
+] @indent.branch @indent.end
+
+[
+  (string)
+] @indent.ignore
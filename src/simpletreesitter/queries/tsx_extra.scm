@@ -3,5 +3,5 @@
 (jsx_closing_element name: (identifier) @type)
 (jsx_self_closing_element name: (identifier) @type)
 (jsx_attribute (property_identifier) @property)
-(jsx_expression) @string.special
+((jsx_expression) @string.special (#set! @string.special priority "9"))
 (jsx_text) @string
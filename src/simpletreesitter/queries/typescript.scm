@@ -4,9 +4,9 @@
 ; ----- strings / regex / escapes -----
 (string) @string
 (template_string) @string
-(escape_sequence) @string.escape
-(regex) @string.regex
-(template_substitution) @string.special
+((escape_sequence) @string.escape (#set! @string.escape priority "9"))
+((regex) @string.regex (#set! @string.regex priority "9"))
+((template_substitution) @string.special (#set! @string.special priority "9"))
 
 ; ----- numbers / booleans / null -----
 (number) @number
@@ -16,7 +16,7 @@
 (undefined) @constant
 
 ; ----- this 表达式 -----
-(this) @variable.builtin
+((this) @variable.builtin (#set! @variable.builtin priority "5"))
 
 ; ----- keywords -----
 "var" @keyword
@@ -74,21 +74,21 @@
 "protected" @keyword
 "override" @keyword
 "implements" @keyword
-"keyof" @keyword.operator
-"as" @keyword.operator
-"satisfies" @keyword.operator
-"is" @keyword.operator
-"infer" @keyword.operator
+("keyof" @keyword.operator (#set! @keyword.operator priority "9"))
+("as" @keyword.operator (#set! @keyword.operator priority "9"))
+("satisfies" @keyword.operator (#set! @keyword.operator priority "9"))
+("is" @keyword.operator (#set! @keyword.operator priority "9"))
+("infer" @keyword.operator (#set! @keyword.operator priority "9"))
 "asserts" @keyword
 
 ; ----- operators -----
-"=" @keyword.operator
-"+=" @keyword.operator
-"-=" @keyword.operator
-"*=" @keyword.operator
-"/=" @keyword.operator
-"%=" @keyword.operator
-"**=" @keyword.operator
+("=" @keyword.operator (#set! @keyword.operator priority "9"))
+("+=" @keyword.operator (#set! @keyword.operator priority "9"))
+("-=" @keyword.operator (#set! @keyword.operator priority "9"))
+("*=" @keyword.operator (#set! @keyword.operator priority "9"))
+("/=" @keyword.operator (#set! @keyword.operator priority "9"))
+("%=" @keyword.operator (#set! @keyword.operator priority "9"))
+("**=" @keyword.operator (#set! @keyword.operator priority "9"))
 "==" @operator
 "===" @operator
 "!=" @operator
@@ -107,9 +107,9 @@
 "||" @operator
 "!" @operator
 "??" @operator
-"??=" @keyword.operator
-"&&=" @keyword.operator
-"||=" @keyword.operator
+("??=" @keyword.operator (#set! @keyword.operator priority "9"))
+("&&=" @keyword.operator (#set! @keyword.operator priority "9"))
+("||=" @keyword.operator (#set! @keyword.operator priority "9"))
 "=>" @operator
 "|" @operator
 "&" @operator
@@ -130,7 +130,7 @@
 
 ; ----- types -----
 (type_identifier) @type
-(predefined_type) @type.builtin
+((predefined_type) @type.builtin (#set! @type.builtin priority "9"))
 
 ; ----- decorators -----
 (decorator) @attribute
@@ -165,10 +165,10 @@
 (call_expression function: (member_expression property: (property_identifier) @method))
 
 ; ----- parameters -----
-(required_parameter pattern: (identifier) @variable.parameter)
-(required_parameter pattern: (rest_pattern (identifier) @variable.parameter))
-(optional_parameter pattern: (identifier) @variable.parameter)
-(arrow_function parameter: (identifier) @variable.parameter)
+(required_parameter pattern: (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
+(required_parameter pattern: (rest_pattern (identifier) @variable.parameter) (#set! @variable.parameter priority "5"))
+(optional_parameter pattern: (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
+(arrow_function parameter: (identifier) @variable.parameter (#set! @variable.parameter priority "5"))
 
 ; ----- properties / fields -----
 (pair key: (property_identifier) @property)
@@ -180,10 +180,10 @@
 
 ; ----- builtins -----
 ((identifier) @variable.builtin
-  (#match? @variable.builtin "^(undefined|arguments|NaN|Infinity)$"))
+  (#match? @variable.builtin "^(undefined|arguments|NaN|Infinity)$") (#set! @variable.builtin priority "5"))
 
 ((identifier) @constant.builtin
-  (#match? @constant.builtin "^(console|JSON|Math|Date|Number|String|Boolean|Array|Object|RegExp|Error|Promise|Symbol|BigInt|Map|Set|WeakMap|WeakSet|Proxy|Reflect|globalThis|window|document)$"))
+  (#match? @constant.builtin "^(console|JSON|Math|Date|Number|String|Boolean|Array|Object|RegExp|Error|Promise|Symbol|BigInt|Map|Set|WeakMap|WeakSet|Proxy|Reflect|globalThis|window|document)$") (#set! @constant.builtin priority "5"))
 
 ; ----- fallback -----
 (identifier) @variable
@@ -0,0 +1,9 @@
+
+; TypeScript additions on top of the JavaScript indents
+[
+  (interface_body)
+  (object_type)
+  (enum_body)
+  (type_arguments)
+  (type_parameters)
+] @indent.begin
@@ -0,0 +1,56 @@
+; scopes
+(program) @local.scope
+(statement_block) @local.scope
+(function_declaration) @local.scope
+(generator_function_declaration) @local.scope
+(function_expression) @local.scope
+(generator_function) @local.scope
+(arrow_function) @local.scope
+(method_definition) @local.scope
+(class_body) @local.scope
+(for_statement) @local.scope
+(for_in_statement) @local.scope
+(catch_clause) @local.scope
+(class_declaration) @local.scope
+(interface_declaration) @local.scope
+(type_alias_declaration) @local.scope
+
+; declarations whose name is visible around the declaring node
+(function_declaration name: (identifier) @local.definition.function
+  (#set! definition.function.scope "parent"))
+(generator_function_declaration name: (identifier) @local.definition.function
+  (#set! definition.function.scope "parent"))
+(function_expression name: (identifier) @local.definition.function)
+(class_declaration name: (type_identifier) @local.definition.type
+  (#set! definition.type.scope "parent"))
+(interface_declaration name: (type_identifier) @local.definition.type
+  (#set! definition.type.scope "parent"))
+(type_alias_declaration name: (type_identifier) @local.definition.type
+  (#set! definition.type.scope "parent"))
+(enum_declaration name: (identifier) @local.definition.type)
+(type_parameter name: (type_identifier) @local.definition.type)
+
+; variables and destructuring
+(variable_declarator name: (identifier) @local.definition.var)
+(object_pattern (shorthand_property_identifier_pattern) @local.definition.var)
+(pair_pattern value: (identifier) @local.definition.var)
+(array_pattern (identifier) @local.definition.var)
+(for_in_statement left: (identifier) @local.definition.var)
+(catch_clause parameter: (identifier) @local.definition.var)
+
+; parameters
+(required_parameter pattern: (identifier) @local.definition.parameter)
+(optional_parameter pattern: (identifier) @local.definition.parameter)
+(required_parameter pattern: (rest_pattern (identifier) @local.definition.parameter))
+(arrow_function parameter: (identifier) @local.definition.parameter)
+
+; imports
+(import_clause (identifier) @local.definition.import)
+(import_specifier name: (identifier) @local.definition.import)
+(import_specifier alias: (identifier) @local.definition.import)
+(namespace_import (identifier) @local.definition.import)
+
+; references
+(identifier) @local.reference
+(shorthand_property_identifier) @local.reference
+(type_identifier) @local.reference
@@ -10,7 +10,7 @@
 ; 标识符与变量
 (identifier)   @variable
 (scope_var)    @variable
-(option_var)   @variable.builtin
+((option_var)   @variable.builtin (#set! @variable.builtin priority "5"))
 
 ; 通用 Ex 命令名（你语法里是命名节点）
 (command_name) @keyword
@@ -27,14 +27,14 @@
 (type (identifier) @type)
 (type [
   "bool" "number" "float" "string" "any"
-] @type.builtin)
+] @type.builtin (#set! @type.builtin priority "9"))
 
 ; 字典键/属性
 (dict_key (identifier) @property)
 (dict_key (string)     @property)
 
 ; 特殊按键（命名节点）
-(special_key) @string.special
+((special_key) @string.special (#set! @string.special priority "9"))
 
 ; 括号
 [
@@ -0,0 +1,22 @@
+; vim_indents.scm — Vim9 的缩进查询
+; 语法只把 def ... enddef 建成块；if/for/while 是普通 Ex 命令，不带结构
+
+(def_function) @indent.begin
+
+; 结尾关键字按文本匹配，不依赖它在语法里的节点名
+(def_function
+  _ @indent.branch @indent.end
+  (#any-of? @indent.branch "enddef" "endfunction" "endfunc"))
+
+; 跨行的列表、字典与参数
+(_ "[") @indent.begin
+(_ "{") @indent.begin
+(_ "(") @indent.begin
+
+[
+  "]"
+  "}"
+  ")"
+] @indent.branch @indent.end
+
+(comment) @indent.ignore
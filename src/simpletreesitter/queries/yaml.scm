@@ -16,16 +16,16 @@
 (double_quote_scalar) @string
 (single_quote_scalar) @string
 (block_scalar) @string
-(escape_sequence) @string.escape
+((escape_sequence) @string.escape (#set! @string.escape priority "9"))
 (integer_scalar) @number
 (float_scalar) @number
 (boolean_scalar) @boolean
 (null_scalar) @constant
-(timestamp_scalar) @string.special
+((timestamp_scalar) @string.special (#set! @string.special priority "9"))
 
 ; ----- anchors / aliases / tags -----
-(anchor) @constant.builtin
-(alias) @constant.builtin
+((anchor) @constant.builtin (#set! @constant.builtin priority "5"))
+((alias) @constant.builtin (#set! @constant.builtin priority "5"))
 (tag) @type
 
 ; ----- directives -----
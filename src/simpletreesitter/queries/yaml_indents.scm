@@ -0,0 +1,15 @@
+[
+  (block_mapping_pair)
+  (flow_mapping)
+  (flow_sequence)
+] @indent.begin
+
+; keys of a mapping that starts on the `- ` line line up after the dash
+(block_sequence_item) @indent.align
+
+[
+  "}"
+  "]"
+] @indent.branch @indent.end
+
+(block_scalar) @indent.ignore
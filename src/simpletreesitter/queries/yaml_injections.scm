@@ -0,0 +1,25 @@
+; CI recipes: GitHub Actions `run:`, GitLab `script:` and friends
+(block_mapping_pair
+  key: (flow_node) @_key
+  value: (flow_node
+    (plain_scalar (string_scalar) @injection.content))
+  (#any-of? @_key "run" "script" "before_script" "after_script")
+  (#set! injection.language "bash"))
+
+; `|` and `>` block scalars: skip the indicator
+(block_mapping_pair
+  key: (flow_node) @_key
+  value: (block_node (block_scalar) @injection.content)
+  (#any-of? @_key "run" "script" "before_script" "after_script")
+  (#set! injection.language "bash")
+  (#offset! @injection.content 0 1 0 0))
+
+; list items of a `script:` sequence
+(block_mapping_pair
+  key: (flow_node) @_key
+  value: (block_node
+    (block_sequence
+      (block_sequence_item
+        (flow_node (plain_scalar (string_scalar) @injection.content)))))
+  (#any-of? @_key "script" "before_script" "after_script")
+  (#set! injection.language "bash"))
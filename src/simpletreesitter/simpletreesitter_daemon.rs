@@ -1,7 +1,7 @@
 use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::ops;
 use tree_sitter::StreamingIterator;
 
@@ -28,6 +28,18 @@ const SUPPORTED_LANGUAGES: &[&str] = &[
     "julia",
     "haskell",
 ];
+/// Linked languages that ship a locals query, the ones `references`, `rename`
+/// and the scope-aware half of `definition` answer for. Sent in `hello`.
+const LOCALS_LANGUAGES: &[&str] = &[
+    "rust",
+    "javascript",
+    "typescript",
+    "tsx",
+    "c",
+    "cpp",
+    "python",
+    "go",
+];
 const PROTOCOL_VERSION: u32 = 7;
 
 const MAX_AST_NODES: usize = 50_000;
@@ -39,15 +51,57 @@ const MAX_CACHED_SOURCE_BYTES: usize = 256 * 1024 * 1024;
 const MAX_HIGHLIGHT_SPANS: usize = 100_000;
 const MAX_SYMBOLS: usize = 100_000;
 const MAX_FOLDS: usize = 50_000;
+/// Syntax errors reported for one buffer. A half-typed file has a handful; a
+/// binary blob opened as C has one per line, and nobody reads past the first
+/// screenful of squiggles anyway.
+const MAX_DIAGNOSTICS: usize = 5_000;
+/// Bytes of offending source quoted in an "unexpected" message.
+const MAX_DIAGNOSTIC_SNIPPET: usize = 32;
+/// Occurrences reported for one `references` request.
+const MAX_REFERENCES: usize = 10_000;
+/// Lines answered by one `indent` request: a `=` over a whole large file is
+/// split by the client rather than computed in one blocking reply.
+const MAX_INDENT_LINES: usize = 10_000;
+/// Captures returned for one ad-hoc `query` request.
+const MAX_QUERY_CAPTURES: usize = 10_000;
+/// Size of the pattern text a `query` request may send. A playground pattern
+/// is a few lines; anything near this is a mistake, not a query.
+const MAX_QUERY_SOURCE_BYTES: usize = 64 * 1024;
+/// In-progress matches one ad-hoc query may hold. Patterns like
+/// `(_ (_) @a (_) @b)` otherwise keep every pairing of siblings alive.
+const MAX_QUERY_IN_PROGRESS: u32 = 4_096;
 const LINE_INDEX_STRIDE: usize = 256;
 /// Ancestors reported for one `scope` request. Deep enough for any real nesting
 /// and shallow enough that a pathological tree cannot make an interactive
 /// keystroke serialise an unbounded payload.
 const MAX_SCOPE_CHAIN: usize = 256;
-/// Injected ranges per buffer. Generous because markdown emits one `inline`
-/// range per paragraph, and stingy enough that a pathological document cannot
-/// make one sync parse an unbounded number of keyholes.
+/// Injected ranges per buffer, all levels together. Generous because markdown
+/// emits one `inline` range per paragraph, and stingy enough that a
+/// pathological document cannot make one sync parse an unbounded number of
+/// keyholes.
 const MAX_INJECTED_RANGES: usize = 8_192;
+/// Levels of injection into injection: markdown inline prose in a markdown
+/// fence in a rust doc comment is three.
+const DEFAULT_INJECTION_DEPTH: usize = 4;
+/// Ceiling for `set_injection_depth`. Each level costs one parse per language
+/// per sync, so the limit bounds sync cost, not only recursion.
+const MAX_INJECTION_DEPTH: usize = 8;
+/// How long a `--listen` daemon stays up with no client connected.
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 600;
+/// Ceiling on threads answering read-only requests. Each holds one buffer
+/// snapshot while it runs, so more would mostly buy memory.
+const MAX_WORKERS: usize = 4;
+/// Threads per `--listen` connection: enough that its `scope` need not wait
+/// for its own full `highlight`, without one thread set per editor outgrowing
+/// the stdio daemon's.
+const LISTEN_WORKERS: usize = 2;
+/// Average lines per block of a delta highlight reply. Small enough that one
+/// typed character resends a screenful or so, large enough that the digests
+/// for a 100k-line file stay a few thousand entries.
+const HIGHLIGHT_BLOCK_LINES: u32 = 64;
+/// Longest a delta block may run when its text offers no boundary, as in a
+/// long run of identical lines.
+const MAX_HIGHLIGHT_BLOCK_LINES: u32 = 16 * HIGHLIGHT_BLOCK_LINES;
 
 fn default_true() -> bool {
     true
@@ -111,6 +165,39 @@ fn digest_symbols(symbols: &[Symbol]) -> String {
         hash.str(symbol.container_name.as_deref().unwrap_or(""));
         hash.u32(symbol.container_lnum.unwrap_or(0));
         hash.u32(symbol.container_col.unwrap_or(0));
+        hash.str(symbol.lang.unwrap_or(""));
+    }
+    hash.finish()
+}
+
+fn digest_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    let mut hash = Fnv1a::new();
+    hash.u32(diagnostics.len() as u32);
+    for diagnostic in diagnostics {
+        hash.u32(diagnostic.lnum);
+        hash.u32(diagnostic.col);
+        hash.u32(diagnostic.end_lnum);
+        hash.u32(diagnostic.end_col);
+        hash.str(diagnostic.kind);
+        hash.str(&diagnostic.message);
+        hash.str(diagnostic.parent.unwrap_or(""));
+        hash.str(diagnostic.injected_lang.unwrap_or(""));
+    }
+    hash.finish()
+}
+
+/// Digest of the spans of the delta block starting at line `origin`, with
+/// line numbers taken from there, so that a block that only moved keeps it.
+fn digest_spans(spans: &[Span], origin: u32) -> String {
+    let mut hash = Fnv1a::new();
+    hash.u32(spans.len() as u32);
+    for span in spans {
+        hash.u32(span.lnum - origin);
+        hash.u32(span.col);
+        hash.u32(span.end_lnum - origin);
+        hash.u32(span.end_col);
+        hash.str(span.group);
+        hash.u32(span.depth.unwrap_or(0));
     }
     hash.finish()
 }
@@ -197,6 +284,33 @@ enum Request {
         /// encoding it does not parse.
         #[serde(default)]
         compact: bool,
+        /// Client-chosen id a later `cancel` can name; 0 is not cancellable.
+        #[serde(default)]
+        request_id: u64,
+        /// Block digests the client holds from earlier delta replies, keyed as
+        /// the replies' `blocks` are. Present, even empty, asks for a delta
+        /// reply; see `Event::Highlights::cleared`.
+        #[serde(default)]
+        have_blocks: Option<HashMap<String, String>>,
+    },
+    /// The highlights as LSP semantic tokens, for editors other than Vim and
+    /// for an LSP shim: the same colouring, in `textDocument/semanticTokens`
+    /// form. `lstart`/`lend` make it the range variant.
+    #[serde(rename = "semantic_tokens")]
+    SemanticTokens {
+        buf: i64,
+        lang: String,
+        #[serde(default)]
+        lstart: Option<u32>,
+        #[serde(default)]
+        lend: Option<u32>,
+        /// LSP position encoding the columns count in: "utf-16" (LSP's
+        /// default, also when empty), "utf-8" or "utf-32".
+        #[serde(default)]
+        encoding: String,
+        /// See `Highlight::request_id`.
+        #[serde(default)]
+        request_id: u64,
     },
     #[serde(rename = "symbols")]
     Symbols {
@@ -232,9 +346,33 @@ enum Request {
         /// See `Symbols::have_digest`.
         #[serde(default)]
         have_digest: String,
+        /// See `Highlight::request_id`.
+        #[serde(default)]
+        request_id: u64,
+    },
+    /// Report where the parse failed: every `ERROR` and missing node of the
+    /// cached tree and of the trees injected into it.
+    #[serde(rename = "diagnostics")]
+    Diagnostics {
+        buf: i64,
+        lang: String,
+        #[serde(default)]
+        max_items: Option<usize>,
+        /// See `Symbols::have_digest`.
+        #[serde(default)]
+        have_digest: String,
+        /// See `Highlight::request_id`.
+        #[serde(default)]
+        request_id: u64,
     },
     #[serde(rename = "dump_ast")]
-    DumpAst { buf: i64, lang: String },
+    DumpAst {
+        buf: i64,
+        lang: String,
+        /// See `Highlight::request_id`.
+        #[serde(default)]
+        request_id: u64,
+    },
     /// Report what the highlighter sees at one point: which captures matched
     /// there, which group each maps to, and the enclosing node chain. Read-only
     /// and cheap — one point query, no state.
@@ -245,6 +383,9 @@ enum Request {
         /// 1-based line and byte column, matching Vim's line()/col().
         lnum: u32,
         col: u32,
+        /// See `Highlight::request_id`.
+        #[serde(default)]
+        request_id: u64,
     },
     /// Report the named-ancestor chain at one point, each entry carrying an
     /// outer and an inner range. One request serves both text objects (pick the
@@ -258,7 +399,126 @@ enum Request {
         /// 1-based line and byte column, matching Vim's line()/col().
         lnum: u32,
         col: u32,
+        /// See `Highlight::request_id`.
+        #[serde(default)]
+        request_id: u64,
+    },
+    /// Every occurrence of the binding under the cursor, resolved through the
+    /// scopes of the language's locals query.
+    #[serde(rename = "references")]
+    References {
+        buf: i64,
+        lang: String,
+        /// 1-based line and byte column, matching Vim's line()/col().
+        lnum: u32,
+        col: u32,
+        #[serde(default)]
+        max_items: Option<usize>,
+    },
+    /// Where the name under the cursor is bound: its local binding, else a
+    /// symbol of the same name.
+    #[serde(rename = "definition")]
+    Definition {
+        buf: i64,
+        lang: String,
+        /// 1-based line and byte column, matching Vim's line()/col().
+        lnum: u32,
+        col: u32,
+    },
+    /// Edits that rename the binding under the cursor, or an error when the
+    /// new name is not an identifier or would be captured by another binding.
+    #[serde(rename = "rename")]
+    Rename {
+        buf: i64,
+        lang: String,
+        /// 1-based line and byte column, matching Vim's line()/col().
+        lnum: u32,
+        col: u32,
+        new_name: String,
+    },
+    /// Indent for lines `lnum..=end_lnum` (just `lnum` when absent), in screen
+    /// columns as `indentexpr` returns them, -1 where the line keeps its own.
+    #[serde(rename = "indent")]
+    Indent {
+        buf: i64,
+        lang: String,
+        lnum: u32,
+        #[serde(default)]
+        end_lnum: Option<u32>,
+        /// The buffer's shiftwidth(), already resolved from 0 to 'tabstop'.
+        shiftwidth: u32,
+        /// Needed to measure aligned columns after leading tabs; default 8.
+        #[serde(default)]
+        tabstop: Option<u32>,
+    },
+    /// Run a client-written query over the cached tree and return every
+    /// capture, e.g. for a `:TsHlQuery` playground or a structural grep.
+    #[serde(rename = "query")]
+    Query {
+        buf: i64,
+        lang: String,
+        /// S-expression source, compiled against the buffer's grammar.
+        query: String,
+        #[serde(default)]
+        lstart: Option<u32>,
+        #[serde(default)]
+        lend: Option<u32>,
+        #[serde(default)]
+        max_items: Option<usize>,
+    },
+    /// Directories of user query files, `<dir>/<lang>/<query>.scm`, applied
+    /// in order over the bundled queries. An empty list restores the bundled
+    /// ones.
+    #[serde(rename = "set_query_paths")]
+    SetQueryPaths { paths: Vec<String> },
+    /// Register a grammar compiled to a shared library under `name`, with
+    /// its queries from `query_dir` (`highlights.scm`, `symbols.scm`, ...).
+    #[serde(rename = "load_grammar")]
+    LoadGrammar {
+        name: String,
+        library: String,
+        #[serde(default)]
+        query_dir: Option<String>,
+    },
+    /// How many levels of injection to follow, from 0 (none) up to
+    /// `MAX_INJECTION_DEPTH`. Takes effect on each buffer's next sync.
+    #[serde(rename = "set_injection_depth")]
+    SetInjectionDepth { depth: usize },
+    /// Drop the answer to a pooled request that has not been sent yet; its
+    /// reply becomes an `error` with message "cancelled". Unknown or already
+    /// answered ids are ignored, and nothing else is sent back.
+    #[serde(rename = "cancel")]
+    Cancel { request_id: u64 },
+    /// Keep a window of `buf` highlighted without being asked: after every
+    /// sync the daemon pushes the highlights of the window's changed lines.
+    /// One window per buffer; subscribing again replaces it.
+    #[serde(rename = "subscribe")]
+    Subscribe {
+        buf: i64,
+        lstart: u32,
+        lend: u32,
+        /// Lines beyond each end of the window that are kept highlighted too,
+        /// so a small scroll finds them painted already.
+        #[serde(default)]
+        margin: u32,
+        #[serde(default = "default_true")]
+        rainbow: bool,
+        #[serde(default)]
+        max_spans: Option<usize>,
+    },
+    /// Move a subscribed window as the client scrolls. The lines it newly
+    /// covers are pushed at once.
+    #[serde(rename = "viewport")]
+    Viewport {
+        buf: i64,
+        lstart: u32,
+        lend: u32,
+        /// Absent keeps the subscription's margin.
+        #[serde(default)]
+        margin: Option<u32>,
     },
+    #[serde(rename = "unsubscribe")]
+    Unsubscribe { buf: i64 },
     #[serde(rename = "close_buffer")]
     CloseBuffer { buf: i64 },
     #[serde(rename = "status")]
@@ -267,6 +527,11 @@ enum Request {
     Hello {
         #[serde(default, rename = "client_protocol")]
         _client_protocol: u32,
+        /// "binary" asks for highlights, symbols and folds as binary frames
+        /// (see `binary_frame`) for the rest of the session; anything else,
+        /// or nothing, keeps JSON.
+        #[serde(default)]
+        framing: String,
     },
 }
 
@@ -291,6 +556,42 @@ enum Event {
         groups: Option<Vec<&'static str>>,
         #[serde(skip_serializing_if = "Option::is_none")]
         cspans: Option<Vec<[u32; 6]>>,
+        /// Delta replies: the digest of every block the request covered,
+        /// keyed by a hash of the block's text (see `highlight_blocks`), for
+        /// the client to send back as `have_blocks`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        blocks: Option<BTreeMap<String, String>>,
+        /// Delta replies: the `[lnum, end_lnum]` ranges whose highlights
+        /// changed. The client clears exactly these lines and applies the
+        /// spans, which are cut to them; everything else it holds stays. A
+        /// reply without `cleared` is a full reply.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cleared: Option<Vec<[u32; 2]>>,
+        /// Pushes only: changed ranges whose spans did not fit in the
+        /// subscription's `max_spans`. They are neither cleared nor sent; the
+        /// client keeps what it holds there and asks with `highlight`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        incomplete: Option<Vec<[u32; 2]>>,
+        /// Pushed for a subscribed window rather than asked for. Always
+        /// compact and always with `cleared`.
+        #[serde(skip_serializing_if = "is_false")]
+        push: bool,
+    },
+    #[serde(rename = "semantic_tokens")]
+    SemanticTokens {
+        buf: i64,
+        revision: u64,
+        request_id: u64,
+        legend: SemanticTokensLegend,
+        encoding: &'static str,
+        /// LSP `SemanticTokens.data`: five integers per token — line delta,
+        /// start delta, length, type index, modifier bits — each token
+        /// relative to the one before, the first to the document start.
+        data: Vec<u32>,
+        /// Set when the spans behind `data` stopped at `MAX_HIGHLIGHT_SPANS`:
+        /// tokens past that point are missing, not plain text.
+        #[serde(skip_serializing_if = "is_false")]
+        truncated: bool,
     },
     #[serde(rename = "symbols")]
     Symbols {
@@ -327,6 +628,18 @@ enum Event {
         #[serde(skip_serializing_if = "is_false")]
         unchanged: bool,
     },
+    #[serde(rename = "diagnostics")]
+    Diagnostics {
+        buf: i64,
+        revision: u64,
+        /// Absent exactly when `unchanged` is set.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        diagnostics: Option<Vec<Diagnostic>>,
+        #[serde(skip_serializing_if = "String::is_empty")]
+        digest: String,
+        #[serde(skip_serializing_if = "is_false")]
+        unchanged: bool,
+    },
     #[serde(rename = "inspect")]
     Inspect {
         buf: i64,
@@ -352,12 +665,101 @@ enum Event {
         anchor_end_col: u32,
         chain: Vec<ScopeNode>,
     },
+    #[serde(rename = "references")]
+    References {
+        buf: i64,
+        revision: u64,
+        lnum: u32,
+        col: u32,
+        /// The identifier under the cursor.
+        name: String,
+        /// Kind of the binding's first definition; absent when the name has no
+        /// definition in this buffer and only same-name unresolved uses are
+        /// reported.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        kind: Option<&'static str>,
+        references: Vec<Reference>,
+    },
+    #[serde(rename = "definition")]
+    Definition {
+        buf: i64,
+        revision: u64,
+        lnum: u32,
+        col: u32,
+        name: String,
+        /// Absent when the name is bound nowhere in this buffer.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        definition: Option<DefinitionSite>,
+    },
+    #[serde(rename = "rename")]
+    Rename {
+        buf: i64,
+        /// Revision the edits were computed against. A client holding a newer
+        /// one must ask again rather than apply them.
+        revision: u64,
+        edits: Vec<RenameEdit>,
+    },
+    #[serde(rename = "indent")]
+    Indent {
+        buf: i64,
+        revision: u64,
+        lnum: u32,
+        /// One entry per line from `lnum` on; shorter than asked for when the
+        /// range ran past the end of the buffer or past `MAX_INDENT_LINES`.
+        indents: Vec<i32>,
+    },
+    #[serde(rename = "query")]
+    Query {
+        buf: i64,
+        revision: u64,
+        /// In document order, by start then by pattern.
+        captures: Vec<QueryCapture>,
+        /// Set when `max_items` or the in-progress match limit cut the
+        /// result short.
+        truncated: bool,
+    },
+    /// A `query` request whose pattern did not compile.
+    #[serde(rename = "query_error")]
+    QueryError {
+        buf: i64,
+        /// "syntax", "node_type", "field", "capture", "predicate",
+        /// "structure" or "language", after tree-sitter's `QueryErrorKind`.
+        kind: &'static str,
+        message: String,
+        /// Byte offset into the query text, and the same point as a 1-based
+        /// line and byte column of that text.
+        offset: usize,
+        row: u32,
+        column: u32,
+    },
+    /// Reply to `set_query_paths`, after one `error` per rejected file.
+    #[serde(rename = "query_paths")]
+    QueryPaths { overrides: Vec<QueryOverride> },
+    /// Reply to `set_injection_depth`: the depth now in effect, after
+    /// clamping.
+    #[serde(rename = "injection_depth")]
+    InjectionDepth { depth: usize },
+    #[serde(rename = "grammar_loaded")]
+    GrammarLoaded {
+        name: String,
+        abi_version: usize,
+        /// Query files found in `query_dir`, e.g. ["highlights", "folds"].
+        queries: Vec<&'static str>,
+    },
     #[serde(rename = "ok")]
     Ok {
         buf: i64,
         op: String,
         #[serde(skip_serializing_if = "Option::is_none")]
         revision: Option<u64>,
+        /// Syncs only: the 1-based `[lnum, end_lnum]` ranges whose syntax the
+        /// sync may have changed, sorted and merged. Besides the edited lines
+        /// this covers what `Tree::changed_ranges` reports for the host and
+        /// every injected tree, so an unterminated string or block comment
+        /// invalidates the lines it swallows however far they are from the
+        /// edit. Empty for a sync that changed nothing.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        changed: Option<Vec<[u32; 2]>>,
     },
     #[serde(rename = "status")]
     Status {
@@ -369,6 +771,13 @@ enum Event {
         unchanged_syncs: u64,
         cached_bytes: usize,
         cache_evictions: u64,
+        /// Parses of injected trees from scratch, and reusing the last sync's.
+        injected_full_parses: u64,
+        injected_incremental_parses: u64,
+        /// Read-only requests dropped for a newer one asking the same of the
+        /// same buffer: read in the same batch, or submitted while they
+        /// waited for a worker.
+        superseded_requests: u64,
         languages: &'static [&'static str],
     },
     #[serde(rename = "hello")]
@@ -376,6 +785,12 @@ enum Event {
         protocol_version: u32,
         version: &'static str,
         capabilities: &'static [&'static str],
+        /// `LOCALS_LANGUAGES`; a grammar loaded later with its own locals
+        /// query answers as well.
+        locals_languages: &'static [&'static str],
+        /// Framing granted for this session; absent means JSON.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        framing: Option<&'static str>,
     },
     #[serde(rename = "error")]
     Error {
@@ -390,9 +805,32 @@ enum Event {
         /// keep None, preserving their v4 wire representation.
         #[serde(skip_serializing_if = "Option::is_none")]
         request_id: Option<u64>,
+        /// Where in which file the error is, for errors about a user's file
+        /// rather than a request, e.g. a query override that does not compile.
+        #[serde(flatten, skip_serializing_if = "Option::is_none")]
+        location: Option<ErrorLocation>,
     },
 }
 
+/// 1-based line and byte column of an error inside `path`.
+#[derive(Debug, Serialize, Clone)]
+struct ErrorLocation {
+    path: String,
+    row: u32,
+    column: u32,
+}
+
+/// A user query file that `set_query_paths` applied over the bundled source.
+#[derive(Debug, Serialize, Clone)]
+struct QueryOverride {
+    lang: &'static str,
+    /// One of `QUERY_OVERRIDE_FILES`.
+    query: &'static str,
+    path: String,
+    /// "replace", or "extend" for a file whose first line is `; extends`.
+    mode: &'static str,
+}
+
 #[derive(Debug, Serialize, Clone)]
 struct Span {
     lnum: u32,
@@ -467,6 +905,61 @@ struct ScopeNode {
     inner_col: u32,
     inner_end_lnum: u32,
     inner_end_col: u32,
+    /// Set when the node is in an injected grammar's tree.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lang: Option<&'static str>,
+}
+
+/// One occurrence of a binding, in source order.
+#[derive(Debug, Serialize, Clone)]
+struct Reference {
+    lnum: u32,
+    col: u32,
+    end_lnum: u32,
+    end_col: u32,
+    /// True where the name is bound (`let x`, a parameter, an import) rather
+    /// than used.
+    definition: bool,
+}
+
+/// Where a `definition` request's identifier is bound. A local binding spans
+/// just the name; a symbol keeps the range `symbols` reports for it, which
+/// starts at the name and ends with the item.
+#[derive(Debug, Serialize, Clone)]
+struct DefinitionSite {
+    lnum: u32,
+    col: u32,
+    end_lnum: u32,
+    end_col: u32,
+    /// Definition kind from the locals query ("parameter", "var", ...) or, for
+    /// a symbol, the symbol kind.
+    kind: &'static str,
+    /// "local" when resolved through scopes, "symbol" for the fallback.
+    source: &'static str,
+}
+
+/// Replace `[col, end_col)` of line `lnum` with `new_text`. Identifiers never
+/// span lines, so one line number is enough; columns are 1-based bytes of the
+/// text before any edit, and edits come in source order.
+#[derive(Debug, Serialize, Clone)]
+struct RenameEdit {
+    lnum: u32,
+    col: u32,
+    end_col: u32,
+    new_text: String,
+}
+
+/// One capture of an ad-hoc query. `pattern` is the index of the pattern in
+/// the query text, so a client can tell apart alternatives that share a name.
+#[derive(Debug, Serialize, Clone)]
+struct QueryCapture {
+    capture: String,
+    pattern: usize,
+    kind: &'static str,
+    lnum: u32,
+    col: u32,
+    end_lnum: u32,
+    end_col: u32,
 }
 
 #[derive(Debug, Serialize, Clone, PartialEq, Eq)]
@@ -476,6 +969,30 @@ struct Fold {
     level: u32,
 }
 
+/// One syntax error in the cached tree.
+///
+/// Positions are 1-based byte columns like every other range on the wire. A
+/// missing node is zero-width (`col == end_col`): tree-sitter invented it at
+/// the point where the token should have been.
+#[derive(Debug, Serialize, Clone)]
+struct Diagnostic {
+    lnum: u32,
+    col: u32,
+    end_lnum: u32,
+    end_col: u32,
+    /// "error" for text the grammar could not place, "missing" for a token it
+    /// had to invent to finish the parse.
+    kind: &'static str,
+    message: String,
+    /// Kind of the nearest named ancestor that is not itself an error, e.g.
+    /// "function_item" — enough to say *where* without dumping the AST.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent: Option<&'static str>,
+    /// Set when the error is in an injected grammar's tree.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    injected_lang: Option<&'static str>,
+}
+
 #[derive(Debug, Serialize, Clone)]
 struct Symbol {
     name: String,
@@ -491,6 +1008,9 @@ struct Symbol {
     container_name: Option<String>,
     container_lnum: Option<u32>,
     container_col: Option<u32>,
+    /// Set when the symbol comes from an injected grammar's tree.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lang: Option<&'static str>,
 }
 
 /// A second grammar parsed over a subset of the host document.
@@ -498,19 +1018,39 @@ struct Symbol {
 /// One tree per injected language, parsed once with `set_included_ranges` over
 /// all of that language's ranges — a markdown file with forty rust fences costs
 /// one rust parse, not forty.
+#[derive(Clone)]
 struct InjectedTree {
     lang: &'static str,
     tree: tree_sitter::Tree,
+    /// 1 for a tree injected into the host, 2 for one injected into that, ...
+    depth: usize,
+    /// Included ranges the tree was parsed over, in document order.
+    ranges: Vec<tree_sitter::Range>,
+    /// Byte ranges of the trees one level down that were injected into this
+    /// one; plays `BufCache::injected_ranges`' part for this tree's captures.
+    injected_ranges: Vec<ops::Range<usize>>,
+}
+
+/// Last sync's injected tree, already edited to the new text, waiting to be
+/// matched with this sync's ranges.
+struct PreviousInjection {
+    lang: &'static str,
+    depth: usize,
+    range_count: usize,
+    tree: tree_sitter::Tree,
 }
 
 // 缓存：每个 buf 保存 lang/text/tree
+#[derive(Clone)]
 struct BufCache {
     lang: String,
-    text: String,
+    // 与快照共享；未变的同步在快照仍被持有时复制 BufCache，也只复制指针。
+    text: std::sync::Arc<str>,
     tree: tree_sitter::Tree,
-    // 注入语法树：markdown 的 inline 与围栏代码块、HTML 的 <script>/<style>。
-    // 每次同步整体重建：注入解析很快，而注入区间会随宿主的任何编辑整体平移，
-    // 维护它们的增量复用不值当。
+    // 注入语法树：各语言 injections 查询标出的区间，如 markdown 的 inline 与围栏
+    // 代码块、HTML 的 <script>/<style>、Rust 的文档注释。
+    // 每次同步按新区间重建，但同语言同层、区间个数不变时把上次的树（已施加与
+    // 宿主相同的 InputEdit）交给 parser 增量复用。
     injections: Vec<InjectedTree>,
     // 上面那些树占据的字节区间，按文档顺序排列且互不重叠。落在其中的宿主
     // capture 会被丢弃 —— markdown 把整段围栏内容捕获成 @text.literal，注入
@@ -518,6 +1058,8 @@ struct BufCache {
     injected_ranges: Vec<ops::Range<usize>>,
     revision: u64,
     line_index: SparseLineIndex,
+    // 上次同步后语法可能变化的行段（1-based，闭区间，已排序合并），随 ok 回包发出。
+    changed_lines: Vec<[u32; 2]>,
 }
 
 /// Sparse mapping from 1-based line numbers to byte offsets.
@@ -527,6 +1069,7 @@ struct BufCache {
 /// its source. Checkpointing every `LINE_INDEX_STRIDE` lines bounds the index to
 /// roughly 1/32 of the source size in that worst case. Lookups scan at most 255
 /// newline boundaries forward from the nearest checkpoint.
+#[derive(Clone)]
 struct SparseLineIndex {
     /// Byte offsets for lines 1, 1 + stride, 1 + 2 * stride, ...
     checkpoints: Box<[usize]>,
@@ -538,7 +1081,19 @@ struct SparseLineIndex {
 struct LangQueries {
     language: tree_sitter::Language,
     hl_query: tree_sitter::Query,
+    hl_predicates: QueryPredicates,
     sym_query: tree_sitter::Query,
+    sym_predicates: QueryPredicates,
+    /// Scopes, definitions and references; None for languages without one.
+    locals_query: Option<(tree_sitter::Query, QueryPredicates)>,
+    /// `@indent.*` captures; None where the `indent` request is not offered.
+    indents_query: Option<(tree_sitter::Query, QueryPredicates)>,
+    /// `@fold` captures, on top of `foldable_kinds`; only loaded grammars and
+    /// user overrides bring one.
+    folds_query: Option<(tree_sitter::Query, QueryPredicates)>,
+    /// `@injection.content` / `@injection.language`; None for languages that
+    /// host no other grammar.
+    injections_query: Option<(tree_sitter::Query, QueryPredicates)>,
 }
 
 /// A grammar compiled only to highlight injected content.
@@ -549,21 +1104,93 @@ struct LangQueries {
 struct InjectionQuery {
     language: tree_sitter::Language,
     hl_query: tree_sitter::Query,
+    hl_predicates: QueryPredicates,
 }
 
 struct Server {
-    // 缓存：buf -> BufCache
-    cache: HashMap<i64, BufCache>,
+    // 缓存：buf -> BufCache。放在 Arc 里，工作线程的快照只是引用计数加一，
+    // 不必为每个只读请求复制整个 buffer。
+    cache: HashMap<i64, std::sync::Arc<BufCache>>,
     // 复用 parser（按语言）
     parsers: HashMap<String, tree_sitter::Parser>,
     // 预编译查询缓存（按语言）
-    queries: HashMap<String, LangQueries>,
+    queries: HashMap<String, std::sync::Arc<LangQueries>>,
     // 注入语法的查询缓存（按注入语言名，含 markdown_inline）
-    injection_queries: HashMap<String, InjectionQuery>,
+    injection_queries: HashMap<String, std::sync::Arc<InjectionQuery>>,
+    // 用户查询覆盖：(语言, 查询名) -> 生效的完整查询源码，已验证可编译
+    query_overrides: HashMap<(&'static str, &'static str), String>,
+    // 注入嵌套层数上限，0 表示不解析注入
+    injection_depth: usize,
     full_parses: u64,
     incremental_parses: u64,
     unchanged_syncs: u64,
     cache_evictions: u64,
+    // 注入树的全量 / 复用上次树的增量解析次数
+    injected_full_parses: u64,
+    injected_incremental_parses: u64,
+    // 同一批输入里被同类同 buffer 的新请求取代、未作答的只读请求数
+    superseded_requests: u64,
+    // hello 协商的二进制帧：只影响 highlights/symbols/folds 回包
+    binary_frames: bool,
+    // subscribe 登记的窗口：buf -> 每次同步后主动推送高亮的行段
+    subscriptions: HashMap<i64, Subscription>,
+    // --listen 各连接在两次请求之间停放的会话；缓存上限与 status 把它们一并计入
+    sessions: HashMap<u64, Session>,
+    // 仅工作线程的快照持有：`cancel` 点名它正在回答的请求时置位，长循环据此提前收手
+    cancel: Option<CancelFlag>,
+}
+
+/// What one `--listen` connection keeps apart from the others: its buffers
+/// and the settings it asked for. Swapped into the `Server` for the duration
+/// of each of its requests and parked in `Server::sessions` in between.
+struct Session {
+    cache: HashMap<i64, std::sync::Arc<BufCache>>,
+    binary_frames: bool,
+    subscriptions: HashMap<i64, Subscription>,
+    injection_depth: usize,
+    /// `set_query_paths` overrides and the queries compiled with them. None
+    /// while the connection has none: it then shares the bundled queries
+    /// every other such connection compiled.
+    queries: Option<SessionQueries>,
+}
+
+#[derive(Default)]
+struct SessionQueries {
+    overrides: HashMap<(&'static str, &'static str), String>,
+    queries: HashMap<String, std::sync::Arc<LangQueries>>,
+    injection_queries: HashMap<String, std::sync::Arc<InjectionQuery>>,
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            cache: HashMap::new(),
+            binary_frames: false,
+            subscriptions: HashMap::new(),
+            injection_depth: DEFAULT_INJECTION_DEPTH,
+            queries: None,
+        }
+    }
+}
+
+/// A window `subscribe` asked the daemon to keep highlighted.
+#[derive(Clone, Copy)]
+struct Subscription {
+    lstart: u32,
+    lend: u32,
+    margin: u32,
+    rainbow: bool,
+    max_spans: Option<usize>,
+}
+
+impl Subscription {
+    /// The window plus its margins, clamped to `line_count` lines.
+    fn lines(&self, line_count: u32) -> (u32, u32) {
+        (
+            self.lstart.saturating_sub(self.margin).max(1),
+            self.lend.saturating_add(self.margin).min(line_count),
+        )
+    }
 }
 
 /// Line-range replacement payload for `edit_lines`.
@@ -589,11 +1216,103 @@ impl Server {
             parsers: HashMap::new(),
             queries: HashMap::new(),
             injection_queries: HashMap::new(),
+            query_overrides: HashMap::new(),
+            injection_depth: DEFAULT_INJECTION_DEPTH,
             full_parses: 0,
             incremental_parses: 0,
             unchanged_syncs: 0,
             cache_evictions: 0,
+            injected_full_parses: 0,
+            injected_incremental_parses: 0,
+            superseded_requests: 0,
+            binary_frames: false,
+            subscriptions: HashMap::new(),
+            sessions: HashMap::new(),
+            cancel: None,
+        }
+    }
+
+    /// Whether `cancel` named the pooled request this snapshot is answering.
+    fn cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    /// Fail a pooled request that was cancelled while it ran; its reply is
+    /// replaced by "cancelled" anyway.
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancelled() {
+            return Err(anyhow!("cancelled"));
+        }
+        Ok(())
+    }
+
+    /// Swap connection `id`'s session in. Returns what it displaced, to be
+    /// handed back to `leave_session`.
+    fn enter_session(&mut self, id: u64) -> Session {
+        let mut session = self.sessions.remove(&id).unwrap_or_else(Session::new);
+        self.swap_session(&mut session);
+        session
+    }
+
+    /// Swap connection `id`'s session out again and park it.
+    fn leave_session(&mut self, id: u64, mut shared: Session) {
+        if shared.queries.is_none() && !self.query_overrides.is_empty() {
+            // The connection just loaded its first overrides, and recompiled
+            // the shared queries with them: those become its own, and the
+            // other connections recompile the bundled ones on next use.
+            shared.queries = Some(SessionQueries::default());
+        }
+        self.swap_session(&mut shared);
+        let mut session = shared;
+        if session
+            .queries
+            .as_ref()
+            .is_some_and(|queries| queries.overrides.is_empty())
+        {
+            session.queries = None;
         }
+        self.sessions.insert(id, session);
+    }
+
+    fn swap_session(&mut self, session: &mut Session) {
+        std::mem::swap(&mut self.cache, &mut session.cache);
+        std::mem::swap(&mut self.binary_frames, &mut session.binary_frames);
+        std::mem::swap(&mut self.subscriptions, &mut session.subscriptions);
+        std::mem::swap(&mut self.injection_depth, &mut session.injection_depth);
+        if let Some(queries) = &mut session.queries {
+            std::mem::swap(&mut self.query_overrides, &mut queries.overrides);
+            std::mem::swap(&mut self.queries, &mut queries.queries);
+            std::mem::swap(&mut self.injection_queries, &mut queries.injection_queries);
+        }
+    }
+
+    /// Drop the queries compiled for language `name`, in every session, so the
+    /// next request recompiles them from the sources now registered.
+    fn forget_queries(&mut self, name: &str) {
+        self.queries.remove(name);
+        self.injection_queries.remove(name);
+        for queries in self
+            .sessions
+            .values_mut()
+            .filter_map(|session| session.queries.as_mut())
+        {
+            queries.queries.remove(name);
+            queries.injection_queries.remove(name);
+        }
+    }
+
+    /// Every cached buffer, the parked `--listen` sessions' included.
+    fn all_cached(&self) -> impl Iterator<Item = &BufCache> {
+        self.cache
+            .values()
+            .chain(
+                self.sessions
+                    .values()
+                    .flat_map(|session| session.cache.values()),
+            )
+            .map(|cache| &**cache)
     }
 
     fn lang_info(lang: &str) -> Result<(tree_sitter::Language, &'static str, &'static str)> {
@@ -693,33 +1412,57 @@ impl Server {
                 queries::HASKELL_QUERY,
                 queries::HASKELL_SYM_QUERY,
             ),
-            _ => return Err(anyhow!("unsupported language: {lang}")),
+            _ => match dynamic_grammar(lang) {
+                Some(grammar) => (grammar.language, grammar.highlights, grammar.symbols),
+                None => return Err(anyhow!("unsupported language: {lang}")),
+            },
         };
         Ok((language, hl_query, sym_query))
     }
 
     fn ensure_queries(&mut self, lang: &str) -> Result<()> {
         if !self.queries.contains_key(lang) {
-            let (language, hl_src, sym_src) = Self::lang_info(lang)?;
-            let hl_query = tree_sitter::Query::new(&language, hl_src)?;
-            let sym_query = tree_sitter::Query::new(&language, sym_src)?;
+            let (language, _, _) = Self::lang_info(lang)?;
+            let source = |query| self.query_source(lang, query).unwrap_or_default();
+            let (hl_query, hl_predicates) = compile_query(&language, source("highlights"))?;
+            let (sym_query, sym_predicates) = compile_query(&language, source("symbols"))?;
+            let optional = |query| {
+                self.query_source(lang, query)
+                    .map(|src| compile_query(&language, src))
+                    .transpose()
+            };
+            let locals_query = optional("locals")?;
+            let indents_query = optional("indents")?;
+            let folds_query = optional("folds")?;
+            let injections_query = optional("injections")?;
             self.queries.insert(
                 lang.to_string(),
-                LangQueries {
+                std::sync::Arc::new(LangQueries {
                     language,
                     hl_query,
+                    hl_predicates,
                     sym_query,
-                },
+                    sym_predicates,
+                    locals_query,
+                    indents_query,
+                    folds_query,
+                    injections_query,
+                }),
             );
         }
         // Compile the fixed injection targets now rather than on first sight of
         // an injected range: `--self-test` only calls this, and it is what makes
         // a broken injected query fail at install time instead of silently
         // leaving `<script>` bodies uncoloured for the one user who has one.
-        for rule in injection_rules(lang) {
-            if let InjectionTarget::Fixed(target) = rule.target {
-                self.ensure_injection_query(target)?;
-            }
+        let targets: Vec<&'static str> = match &self.queries[lang].injections_query {
+            Some((query, _)) => (0..query.pattern_count())
+                .filter_map(|pattern| fixed_injection_language(query, pattern))
+                .filter_map(injection_target)
+                .collect(),
+            None => Vec::new(),
+        };
+        for target in targets {
+            self.ensure_injection_query(target)?;
         }
         Ok(())
     }
@@ -728,21 +1471,142 @@ impl Server {
         if self.injection_queries.contains_key(name) {
             return Ok(());
         }
-        let (language, hl_src): (tree_sitter::Language, &str) = if name == "markdown_inline" {
-            (
-                tree_sitter_md::INLINE_LANGUAGE.into(),
-                queries::MD_INLINE_QUERY,
-            )
-        } else {
-            let (language, hl_src, _) = Self::lang_info(name)?;
-            (language, hl_src)
-        };
-        let hl_query = tree_sitter::Query::new(&language, hl_src)?;
-        self.injection_queries
-            .insert(name.to_string(), InjectionQuery { language, hl_query });
+        let language = query_language(name)?;
+        let hl_src = self.query_source(name, "highlights").unwrap_or_default();
+        let (hl_query, hl_predicates) = compile_query(&language, hl_src)?;
+        self.injection_queries.insert(
+            name.to_string(),
+            std::sync::Arc::new(InjectionQuery {
+                language,
+                hl_query,
+                hl_predicates,
+            }),
+        );
         Ok(())
     }
 
+    /// Source of `query` for `lang`: a user override when one was loaded,
+    /// else the bundled one; None when the language has no such query.
+    fn query_source(&self, lang: &str, query: &str) -> Option<&str> {
+        let key = injection_language_for_tag(lang).unwrap_or(lang);
+        match self
+            .query_overrides
+            .iter()
+            .find(|((l, q), _)| *l == key && *q == query)
+        {
+            Some((_, source)) => Some(source),
+            None => bundled_query_source(lang, query),
+        }
+    }
+
+    /// Load user query files from `paths` and recompile what is in use.
+    ///
+    /// Each file is compiled against what it overrides before it is accepted,
+    /// so a broken one is reported (with its own row and column) and skipped
+    /// instead of taking highlighting down with it: that language keeps the
+    /// bundled query, or the previous directory's.
+    fn set_query_paths(
+        &mut self,
+        paths: &[String],
+    ) -> (
+        Vec<QueryOverride>,
+        Vec<(anyhow::Error, Option<ErrorLocation>)>,
+    ) {
+        let mut sources: HashMap<(&'static str, &'static str), String> = HashMap::new();
+        let mut applied = Vec::new();
+        let mut errors = Vec::new();
+        let loaded: Vec<&'static str> = dynamic_grammars()
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .values()
+            .map(|grammar| grammar.name)
+            .collect();
+        let names = SUPPORTED_LANGUAGES
+            .iter()
+            .copied()
+            .chain(["markdown_inline"])
+            .chain(loaded);
+        for dir in paths {
+            for lang in names.clone() {
+                for &query in QUERY_OVERRIDE_FILES {
+                    let path = std::path::Path::new(dir)
+                        .join(lang)
+                        .join(format!("{query}.scm"));
+                    let text = match std::fs::read_to_string(&path) {
+                        Ok(text) => text,
+                        Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+                        Err(error) => {
+                            errors.push((anyhow!("{}: {error}", path.display()), None));
+                            continue;
+                        }
+                    };
+                    let path = path.to_string_lossy().into_owned();
+                    let extend = text
+                        .lines()
+                        .next()
+                        .is_some_and(|line| line.trim() == "; extends");
+                    let (base, source) = if extend {
+                        let base = sources
+                            .get(&(lang, query))
+                            .map(String::as_str)
+                            .or_else(|| bundled_query_source(lang, query))
+                            .unwrap_or_default();
+                        let base = if base.is_empty() || base.ends_with('\n') {
+                            base.to_string()
+                        } else {
+                            format!("{base}\n")
+                        };
+                        let source = format!("{base}{text}");
+                        (base, source)
+                    } else {
+                        (String::new(), text)
+                    };
+                    let compiled =
+                        query_language(lang).and_then(|language| compile_query(&language, &source));
+                    if let Err(error) = compiled {
+                        // Rows of an extending file are counted from its own
+                        // first line, not from the bundled source above it.
+                        let location = error.downcast_ref::<tree_sitter::QueryError>().map(|e| {
+                            let base_rows = base.matches('\n').count();
+                            ErrorLocation {
+                                path: path.clone(),
+                                row: e.row.saturating_sub(base_rows) as u32 + 1,
+                                column: e.column as u32 + 1,
+                            }
+                        });
+                        errors.push((anyhow!("{path}: {error}"), location));
+                        continue;
+                    }
+                    sources.insert((lang, query), source);
+                    applied.push(QueryOverride {
+                        lang,
+                        query,
+                        path,
+                        mode: if extend { "extend" } else { "replace" },
+                    });
+                }
+            }
+        }
+        self.query_overrides = sources;
+        let langs: Vec<String> = self.queries.drain().map(|(lang, _)| lang).collect();
+        let injected: Vec<String> = self
+            .injection_queries
+            .drain()
+            .map(|(name, _)| name)
+            .collect();
+        for lang in langs {
+            if let Err(error) = self.ensure_queries(&lang) {
+                errors.push((error, None));
+            }
+        }
+        for name in injected {
+            if let Err(error) = self.ensure_injection_query(&name) {
+                errors.push((error, None));
+            }
+        }
+        (applied, errors)
+    }
+
     fn parser_for(
         &mut self,
         lang: &str,
@@ -775,22 +1639,44 @@ impl Server {
 
         if let Some(cache) = self.cache.get_mut(&buf)
             && cache.lang == lang
-            && cache.text == text
+            && *cache.text == *text
         {
+            let cache = std::sync::Arc::make_mut(cache);
             cache.revision = revision;
+            cache.changed_lines.clear();
             self.unchanged_syncs += 1;
             return Ok(ParseMode::Unchanged);
         }
 
-        let old_tree = self.cache.get(&buf).and_then(|cache| {
-            if cache.lang != lang {
-                return None;
-            }
-            let edit = compute_input_edit(&cache.text, &text)?;
-            let mut tree = cache.tree.clone();
-            tree.edit(&edit);
-            Some(tree)
-        });
+        // The injected trees take the same edit as the host: their byte and
+        // point coordinates are the host document's.
+        let (old_tree, old_injections, edit) = self
+            .cache
+            .get(&buf)
+            .and_then(|cache| {
+                if cache.lang != lang {
+                    return None;
+                }
+                let edit = compute_input_edit(&cache.text, &text)?;
+                let mut tree = cache.tree.clone();
+                tree.edit(&edit);
+                let injections = cache
+                    .injections
+                    .iter()
+                    .map(|injected| {
+                        let mut tree = injected.tree.clone();
+                        tree.edit(&edit);
+                        PreviousInjection {
+                            lang: injected.lang,
+                            depth: injected.depth,
+                            range_count: injected.ranges.len(),
+                            tree,
+                        }
+                    })
+                    .collect();
+                Some((Some(tree), injections, Some(edit)))
+            })
+            .unwrap_or_default();
 
         let p = self.parser_for(lang, language.clone())?;
         let tree = p
@@ -803,52 +1689,171 @@ impl Server {
             self.full_parses += 1;
             ParseMode::Full
         };
-        let (injections, injected_ranges) = self.parse_injections(lang, &tree, &text)?;
+        // The edited text itself, then whatever the reparse restructured: a
+        // renamed identifier can change no node yet still match another
+        // highlight predicate.
+        let mut changed: Vec<ops::Range<tree_sitter::Point>> = edit
+            .iter()
+            .map(|edit| edit.start_position..edit.new_end_position)
+            .collect();
+        if let Some(old_tree) = &old_tree {
+            changed.extend(
+                old_tree
+                    .changed_ranges(&tree)
+                    .map(|range| range.start_point..range.end_point),
+            );
+        }
+        let (injections, injected_ranges) =
+            self.parse_injections(lang, &tree, &text, old_injections, &mut changed)?;
         self.reserve_cache_capacity(buf, text.len());
         let line_index = SparseLineIndex::new(&text);
+        let changed_lines = if old_tree.is_some() {
+            merge_changed_lines(&changed)
+        } else {
+            vec![[1, line_index.line_count as u32]]
+        };
         self.cache.insert(
             buf,
-            BufCache {
+            std::sync::Arc::new(BufCache {
                 lang: lang.to_string(),
-                text,
+                text: text.into(),
                 tree,
                 injections,
                 injected_ranges,
                 revision,
                 line_index,
-            },
+                changed_lines,
+            }),
         );
         Ok(mode)
     }
 
-    /// Parse every language injected into `host_tree`, one tree per language.
+    fn changed_lines(&self, buf: i64) -> Option<Vec<[u32; 2]>> {
+        Some(self.cache.get(&buf)?.changed_lines.clone())
+    }
+
+    /// Parse every language injected into `host_tree`, and into those trees in
+    /// turn, up to `injection_depth` levels: one tree per language per level.
     ///
-    /// Returns the trees plus the union of the byte ranges they own, in
-    /// document order, which the highlighter uses to let an injected grammar
-    /// win over the host's own capture for the same text.
+    /// Returns the trees plus the byte ranges injected into the host itself,
+    /// in document order, which the highlighter uses to let an injected
+    /// grammar win over the host's own capture for the same text. Each tree
+    /// carries the same list for the level below it. All levels together
+    /// share one budget of `MAX_INJECTED_RANGES`.
+    ///
+    /// What the injected trees changed since the last sync is appended to
+    /// `changed`: a reused tree's `changed_ranges`, all of a tree parsed
+    /// afresh, and all of a previous tree nothing reused.
     fn parse_injections(
         &mut self,
         lang: &str,
         host_tree: &tree_sitter::Tree,
         text: &str,
+        mut previous: Vec<PreviousInjection>,
+        changed: &mut Vec<ops::Range<tree_sitter::Point>>,
     ) -> Result<(Vec<InjectedTree>, Vec<ops::Range<usize>>)> {
-        let rules = injection_rules(lang);
-        if rules.is_empty() {
+        if self.injection_depth == 0 {
             return Ok((Vec::new(), Vec::new()));
         }
-        let mut found: Vec<(&'static str, tree_sitter::Range)> = Vec::new();
-        collect_injection_ranges(host_tree.root_node(), rules, text, &mut found);
-        if found.is_empty() {
-            return Ok((Vec::new(), Vec::new()));
+        let mut budget = MAX_INJECTED_RANGES;
+        let found = self.find_injections(lang, host_tree.root_node(), None, text, &mut budget);
+        let injected_ranges = found
+            .iter()
+            .map(|(_, range)| range.start_byte..range.end_byte)
+            .collect();
+        let mut injections = Vec::new();
+        let mut level = self.parse_injection_level(found, text, 1, &mut previous, changed)?;
+        while !level.is_empty() {
+            let depth = level[0].depth;
+            let first = injections.len();
+            injections.append(&mut level);
+            if depth >= self.injection_depth {
+                break;
+            }
+            let mut found = Vec::new();
+            for parent in injections.iter_mut().skip(first) {
+                let nested = self.find_injections(
+                    parent.lang,
+                    parent.tree.root_node(),
+                    Some(&parent.ranges),
+                    text,
+                    &mut budget,
+                );
+                parent.injected_ranges = nested
+                    .iter()
+                    .map(|(_, range)| range.start_byte..range.end_byte)
+                    .collect();
+                found.extend(nested);
+            }
+            // Trees of one level own disjoint bytes, so their children do too;
+            // only the order needs restoring.
+            found.sort_by_key(|(_, range)| range.start_byte);
+            level = self.parse_injection_level(found, text, depth + 1, &mut previous, changed)?;
+        }
+        changed.extend(previous.iter().flat_map(|old| {
+            old.tree
+                .included_ranges()
+                .into_iter()
+                .map(|range| range.start_point..range.end_point)
+        }));
+        Ok((injections, injected_ranges))
+    }
+
+    /// Ranges that `lang`'s injections query finds under `root`, clipped to
+    /// `within` when `root` belongs to an injected tree: a markdown fence in
+    /// rust doc comments spans the `///` between its lines, and those bytes
+    /// are not the fence's.
+    fn find_injections(
+        &mut self,
+        lang: &str,
+        root: tree_sitter::Node,
+        within: Option<&[tree_sitter::Range]>,
+        text: &str,
+        budget: &mut usize,
+    ) -> Vec<(&'static str, tree_sitter::Range)> {
+        // A host whose own queries do not compile goes without injections; the
+        // highlight request that needs those queries reports why. The same
+        // holds for `markdown_inline`, which hosts nothing.
+        if *budget == 0 || self.ensure_queries(lang).is_err() {
+            return Vec::new();
+        }
+        let Some(query) = &self.queries[lang].injections_query else {
+            return Vec::new();
+        };
+        let mut found = collect_injection_ranges(query, root, text, *budget);
+        if let Some(within) = within {
+            found = found
+                .into_iter()
+                .flat_map(|(target, range)| {
+                    clip_to_ranges(range, within).map(move |piece| (target, piece))
+                })
+                .collect();
+            found.truncate(*budget);
         }
+        *budget -= found.len();
+        found
+    }
 
+    /// Parse one level of injections, one tree per target language.
+    ///
+    /// The last sync's tree of the same language and level is reused when it
+    /// had as many ranges as there are now: typing inside a fence only moves
+    /// the ranges, and tree-sitter reparses what the edit and the changed
+    /// included ranges touched. A range that appeared or vanished means a
+    /// fresh parse, since pairing old ranges with new ones would be a guess.
+    fn parse_injection_level(
+        &mut self,
+        found: Vec<(&'static str, tree_sitter::Range)>,
+        text: &str,
+        depth: usize,
+        previous: &mut Vec<PreviousInjection>,
+        changed: &mut Vec<ops::Range<tree_sitter::Point>>,
+    ) -> Result<Vec<InjectedTree>> {
         // Group by target language while preserving document order: a parser
         // wants all of one language's ranges in one `set_included_ranges` call,
         // ascending and non-overlapping.
         let mut grouped: Vec<(&'static str, Vec<tree_sitter::Range>)> = Vec::new();
-        let mut injected_ranges: Vec<ops::Range<usize>> = Vec::with_capacity(found.len());
         for (target, range) in found {
-            injected_ranges.push(range.start_byte..range.end_byte);
             match grouped.iter_mut().find(|(name, _)| *name == target) {
                 Some((_, ranges)) => ranges.push(range),
                 None => grouped.push((target, vec![range])),
@@ -867,17 +1872,46 @@ impl Server {
             // fence inside markdown must not clobber the parser a rust buffer
             // is reusing, included ranges and all.
             let key = format!("injection-{target}");
+            let old_tree = previous
+                .iter()
+                .position(|old| {
+                    old.lang == target && old.depth == depth && old.range_count == ranges.len()
+                })
+                .map(|index| previous.swap_remove(index).tree);
             let parser = self.parser_for(&key, language)?;
             parser.set_included_ranges(&ranges)?;
-            let parsed = parser.parse(text, None);
+            let parsed = parser.parse(text, old_tree.as_ref());
             // Restore the default (whole-document) range unconditionally, or the
             // next user of this pooled parser silently parses a keyhole.
             parser.set_included_ranges(&[])?;
+            if old_tree.is_some() {
+                self.injected_incremental_parses += 1;
+            } else {
+                self.injected_full_parses += 1;
+            }
+            match (&old_tree, &parsed) {
+                (Some(old_tree), Some(tree)) => changed.extend(
+                    old_tree
+                        .changed_ranges(tree)
+                        .map(|range| range.start_point..range.end_point),
+                ),
+                _ => changed.extend(
+                    ranges
+                        .iter()
+                        .map(|range| range.start_point..range.end_point),
+                ),
+            }
             if let Some(tree) = parsed {
-                injections.push(InjectedTree { lang: target, tree });
+                injections.push(InjectedTree {
+                    lang: target,
+                    tree,
+                    depth,
+                    ranges,
+                    injected_ranges: Vec::new(),
+                });
             }
         }
-        Ok((injections, injected_ranges))
+        Ok(injections)
     }
 
     /// Apply a line-range splice reported by the editor, then reuse the normal
@@ -908,7 +1942,7 @@ impl Server {
 
         // 还原为逻辑行；缓存文本总是 join(lines, "\n") + (eol ? "\n" : "")。
         let spliced: Result<String> = {
-            let old_text = cache.text.as_str();
+            let old_text = &*cache.text;
             let body = old_text.strip_suffix('\n').unwrap_or(old_text);
             let old_lines: Vec<&str> = body.split('\n').collect();
 
@@ -958,34 +1992,88 @@ impl Server {
         }
     }
 
+    /// Evict buffers until `current_buf` fits, taking them from whichever
+    /// `--listen` connection holds the most source, the caller included, so
+    /// one editor cannot crowd out the rest. An evicted buffer's client
+    /// resyncs it after a "not cached" error, like after any eviction.
     fn reserve_cache_capacity(&mut self, current_buf: i64, incoming_bytes: usize) {
         loop {
-            let contains_current = self.cache.contains_key(&current_buf);
-            let resulting_count = self.cache.len() + usize::from(!contains_current);
-            let bytes_without_current: usize = self
-                .cache
-                .iter()
-                .filter(|(buf, _)| **buf != current_buf)
-                .map(|(_, cache)| cache.text.len())
-                .sum();
+            let current = self.cache.get(&current_buf).map(|cache| cache.text.len());
+            let resulting_count = self.all_cached().count() + usize::from(current.is_none());
+            let bytes_without_current = self
+                .all_cached()
+                .map(|cache| cache.text.len())
+                .sum::<usize>()
+                - current.unwrap_or(0);
             if resulting_count <= MAX_CACHED_BUFFERS
                 && bytes_without_current.saturating_add(incoming_bytes) <= MAX_CACHED_SOURCE_BYTES
             {
                 break;
             }
-            let Some(victim) = self
+            let own_victim = self
                 .cache
                 .keys()
                 .copied()
-                .find(|buffer| *buffer != current_buf)
-            else {
-                break;
-            };
-            self.cache.remove(&victim);
+                .find(|buffer| *buffer != current_buf);
+            let own_bytes: usize = self
+                .cache
+                .iter()
+                .filter(|(buf, _)| **buf != current_buf)
+                .map(|(_, cache)| cache.text.len())
+                .sum();
+            let largest_session = self
+                .sessions
+                .iter()
+                .filter(|(_, session)| !session.cache.is_empty())
+                .map(|(id, session)| {
+                    let bytes: usize = session.cache.values().map(|cache| cache.text.len()).sum();
+                    (bytes, *id)
+                })
+                .max()
+                .filter(|(bytes, _)| own_victim.is_none() || *bytes > own_bytes);
+            match (largest_session, own_victim) {
+                (Some((_, id)), _) => {
+                    let session = self.sessions.get_mut(&id).unwrap();
+                    let victim = *session.cache.keys().next().unwrap();
+                    session.cache.remove(&victim);
+                }
+                (None, Some(victim)) => {
+                    self.cache.remove(&victim);
+                }
+                (None, None) => break,
+            }
             self.cache_evictions += 1;
         }
     }
 
+    /// A server holding only `buf`'s cache and the compiled queries, for a
+    /// worker to answer a read-only request from. None when the request would
+    /// fail anyway (uncached buffer, wrong language): the error is answered
+    /// inline like before.
+    fn snapshot(&mut self, buf: i64, lang: &str) -> Option<Server> {
+        self.ensure_queries(lang).ok()?;
+        let injected: Vec<&'static str> = self
+            .get_cache(buf, lang)
+            .ok()?
+            .injections
+            .iter()
+            .map(|injected| injected.lang)
+            .collect();
+        for injected_lang in injected {
+            self.ensure_queries(injected_lang).ok();
+        }
+        let mut snapshot = Server::new();
+        snapshot
+            .cache
+            .insert(buf, std::sync::Arc::clone(self.cache.get(&buf)?));
+        snapshot.queries = self.queries.clone();
+        snapshot.injection_queries = self.injection_queries.clone();
+        snapshot.query_overrides = self.query_overrides.clone();
+        snapshot.injection_depth = self.injection_depth;
+        snapshot.binary_frames = self.binary_frames;
+        Some(snapshot)
+    }
+
     fn get_cache(&self, buf: i64, lang: &str) -> Result<&BufCache> {
         let c = self
             .cache
@@ -1002,6 +2090,32 @@ impl Server {
     }
 }
 
+/// 1-based inclusive line ranges covering `ranges`, sorted and merged.
+fn merge_changed_lines(ranges: &[ops::Range<tree_sitter::Point>]) -> Vec<[u32; 2]> {
+    let mut lines: Vec<[u32; 2]> = ranges
+        .iter()
+        .map(|range| {
+            let start = range.start.row as u32 + 1;
+            // An end at column 0 stops before that line's first byte.
+            let end = if range.end.column == 0 && range.end.row > range.start.row {
+                range.end.row
+            } else {
+                range.end.row + 1
+            };
+            [start, (end as u32).max(start)]
+        })
+        .collect();
+    lines.sort_unstable();
+    let mut merged: Vec<[u32; 2]> = Vec::with_capacity(lines.len());
+    for [start, end] in lines {
+        match merged.last_mut() {
+            Some(last) if start <= last[1] + 1 => last[1] = last[1].max(end),
+            _ => merged.push([start, end]),
+        }
+    }
+    merged
+}
+
 /// Compute one conservative edit that turns `old` into `new`.
 ///
 /// Tree-sitter only needs a valid edit; it does not have to be the smallest
@@ -1060,15 +2174,44 @@ fn byte_offset_to_point(text: &str, offset: usize) -> tree_sitter::Point {
 
 const USAGE: &str = "\
 Usage: ts-hl-daemon [OPTION]
+       ts-hl-daemon --listen PATH [--idle-timeout SECONDS]
+       ts-hl-daemon --lsp
+       ts-hl-daemon grep --lang LANG [--json] QUERY PATH...
+       ts-hl-daemon highlight [--lang LANG] [--format FORMAT] [FILE]
 
 With no arguments the daemon serves newline-delimited JSON requests on stdin
 and writes replies to stdout.  That is how the Vim plugin starts it; there is
 nothing useful to do with it interactively.
 
+--listen serves the same protocol on a Unix domain socket at PATH, so several
+editors share one process and its compiled grammars and queries.  Each
+connection has buffer numbers of its own.  The daemon exits once no client has
+been connected for SECONDS (default 600; 0 never exits).
+
+--lsp speaks the Language Server Protocol on stdin and stdout instead:
+document symbols, folding and selection ranges, semantic tokens and parse
+errors as diagnostics, for editors other than Vim.
+
 Options:
   -V, --version    print the version and exit
   -h, --help       print this help and exit
       --self-test  compile every bundled grammar's queries and exit
+
+grep runs the tree-sitter QUERY over every LANG file under each PATH and
+prints one `file:line:col:capture:text` line per capture, a format `:cexpr`
+and 'grepformat' read as is.  Files named on the command line are searched
+whatever their extension; directories are walked, skipping hidden entries.
+  -l, --lang LANG  grammar to parse with and to pick files by
+      --json       print one JSON object per capture instead
+Exit status is 0 when something matched, 1 when nothing did, 2 on error.
+
+highlight colours FILE, or stdin when FILE is missing or `-`, the way the Vim
+plugin does, injected languages and rainbow brackets included.  LANG defaults
+to the one FILE's extension names.
+  -l, --lang LANG      grammar to parse with
+  -f, --format FORMAT  `ansi` for the terminal (the default), `html` for a
+                       standalone page with one CSS class per TS* group, or
+                       `json` for the raw highlight spans
 ";
 
 /// Loads and compiles the highlight queries for every bundled grammar.
@@ -1087,6 +2230,517 @@ fn self_test() -> Result<()> {
     Ok(())
 }
 
+/// Options of the `grep` subcommand.
+struct GrepOptions {
+    lang: &'static str,
+    query: String,
+    paths: Vec<std::path::PathBuf>,
+    json: bool,
+}
+
+/// One `grep` hit in `--json` form: a `query` capture plus where it was found
+/// and the first line of the captured text.
+#[derive(Serialize)]
+struct GrepMatch<'a> {
+    path: &'a str,
+    #[serde(flatten)]
+    capture: &'a QueryCapture,
+    text: &'a str,
+}
+
+fn parse_grep_args(args: &[String]) -> Result<GrepOptions> {
+    let mut lang = None;
+    let mut json = false;
+    let mut positional = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-l" | "--lang" => {
+                let tag = iter
+                    .next()
+                    .ok_or_else(|| anyhow!("{arg} needs a language"))?;
+                lang = Some(
+                    injection_language_for_tag(tag)
+                        .ok_or_else(|| anyhow!("unsupported language: {tag}"))?,
+                );
+            }
+            "--json" => json = true,
+            "--" => positional.extend(iter.by_ref().cloned()),
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(anyhow!("unknown grep option: {flag}"));
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+    let lang = lang.ok_or_else(|| anyhow!("grep needs --lang"))?;
+    let mut positional = positional.into_iter();
+    let query = positional
+        .next()
+        .ok_or_else(|| anyhow!("grep needs a query"))?;
+    let paths: Vec<_> = positional.map(std::path::PathBuf::from).collect();
+    if paths.is_empty() {
+        return Err(anyhow!("grep needs at least one path"));
+    }
+    Ok(GrepOptions {
+        lang,
+        query,
+        paths,
+        json,
+    })
+}
+
+/// Grammar for a file found while walking a directory, from its extension
+/// through the same aliases as a fenced code block's tag.
+fn grep_language_for_path(path: &std::path::Path) -> Option<&'static str> {
+    injection_language_for_tag(path.extension()?.to_str()?)
+}
+
+/// Files to search under `path`, in name order. Hidden entries and symlinked
+/// directories are skipped; a file named explicitly is always searched.
+fn grep_files(path: &std::path::Path, lang: &str, out: &mut Vec<std::path::PathBuf>) {
+    let mut stack = vec![path.to_path_buf()];
+    let mut explicit = true;
+    while let Some(path) = stack.pop() {
+        let is_dir = if explicit {
+            path.is_dir()
+        } else {
+            std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.is_dir())
+        };
+        if !is_dir {
+            if explicit || grep_language_for_path(&path) == Some(lang) {
+                out.push(path);
+            }
+        } else if let Ok(entries) = std::fs::read_dir(&path) {
+            let mut children: Vec<_> = entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+                .map(|entry| entry.path())
+                .collect();
+            children.sort();
+            stack.extend(children.into_iter().rev());
+        }
+        explicit = false;
+    }
+}
+
+/// Run a `grep`: true when any capture was printed. Unreadable, oversized and
+/// non-UTF-8 files are reported on `err` and skipped, like grep does.
+fn run_grep(opts: &GrepOptions, out: &mut dyn Write, err: &mut dyn Write) -> Result<bool> {
+    let (language, _, _) = Server::lang_info(opts.lang)?;
+    let query = compile_query(&language, &opts.query)?;
+    let mut parser = tree_sitter::Parser::new();
+    parser.set_language(&language)?;
+    let mut files = Vec::new();
+    for path in &opts.paths {
+        grep_files(path, opts.lang, &mut files);
+    }
+    let mut matched = false;
+    for file in files {
+        let name = file.to_string_lossy();
+        let text = match std::fs::metadata(&file) {
+            Ok(meta) if meta.len() > MAX_SOURCE_BYTES as u64 => {
+                writeln!(err, "{name}: skipped, larger than {MAX_SOURCE_BYTES} bytes")?;
+                continue;
+            }
+            Ok(_) => match std::fs::read_to_string(&file) {
+                Ok(text) => text,
+                Err(error) => {
+                    writeln!(err, "{name}: {error}")?;
+                    continue;
+                }
+            },
+            Err(error) => {
+                writeln!(err, "{name}: {error}")?;
+                continue;
+            }
+        };
+        let Some(tree) = parser.parse(&text, None) else {
+            writeln!(err, "{name}: parse failed")?;
+            continue;
+        };
+        let (captures, truncated) =
+            collect_query_captures(&query, tree.root_node(), &text, None, MAX_QUERY_CAPTURES);
+        for (capture, range) in &captures {
+            let captured = text[range.clone()].lines().next().unwrap_or("");
+            if opts.json {
+                let hit = GrepMatch {
+                    path: &name,
+                    capture,
+                    text: captured,
+                };
+                serde_json::to_writer(&mut *out, &hit)?;
+                writeln!(out)?;
+            } else {
+                writeln!(
+                    out,
+                    "{name}:{}:{}:{}:{captured}",
+                    capture.lnum, capture.col, capture.capture
+                )?;
+            }
+        }
+        if truncated {
+            writeln!(err, "{name}: stopped after {MAX_QUERY_CAPTURES} captures")?;
+        }
+        matched |= !captures.is_empty();
+    }
+    Ok(matched)
+}
+
+fn grep_main(args: &[String]) -> i32 {
+    let opts = match parse_grep_args(args) {
+        Ok(opts) => opts,
+        Err(error) => {
+            eprintln!("ts-hl-daemon grep: {error}\n\n{USAGE}");
+            return 2;
+        }
+    };
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    let result = run_grep(&opts, &mut out, &mut std::io::stderr()).and_then(|matched| {
+        out.flush()?;
+        Ok(matched)
+    });
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        // `grep ... | head` closing the pipe early is not an error.
+        Err(error)
+            if error
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe) =>
+        {
+            0
+        }
+        Err(error) => {
+            eprintln!("ts-hl-daemon grep: {error}");
+            2
+        }
+    }
+}
+
+/// Output form of the `highlight` subcommand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HighlightFormat {
+    Ansi,
+    Html,
+    Json,
+}
+
+/// Options of the `highlight` subcommand. No `path` means stdin.
+struct HighlightOptions {
+    lang: Option<&'static str>,
+    path: Option<std::path::PathBuf>,
+    format: HighlightFormat,
+}
+
+fn parse_highlight_args(args: &[String]) -> Result<HighlightOptions> {
+    let mut lang = None;
+    let mut format = HighlightFormat::Ansi;
+    let mut positional = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-l" | "--lang" => {
+                let tag = iter
+                    .next()
+                    .ok_or_else(|| anyhow!("{arg} needs a language"))?;
+                lang = Some(
+                    injection_language_for_tag(tag)
+                        .ok_or_else(|| anyhow!("unsupported language: {tag}"))?,
+                );
+            }
+            "-f" | "--format" => {
+                format = match iter.next().map(String::as_str) {
+                    Some("ansi") => HighlightFormat::Ansi,
+                    Some("html") => HighlightFormat::Html,
+                    Some("json") => HighlightFormat::Json,
+                    Some(other) => return Err(anyhow!("unknown format: {other}")),
+                    None => return Err(anyhow!("{arg} needs ansi, html or json")),
+                };
+            }
+            "--" => positional.extend(iter.by_ref().cloned()),
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(anyhow!("unknown highlight option: {flag}"));
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+    if positional.len() > 1 {
+        return Err(anyhow!("highlight takes one file"));
+    }
+    let path = positional
+        .pop()
+        .filter(|path| path != "-")
+        .map(std::path::PathBuf::from);
+    if lang.is_none() && path.as_deref().and_then(grep_language_for_path).is_none() {
+        return Err(anyhow!("highlight needs --lang for this input"));
+    }
+    Ok(HighlightOptions { lang, path, format })
+}
+
+/// Terminal and HTML styling per highlight group: `(group, SGR parameters,
+/// CSS declarations)`. Colours follow the plugin's rainbow palette; groups
+/// missing here, like variables and punctuation, keep the default colour.
+const HIGHLIGHT_STYLES: &[(&str, &str, &str)] = &[
+    (
+        "TSComment",
+        "3;38;5;242",
+        "color: #5c6370; font-style: italic",
+    ),
+    ("TSString", "38;5;114", "color: #98c379"),
+    ("TSLiteral", "38;5;114", "color: #98c379"),
+    ("TStringRegex", "38;5;73", "color: #56b6c2"),
+    ("TStringEscape", "38;5;73", "color: #56b6c2"),
+    ("TStringSpecial", "38;5;73", "color: #56b6c2"),
+    ("TSNumber", "38;5;173", "color: #d19a66"),
+    ("TSBoolean", "38;5;173", "color: #d19a66"),
+    ("TSConstant", "38;5;173", "color: #d19a66"),
+    ("TSConstBuiltin", "38;5;173", "color: #d19a66"),
+    ("TSVariableBuiltin", "38;5;168", "color: #e06c75"),
+    ("TSVariableParameter", "38;5;168", "color: #e06c75"),
+    ("TSProperty", "38;5;168", "color: #e06c75"),
+    ("TSField", "38;5;168", "color: #e06c75"),
+    ("TSKeyword", "38;5;176", "color: #c678dd"),
+    ("TSKeywordOperator", "38;5;176", "color: #c678dd"),
+    ("TSOperator", "38;5;73", "color: #56b6c2"),
+    ("TSFunction", "38;5;75", "color: #61afef"),
+    ("TSFunctionBuiltin", "38;5;73", "color: #56b6c2"),
+    ("TSMethod", "38;5;75", "color: #61afef"),
+    ("TSMacro", "38;5;73", "color: #56b6c2"),
+    ("TSType", "38;5;180", "color: #e5c07b"),
+    ("TSTypeBuiltin", "38;5;180", "color: #e5c07b"),
+    ("TSNamespace", "38;5;180", "color: #e5c07b"),
+    ("TSAttribute", "38;5;180", "color: #e5c07b"),
+    ("TSTitle", "1;38;5;168", "color: #e06c75; font-weight: bold"),
+    ("TSEmphasis", "3", "font-style: italic"),
+    ("TSStrong", "1", "font-weight: bold"),
+    ("TSStrike", "9", "text-decoration: line-through"),
+    (
+        "TSURI",
+        "4;38;5;75",
+        "color: #61afef; text-decoration: underline",
+    ),
+    ("TSLink", "38;5;75", "color: #61afef"),
+    ("TSRainbow1", "38;5;168", "color: #e06c75"),
+    ("TSRainbow2", "38;5;180", "color: #e5c07b"),
+    ("TSRainbow3", "38;5;75", "color: #61afef"),
+    ("TSRainbow4", "38;5;176", "color: #c678dd"),
+    ("TSRainbow5", "38;5;73", "color: #56b6c2"),
+    ("TSRainbow6", "38;5;114", "color: #98c379"),
+];
+
+/// Group a span is drawn with: its own, or for a bracket with a nesting depth
+/// the rainbow group the plugin would link it to.
+fn highlight_class(span: &Span) -> &'static str {
+    const RAINBOW: [&str; 6] = [
+        "TSRainbow1",
+        "TSRainbow2",
+        "TSRainbow3",
+        "TSRainbow4",
+        "TSRainbow5",
+        "TSRainbow6",
+    ];
+    match span.depth {
+        Some(depth) if depth > 0 => RAINBOW[(depth as usize - 1) % RAINBOW.len()],
+        _ => span.group,
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// Highlight `text` as `lang` and write it out in `format`. `title` names the
+/// HTML page. Spans come from `run_highlight_cached` with rainbow depths, so
+/// injected languages are coloured exactly as in the editor. Past
+/// `MAX_HIGHLIGHT_SPANS` the rest of the text is written plain, and `err`
+/// says so.
+fn render_highlights(
+    lang: &str,
+    text: String,
+    title: &str,
+    format: HighlightFormat,
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+) -> Result<()> {
+    const BUF: i64 = 1;
+    let mut server = Server::new();
+    server.set_text(BUF, lang, text, 1)?;
+    let (_, spans) = run_highlight_cached(&mut server, BUF, lang, None, true, None)?;
+    if spans.len() >= MAX_HIGHLIGHT_SPANS {
+        writeln!(
+            err,
+            "{title}: stopped after {MAX_HIGHLIGHT_SPANS} highlight spans"
+        )?;
+    }
+    if format == HighlightFormat::Json {
+        serde_json::to_writer(&mut *out, &spans)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    let cache = server.get_cache(BUF, lang)?;
+    let text = &*cache.text;
+    // Line starts in one pass: `line_start_byte` scans from its checkpoint on
+    // every call, which on a minified file is a scan of the file per span.
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(at, _)| at + 1))
+        .collect();
+    let byte_at = |lnum: u32, col: u32| {
+        let start = line_starts[lnum as usize - 1];
+        let end = line_starts
+            .get(lnum as usize)
+            .copied()
+            .unwrap_or(text.len());
+        (start + col as usize - 1).min(end)
+    };
+    let pieces = spans
+        .iter()
+        .map(|span| {
+            (
+                byte_at(span.lnum, span.col),
+                byte_at(span.end_lnum, span.end_col),
+                highlight_class(span),
+            )
+        })
+        .filter(|(start, end, _)| start < end)
+        .collect();
+    let segments = innermost_segments(pieces);
+    let style = |class: &str| {
+        HIGHLIGHT_STYLES
+            .iter()
+            .find(|(group, ..)| *group == class)
+            .map(|&(_, sgr, css)| (sgr, css))
+    };
+
+    let bytes = text.as_bytes();
+    let mut at = 0;
+    match format {
+        HighlightFormat::Ansi => {
+            for (start, end, class) in segments {
+                out.write_all(&bytes[at..start])?;
+                match style(class) {
+                    // Reset before every newline so a pager or a `head` that
+                    // cuts between lines never leaves the terminal coloured.
+                    Some((sgr, _)) => {
+                        for (index, line) in bytes[start..end].split(|&b| b == b'\n').enumerate() {
+                            if index > 0 {
+                                out.write_all(b"\n")?;
+                            }
+                            if !line.is_empty() {
+                                write!(out, "\x1b[{sgr}m")?;
+                                out.write_all(line)?;
+                                out.write_all(b"\x1b[0m")?;
+                            }
+                        }
+                    }
+                    None => out.write_all(&bytes[start..end])?,
+                }
+                at = end;
+            }
+            out.write_all(&bytes[at..])?;
+        }
+        HighlightFormat::Html => {
+            writeln!(out, "<!DOCTYPE html>")?;
+            writeln!(out, "<html>\n<head>\n<meta charset=\"utf-8\">")?;
+            writeln!(out, "<title>{}</title>", escape_html(title))?;
+            writeln!(out, "<style>")?;
+            writeln!(
+                out,
+                "pre.ts-hl {{ background: #282c34; color: #abb2bf; padding: 1em; }}"
+            )?;
+            for (group, _, css) in HIGHLIGHT_STYLES {
+                writeln!(out, ".{group} {{ {css} }}")?;
+            }
+            writeln!(out, "</style>\n</head>\n<body>")?;
+            write!(out, "<pre class=\"ts-hl\"><code>")?;
+            // Every group gets its class, styled or not, so a stylesheet of
+            // one's own can colour variables and punctuation too.
+            for (start, end, class) in segments {
+                write!(out, "{}", escape_html(&text[at..start]))?;
+                write!(
+                    out,
+                    "<span class=\"{class}\">{}</span>",
+                    escape_html(&text[start..end])
+                )?;
+                at = end;
+            }
+            write!(out, "{}", escape_html(&text[at..]))?;
+            writeln!(out, "</code></pre>\n</body>\n</html>")?;
+        }
+        HighlightFormat::Json => unreachable!("returned above"),
+    }
+    Ok(())
+}
+
+/// Run a `highlight`: read the file, or stdin, and render it.
+fn run_highlight(opts: &HighlightOptions, out: &mut dyn Write, err: &mut dyn Write) -> Result<()> {
+    let (text, title) = match &opts.path {
+        Some(path) => {
+            let name = path.to_string_lossy();
+            if std::fs::metadata(path)
+                .map_err(|error| anyhow!("{name}: {error}"))?
+                .len()
+                > MAX_SOURCE_BYTES as u64
+            {
+                return Err(anyhow!("{name}: larger than {MAX_SOURCE_BYTES} bytes"));
+            }
+            let text = std::fs::read_to_string(path).map_err(|error| anyhow!("{name}: {error}"))?;
+            (text, name.into_owned())
+        }
+        None => {
+            let mut text = String::new();
+            std::io::stdin()
+                .take(MAX_SOURCE_BYTES as u64 + 1)
+                .read_to_string(&mut text)?;
+            if text.len() > MAX_SOURCE_BYTES {
+                return Err(anyhow!("stdin: larger than {MAX_SOURCE_BYTES} bytes"));
+            }
+            (text, "stdin".to_string())
+        }
+    };
+    let lang = opts
+        .lang
+        .or_else(|| opts.path.as_deref().and_then(grep_language_for_path))
+        .ok_or_else(|| anyhow!("highlight needs --lang for this input"))?;
+    render_highlights(lang, text, &title, opts.format, out, err)
+}
+
+fn highlight_main(args: &[String]) -> i32 {
+    let opts = match parse_highlight_args(args) {
+        Ok(opts) => opts,
+        Err(error) => {
+            eprintln!("ts-hl-daemon highlight: {error}\n\n{USAGE}");
+            return 2;
+        }
+    };
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    match run_highlight(&opts, &mut out, &mut std::io::stderr()).and_then(|()| Ok(out.flush()?)) {
+        Ok(()) => 0,
+        // `highlight ... | head` closing the pipe early is not an error.
+        Err(error)
+            if error
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe) =>
+        {
+            0
+        }
+        Err(error) => {
+            eprintln!("ts-hl-daemon highlight: {error}");
+            1
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().skip(1).collect();
     match args.first().map(String::as_str) {
@@ -1109,6 +2763,10 @@ fn main() -> Result<()> {
                 std::process::exit(1);
             }
         },
+        Some("grep") => std::process::exit(grep_main(&args[1..])),
+        Some("highlight") => std::process::exit(highlight_main(&args[1..])),
+        Some("--listen") => std::process::exit(listen_main(&args[1..])),
+        Some("--lsp") => std::process::exit(lsp_main()),
         Some(other) => {
             eprintln!("unknown argument: {other}\n\n{USAGE}");
             std::process::exit(2);
@@ -1117,1116 +2775,3242 @@ fn main() -> Result<()> {
 }
 
 fn serve() -> Result<()> {
-    let stdin = std::io::stdin();
-    let lines = BufReader::new(stdin).lines();
-    let mut out = std::io::stdout();
+    let batches = read_batches(std::io::stdin());
+    let out: SharedOut = std::sync::Arc::new(std::sync::Mutex::new(Box::new(std::io::stdout())));
+    let workers = std::thread::available_parallelism()
+        .map_or(2, |count| count.get())
+        .clamp(2, MAX_WORKERS);
+    let pool = WorkerPool::new(workers, out);
     let mut server = Server::new();
+    let result = serve_batches(&mut server, &pool, batches);
+    // Requests already read are still answered before the daemon exits.
+    pool.finish();
+    result
+}
 
-    for line in lines {
-        let line = match line {
-            Ok(s) => s,
-            Err(_) => break,
-        };
-        if line.trim().is_empty() {
-            continue;
+/// Lines of `input` in batches: each batch is everything that arrived while
+/// the last one was being answered.
+fn read_batches(input: impl std::io::Read + Send + 'static) -> impl Iterator<Item = Vec<String>> {
+    // Lines are read on their own thread so that whatever piled up while the
+    // last batch was being answered can be taken, and coalesced, in one go.
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(input).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(line).is_err() {
+                break;
+            }
         }
-        let req = match serde_json::from_str::<Request>(&line) {
-            Ok(r) => r,
-            Err(e) => {
-                send(
-                    &mut out,
-                    &Event::Error {
-                        message: format!("invalid request: {e}"),
-                        buf: None,
-                        op: None,
-                        request_id: None,
-                    },
-                )?;
-                continue;
+    });
+    std::iter::from_fn(move || {
+        let mut batch = vec![receiver.recv().ok()?];
+        batch.extend(receiver.try_iter());
+        Some(batch)
+    })
+}
+
+/// Answer batches of request lines in order. Syncs and configuration are
+/// answered here, one at a time, so every buffer's edits apply in the order
+/// they were sent; read-only requests go to the pool with a snapshot of their
+/// buffer, unless a newer one asking the same question is in the batch.
+fn serve_batches(
+    server: &mut Server,
+    pool: &WorkerPool,
+    batches: impl Iterator<Item = Vec<String>>,
+) -> Result<()> {
+    for batch in batches {
+        let requests: Vec<_> = batch.iter().filter_map(|line| read_request(line)).collect();
+        let superseded = superseded_requests(&requests);
+        for (request, superseded) in requests.into_iter().zip(superseded) {
+            let mut reply = Vec::new();
+            serve_request(server, pool, request, superseded, &mut reply)?;
+            if !reply.is_empty() {
+                pool.write(&reply)?;
             }
-        };
-        match req {
-            Request::SetText {
-                buf,
-                lang,
-                text,
-                revision,
-            } => match server.set_text(buf, &lang, text, revision) {
-                Ok(_) => send(
-                    &mut out,
-                    &Event::Ok {
-                        buf,
-                        op: "set_text".to_string(),
-                        revision: Some(revision),
-                    },
-                )?,
-                Err(e) => send(
-                    &mut out,
-                    &Event::Error {
-                        message: e.to_string(),
-                        buf: Some(buf),
-                        op: Some("set_text"),
-                        request_id: None,
-                    },
-                )?,
-            },
-            Request::EditLines {
-                buf,
-                lang,
-                revision,
-                lstart,
-                old_lend,
-                lines,
-                line_count,
-                eol,
-            } => {
-                let splice = LineSplice {
-                    lstart,
-                    old_lend,
-                    lines,
-                    line_count,
-                    eol,
-                };
-                match server.edit_lines(buf, &lang, revision, splice) {
-                    Ok(_) => send(
-                        &mut out,
-                        &Event::Ok {
-                            buf,
-                            op: "edit_lines".to_string(),
-                            revision: Some(revision),
-                        },
-                    )?,
-                    Err(e) => send(
-                        &mut out,
-                        &Event::Error {
-                            message: e.to_string(),
-                            buf: Some(buf),
-                            op: Some("edit_lines"),
-                            request_id: None,
-                        },
-                    )?,
-                }
-            }
-            Request::Highlight {
-                buf,
-                lang,
-                lstart,
-                lend,
-                rainbow,
-                max_spans,
-                compact,
-            } => {
-                let lrange = lstart.zip(lend);
-                match run_highlight_cached(&mut server, buf, &lang, lrange, rainbow, max_spans) {
-                    Ok((revision, spans)) => {
-                        let (groups, cspans) = if compact {
-                            let (groups, rows) = compact_spans(&spans);
-                            (Some(groups), Some(rows))
-                        } else {
-                            (None, None)
-                        };
-                        send(
-                            &mut out,
-                            &Event::Highlights {
-                                buf,
-                                revision,
-                                spans: if compact { None } else { Some(spans) },
-                                groups,
-                                cspans,
-                            },
-                        )?
-                    }
-                    Err(e) => send(
-                        &mut out,
-                        &Event::Error {
-                            message: e.to_string(),
-                            buf: Some(buf),
-                            op: Some("highlight"),
-                            request_id: None,
-                        },
-                    )?,
-                }
+        }
+    }
+    Ok(())
+}
+
+/// Answer one request of a batch: inline into `reply`, or by handing it to
+/// `pool`, whose worker writes the reply itself.
+fn serve_request(
+    server: &mut Server,
+    pool: &WorkerPool,
+    request: std::result::Result<Request, Event>,
+    superseded: bool,
+    reply: &mut Vec<u8>,
+) -> Result<()> {
+    // Jobs the pool dropped since the last request count as well.
+    server.superseded_requests += pool
+        .superseded
+        .swap(0, std::sync::atomic::Ordering::Relaxed);
+    match request {
+        Err(error) => send(reply, &error)?,
+        Ok(Request::Cancel { request_id }) => pool.cancel(request_id),
+        Ok(request) if superseded => {
+            server.superseded_requests += 1;
+            if let Some((buf, _, op, request_id)) = pooled_request(&request) {
+                send(reply, &superseded_error(buf, op, request_id))?;
             }
-            Request::Symbols {
-                buf,
-                lang,
-                request_id,
-                lstart,
-                lend,
-                max_items,
-                kinds,
-                have_digest,
-            } => {
-                let lrange = lstart.zip(lend);
-                match run_symbols_cached_filtered(
-                    &mut server,
+        }
+        Ok(request) => {
+            let pooled = pooled_request(&request).and_then(|(buf, lang, op, request_id)| {
+                Some((server.snapshot(buf, lang)?, buf, op, request_id))
+            });
+            match pooled {
+                Some((snapshot, buf, op, request_id)) => pool.submit(PooledJob {
                     buf,
-                    &lang,
-                    lrange,
-                    max_items,
-                    &kinds,
-                ) {
-                    Ok((revision, symbols)) => {
-                        let digest = digest_symbols(&symbols);
-                        let unchanged = !have_digest.is_empty() && have_digest == digest;
-                        send(
-                            &mut out,
-                            &Event::Symbols {
-                                buf,
-                                revision,
-                                request_id,
-                                symbols: if unchanged { None } else { Some(symbols) },
-                                digest,
-                                unchanged,
-                            },
-                        )?
-                    }
-                    Err(e) => send(
-                        &mut out,
-                        &Event::Error {
-                            message: e.to_string(),
-                            buf: Some(buf),
-                            op: Some("symbols"),
-                            request_id: Some(request_id),
-                        },
-                    )?,
-                }
-            }
-            Request::Folds {
-                buf,
-                lang,
-                max_items,
-                have_digest,
-            } => match run_folds_cached(&server, buf, &lang, max_items) {
-                Ok((revision, folds)) => {
-                    let digest = digest_folds(&folds);
-                    let unchanged = !have_digest.is_empty() && have_digest == digest;
-                    send(
-                        &mut out,
-                        &Event::Folds {
-                            buf,
-                            revision,
-                            folds: if unchanged { None } else { Some(folds) },
-                            digest,
-                            unchanged,
-                        },
-                    )?
-                }
-                Err(e) => send(
-                    &mut out,
-                    &Event::Error {
-                        message: e.to_string(),
-                        buf: Some(buf),
-                        op: Some("folds"),
-                        request_id: None,
-                    },
-                )?,
-            },
-            Request::DumpAst { buf, lang } => match dump_ast_cached(&mut server, buf, &lang) {
-                Ok((revision, lines)) => send(
-                    &mut out,
-                    &Event::Ast {
-                        buf,
-                        revision,
-                        lines,
-                    },
-                )?,
-                Err(e) => send(
-                    &mut out,
-                    &Event::Error {
-                        message: e.to_string(),
-                        buf: Some(buf),
-                        op: Some("dump_ast"),
-                        request_id: None,
-                    },
-                )?,
-            },
-            Request::Inspect {
-                buf,
-                lang,
-                lnum,
-                col,
-            } => match inspect_cached(&mut server, buf, &lang, lnum, col) {
-                Ok((revision, captures, node_chain)) => send(
-                    &mut out,
-                    &Event::Inspect {
-                        buf,
-                        revision,
-                        lnum,
-                        col,
-                        captures,
-                        node_chain,
-                    },
-                )?,
-                Err(e) => send(
-                    &mut out,
-                    &Event::Error {
-                        message: e.to_string(),
-                        buf: Some(buf),
-                        op: Some("inspect"),
-                        request_id: None,
-                    },
-                )?,
-            },
-            Request::Scope {
-                buf,
-                lang,
-                lnum,
-                col,
-            } => match scope_chain_cached(&server, buf, &lang, lnum, col) {
-                Ok(answer) => send(
-                    &mut out,
-                    &Event::Scope {
-                        buf,
-                        revision: answer.revision,
-                        lnum,
-                        col,
-                        anchor_lnum: answer.anchor[0],
-                        anchor_col: answer.anchor[1],
-                        anchor_end_lnum: answer.anchor[2],
-                        anchor_end_col: answer.anchor[3],
-                        chain: answer.chain,
-                    },
-                )?,
-                Err(e) => send(
-                    &mut out,
-                    &Event::Error {
-                        message: e.to_string(),
-                        buf: Some(buf),
-                        op: Some("scope"),
-                        request_id: None,
-                    },
-                )?,
-            },
-            Request::CloseBuffer { buf } => {
-                server.cache.remove(&buf);
-                send(
-                    &mut out,
-                    &Event::Ok {
-                        buf,
-                        op: "close_buffer".to_string(),
-                        revision: None,
-                    },
-                )?;
-            }
-            Request::Status => send(
-                &mut out,
-                &Event::Status {
-                    protocol_version: PROTOCOL_VERSION,
-                    version: env!("CARGO_PKG_VERSION"),
-                    cached_buffers: server.cache.len(),
-                    full_parses: server.full_parses,
-                    incremental_parses: server.incremental_parses,
-                    unchanged_syncs: server.unchanged_syncs,
-                    cached_bytes: server.cache.values().map(|cache| cache.text.len()).sum(),
-                    cache_evictions: server.cache_evictions,
-                    languages: SUPPORTED_LANGUAGES,
-                },
-            )?,
-            Request::Hello { .. } => {
-                send(
-                    &mut out,
-                    &Event::Hello {
-                        protocol_version: PROTOCOL_VERSION,
-                        version: env!("CARGO_PKG_VERSION"),
-                        capabilities: &[
-                            "revision",
-                            "incremental_parse",
-                            "close_buffer",
-                            "status",
-                            "bounded_results",
-                            "edit_lines",
-                            "folds",
-                            "symbol_kind_filter",
-                            "error_op",
-                            "symbol_request_id",
-                            "inspect",
-                            "scope",
-                            "injections",
-                            "payload_digest",
-                            "compact_spans",
-                        ],
-                    },
-                )?;
+                    op,
+                    request_id,
+                    question: request_question(&request).unwrap_or_default(),
+                    sequence: 0,
+                    snapshot,
+                    request,
+                }),
+                None => handle_request(server, request, reply)?,
             }
         }
     }
     Ok(())
 }
 
-fn send(out: &mut std::io::Stdout, ev: &Event) -> Result<()> {
-    let js = serde_json::to_string(ev)?;
-    out.write_all(js.as_bytes())?;
-    out.write_all(b"\n")?;
-    out.flush()?;
-    Ok(())
+fn superseded_error(buf: i64, op: &'static str, request_id: u64) -> Event {
+    Event::Error {
+        message: "superseded".to_string(),
+        buf: Some(buf),
+        op: Some(op),
+        request_id: (request_id != 0).then_some(request_id),
+        location: None,
+    }
 }
 
-// 将行号范围转为字节范围（用于 QueryCursor 限制扫描区间）
-#[cfg(test)]
-fn line_range_to_byte_range(text: &str, ls: u32, le: u32) -> ops::Range<usize> {
-    line_range_from_index(&SparseLineIndex::new(text), text, ls, le)
+/// Which requests of a batch a later one makes pointless: a read-only request
+/// is superseded by a newer one asking the same question of the same buffer. A
+/// client that sent `highlight` for revisions 10, 11 and 12 only keeps the
+/// reply to 12, whatever syncs came in between. The question includes every
+/// argument but the request id: two windows highlighting different ranges of
+/// one buffer, or the Outline and a symbol jump, are both answered. A request
+/// of an earlier batch still queued in the pool is caught there instead
+/// (`WorkerPool::newest`).
+fn superseded_requests(requests: &[std::result::Result<Request, Event>]) -> Vec<bool> {
+    let mut newer = std::collections::HashSet::new();
+    let mut superseded = vec![false; requests.len()];
+    for (index, request) in requests.iter().enumerate().rev() {
+        let Some(question) = request.as_ref().ok().and_then(request_question) else {
+            continue;
+        };
+        superseded[index] = !newer.insert(question);
+    }
+    superseded
 }
 
-impl SparseLineIndex {
-    fn new(text: &str) -> Self {
-        let mut checkpoints = vec![0];
-        let mut newline_count = 0_usize;
-        for (index, byte) in text.bytes().enumerate() {
-            if byte != b'\n' {
-                continue;
-            }
-            newline_count += 1;
-            if newline_count.is_multiple_of(LINE_INDEX_STRIDE) {
-                checkpoints.push(index + 1);
-            }
+/// The question a read-only request asks, as `superseded_requests` and the
+/// worker pool compare them: op, buffer, and every argument but the request
+/// id.
+type Question = (&'static str, i64, String);
+
+fn request_question(request: &Request) -> Option<Question> {
+    let (buf, _, op, _) = pooled_request(request)?;
+    let shape = match request {
+        Request::Highlight {
+            lstart,
+            lend,
+            rainbow,
+            max_spans,
+            compact,
+            have_blocks,
+            ..
+        } => {
+            // Sorted, so the same blocks in another order still match.
+            let have_blocks: Option<BTreeMap<_, _>> =
+                have_blocks.as_ref().map(|blocks| blocks.iter().collect());
+            format!("{lstart:?} {lend:?} {rainbow} {max_spans:?} {compact} {have_blocks:?}")
         }
-        Self {
-            checkpoints: checkpoints.into_boxed_slice(),
-            line_count: newline_count + 1,
+        Request::Folds { max_items, .. } | Request::Diagnostics { max_items, .. } => {
+            format!("{max_items:?}")
         }
-    }
+        Request::Inspect { lnum, col, .. } | Request::Scope { lnum, col, .. } => {
+            format!("{lnum} {col}")
+        }
+        Request::Symbols {
+            lstart,
+            lend,
+            max_items,
+            kinds,
+            ..
+        } => format!("{lstart:?} {lend:?} {max_items:?} {kinds:?}"),
+        Request::SemanticTokens {
+            lstart,
+            lend,
+            encoding,
+            ..
+        } => format!("{lstart:?} {lend:?} {encoding}"),
+        _ => String::new(),
+    };
+    Some((op, buf, shape))
+}
 
-    fn line_start_byte(&self, text: &str, line: u32) -> usize {
-        let Some(target_line) = usize::try_from(line.saturating_sub(1)).ok() else {
-            return text.len();
-        };
-        if target_line >= self.line_count {
-            return text.len();
+/// Buffer, language, op and request id of a request the pool may answer.
+///
+/// Only requests that read a buffer's tree qualify: they can run against a
+/// snapshot, so a slow full `highlight` or `dump_ast` no longer holds up the
+/// `scope` request for the cursor behind it.
+fn pooled_request(request: &Request) -> Option<(i64, &str, &'static str, u64)> {
+    Some(match request {
+        Request::Highlight {
+            buf,
+            lang,
+            request_id,
+            ..
+        } => (*buf, lang, "highlight", *request_id),
+        Request::Symbols {
+            buf,
+            lang,
+            request_id,
+            ..
+        } => (*buf, lang, "symbols", *request_id),
+        Request::Folds {
+            buf,
+            lang,
+            request_id,
+            ..
+        } => (*buf, lang, "folds", *request_id),
+        Request::Diagnostics {
+            buf,
+            lang,
+            request_id,
+            ..
+        } => (*buf, lang, "diagnostics", *request_id),
+        Request::DumpAst {
+            buf,
+            lang,
+            request_id,
+        } => (*buf, lang, "dump_ast", *request_id),
+        Request::Inspect {
+            buf,
+            lang,
+            request_id,
+            ..
+        } => (*buf, lang, "inspect", *request_id),
+        Request::Scope {
+            buf,
+            lang,
+            request_id,
+            ..
+        } => (*buf, lang, "scope", *request_id),
+        Request::SemanticTokens {
+            buf,
+            lang,
+            request_id,
+            ..
+        } => (*buf, lang, "semantic_tokens", *request_id),
+        _ => return None,
+    })
+}
+
+/// Set by `cancel` for one pooled request.
+type CancelFlag = std::sync::Arc<std::sync::atomic::AtomicBool>;
+
+/// Replies written from several threads; each is written whole under the lock.
+type SharedOut = std::sync::Arc<std::sync::Mutex<Box<dyn Write + Send>>>;
+
+struct PooledJob {
+    buf: i64,
+    op: &'static str,
+    request_id: u64,
+    question: Question,
+    /// Numbers the pool's submissions; set by `WorkerPool::submit`.
+    sequence: u64,
+    snapshot: Server,
+    request: Request,
+}
+
+/// Threads answering read-only requests off the thread that reads stdin.
+///
+/// A job carries a `Server` holding its buffer's cache as it was when the
+/// request was read, so a sync that arrives meanwhile never races a running
+/// query, and the reply's `revision` names the text it describes.
+struct WorkerPool {
+    jobs: Option<std::sync::mpsc::Sender<PooledJob>>,
+    workers: Vec<std::thread::JoinHandle<()>>,
+    out: SharedOut,
+    /// Cancellable requests not answered yet, each with the flag `cancel`
+    /// sets. A running job's snapshot holds the same flag (`Server::cancel`).
+    in_flight: std::sync::Arc<std::sync::Mutex<HashMap<u64, CancelFlag>>>,
+    /// The latest submission of each question not started yet. A queued job
+    /// whose question was asked again since, in this batch or a later one,
+    /// is answered "superseded" instead of being run.
+    newest: std::sync::Arc<std::sync::Mutex<HashMap<Question, u64>>>,
+    submitted: std::sync::atomic::AtomicU64,
+    /// Jobs answered "superseded", not yet added to the server's count.
+    superseded: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl WorkerPool {
+    fn new(size: usize, out: SharedOut) -> Self {
+        let (jobs, receiver) = std::sync::mpsc::channel::<PooledJob>();
+        let receiver = std::sync::Arc::new(std::sync::Mutex::new(receiver));
+        let in_flight = std::sync::Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let newest = std::sync::Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let superseded = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = std::sync::Arc::clone(&receiver);
+                let out = std::sync::Arc::clone(&out);
+                let in_flight = std::sync::Arc::clone(&in_flight);
+                let newest = std::sync::Arc::clone(&newest);
+                let superseded = std::sync::Arc::clone(&superseded);
+                std::thread::spawn(move || {
+                    loop {
+                        let job = receiver
+                            .lock()
+                            .unwrap_or_else(|poisoned| poisoned.into_inner())
+                            .recv();
+                        let Ok(job) = job else {
+                            break;
+                        };
+                        run_pooled(job, &out, &in_flight, &newest, &superseded);
+                    }
+                })
+            })
+            .collect();
+        WorkerPool {
+            jobs: Some(jobs),
+            workers,
+            out,
+            in_flight,
+            newest,
+            submitted: std::sync::atomic::AtomicU64::new(0),
+            superseded,
         }
+    }
 
-        let checkpoint_index = target_line / LINE_INDEX_STRIDE;
-        let checkpoint_line = checkpoint_index * LINE_INDEX_STRIDE;
-        let mut offset = self.checkpoints[checkpoint_index];
-        for _ in checkpoint_line..target_line {
-            let Some(relative_newline) = text.as_bytes()[offset..]
-                .iter()
-                .position(|byte| *byte == b'\n')
-            else {
-                return text.len();
-            };
-            offset += relative_newline + 1;
+    fn submit(&self, mut job: PooledJob) {
+        job.sequence = self
+            .submitted
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.newest
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(job.question.clone(), job.sequence);
+        if job.request_id != 0 {
+            self.in_flight
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .insert(job.request_id, CancelFlag::default());
+        }
+        if let Some(jobs) = &self.jobs {
+            // Workers only stop once `finish` dropped the sender.
+            let _ = jobs.send(job);
         }
-        offset
     }
 
-    /// Byte offset -> tree-sitter `Point` (0-based row, byte column).
-    ///
-    /// Text objects need points for offsets no node starts at — an inner range
-    /// trimmed in past a brace, a parameter's trailing comma — and this runs on
-    /// a key the user holds down, so a scan from byte 0 is not affordable.
-    /// Binary-searching the checkpoints bounds the scan to one stride of lines
-    /// however far into the file the offset is.
-    fn point_at_byte(&self, text: &str, offset: usize) -> tree_sitter::Point {
-        let offset = offset.min(text.len());
-        let checkpoint_index = match self.checkpoints.binary_search(&offset) {
-            Ok(index) => index,
-            Err(index) => index.saturating_sub(1),
-        };
-        let base = self.checkpoints[checkpoint_index];
-        let mut row = checkpoint_index * LINE_INDEX_STRIDE;
-        let mut line_start = base;
-        for (index, byte) in text.as_bytes()[base..offset].iter().enumerate() {
-            if *byte == b'\n' {
-                row += 1;
-                line_start = base + index + 1;
-            }
+    fn cancel(&self, request_id: u64) {
+        if let Some(cancel) = self
+            .in_flight
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&request_id)
+        {
+            cancel.store(true, std::sync::atomic::Ordering::Relaxed);
         }
-        tree_sitter::Point {
-            row,
-            column: offset - line_start,
+    }
+
+    fn write(&self, reply: &[u8]) -> Result<()> {
+        let mut out = self
+            .out
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        out.write_all(reply)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Wait until every submitted request has been answered.
+    fn finish(mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
         }
     }
 }
 
-fn line_range_from_index(
-    line_index: &SparseLineIndex,
-    text: &str,
-    ls: u32,
-    le: u32,
-) -> ops::Range<usize> {
-    // ls/le 为 1-based；结束偏移是 le 下一行的开头。
-    let start_line = ls.max(1);
-    let end_line = le.max(start_line);
-    let start = line_index.line_start_byte(text, start_line);
-    let end = line_index.line_start_byte(text, end_line.saturating_add(1));
-    ops::Range { start, end }
+fn run_pooled(
+    job: PooledJob,
+    out: &std::sync::Mutex<Box<dyn Write + Send>>,
+    in_flight: &std::sync::Mutex<HashMap<u64, CancelFlag>>,
+    newest: &std::sync::Mutex<HashMap<Question, u64>>,
+    superseded: &std::sync::atomic::AtomicU64,
+) {
+    let PooledJob {
+        buf,
+        op,
+        request_id,
+        question,
+        sequence,
+        mut snapshot,
+        request,
+    } = job;
+    let overtaken = {
+        let mut newest = newest
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let overtaken = newest.get(&question) != Some(&sequence);
+        if !overtaken {
+            newest.remove(&question);
+        }
+        overtaken
+    };
+    // The snapshot watches the flag too: a query already running stops at
+    // its next check rather than finishing work nobody will read.
+    snapshot.cancel = in_flight
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(&request_id)
+        .cloned();
+    let mut reply = Vec::new();
+    // Cancelled or asked again while still queued: never started at all.
+    if !snapshot.cancelled() {
+        if overtaken {
+            superseded.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let _ = send(&mut reply, &superseded_error(buf, op, request_id));
+        } else {
+            let _ = handle_request(&mut snapshot, request, &mut reply);
+        }
+    }
+    in_flight
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .remove(&request_id);
+    if snapshot.cancelled() {
+        reply.clear();
+        let _ = send(
+            &mut reply,
+            &Event::Error {
+                message: "cancelled".to_string(),
+                buf: Some(buf),
+                op: Some(op),
+                request_id: Some(request_id),
+                location: None,
+            },
+        );
+    }
+    let mut out = out.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    // A closed stdout also ends the reader, which shuts the pool down.
+    let _ = out.write_all(&reply).and_then(|()| out.flush());
 }
 
-fn expand_range_for_multiline_token(
-    root: tree_sitter::Node,
-    mut range: ops::Range<usize>,
-) -> ops::Range<usize> {
-    if range.start >= range.end {
-        return range;
+struct ListenOptions {
+    path: std::path::PathBuf,
+    idle_timeout: Option<std::time::Duration>,
+}
+
+fn parse_listen_args(args: &[String]) -> Result<ListenOptions> {
+    let mut path = None;
+    let mut idle_timeout = Some(std::time::Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS));
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--idle-timeout" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| anyhow!("{arg} needs a number of seconds"))?;
+                let seconds: u64 = value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid {arg}: {value}"))?;
+                idle_timeout = (seconds > 0).then(|| std::time::Duration::from_secs(seconds));
+            }
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(anyhow!("unknown listen option: {flag}"));
+            }
+            _ if path.is_none() => path = Some(std::path::PathBuf::from(arg)),
+            _ => return Err(anyhow!("unexpected argument: {arg}")),
+        }
     }
-    let Some(mut node) = root.descendant_for_byte_range(range.start, range.start) else {
-        return range;
+    Ok(ListenOptions {
+        path: path.ok_or_else(|| anyhow!("--listen needs a socket path"))?,
+        idle_timeout,
+    })
+}
+
+fn listen_main(args: &[String]) -> i32 {
+    let opts = match parse_listen_args(args) {
+        Ok(opts) => opts,
+        Err(error) => {
+            eprintln!("ts-hl-daemon: {error}\n\n{USAGE}");
+            return 2;
+        }
     };
-    loop {
-        let kind = node.kind();
-        if node.start_byte() < range.start
-            && node.end_byte() > range.start
-            && (kind.contains("string")
-                || kind.contains("comment")
-                || kind.contains("heredoc")
-                || kind.contains("raw_text"))
-        {
-            range.start = node.start_byte();
-            break;
+    match listen(&opts) {
+        Ok(()) => 0,
+        Err(error) => {
+            eprintln!("ts-hl-daemon: {error:#}");
+            1
         }
-        let Some(parent) = node.parent() else {
-            break;
-        };
-        node = parent;
     }
-    range
 }
 
-// 复用缓存的 Tree + bytes 做高亮
-fn run_highlight_cached(
-    server: &mut Server,
-    buf: i64,
-    lang: &str,
-    lrange: Option<(u32, u32)>,
-    rainbow: bool,
-    max_spans: Option<usize>,
-) -> Result<(u64, Vec<Span>)> {
-    server.ensure_queries(lang)?;
-    let cache = server.get_cache(buf, lang)?;
-    let bytes = cache.text.as_bytes();
-    let root = cache.tree.root_node();
-    let lang_queries = server.queries.get(&cache.lang).unwrap();
+#[cfg(not(unix))]
+fn listen(_opts: &ListenOptions) -> Result<()> {
+    Err(anyhow!("--listen needs Unix domain sockets"))
+}
 
-    // 宿主查询之外，每棵注入树再跑一遍它自己语言的高亮查询。
-    let mut passes: Vec<(&tree_sitter::Query, tree_sitter::Node, bool)> =
-        vec![(&lang_queries.hl_query, root, false)];
-    for injected in &cache.injections {
-        if let Some(query) = server.injection_queries.get(injected.lang) {
-            passes.push((&query.hl_query, injected.tree.root_node(), true));
+/// Serve every connection to `opts.path` from one shared `Server`.
+///
+/// Grammars, parsers and compiled queries are shared; buffers are not. Every
+/// Vim numbers its buffers from 1, so each connection has a `Session` of its
+/// own — buffers, subscriptions, framing, injection depth and query overrides
+/// — swapped into the server for the duration of one request. The cache caps
+/// count every session's buffers together. Each connection is served like
+/// stdin: syncs one at a time under the server's lock, read-only requests by
+/// a small pool of its own from snapshots, so one client's full `highlight`
+/// does not hold up another's `scope`.
+#[cfg(unix)]
+fn listen(opts: &ListenOptions) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::{Arc, Mutex, PoisonError};
+
+    let path = &opts.path;
+    // A socket left behind by a daemon that died is in the way of bind(); one
+    // that still answers belongs to a live daemon, and anything else is not
+    // ours to delete.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{} exists and is not a socket", path.display()));
         }
-    }
-
-    let mut spans = Vec::with_capacity(4096);
-    let limit = max_spans
-        .unwrap_or(MAX_HIGHLIGHT_SPANS)
-        .min(MAX_HIGHLIGHT_SPANS);
-    // Dedup by an explicit semantic priority. Capture iteration is ordered by
-    // source position, but same-range pattern ordering is not an API contract.
-    let mut seen = HashMap::<(u32, u32, u32, u32), (usize, u8)>::new();
-    'passes: for (query, pass_root, injected) in passes {
-        let mut cursor = tree_sitter::QueryCursor::new();
-        if let Some((ls, le)) = lrange {
-            let b_range = expand_range_for_multiline_token(
-                pass_root,
-                line_range_from_index(&cache.line_index, &cache.text, ls, le),
-            );
-            cursor.set_byte_range(b_range);
+        if UnixStream::connect(path).is_ok() {
+            return Err(anyhow!(
+                "a daemon is already listening on {}",
+                path.display()
+            ));
         }
-        let mut it = cursor.captures(query, pass_root, bytes);
-        while let Some((m, cap_ix)) = it.next() {
-            let cap = m.captures[*cap_ix];
-            let node = cap.node;
-            if node.start_byte() >= node.end_byte() {
-                continue;
-            }
-            // Inside an injected range the injected grammar has the last word:
-            // markdown captures a whole fence as @text.literal, and leaving that
-            // in place would paint one flat colour over the rust underneath it.
-            if !injected && covered_by_injection(&cache.injected_ranges, node) {
-                continue;
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|error| anyhow!("cannot listen on {}: {error}", path.display()))?;
+
+    let server = Arc::new(Mutex::new(Server::new()));
+    // (connected clients, when the last one left)
+    let clients = Arc::new(Mutex::new((0_usize, std::time::Instant::now())));
+    if let Some(idle_timeout) = opts.idle_timeout {
+        let clients = Arc::clone(&clients);
+        let path = path.clone();
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(idle_timeout.min(std::time::Duration::from_secs(1)));
+                let (connected, idle_since) =
+                    *clients.lock().unwrap_or_else(PoisonError::into_inner);
+                if connected == 0 && idle_since.elapsed() >= idle_timeout {
+                    let _ = std::fs::remove_file(&path);
+                    std::process::exit(0);
+                }
             }
-            let sp = node.start_position();
-            let ep = node.end_position();
+        });
+    }
 
-            let lnum = sp.row as u32 + 1;
-            let col = sp.column as u32 + 1;
-            let end_lnum = ep.row as u32 + 1;
-            let end_col = ep.column as u32 + 1;
+    for (session, stream) in (0..).zip(listener.incoming()) {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let server = Arc::clone(&server);
+        let clients = Arc::clone(&clients);
+        clients.lock().unwrap_or_else(PoisonError::into_inner).0 += 1;
+        std::thread::spawn(move || {
+            let _ = serve_connection(&server, session, stream);
+            // The connection's buffers go with it.
+            server
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .sessions
+                .remove(&session);
+            let mut clients = clients.lock().unwrap_or_else(PoisonError::into_inner);
+            clients.0 -= 1;
+            clients.1 = std::time::Instant::now();
+        });
+    }
+    Ok(())
+}
 
-            if let Some((ls, le)) = lrange
-                && (end_lnum < ls || lnum > le)
-            {
-                continue;
+#[cfg(unix)]
+fn serve_connection(
+    server: &std::sync::Mutex<Server>,
+    session: u64,
+    stream: std::os::unix::net::UnixStream,
+) -> Result<()> {
+    let batches = read_batches(stream.try_clone()?);
+    let out: SharedOut = std::sync::Arc::new(std::sync::Mutex::new(Box::new(stream.try_clone()?)));
+    let pool = WorkerPool::new(LISTEN_WORKERS, out);
+    let result = (|| {
+        for batch in batches {
+            let requests: Vec<_> = batch.iter().filter_map(|line| read_request(line)).collect();
+            let superseded = superseded_requests(&requests);
+            for (request, superseded) in requests.into_iter().zip(superseded) {
+                let mut reply = Vec::new();
+                {
+                    let mut server = server
+                        .lock()
+                        .unwrap_or_else(std::sync::PoisonError::into_inner);
+                    let shared = server.enter_session(session);
+                    let result = serve_request(&mut server, &pool, request, superseded, &mut reply);
+                    server.leave_session(session, shared);
+                    result?;
+                }
+                // Written outside the lock: a client that stops reading
+                // stalls only itself.
+                if !reply.is_empty() {
+                    pool.write(&reply)?;
+                }
             }
+        }
+        Ok(())
+    })();
+    // Unblocks the reader when the reply side failed first.
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    pool.finish();
+    result
+}
 
-            let key = (lnum, col, end_lnum, end_col);
-            let cname = query.capture_names()[cap.index as usize];
-            let priority = capture_priority(cname);
-            let group = map_capture_to_group(cname);
-            if group.is_empty() {
-                continue;
-            }
-            let depth = if rainbow && cname == "punctuation.bracket" {
-                let d = bracket_depth(node);
-                if d > 0 { Some(d) } else { None }
-            } else {
-                None
-            };
-            let span = Span {
-                lnum,
-                col,
-                end_lnum,
-                end_col,
-                group,
-                depth,
-            };
-            if let Some((index, old_priority)) = seen.get_mut(&key) {
-                if priority > *old_priority {
-                    spans[*index] = span;
-                    *old_priority = priority;
+/// Largest LSP message body accepted: a whole buffer at the source limit,
+/// with room for JSON escaping.
+const MAX_LSP_MESSAGE_BYTES: usize = 4 * MAX_SOURCE_BYTES;
+
+fn lsp_main() -> i32 {
+    let mut input = std::io::stdin().lock();
+    let mut out = std::io::stdout().lock();
+    match serve_lsp(&mut input, &mut out) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("ts-hl-daemon --lsp: {error:#}");
+            1
+        }
+    }
+}
+
+/// Answer LSP messages until `exit`; the exit status LSP prescribes, 0 only
+/// after a `shutdown`.
+fn serve_lsp(input: &mut dyn BufRead, out: &mut dyn Write) -> Result<i32> {
+    let mut lsp = LspServer::new();
+    while let Some(body) = read_lsp_frame(input)? {
+        match serde_json::from_slice(&body) {
+            Ok(message) => {
+                if let Some(code) = lsp.handle(message, out)? {
+                    return Ok(code);
                 }
-                continue;
             }
-            if spans.len() >= limit {
-                break 'passes;
+            Err(error) => write_lsp_message(
+                out,
+                &serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {"code": -32700, "message": error.to_string()},
+                }),
+            )?,
+        }
+    }
+    // The client went away without `exit`.
+    Ok(1)
+}
+
+/// The body of the next `Content-Length`-framed message, None at end of input.
+fn read_lsp_frame(input: &mut dyn BufRead) -> Result<Option<Vec<u8>>> {
+    let mut length = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
             }
-            seen.insert(key, (spans.len(), priority));
-            spans.push(span);
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            length = Some(
+                value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("invalid Content-Length: {}", value.trim()))?,
+            );
         }
     }
+    let length = length.unwrap_or_default();
+    if length > MAX_LSP_MESSAGE_BYTES {
+        return Err(anyhow!(
+            "message of {length} bytes exceeds the limit of {MAX_LSP_MESSAGE_BYTES}"
+        ));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(body))
+}
 
-    Ok((cache.revision, spans))
+fn write_lsp_message(out: &mut dyn Write, message: &serde_json::Value) -> Result<()> {
+    let body = serde_json::to_vec(message)?;
+    write!(out, "Content-Length: {}\r\n\r\n", body.len())?;
+    out.write_all(&body)?;
+    out.flush()?;
+    Ok(())
 }
 
-// 复用缓存 Tree + bytes 做符号
-#[cfg(test)]
-fn run_symbols_cached(
-    server: &mut Server,
-    buf: i64,
-    lang: &str,
-    lrange: Option<(u32, u32)>,
-    max_items: Option<usize>,
-) -> Result<(u64, Vec<Symbol>)> {
-    run_symbols_cached_filtered(server, buf, lang, lrange, max_items, &[])
+/// LSP language id -> bundled grammar.
+fn lsp_language(language_id: &str) -> Option<&'static str> {
+    match language_id {
+        "typescriptreact" => Some("tsx"),
+        "javascriptreact" => Some("javascript"),
+        "shellscript" | "sh" => Some("bash"),
+        "c++" => Some("cpp"),
+        other => SUPPORTED_LANGUAGES
+            .iter()
+            .find(|lang| **lang == other)
+            .copied(),
+    }
 }
 
-fn symbol_kind_allowed(kind: &str, kinds: &[String]) -> bool {
-    kinds.is_empty() || kinds.iter().any(|candidate| candidate == kind)
+/// LSP `SymbolKind` for a `symbols` kind.
+fn lsp_symbol_kind(kind: &str) -> u32 {
+    match kind {
+        "namespace" => 3,
+        "class" | "type" => 5,
+        "method" => 6,
+        "property" => 7,
+        "field" => 8,
+        "enum" => 10,
+        "function" | "macro" => 12,
+        "const" => 14,
+        "variant" => 22,
+        "struct" => 23,
+        _ => 13,
+    }
 }
 
-fn run_symbols_cached_filtered(
-    server: &mut Server,
+/// One document the editor opened, mapped onto a daemon buffer.
+struct LspDocument {
     buf: i64,
-    lang: &str,
-    lrange: Option<(u32, u32)>,
-    max_items: Option<usize>,
-    kinds: &[String],
-) -> Result<(u64, Vec<Symbol>)> {
-    server.ensure_queries(lang)?;
-    let cache = server.get_cache(buf, lang)?;
-    let bytes = cache.text.as_bytes();
-    let root = cache.tree.root_node();
-    let query = &server.queries.get(&cache.lang).unwrap().sym_query;
-    let mut cursor = tree_sitter::QueryCursor::new();
+    lang: &'static str,
+    version: i64,
+    /// The editor's copy, which incremental changes apply to. Kept here as
+    /// well as in the buffer cache, which may evict it.
+    text: String,
+}
 
-    if let Some((ls, le)) = lrange {
-        let b_range = line_range_from_index(&cache.line_index, &cache.text, ls, le);
-        cursor.set_byte_range(b_range);
-    }
+/// `--lsp` front-end: the same `Server`, driven by LSP messages instead of
+/// the line protocol.
+struct LspServer {
+    server: Server,
+    documents: HashMap<String, LspDocument>,
+    next_buf: i64,
+    encoding: PositionEncoding,
+    initialized: bool,
+    shutdown: bool,
+}
 
-    let limit = max_items.unwrap_or(MAX_SYMBOLS).min(MAX_SYMBOLS);
-    use std::collections::{HashMap, HashSet};
-    let mut seen = HashSet::<(
-        &'static str,
-        String,
-        u32,
-        u32,
-        Option<&'static str>,
-        Option<String>,
-        Option<u32>,
-        Option<u32>,
-    )>::new();
-    let mut seen_at = HashMap::<(u32, u32), &'static str>::new();
-
-    let mut symbols = Vec::with_capacity(limit.min(4096));
-
-    // 1) 先用查询收集符号
-    let mut it = cursor.captures(query, root, bytes);
-    while let Some((m, cap_ix)) = it.next() {
-        if symbols.len() >= limit {
-            break;
+impl LspServer {
+    fn new() -> Self {
+        LspServer {
+            server: Server::new(),
+            documents: HashMap::new(),
+            next_buf: 1,
+            encoding: PositionEncoding::Utf16,
+            initialized: false,
+            shutdown: false,
         }
-        let cap = m.captures[*cap_ix];
-        let node = cap.node;
-        if node.start_byte() >= node.end_byte() {
-            continue;
+    }
+
+    /// Handle one message; Some(exit status) once the client sent `exit`.
+    fn handle(&mut self, message: serde_json::Value, out: &mut dyn Write) -> Result<Option<i32>> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let Some(id) = message.get("id") else {
+            match method {
+                "exit" => return Ok(Some(if self.shutdown { 0 } else { 1 })),
+                "textDocument/didOpen" => self.did_open(params, out)?,
+                "textDocument/didChange" => self.did_change(params, out)?,
+                "textDocument/didClose" => self.did_close(params, out)?,
+                _ => {}
+            }
+            return Ok(None);
+        };
+        // A response: this server sends no requests, so nothing waits on it.
+        if method.is_empty() {
+            return Ok(None);
         }
-        let cname = query.capture_names()[cap.index as usize];
-        let mut kind = map_symbol_capture(cname);
-        if kind.is_empty() {
-            continue;
+        let result = match method {
+            "initialize" => Ok(self.initialize(params)),
+            _ if !self.initialized => Err((-32002, "server not initialized".to_string())),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(serde_json::Value::Null)
+            }
+            "textDocument/documentSymbol" => self.document_symbols(params),
+            "textDocument/foldingRange" => self.folding_ranges(params),
+            "textDocument/selectionRange" => self.selection_ranges(params),
+            "textDocument/semanticTokens/full" => self.semantic_tokens(params, false, out),
+            "textDocument/semanticTokens/range" => self.semantic_tokens(params, true, out),
+            _ => Err((-32601, format!("unhandled method: {method}"))),
+        };
+        let reply = match result {
+            Ok(result) => serde_json::json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": code, "message": message},
+            }),
+        };
+        write_lsp_message(out, &reply)?;
+        Ok(None)
+    }
+
+    fn initialize(&mut self, params: &serde_json::Value) -> serde_json::Value {
+        self.initialized = true;
+        // Byte columns are what the trees hold; counting anything else costs a
+        // scan of the line.
+        let offers_utf8 = params["capabilities"]["general"]["positionEncodings"]
+            .as_array()
+            .is_some_and(|offered| offered.iter().any(|name| name == "utf-8"));
+        if offers_utf8 {
+            self.encoding = PositionEncoding::Utf8;
         }
+        serde_json::json!({
+            "capabilities": {
+                "positionEncoding": self.encoding.name(),
+                "textDocumentSync": {"openClose": true, "change": 2},
+                "documentSymbolProvider": true,
+                "foldingRangeProvider": true,
+                "selectionRangeProvider": true,
+                "semanticTokensProvider": {
+                    "legend": SEMANTIC_TOKENS_LEGEND,
+                    "full": true,
+                    "range": true,
+                },
+            },
+            "serverInfo": {"name": "ts-hl-daemon", "version": env!("CARGO_PKG_VERSION")},
+        })
+    }
 
-        if cache.lang == "rust" && kind == "function" && ancestor_kind(node, "impl_item").is_some()
-        {
-            kind = "method";
+    fn did_open(&mut self, params: &serde_json::Value, out: &mut dyn Write) -> Result<()> {
+        let document = &params["textDocument"];
+        let Some(uri) = document["uri"].as_str() else {
+            return Ok(());
+        };
+        // A language without a grammar gets no features, not an error per request.
+        let Some(lang) = document["languageId"].as_str().and_then(lsp_language) else {
+            return Ok(());
+        };
+        let buf = self.next_buf;
+        self.next_buf += 1;
+        self.documents.insert(
+            uri.to_string(),
+            LspDocument {
+                buf,
+                lang,
+                version: document["version"].as_i64().unwrap_or_default(),
+                text: document["text"].as_str().unwrap_or_default().to_string(),
+            },
+        );
+        self.sync(uri, out)
+    }
+
+    fn did_change(&mut self, params: &serde_json::Value, out: &mut dyn Write) -> Result<()> {
+        let Some(uri) = params["textDocument"]["uri"].as_str() else {
+            return Ok(());
+        };
+        let encoding = self.encoding;
+        let Some(document) = self.documents.get_mut(uri) else {
+            return Ok(());
+        };
+        for change in params["contentChanges"].as_array().into_iter().flatten() {
+            let text = change["text"].as_str().unwrap_or_default();
+            match change.get("range") {
+                Some(range) => {
+                    let start = lsp_offset(&document.text, encoding, &range["start"]);
+                    let end = lsp_offset(&document.text, encoding, &range["end"]).max(start);
+                    document.text.replace_range(start..end, text);
+                }
+                None => document.text = text.to_string(),
+            }
         }
+        document.version = params["textDocument"]["version"]
+            .as_i64()
+            .unwrap_or(document.version);
+        self.sync(uri, out)
+    }
 
-        if !symbol_kind_allowed(kind, kinds) {
-            continue;
+    fn did_close(&mut self, params: &serde_json::Value, out: &mut dyn Write) -> Result<()> {
+        let Some(uri) = params["textDocument"]["uri"].as_str() else {
+            return Ok(());
+        };
+        if let Some(document) = self.documents.remove(uri) {
+            self.server.cache.remove(&document.buf);
+            publish_diagnostics(out, uri, None, Vec::new())?;
         }
+        Ok(())
+    }
 
-        let name = node_text(node, bytes);
-        let sp = node.start_position();
-        let lnum = sp.row as u32 + 1;
-        let col = sp.column as u32 + 1;
-        // Query 捕获的一般只是名称节点；向上找到真正的定义，范围才会覆盖函数体。
-        let def_end = definition_node(node, &cache.lang, kind).end_position();
-        let sym_end_lnum = def_end.row as u32 + 1;
-        let sym_end_col = def_end.column as u32 + 1;
-
-        if let Some((ls, le)) = lrange
-            && (lnum < ls || lnum > le)
+    /// Parse `uri`'s text into its buffer and publish its parse errors.
+    fn sync(&mut self, uri: &str, out: &mut dyn Write) -> Result<()> {
+        let document = &self.documents[uri];
+        let (buf, lang, version) = (document.buf, document.lang, document.version);
+        let revision = version.max(0) as u64;
+        if let Err(error) = self
+            .server
+            .set_text(buf, lang, document.text.clone(), revision)
         {
-            continue;
+            return write_lsp_message(
+                out,
+                &serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "method": "window/logMessage",
+                    "params": {"type": 1, "message": format!("{uri}: {error}")},
+                }),
+            );
         }
-
-        // 容器信息（可选）
-        let mut ckind: Option<&'static str> = None;
-        let mut cname_opt: Option<String> = None;
-        let mut clnum: Option<u32> = None;
-        let mut ccol: Option<u32> = None;
-
-        // Rust 容器推断
-        if cache.lang == "rust" {
-            match kind {
-                "field" => {
-                    if let Some(vinfo) = variant_info(node, bytes) {
-                        ckind = Some("variant");
-                        cname_opt = Some(vinfo.0);
-                        clnum = Some(vinfo.1);
-                        ccol = Some(vinfo.2);
-                    } else if let Some(sinfo) = struct_info(node, bytes) {
-                        ckind = Some("struct");
-                        cname_opt = Some(sinfo.0);
-                        clnum = Some(sinfo.1);
-                        ccol = Some(sinfo.2);
-                    } else if let Some(minfo) = mod_info(node, bytes) {
-                        ckind = Some("namespace");
-                        cname_opt = Some(minfo.0);
-                        clnum = Some(minfo.1);
-                        ccol = Some(minfo.2);
-                    }
-                }
-                "variant" => {
-                    if let Some(einfo) = enum_info(node, bytes) {
-                        ckind = Some("enum");
-                        cname_opt = Some(einfo.0);
-                        clnum = Some(einfo.1);
-                        ccol = Some(einfo.2);
-                    }
-                }
-                "method" => {
-                    if let Some(tinfo) = impl_type_info(node, bytes) {
-                        ckind = Some("type");
-                        cname_opt = Some(tinfo.0);
-                        clnum = Some(tinfo.1);
-                        ccol = Some(tinfo.2);
-                    }
-                }
-                "function" => {
-                    if let Some(finfo) = outer_fn_info(node, bytes) {
-                        ckind = Some("function");
-                        cname_opt = Some(finfo.0);
-                        clnum = Some(finfo.1);
-                        ccol = Some(finfo.2);
-                    } else if let Some(minfo) = mod_info(node, bytes) {
-                        ckind = Some("namespace");
-                        cname_opt = Some(minfo.0);
-                        clnum = Some(minfo.1);
-                        ccol = Some(minfo.2);
-                    }
-                }
-                "const" => {
-                    if let Some(minfo) = mod_info(node, bytes) {
-                        ckind = Some("namespace");
-                        cname_opt = Some(minfo.0);
-                        clnum = Some(minfo.1);
-                        ccol = Some(minfo.2);
-                    }
-                }
-                _ => {}
+        let diagnostics = match run_diagnostics_cached(&self.server, buf, lang, None) {
+            Ok((_, diagnostics)) => {
+                let cache = self.server.get_cache(buf, lang)?;
+                diagnostics
+                    .iter()
+                    .map(|diagnostic| {
+                        serde_json::json!({
+                            "range": lsp_range(
+                                cache,
+                                self.encoding,
+                                [diagnostic.lnum, diagnostic.col, diagnostic.end_lnum, diagnostic.end_col],
+                            ),
+                            "severity": 1,
+                            "code": diagnostic.kind,
+                            "source": "tree-sitter",
+                            "message": diagnostic.message,
+                        })
+                    })
+                    .collect()
             }
+            Err(_) => Vec::new(),
+        };
+        publish_diagnostics(out, uri, Some(version), diagnostics)
+    }
+
+    /// The buffer behind a request's `textDocument`, synced back in if the
+    /// cache evicted it since.
+    fn document(&mut self, params: &serde_json::Value) -> Option<(i64, &'static str)> {
+        let document = self
+            .documents
+            .get(params["textDocument"]["uri"].as_str()?)?;
+        let (buf, lang) = (document.buf, document.lang);
+        if self.server.get_cache(buf, lang).is_err() {
+            let revision = document.version.max(0) as u64;
+            let text = document.text.clone();
+            self.server.set_text(buf, lang, text, revision).ok()?;
         }
+        Some((buf, lang))
+    }
 
-        // JavaScript 容器推断：method → class
-        if cache.lang == "javascript"
-            && kind == "method"
-            && let Some(cls) = ancestor_kind(node, "class_declaration")
-            && let Some(cls_name) = child_text_by_kind(cls, "identifier", bytes)
-            && let Some((ln, co)) = child_pos_by_kind(cls, "identifier")
-        {
-            ckind = Some("class");
-            cname_opt = Some(cls_name);
-            clnum = Some(ln);
-            ccol = Some(co);
-        }
-
-        // TypeScript/TSX 容器推断：method/field → class/interface，variant → enum
-        if cache.lang == "typescript" || cache.lang == "tsx" {
-            if kind == "method" || kind == "field" {
-                for (ancestor, container_kind, name_kind) in [
-                    ("class_declaration", "class", "type_identifier"),
-                    ("abstract_class_declaration", "class", "type_identifier"),
-                    ("interface_declaration", "type", "type_identifier"),
-                ] {
-                    if let Some(cls) = ancestor_kind(node, ancestor)
-                        && let Some(cls_name) = child_text_by_kind(cls, name_kind, bytes)
-                        && let Some((ln, co)) = child_pos_by_kind(cls, name_kind)
-                    {
-                        ckind = Some(container_kind);
-                        cname_opt = Some(cls_name);
-                        clnum = Some(ln);
-                        ccol = Some(co);
-                        break;
-                    }
-                }
-            } else if kind == "variant"
-                && let Some(en) = ancestor_kind(node, "enum_declaration")
-                && let Some(enum_name) = child_text_by_kind(en, "identifier", bytes)
-                && let Some((ln, co)) = child_pos_by_kind(en, "identifier")
-            {
-                ckind = Some("enum");
-                cname_opt = Some(enum_name);
-                clnum = Some(ln);
-                ccol = Some(co);
+    fn document_symbols(&mut self, params: &serde_json::Value) -> LspResult {
+        let Some((buf, lang)) = self.document(params) else {
+            return Ok(serde_json::Value::Null);
+        };
+        let (_, mut symbols) =
+            run_symbols_cached_filtered(&mut self.server, buf, lang, None, None, &[])
+                .map_err(lsp_error)?;
+        let cache = self.server.get_cache(buf, lang).map_err(lsp_error)?;
+        // Nest by range: sorted outermost first, a symbol belongs to the
+        // innermost open one that still contains it.
+        symbols.sort_by_key(|symbol| {
+            (
+                symbol.lnum,
+                symbol.col,
+                std::cmp::Reverse((symbol.end_lnum, symbol.end_col)),
+            )
+        });
+        let mut roots = Vec::new();
+        let mut open: Vec<((u32, u32), serde_json::Value)> = Vec::new();
+        let close = |open: &mut Vec<((u32, u32), serde_json::Value)>, roots: &mut Vec<_>| {
+            let (_, symbol) = open.pop().unwrap();
+            match open.last_mut() {
+                Some((_, parent)) => parent["children"].as_array_mut().unwrap().push(symbol),
+                None => roots.push(symbol),
             }
-        }
-
-        // JSON/YAML 容器推断：二级键 → 顶层键
-        if (cache.lang == "json" || cache.lang == "yaml") && kind == "field" {
-            let pair_kind = if cache.lang == "json" {
-                "pair"
-            } else {
-                "block_mapping_pair"
-            };
-            let key_kind = if cache.lang == "json" {
-                "string_content"
-            } else {
-                "string_scalar"
-            };
-            if let Some(own_pair) = ancestor_kind(node, pair_kind)
-                && let Some(outer_pair) = ancestor_kind(own_pair, pair_kind)
-                && let Some(key_node) = outer_pair
-                    .child_by_field_name("key")
-                    .and_then(|key| descendant_by_kind(key, key_kind))
-            {
-                let sp = key_node.start_position();
-                ckind = Some("property");
-                cname_opt = Some(node_text(key_node, bytes));
-                clnum = Some(sp.row as u32 + 1);
-                ccol = Some(sp.column as u32 + 1);
+        };
+        for symbol in &symbols {
+            let end = (symbol.end_lnum.max(symbol.lnum), symbol.end_col);
+            while open.last().is_some_and(|(open_end, _)| *open_end < end) {
+                close(&mut open, &mut roots);
             }
+            // The name, where `symbols` starts the range.
+            let name_end = match symbol.end_lnum > symbol.lnum {
+                true => symbol.col + symbol.name.len() as u32,
+                false => (symbol.col + symbol.name.len() as u32).min(symbol.end_col),
+            };
+            let range = [symbol.lnum, symbol.col, end.0, end.1];
+            let selection = [symbol.lnum, symbol.col, symbol.lnum, name_end];
+            open.push((
+                end,
+                serde_json::json!({
+                    "name": symbol.name,
+                    "kind": lsp_symbol_kind(symbol.kind),
+                    "range": lsp_range(cache, self.encoding, range),
+                    "selectionRange": lsp_range(cache, self.encoding, selection),
+                    "children": [],
+                }),
+            ));
+        }
+        while !open.is_empty() {
+            close(&mut open, &mut roots);
         }
+        Ok(roots.into())
+    }
 
-        // TOML 容器推断：pair → table
-        if cache.lang == "toml"
-            && kind == "property"
-            && let Some(table) =
-                ancestor_kind(node, "table").or_else(|| ancestor_kind(node, "table_array_element"))
-        {
-            for key_kind in ["bare_key", "dotted_key", "quoted_key"] {
-                if let Some(key_name) = child_text_by_kind(table, key_kind, bytes)
-                    && let Some((ln, co)) = child_pos_by_kind(table, key_kind)
-                {
-                    ckind = Some("namespace");
-                    cname_opt = Some(key_name);
-                    clnum = Some(ln);
-                    ccol = Some(co);
-                    break;
+    fn folding_ranges(&mut self, params: &serde_json::Value) -> LspResult {
+        let Some((buf, lang)) = self.document(params) else {
+            return Ok(serde_json::Value::Null);
+        };
+        let (_, folds) = run_folds_cached(&self.server, buf, lang, None).map_err(lsp_error)?;
+        Ok(folds
+            .iter()
+            .map(|fold| serde_json::json!({"startLine": fold.lnum - 1, "endLine": fold.end_lnum - 1}))
+            .collect())
+    }
+
+    fn selection_ranges(&mut self, params: &serde_json::Value) -> LspResult {
+        let Some((buf, lang)) = self.document(params) else {
+            return Ok(serde_json::Value::Null);
+        };
+        let mut ranges = Vec::new();
+        for position in params["positions"].as_array().into_iter().flatten() {
+            let cache = self.server.get_cache(buf, lang).map_err(lsp_error)?;
+            let lnum = position["line"].as_u64().unwrap_or_default() as u32 + 1;
+            let line = line_range_from_index(&cache.line_index, &cache.text, lnum, lnum);
+            let line = cache.text[line].trim_end_matches('\n');
+            let character = position["character"].as_u64().unwrap_or_default() as u32;
+            let col = self.encoding.byte_column(line, character) as u32 + 1;
+            let answer =
+                scope_chain_cached(&self.server, buf, lang, lnum, col).map_err(lsp_error)?;
+            // Built outermost first, so that each range can hold its parent.
+            let mut selection = serde_json::Value::Null;
+            for node in answer.chain.iter().rev() {
+                let range = lsp_range(
+                    cache,
+                    self.encoding,
+                    [node.lnum, node.col, node.end_lnum, node.end_col],
+                );
+                if selection.get("range") == Some(&range) {
+                    continue;
                 }
+                selection = match selection {
+                    serde_json::Value::Null => serde_json::json!({"range": range}),
+                    parent => serde_json::json!({"range": range, "parent": parent}),
+                };
+            }
+            if selection.is_null() {
+                let point = lsp_range(cache, self.encoding, [lnum, col, lnum, col]);
+                selection = serde_json::json!({"range": point});
             }
+            ranges.push(selection);
         }
+        Ok(ranges.into())
+    }
 
-        // Python 容器推断：method → class
-        if cache.lang == "python"
-            && kind == "method"
-            && let Some(cls) = ancestor_kind(node, "class_definition")
-            && let Some(cls_name) = child_text_by_kind(cls, "identifier", bytes)
-            && let Some((ln, co)) = child_pos_by_kind(cls, "identifier")
-        {
-            ckind = Some("class");
-            cname_opt = Some(cls_name);
-            clnum = Some(ln);
-            ccol = Some(co);
-        }
-
-        // Go 容器推断：method → receiver type, field → struct
-        if cache.lang == "go" {
-            if kind == "method" {
-                // method_declaration 的 receiver 有 parameter_declaration → type_identifier
-                if let Some(mdecl) = node.parent().filter(|p| p.kind() == "method_declaration") {
-                    let mut c = mdecl.walk();
-                    for ch in mdecl.children(&mut c) {
-                        if ch.kind() == "parameter_list" {
-                            let mut c2 = ch.walk();
-                            for pd in ch.children(&mut c2) {
-                                if pd.kind() == "parameter_declaration"
-                                    && let Some(tname) =
-                                        child_text_by_kind(pd, "type_identifier", bytes)
-                                {
-                                    let sp = pd.start_position();
-                                    ckind = Some("type");
-                                    cname_opt = Some(tname);
-                                    clnum = Some(sp.row as u32 + 1);
-                                    ccol = Some(sp.column as u32 + 1);
-                                }
-                            }
-                            break;
-                        }
-                    }
-                }
-            } else if kind == "field"
-                && let Some(type_spec) = ancestor_kind(node, "type_spec")
-                && let Some(type_name) = child_text_by_kind(type_spec, "type_identifier", bytes)
-                && let Some((ln, co)) = child_pos_by_kind(type_spec, "type_identifier")
-            {
-                ckind = Some("type");
-                cname_opt = Some(type_name);
-                clnum = Some(ln);
-                ccol = Some(co);
-            }
+    /// Semantic tokens for the document or a range of it. `SemanticTokens`
+    /// has no way to say it is partial, so a cut at `MAX_HIGHLIGHT_SPANS` is
+    /// logged to the client instead.
+    fn semantic_tokens(
+        &mut self,
+        params: &serde_json::Value,
+        range: bool,
+        out: &mut dyn Write,
+    ) -> LspResult {
+        let Some((buf, lang)) = self.document(params) else {
+            return Ok(serde_json::Value::Null);
+        };
+        let lrange = range.then(|| {
+            let line =
+                |position: &serde_json::Value| position["line"].as_u64().unwrap_or_default() as u32;
+            (
+                line(&params["range"]["start"]) + 1,
+                line(&params["range"]["end"]) + 1,
+            )
+        });
+        let (_, _, data, truncated) =
+            run_semantic_tokens_cached(&mut self.server, buf, lang, lrange, self.encoding.name())
+                .map_err(lsp_error)?;
+        if truncated {
+            let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+            write_lsp_message(
+                out,
+                &serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "method": "window/logMessage",
+                    "params": {
+                        "type": 2,
+                        "message": format!(
+                            "{uri}: semantic tokens stopped after {MAX_HIGHLIGHT_SPANS} highlight spans"
+                        ),
+                    },
+                }),
+            )
+            .map_err(lsp_error)?;
         }
+        Ok(serde_json::json!({"data": data}))
+    }
+}
 
-        if cache.lang == "vim" {
-            if kind == "namespace" && name == "END" {
-                continue;
+/// A request's result, or its JSON-RPC error code and message.
+type LspResult = std::result::Result<serde_json::Value, (i64, String)>;
+
+fn lsp_error(error: anyhow::Error) -> (i64, String) {
+    (-32603, error.to_string())
+}
+
+fn publish_diagnostics(
+    out: &mut dyn Write,
+    uri: &str,
+    version: Option<i64>,
+    diagnostics: Vec<serde_json::Value>,
+) -> Result<()> {
+    let mut params = serde_json::json!({"uri": uri, "diagnostics": diagnostics});
+    if let Some(version) = version {
+        params["version"] = version.into();
+    }
+    write_lsp_message(
+        out,
+        &serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": params,
+        }),
+    )
+}
+
+/// LSP `Range` of a 1-based `[lnum, col, end_lnum, end_col]` with byte columns.
+fn lsp_range(cache: &BufCache, encoding: PositionEncoding, range: [u32; 4]) -> serde_json::Value {
+    let position = |lnum: u32, col: u32| {
+        let line = line_range_from_index(&cache.line_index, &cache.text, lnum, lnum);
+        let text = &cache.text[line];
+        let prefix = (col.max(1) as usize - 1).min(text.len());
+        let character = text
+            .get(..prefix)
+            .map_or(prefix as u32, |prefix| encoding.units(prefix));
+        serde_json::json!({"line": lnum.max(1) - 1, "character": character})
+    };
+    serde_json::json!({
+        "start": position(range[0], range[1]),
+        "end": position(range[2], range[3]),
+    })
+}
+
+/// Byte offset into `text` of an LSP `Position`.
+fn lsp_offset(text: &str, encoding: PositionEncoding, position: &serde_json::Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or_default();
+    let character = position["character"].as_u64().unwrap_or_default() as u32;
+    let mut start = 0;
+    for _ in 0..line {
+        match text[start..].find('\n') {
+            Some(newline) => start += newline + 1,
+            None => return text.len(),
+        }
+    }
+    let end = text[start..]
+        .find('\n')
+        .map_or(text.len(), |newline| start + newline);
+    start + encoding.byte_column(&text[start..end], character)
+}
+
+/// Parse one request line: None for a blank line, the error reply for one that
+/// is not a request.
+fn read_request(line: &str) -> Option<std::result::Result<Request, Event>> {
+    if line.trim().is_empty() {
+        return None;
+    }
+    Some(
+        serde_json::from_str::<Request>(line).map_err(|e| Event::Error {
+            message: format!("invalid request: {e}"),
+            buf: None,
+            op: None,
+            request_id: None,
+            location: None,
+        }),
+    )
+}
+
+fn handle_request(server: &mut Server, req: Request, out: &mut dyn Write) -> Result<()> {
+    match req {
+        Request::SetText {
+            buf,
+            lang,
+            text,
+            revision,
+        } => match server.set_text(buf, &lang, text, revision) {
+            Ok(_) => {
+                let changed = server.changed_lines(buf);
+                send(
+                    out,
+                    &Event::Ok {
+                        buf,
+                        op: "set_text".to_string(),
+                        revision: Some(revision),
+                        changed: changed.clone(),
+                    },
+                )?;
+                push_highlights(server, buf, &changed.unwrap_or_default(), out)?;
             }
-            if kind == "variable" {
-                // 处于函数内的变量：标注容器为 function，交给插件的 hide_inner 逻辑过滤
-                let mut cur = node;
-                let mut in_func = false;
-                while let Some(parent) = cur.parent() {
-                    let pk = parent.kind();
-                    // 兼容你的 Vim9 语法（def_function）
-                    if pk == "def_function"
-                        || pk == "function_definition"
-                        || pk == "vim9_function_definition"
-                    {
-                        in_func = true;
-                        break;
-                    }
-                    cur = parent;
-                }
-                if in_func {
-                    ckind = Some("function");
+            Err(e) => send(
+                out,
+                &Event::Error {
+                    message: e.to_string(),
+                    buf: Some(buf),
+                    op: Some("set_text"),
+                    request_id: None,
+                    location: None,
+                },
+            )?,
+        },
+        Request::EditLines {
+            buf,
+            lang,
+            revision,
+            lstart,
+            old_lend,
+            lines,
+            line_count,
+            eol,
+        } => {
+            let splice = LineSplice {
+                lstart,
+                old_lend,
+                lines,
+                line_count,
+                eol,
+            };
+            match server.edit_lines(buf, &lang, revision, splice) {
+                Ok(_) => {
+                    let changed = server.changed_lines(buf);
+                    send(
+                        out,
+                        &Event::Ok {
+                            buf,
+                            op: "edit_lines".to_string(),
+                            revision: Some(revision),
+                            changed: changed.clone(),
+                        },
+                    )?;
+                    push_highlights(server, buf, &changed.unwrap_or_default(), out)?;
                 }
+                Err(e) => send(
+                    out,
+                    &Event::Error {
+                        message: e.to_string(),
+                        buf: Some(buf),
+                        op: Some("edit_lines"),
+                        request_id: None,
+                        location: None,
+                    },
+                )?,
             }
         }
-
-        // 同一位置的 function/method 去重规则
-        if let Some(&prev) = seen_at.get(&(lnum, col)) {
-            if prev == "method" && kind == "function" {
-                continue;
+        Request::Highlight {
+            buf,
+            lang,
+            lstart,
+            lend,
+            rainbow,
+            max_spans,
+            compact,
+            request_id,
+            have_blocks,
+        } => {
+            let lrange = lstart.zip(lend);
+            // A binary frame carries the compact rows.
+            let compact = compact || server.binary_frames;
+            let result = match &have_blocks {
+                Some(have) => {
+                    run_highlight_delta(server, buf, &lang, lrange, rainbow, max_spans, have)
+                }
+                None => run_highlight_cached(server, buf, &lang, lrange, rainbow, max_spans)
+                    .map(|(revision, spans)| (revision, spans, None)),
+            };
+            match result {
+                Ok((revision, spans, delta)) => {
+                    let (blocks, cleared) = delta.unzip();
+                    let (groups, cspans) = if compact {
+                        let (groups, rows) = compact_spans(&spans);
+                        (Some(groups), Some(rows))
+                    } else {
+                        (None, None)
+                    };
+                    send_reply(
+                        out,
+                        &Event::Highlights {
+                            buf,
+                            revision,
+                            spans: if compact { None } else { Some(spans) },
+                            groups,
+                            cspans,
+                            blocks,
+                            cleared,
+                            incomplete: None,
+                            push: false,
+                        },
+                        server.binary_frames,
+                    )?
+                }
+                Err(e) => send(
+                    out,
+                    &Event::Error {
+                        message: e.to_string(),
+                        buf: Some(buf),
+                        op: Some("highlight"),
+                        request_id: (request_id != 0).then_some(request_id),
+                        location: None,
+                    },
+                )?,
             }
-            if prev == "function" && kind == "method" {
-                if let Some(pos) = symbols.iter().position(|s: &Symbol| {
-                    s.lnum == lnum && s.col == col && s.kind == "function" && s.name == name
-                }) {
-                    symbols.remove(pos);
+        }
+        Request::Symbols {
+            buf,
+            lang,
+            request_id,
+            lstart,
+            lend,
+            max_items,
+            kinds,
+            have_digest,
+        } => {
+            let lrange = lstart.zip(lend);
+            match run_symbols_cached_filtered(server, buf, &lang, lrange, max_items, &kinds) {
+                Ok((revision, symbols)) => {
+                    let digest = digest_symbols(&symbols);
+                    let unchanged = !have_digest.is_empty() && have_digest == digest;
+                    send_reply(
+                        out,
+                        &Event::Symbols {
+                            buf,
+                            revision,
+                            request_id,
+                            symbols: if unchanged { None } else { Some(symbols) },
+                            digest,
+                            unchanged,
+                        },
+                        server.binary_frames,
+                    )?
                 }
-                seen_at.insert((lnum, col), "method");
+                Err(e) => send(
+                    out,
+                    &Event::Error {
+                        message: e.to_string(),
+                        buf: Some(buf),
+                        op: Some("symbols"),
+                        request_id: Some(request_id),
+                        location: None,
+                    },
+                )?,
             }
-        } else {
-            seen_at.insert((lnum, col), kind);
         }
-
-        let key = (
-            kind,
-            name.clone(),
+        Request::Folds {
+            buf,
+            lang,
+            max_items,
+            have_digest,
+            request_id,
+        } => match run_folds_cached(server, buf, &lang, max_items) {
+            Ok((revision, folds)) => {
+                let digest = digest_folds(&folds);
+                let unchanged = !have_digest.is_empty() && have_digest == digest;
+                send_reply(
+                    out,
+                    &Event::Folds {
+                        buf,
+                        revision,
+                        folds: if unchanged { None } else { Some(folds) },
+                        digest,
+                        unchanged,
+                    },
+                    server.binary_frames,
+                )?
+            }
+            Err(e) => send(
+                out,
+                &Event::Error {
+                    message: e.to_string(),
+                    buf: Some(buf),
+                    op: Some("folds"),
+                    request_id: (request_id != 0).then_some(request_id),
+                    location: None,
+                },
+            )?,
+        },
+        Request::Diagnostics {
+            buf,
+            lang,
+            max_items,
+            have_digest,
+            request_id,
+        } => match run_diagnostics_cached(server, buf, &lang, max_items) {
+            Ok((revision, diagnostics)) => {
+                let digest = digest_diagnostics(&diagnostics);
+                let unchanged = !have_digest.is_empty() && have_digest == digest;
+                send(
+                    out,
+                    &Event::Diagnostics {
+                        buf,
+                        revision,
+                        diagnostics: if unchanged { None } else { Some(diagnostics) },
+                        digest,
+                        unchanged,
+                    },
+                )?
+            }
+            Err(e) => send(
+                out,
+                &Event::Error {
+                    message: e.to_string(),
+                    buf: Some(buf),
+                    op: Some("diagnostics"),
+                    request_id: (request_id != 0).then_some(request_id),
+                    location: None,
+                },
+            )?,
+        },
+        Request::DumpAst {
+            buf,
+            lang,
+            request_id,
+        } => match dump_ast_cached(server, buf, &lang) {
+            Ok((revision, lines)) => send(
+                out,
+                &Event::Ast {
+                    buf,
+                    revision,
+                    lines,
+                },
+            )?,
+            Err(e) => send(
+                out,
+                &Event::Error {
+                    message: e.to_string(),
+                    buf: Some(buf),
+                    op: Some("dump_ast"),
+                    request_id: (request_id != 0).then_some(request_id),
+                    location: None,
+                },
+            )?,
+        },
+        Request::Inspect {
+            buf,
+            lang,
             lnum,
             col,
-            ckind,
-            cname_opt.clone(),
-            clnum,
-            ccol,
-        );
-        if seen.contains(&key) {
-            continue;
-        }
-        seen.insert(key);
-
-        symbols.push(Symbol {
-            name,
-            kind,
+            request_id,
+        } => match inspect_cached(server, buf, &lang, lnum, col) {
+            Ok((revision, captures, node_chain)) => send(
+                out,
+                &Event::Inspect {
+                    buf,
+                    revision,
+                    lnum,
+                    col,
+                    captures,
+                    node_chain,
+                },
+            )?,
+            Err(e) => send(
+                out,
+                &Event::Error {
+                    message: e.to_string(),
+                    buf: Some(buf),
+                    op: Some("inspect"),
+                    request_id: (request_id != 0).then_some(request_id),
+                    location: None,
+                },
+            )?,
+        },
+        Request::Scope {
+            buf,
+            lang,
             lnum,
             col,
-            end_lnum: sym_end_lnum,
-            end_col: sym_end_col,
-            container_kind: ckind,
-            container_name: cname_opt,
-            container_lnum: clnum,
-            container_col: ccol,
-        });
-    }
-
-    // 2) Vim9 grammar currently parses some valid `def`/`var` lines as generic
-    // Ex commands. A small line-oriented fallback keeps the core outline useful
-    // while the grammar evolves.
-    if cache.lang == "vim" && symbols.len() < limit {
-        for symbol in extract_vim_declarations(&cache.text, lrange, limit - symbols.len(), kinds) {
-            let key = (
-                symbol.kind,
-                symbol.name.clone(),
-                symbol.lnum,
-                symbol.col,
-                symbol.container_kind,
-                symbol.container_name.clone(),
-                symbol.container_lnum,
-                symbol.container_col,
-            );
-            if seen.insert(key) {
-                symbols.push(symbol);
-            }
-        }
-    }
-
-    // 3) 额外：Vim 语言从 command 节点中补充提取符号
-    if cache.lang == "vim" && symbols.len() < limit {
-        let mut stack = Vec::<tree_sitter::Node>::with_capacity(1024);
-        stack.push(root);
-        while let Some(n) = stack.pop() {
-            if symbols.len() >= limit {
-                break;
-            }
-            if n.kind() == "command" {
-                let cmd_name_text = {
-                    let mut cursor = n.walk();
-                    n.children(&mut cursor)
-                        .find(|c| c.kind() == "command_name")
-                        .map(|c| node_text(c, bytes))
-                };
-                if let Some(ref cmd) = cmd_name_text {
-                    let sp = n.start_position();
-                    let lnum = sp.row as u32 + 1;
-                    let col = sp.column as u32 + 1;
+            request_id,
+        } => match scope_chain_cached(server, buf, &lang, lnum, col) {
+            Ok(answer) => send(
+                out,
+                &Event::Scope {
+                    buf,
+                    revision: answer.revision,
+                    lnum,
+                    col,
+                    anchor_lnum: answer.anchor[0],
+                    anchor_col: answer.anchor[1],
+                    anchor_end_lnum: answer.anchor[2],
+                    anchor_end_col: answer.anchor[3],
+                    chain: answer.chain,
+                },
+            )?,
+            Err(e) => send(
+                out,
+                &Event::Error {
+                    message: e.to_string(),
+                    buf: Some(buf),
+                    op: Some("scope"),
+                    request_id: (request_id != 0).then_some(request_id),
+                    location: None,
+                },
+            )?,
+        },
+        Request::SemanticTokens {
+            buf,
+            lang,
+            lstart,
+            lend,
+            encoding,
+            request_id,
+        } => match run_semantic_tokens_cached(server, buf, &lang, lstart.zip(lend), &encoding) {
+            Ok((revision, encoding, data, truncated)) => send(
+                out,
+                &Event::SemanticTokens {
+                    buf,
+                    revision,
+                    request_id,
+                    legend: SEMANTIC_TOKENS_LEGEND,
+                    encoding: encoding.name(),
+                    data,
+                    truncated,
+                },
+            )?,
+            Err(e) => send(
+                out,
+                &Event::Error {
+                    message: e.to_string(),
+                    buf: Some(buf),
+                    op: Some("semantic_tokens"),
+                    request_id: (request_id != 0).then_some(request_id),
+                    location: None,
+                },
+            )?,
+        },
+        Request::References {
+            buf,
+            lang,
+            lnum,
+            col,
+            max_items,
+        } => match run_references_cached(server, buf, &lang, lnum, col, max_items) {
+            Ok(answer) => send(
+                out,
+                &Event::References {
+                    buf,
+                    revision: answer.revision,
+                    lnum,
+                    col,
+                    name: answer.name,
+                    kind: answer.kind,
+                    references: answer.references,
+                },
+            )?,
+            Err(e) => send(
+                out,
+                &Event::Error {
+                    message: e.to_string(),
+                    buf: Some(buf),
+                    op: Some("references"),
+                    request_id: None,
+                    location: None,
+                },
+            )?,
+        },
+        Request::Definition {
+            buf,
+            lang,
+            lnum,
+            col,
+        } => match run_definition_cached(server, buf, &lang, lnum, col) {
+            Ok(answer) => send(
+                out,
+                &Event::Definition {
+                    buf,
+                    revision: answer.revision,
+                    lnum,
+                    col,
+                    name: answer.name,
+                    definition: answer.definition,
+                },
+            )?,
+            Err(e) => send(
+                out,
+                &Event::Error {
+                    message: e.to_string(),
+                    buf: Some(buf),
+                    op: Some("definition"),
+                    request_id: None,
+                    location: None,
+                },
+            )?,
+        },
+        Request::Rename {
+            buf,
+            lang,
+            lnum,
+            col,
+            new_name,
+        } => match run_rename_cached(server, buf, &lang, lnum, col, &new_name) {
+            Ok(answer) => send(
+                out,
+                &Event::Rename {
+                    buf,
+                    revision: answer.revision,
+                    edits: answer.edits,
+                },
+            )?,
+            Err(e) => send(
+                out,
+                &Event::Error {
+                    message: e.to_string(),
+                    buf: Some(buf),
+                    op: Some("rename"),
+                    request_id: None,
+                    location: None,
+                },
+            )?,
+        },
+        Request::Indent {
+            buf,
+            lang,
+            lnum,
+            end_lnum,
+            shiftwidth,
+            tabstop,
+        } => match run_indent_cached(server, buf, &lang, lnum, end_lnum, shiftwidth, tabstop) {
+            Ok((revision, indents)) => send(
+                out,
+                &Event::Indent {
+                    buf,
+                    revision,
+                    lnum,
+                    indents,
+                },
+            )?,
+            Err(e) => send(
+                out,
+                &Event::Error {
+                    message: e.to_string(),
+                    buf: Some(buf),
+                    op: Some("indent"),
+                    request_id: None,
+                    location: None,
+                },
+            )?,
+        },
+        Request::Query {
+            buf,
+            lang,
+            query,
+            lstart,
+            lend,
+            max_items,
+        } => {
+            let lrange = lstart.zip(lend);
+            match run_query_cached(server, buf, &lang, &query, lrange, max_items) {
+                Ok((revision, captures, truncated)) => send(
+                    out,
+                    &Event::Query {
+                        buf,
+                        revision,
+                        captures,
+                        truncated,
+                    },
+                )?,
+                Err(e) => match e.downcast_ref::<tree_sitter::QueryError>() {
+                    Some(error) => send(
+                        out,
+                        &Event::QueryError {
+                            buf,
+                            kind: query_error_kind(&error.kind),
+                            message: error.message.clone(),
+                            offset: error.offset,
+                            row: error.row as u32 + 1,
+                            column: error.column as u32 + 1,
+                        },
+                    )?,
+                    None => send(
+                        out,
+                        &Event::Error {
+                            message: e.to_string(),
+                            buf: Some(buf),
+                            op: Some("query"),
+                            request_id: None,
+                            location: None,
+                        },
+                    )?,
+                },
+            }
+        }
+        Request::SetQueryPaths { paths } => {
+            let (overrides, errors) = server.set_query_paths(&paths);
+            for (error, location) in errors {
+                send(
+                    out,
+                    &Event::Error {
+                        message: error.to_string(),
+                        buf: None,
+                        op: Some("set_query_paths"),
+                        request_id: None,
+                        location,
+                    },
+                )?;
+            }
+            send(out, &Event::QueryPaths { overrides })?;
+        }
+        Request::SetInjectionDepth { depth } => {
+            server.injection_depth = depth.min(MAX_INJECTION_DEPTH);
+            send(
+                out,
+                &Event::InjectionDepth {
+                    depth: server.injection_depth,
+                },
+            )?;
+        }
+        Request::LoadGrammar {
+            name,
+            library,
+            query_dir,
+        } => match load_grammar(&name, &library, query_dir.as_deref()) {
+            Ok(grammar) => {
+                server.forget_queries(&name);
+                send(
+                    out,
+                    &Event::GrammarLoaded {
+                        name,
+                        abi_version: grammar.language.abi_version(),
+                        queries: grammar.query_names(),
+                    },
+                )?
+            }
+            Err(e) => send(
+                out,
+                &Event::Error {
+                    message: format!("{e:#}"),
+                    buf: None,
+                    op: Some("load_grammar"),
+                    request_id: None,
+                    location: None,
+                },
+            )?,
+        },
+        // Answered inline, a request is complete before its cancel is read;
+        // only the worker pool has anything to drop.
+        Request::Cancel { .. } => {}
+        Request::Subscribe {
+            buf,
+            lstart,
+            lend,
+            margin,
+            rainbow,
+            max_spans,
+        } => {
+            let subscription = Subscription {
+                lstart,
+                lend,
+                margin,
+                rainbow,
+                max_spans,
+            };
+            server.subscriptions.insert(buf, subscription);
+            send(
+                out,
+                &Event::Ok {
+                    buf,
+                    op: "subscribe".to_string(),
+                    revision: server.cache.get(&buf).map(|cache| cache.revision),
+                    changed: None,
+                },
+            )?;
+            // Before the first sync there is nothing to paint; that sync's
+            // `changed` covers the whole buffer.
+            push_highlights(server, buf, &[[1, u32::MAX]], out)?;
+        }
+        Request::Viewport {
+            buf,
+            lstart,
+            lend,
+            margin,
+        } => {
+            let Some(subscription) = server.subscriptions.get_mut(&buf) else {
+                return send(
+                    out,
+                    &Event::Error {
+                        message: format!("buffer not subscribed: {buf}"),
+                        buf: Some(buf),
+                        op: Some("viewport"),
+                        request_id: None,
+                        location: None,
+                    },
+                );
+            };
+            let old = *subscription;
+            subscription.lstart = lstart;
+            subscription.lend = lend;
+            subscription.margin = margin.unwrap_or(old.margin);
+            let new = *subscription;
+            send(
+                out,
+                &Event::Ok {
+                    buf,
+                    op: "viewport".to_string(),
+                    revision: server.cache.get(&buf).map(|cache| cache.revision),
+                    changed: None,
+                },
+            )?;
+            let line_count = server
+                .cache
+                .get(&buf)
+                .map_or(0, |cache| cache.line_index.line_count as u32);
+            let exposed = lines_not_covered(new.lines(line_count), old.lines(line_count));
+            push_highlights(server, buf, &exposed, out)?;
+        }
+        Request::Unsubscribe { buf } => {
+            server.subscriptions.remove(&buf);
+            send(
+                out,
+                &Event::Ok {
+                    buf,
+                    op: "unsubscribe".to_string(),
+                    revision: None,
+                    changed: None,
+                },
+            )?;
+        }
+        Request::CloseBuffer { buf } => {
+            server.cache.remove(&buf);
+            server.subscriptions.remove(&buf);
+            send(
+                out,
+                &Event::Ok {
+                    buf,
+                    op: "close_buffer".to_string(),
+                    revision: None,
+                    changed: None,
+                },
+            )?;
+        }
+        Request::Status => send(
+            out,
+            &Event::Status {
+                protocol_version: PROTOCOL_VERSION,
+                version: env!("CARGO_PKG_VERSION"),
+                cached_buffers: server.all_cached().count(),
+                full_parses: server.full_parses,
+                incremental_parses: server.incremental_parses,
+                unchanged_syncs: server.unchanged_syncs,
+                cached_bytes: server.all_cached().map(|cache| cache.text.len()).sum(),
+                cache_evictions: server.cache_evictions,
+                injected_full_parses: server.injected_full_parses,
+                injected_incremental_parses: server.injected_incremental_parses,
+                superseded_requests: server.superseded_requests,
+                languages: SUPPORTED_LANGUAGES,
+            },
+        )?,
+        Request::Hello { framing, .. } => {
+            server.binary_frames = framing == "binary";
+            send(
+                out,
+                &Event::Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    version: env!("CARGO_PKG_VERSION"),
+                    capabilities: &[
+                        "revision",
+                        "incremental_parse",
+                        "close_buffer",
+                        "status",
+                        "bounded_results",
+                        "edit_lines",
+                        "folds",
+                        "symbol_kind_filter",
+                        "error_op",
+                        "symbol_request_id",
+                        "inspect",
+                        "scope",
+                        "injections",
+                        "payload_digest",
+                        "compact_spans",
+                        "diagnostics",
+                        "references",
+                        "definition",
+                        "rename",
+                        "indent",
+                        "query",
+                        "set_query_paths",
+                        "load_grammar",
+                        "nested_injections",
+                        "listen",
+                        "cancel",
+                        "supersede",
+                        "binary_frames",
+                        "highlight_delta",
+                        "changed_ranges",
+                        "subscribe",
+                        "semantic_tokens",
+                    ],
+                    locals_languages: LOCALS_LANGUAGES,
+                    framing: server.binary_frames.then_some("binary"),
+                },
+            )?;
+        }
+    }
+    Ok(())
+}
 
-                    if let Some((ls, le)) = lrange
-                        && (lnum < ls || lnum > le)
-                    {
-                        // 压入子节点继续遍历
-                        let mut child_cursor = n.walk();
-                        for ch in n.children(&mut child_cursor) {
-                            stack.push(ch);
-                        }
-                        continue;
-                    }
+fn send(out: &mut dyn Write, ev: &Event) -> Result<()> {
+    let js = serde_json::to_string(ev)?;
+    out.write_all(js.as_bytes())?;
+    out.write_all(b"\n")?;
+    out.flush()?;
+    Ok(())
+}
 
-                    let cmd_lower = cmd.trim().to_lowercase();
-                    let (sym_kind, sym_name) = match cmd_lower.as_str() {
-                        // 映射命令
-                        "nnoremap" | "vnoremap" | "inoremap" | "tnoremap" | "cnoremap"
-                        | "xnoremap" | "onoremap" | "snoremap" | "noremap" | "nmap" | "vmap"
-                        | "imap" | "tmap" | "cmap" | "xmap" | "omap" | "smap" | "map" => {
-                            // 从源码行中提取 lhs
-                            let line_text = {
-                                let start_byte = n.start_byte();
-                                let end_byte = n.end_byte();
-                                let s = &bytes[start_byte..end_byte];
-                                String::from_utf8_lossy(s).to_string()
-                            };
-                            // 解析：cmd [modifiers...] lhs rhs
-                            let mut parts = line_text.split_whitespace();
-                            let _cmd_part = parts.next(); // skip command name
-                            let mut lhs = String::new();
-                            for part in parts {
-                                let pl = part.to_lowercase();
-                                // 跳过独立修饰词
-                                if pl == "<silent>"
-                                    || pl == "<buffer>"
-                                    || pl == "<expr>"
-                                    || pl == "<nowait>"
-                                    || pl == "<unique>"
-                                    || pl == "<silent><expr>"
-                                {
-                                    continue;
-                                }
-                                // 第一个非修饰词就是 lhs
-                                // 但可能以修饰词为前缀：<silent><leader>gk → 去掉 <silent>
-                                let mut s = part.to_string();
-                                loop {
-                                    let sl = s.to_lowercase();
-                                    let prefix_length = [
-                                        "<silent>", "<buffer>", "<expr>", "<nowait>", "<unique>",
-                                        "<script>",
-                                    ]
-                                    .into_iter()
-                                    .find(|prefix| sl.starts_with(prefix))
-                                    .map(str::len);
-                                    let Some(prefix_length) = prefix_length else {
-                                        break;
-                                    };
-                                    s = s[prefix_length..].to_string();
-                                }
-                                if s.is_empty() {
-                                    continue;
-                                }
-                                lhs = s;
-                                break;
-                            }
-                            if lhs.is_empty() {
-                                (None, None)
-                            } else {
-                                (Some("mapping"), Some(format!("{} {}", cmd.trim(), lhs)))
-                            }
-                        }
-                        // Plug 插件
-                        "plug" => {
-                            let mut cursor = n.walk();
-                            let plug_name = n
-                                .children(&mut cursor)
-                                .find(|c| c.kind() == "safe_arg")
-                                .map(|c| {
-                                    let t = node_text(c, bytes).trim().to_string();
-                                    // 'user/repo' -> repo
-                                    let unquoted = t.trim_matches('\'').trim_matches('"');
-                                    if let Some(slash) = unquoted.rfind('/') {
-                                        unquoted[slash + 1..].to_string()
-                                    } else {
-                                        unquoted.to_string()
-                                    }
-                                });
-                            match plug_name {
-                                Some(name) => (Some("module"), Some(format!("Plug: {}", name))),
-                                None => (None, None),
-                            }
-                        }
-                        // Set 选项
-                        "set" | "setlocal" | "setglobal" => {
-                            let mut cursor = n.walk();
-                            let opts: Vec<String> = n
-                                .children(&mut cursor)
-                                .filter(|c| c.kind() == "safe_arg" || c.kind() == "raw_text")
-                                .map(|c| node_text(c, bytes).trim().to_string())
-                                .collect();
-                            let opts_str = opts.join(" ");
-                            if opts_str.is_empty() {
-                                (None, None)
-                            } else {
-                                (
-                                    Some("property"),
-                                    Some(format!("{} {}", cmd.trim(), opts_str)),
-                                )
-                            }
-                        }
-                        // Augroup
-                        "augroup" => {
-                            let mut cursor = n.walk();
-                            let group = n
-                                .children(&mut cursor)
-                                .find(|c| c.kind() == "safe_arg")
-                                .map(|c| node_text(c, bytes).trim().to_string());
-                            match group {
-                                Some(g) if g != "END" => {
-                                    (Some("namespace"), Some(format!("augroup {}", g)))
-                                }
-                                _ => (None, None),
-                            }
-                        }
-                        // Autocmd
-                        "autocmd" | "autocmd!" => {
-                            let mut cursor = n.walk();
+/// `send`, but as a binary frame when the session negotiated one and the
+/// reply has a binary form.
+fn send_reply(out: &mut dyn Write, ev: &Event, binary: bool) -> Result<()> {
+    match binary.then(|| binary_frame(ev)).flatten() {
+        Some(frame) => {
+            out.write_all(&frame)?;
+            out.flush()?;
+            Ok(())
+        }
+        None => send(out, ev),
+    }
+}
+
+/// Binary frame for the replies whose payload is a long list of numbers:
+/// highlights, symbols and folds. Every integer is a little-endian u32:
+///
+/// ```text
+/// 0x00              marker byte; a JSON reply always starts with `{`
+/// length            bytes that follow this field
+/// header length
+/// header            the JSON reply without its list, plus `stride` (words
+///                   per row) and `rows`
+/// rows * stride     the list, one fixed-width row per entry
+/// ```
+///
+/// Rows hold no strings. Highlight rows are the compact `[lnum, col,
+/// end_lnum, end_col, group, depth]` indexing the header's `groups`. Symbol
+/// rows are `[name, kind, lnum, col, end_lnum, end_col, container_kind,
+/// container_name, container_lnum, container_col, lang]`: names and kinds index
+/// the header's `strings`, the optional strings do so plus one and the
+/// optional numbers are 0 when absent. Fold rows are `[lnum, end_lnum, level]`.
+fn binary_frame(ev: &Event) -> Option<Vec<u8>> {
+    let mut strings: Vec<String> = Vec::new();
+    let mut string_ids: HashMap<String, u32> = HashMap::new();
+    let mut intern = |text: &str| -> u32 {
+        if let Some(&id) = string_ids.get(text) {
+            return id;
+        }
+        strings.push(text.to_string());
+        string_ids.insert(text.to_string(), strings.len() as u32 - 1);
+        strings.len() as u32 - 1
+    };
+    let (list, stride, words): (&str, usize, Vec<u32>) = match ev {
+        Event::Highlights {
+            cspans: Some(rows), ..
+        } => ("cspans", 6, rows.iter().flatten().copied().collect()),
+        Event::Symbols { symbols, .. } => {
+            let mut words = Vec::new();
+            for symbol in symbols.iter().flatten() {
+                words.extend([
+                    intern(&symbol.name),
+                    intern(symbol.kind),
+                    symbol.lnum,
+                    symbol.col,
+                    symbol.end_lnum,
+                    symbol.end_col,
+                    symbol.container_kind.map_or(0, |kind| intern(kind) + 1),
+                    symbol
+                        .container_name
+                        .as_deref()
+                        .map_or(0, |name| intern(name) + 1),
+                    symbol.container_lnum.unwrap_or(0),
+                    symbol.container_col.unwrap_or(0),
+                    symbol.lang.map_or(0, |lang| intern(lang) + 1),
+                ]);
+            }
+            ("symbols", 11, words)
+        }
+        Event::Folds { folds, .. } => (
+            "folds",
+            3,
+            folds
+                .iter()
+                .flatten()
+                .flat_map(|fold| [fold.lnum, fold.end_lnum, fold.level])
+                .collect(),
+        ),
+        _ => return None,
+    };
+    let mut header = serde_json::to_value(ev).ok()?;
+    let fields = header.as_object_mut()?;
+    fields.remove(list);
+    fields.insert("stride".to_string(), stride.into());
+    fields.insert("rows".to_string(), (words.len() / stride).into());
+    if matches!(ev, Event::Symbols { .. }) {
+        fields.insert("strings".to_string(), strings.into());
+    }
+    let header = serde_json::to_vec(&header).ok()?;
+    let length = 4 + header.len() + 4 * words.len();
+    let mut frame = Vec::with_capacity(5 + length);
+    frame.push(0);
+    frame.extend((length as u32).to_le_bytes());
+    frame.extend((header.len() as u32).to_le_bytes());
+    frame.extend(header);
+    for word in words {
+        frame.extend(word.to_le_bytes());
+    }
+    Some(frame)
+}
+
+// 将行号范围转为字节范围（用于 QueryCursor 限制扫描区间）
+#[cfg(test)]
+fn line_range_to_byte_range(text: &str, ls: u32, le: u32) -> ops::Range<usize> {
+    line_range_from_index(&SparseLineIndex::new(text), text, ls, le)
+}
+
+impl SparseLineIndex {
+    fn new(text: &str) -> Self {
+        let mut checkpoints = vec![0];
+        let mut newline_count = 0_usize;
+        for (index, byte) in text.bytes().enumerate() {
+            if byte != b'\n' {
+                continue;
+            }
+            newline_count += 1;
+            if newline_count.is_multiple_of(LINE_INDEX_STRIDE) {
+                checkpoints.push(index + 1);
+            }
+        }
+        Self {
+            checkpoints: checkpoints.into_boxed_slice(),
+            line_count: newline_count + 1,
+        }
+    }
+
+    fn line_start_byte(&self, text: &str, line: u32) -> usize {
+        let Some(target_line) = usize::try_from(line.saturating_sub(1)).ok() else {
+            return text.len();
+        };
+        if target_line >= self.line_count {
+            return text.len();
+        }
+
+        let checkpoint_index = target_line / LINE_INDEX_STRIDE;
+        let checkpoint_line = checkpoint_index * LINE_INDEX_STRIDE;
+        let mut offset = self.checkpoints[checkpoint_index];
+        for _ in checkpoint_line..target_line {
+            let Some(relative_newline) = text.as_bytes()[offset..]
+                .iter()
+                .position(|byte| *byte == b'\n')
+            else {
+                return text.len();
+            };
+            offset += relative_newline + 1;
+        }
+        offset
+    }
+
+    /// Byte offset -> tree-sitter `Point` (0-based row, byte column).
+    ///
+    /// Text objects need points for offsets no node starts at — an inner range
+    /// trimmed in past a brace, a parameter's trailing comma — and this runs on
+    /// a key the user holds down, so a scan from byte 0 is not affordable.
+    /// Binary-searching the checkpoints bounds the scan to one stride of lines
+    /// however far into the file the offset is.
+    fn point_at_byte(&self, text: &str, offset: usize) -> tree_sitter::Point {
+        let offset = offset.min(text.len());
+        let checkpoint_index = match self.checkpoints.binary_search(&offset) {
+            Ok(index) => index,
+            Err(index) => index.saturating_sub(1),
+        };
+        let base = self.checkpoints[checkpoint_index];
+        let mut row = checkpoint_index * LINE_INDEX_STRIDE;
+        let mut line_start = base;
+        for (index, byte) in text.as_bytes()[base..offset].iter().enumerate() {
+            if *byte == b'\n' {
+                row += 1;
+                line_start = base + index + 1;
+            }
+        }
+        tree_sitter::Point {
+            row,
+            column: offset - line_start,
+        }
+    }
+}
+
+fn line_range_from_index(
+    line_index: &SparseLineIndex,
+    text: &str,
+    ls: u32,
+    le: u32,
+) -> ops::Range<usize> {
+    // ls/le 为 1-based；结束偏移是 le 下一行的开头。
+    let start_line = ls.max(1);
+    let end_line = le.max(start_line);
+    let start = line_index.line_start_byte(text, start_line);
+    let end = line_index.line_start_byte(text, end_line.saturating_add(1));
+    ops::Range { start, end }
+}
+
+fn expand_range_for_multiline_token(
+    root: tree_sitter::Node,
+    mut range: ops::Range<usize>,
+) -> ops::Range<usize> {
+    if range.start >= range.end {
+        return range;
+    }
+    let Some(mut node) = root.descendant_for_byte_range(range.start, range.start) else {
+        return range;
+    };
+    loop {
+        let kind = node.kind();
+        if node.start_byte() < range.start
+            && node.end_byte() > range.start
+            && (kind.contains("string")
+                || kind.contains("comment")
+                || kind.contains("heredoc")
+                || kind.contains("raw_text"))
+        {
+            range.start = node.start_byte();
+            break;
+        }
+        let Some(parent) = node.parent() else {
+            break;
+        };
+        node = parent;
+    }
+    range
+}
+
+/// One highlight query run: the query, its predicates, the tree it runs over,
+/// the injected language (None for the host), and the ranges injected into
+/// that tree one level down, where its captures give way.
+type HighlightPass<'a> = (
+    &'a tree_sitter::Query,
+    &'a QueryPredicates,
+    tree_sitter::Node<'a>,
+    Option<&'static str>,
+    &'a [ops::Range<usize>],
+);
+
+/// The host pass, then one per injected tree, outer levels first. Shared by
+/// highlight and inspect so the two always agree on what is on screen.
+fn highlight_passes<'a>(
+    server: &'a Server,
+    cache: &'a BufCache,
+    lang_queries: &'a LangQueries,
+) -> Vec<HighlightPass<'a>> {
+    let mut passes: Vec<HighlightPass> = vec![(
+        &lang_queries.hl_query,
+        &lang_queries.hl_predicates,
+        cache.tree.root_node(),
+        None,
+        &cache.injected_ranges,
+    )];
+    for injected in &cache.injections {
+        if let Some(query) = server.injection_queries.get(injected.lang) {
+            passes.push((
+                &query.hl_query,
+                &query.hl_predicates,
+                injected.tree.root_node(),
+                Some(injected.lang),
+                &injected.injected_ranges,
+            ));
+        }
+    }
+    passes
+}
+
+/// Query progress callback that stops the cursor once `cancel` is set, so a
+/// cancelled pooled request leaves a long query early.
+fn stop_when_cancelled(
+    cancel: Option<&std::sync::atomic::AtomicBool>,
+) -> impl FnMut(&tree_sitter::QueryCursorState) -> std::ops::ControlFlow<()> + '_ {
+    move |_| match cancel {
+        Some(cancel) if cancel.load(std::sync::atomic::Ordering::Relaxed) => {
+            std::ops::ControlFlow::Break(())
+        }
+        _ => std::ops::ControlFlow::Continue(()),
+    }
+}
+
+// 复用缓存的 Tree + bytes 做高亮
+fn run_highlight_cached(
+    server: &mut Server,
+    buf: i64,
+    lang: &str,
+    lrange: Option<(u32, u32)>,
+    rainbow: bool,
+    max_spans: Option<usize>,
+) -> Result<(u64, Vec<Span>)> {
+    server.ensure_queries(lang)?;
+    let cache = server.get_cache(buf, lang)?;
+    let bytes = cache.text.as_bytes();
+    let lang_queries = server.queries.get(&cache.lang).unwrap();
+
+    // 宿主查询之外，每棵注入树再跑一遍它自己语言的高亮查询。
+    let passes = highlight_passes(server, cache, lang_queries);
+
+    let mut spans = Vec::with_capacity(4096);
+    let limit = max_spans
+        .unwrap_or(MAX_HIGHLIGHT_SPANS)
+        .min(MAX_HIGHLIGHT_SPANS);
+    // Dedup by an explicit semantic priority. Capture iteration is ordered by
+    // source position, but same-range pattern ordering is not an API contract.
+    let mut seen = HashMap::<(u32, u32, u32, u32), (usize, u8)>::new();
+    let mut stop = stop_when_cancelled(server.cancel.as_deref());
+    'passes: for (query, predicates, pass_root, _, covered) in passes {
+        let mut cursor = tree_sitter::QueryCursor::new();
+        if let Some((ls, le)) = lrange {
+            let b_range = expand_range_for_multiline_token(
+                pass_root,
+                line_range_from_index(&cache.line_index, &cache.text, ls, le),
+            );
+            cursor.set_byte_range(b_range);
+        }
+        let options = tree_sitter::QueryCursorOptions::new().progress_callback(&mut stop);
+        let mut it = cursor.captures_with_options(query, pass_root, bytes, options);
+        while let Some((m, cap_ix)) = it.next() {
+            let cap = m.captures[*cap_ix];
+            let node = cap.node;
+            if node.start_byte() >= node.end_byte() || !predicates.accepts(m, bytes) {
+                continue;
+            }
+            // Inside an injected range the injected grammar has the last word:
+            // markdown captures a whole fence as @text.literal, and leaving that
+            // in place would paint one flat colour over the rust underneath it.
+            // The same holds a level down, for a fence inside that rust.
+            if covered_by_injection(covered, node) {
+                continue;
+            }
+            let (sp, ep) = predicates.capture_points(m.pattern_index, cap);
+
+            let lnum = sp.row as u32 + 1;
+            let col = sp.column as u32 + 1;
+            let end_lnum = ep.row as u32 + 1;
+            let end_col = ep.column as u32 + 1;
+
+            if let Some((ls, le)) = lrange
+                && (end_lnum < ls || lnum > le)
+            {
+                continue;
+            }
+
+            let key = (lnum, col, end_lnum, end_col);
+            let cname = query.capture_names()[cap.index as usize];
+            let priority = pattern_priority(query, m.pattern_index, cap.index)
+                .unwrap_or_else(|| capture_priority(cname));
+            let group = map_capture_to_group(cname);
+            if group.is_empty() {
+                continue;
+            }
+            let depth = if rainbow && cname == "punctuation.bracket" {
+                let d = bracket_depth(node);
+                if d > 0 { Some(d) } else { None }
+            } else {
+                None
+            };
+            let span = Span {
+                lnum,
+                col,
+                end_lnum,
+                end_col,
+                group,
+                depth,
+            };
+            if let Some((index, old_priority)) = seen.get_mut(&key) {
+                if priority > *old_priority {
+                    spans[*index] = span;
+                    *old_priority = priority;
+                }
+                continue;
+            }
+            if spans.len() >= limit {
+                break 'passes;
+            }
+            seen.insert(key, (spans.len(), priority));
+            spans.push(span);
+        }
+    }
+
+    server.check_cancelled()?;
+    Ok((cache.revision, spans))
+}
+
+/// `span` cut to lines `start..=end`, or None when nothing of it is left there.
+/// A cut end runs to the end of line `end`.
+fn clip_span(cache: &BufCache, mut span: Span, start: u32, end: u32) -> Option<Span> {
+    if span.lnum < start {
+        (span.lnum, span.col) = (start, 1);
+    }
+    if span.end_lnum > end {
+        let line = line_range_from_index(&cache.line_index, &cache.text, end, end);
+        let text = &cache.text.as_bytes()[line];
+        let len = text.strip_suffix(b"\n").unwrap_or(text).len() as u32;
+        (span.end_lnum, span.end_col) = (end, len + 1);
+    }
+    ((span.lnum, span.col) < (span.end_lnum, span.end_col)).then_some(span)
+}
+
+/// Lines of `new` outside `old`, both 1-based inclusive: what a moved window
+/// newly covers.
+fn lines_not_covered(new: (u32, u32), old: (u32, u32)) -> Vec<[u32; 2]> {
+    let ((start, end), (old_start, old_end)) = (new, old);
+    if start > end {
+        return Vec::new();
+    }
+    if old_start > old_end || end < old_start || start > old_end {
+        return vec![[start, end]];
+    }
+    let mut lines = Vec::new();
+    if start < old_start {
+        lines.push([start, old_start - 1]);
+    }
+    if end > old_end {
+        lines.push([old_end + 1, end]);
+    }
+    lines
+}
+
+/// Push the highlights of `lines` (1-based inclusive, sorted, disjoint) that
+/// fall inside `buf`'s subscribed window, as a compact `highlights` event
+/// whose `cleared` names exactly those lines. Nothing when `buf` has no
+/// subscription or none of `lines` is in its window.
+///
+/// A range whose spans `max_spans` would cut short goes in `incomplete`
+/// instead, without spans: the cut can fall anywhere, before the injected
+/// trees' passes included, so no line of it could be cleared safely.
+fn push_highlights(
+    server: &mut Server,
+    buf: i64,
+    lines: &[[u32; 2]],
+    out: &mut dyn Write,
+) -> Result<()> {
+    let Some(subscription) = server.subscriptions.get(&buf).copied() else {
+        return Ok(());
+    };
+    let Some(cache) = server.cache.get(&buf) else {
+        return Ok(());
+    };
+    let (lang, revision) = (cache.lang.clone(), cache.revision);
+    let (start, end) = subscription.lines(cache.line_index.line_count as u32);
+    let cleared: Vec<[u32; 2]> = lines
+        .iter()
+        .map(|&[lnum, end_lnum]| [lnum.max(start), end_lnum.min(end)])
+        .filter(|[lnum, end_lnum]| lnum <= end_lnum)
+        .collect();
+    if cleared.is_empty() {
+        return Ok(());
+    }
+    let limit = subscription
+        .max_spans
+        .unwrap_or(MAX_HIGHLIGHT_SPANS)
+        .min(MAX_HIGHLIGHT_SPANS);
+    let mut spans = Vec::new();
+    let (mut complete, mut incomplete) = (Vec::new(), Vec::new());
+    for &[lnum, end_lnum] in &cleared {
+        let room = limit - spans.len();
+        if room == 0 {
+            incomplete.push([lnum, end_lnum]);
+            continue;
+        }
+        let found = run_highlight_cached(
+            server,
+            buf,
+            &lang,
+            Some((lnum, end_lnum)),
+            subscription.rainbow,
+            Some(room),
+        );
+        let found = match found {
+            Ok((_, found)) => found,
+            Err(e) => {
+                return send(
+                    out,
+                    &Event::Error {
+                        message: e.to_string(),
+                        buf: Some(buf),
+                        op: Some("subscribe"),
+                        request_id: None,
+                        location: None,
+                    },
+                );
+            }
+        };
+        if found.len() >= room {
+            incomplete.push([lnum, end_lnum]);
+            continue;
+        }
+        let cache = &server.cache[&buf];
+        spans.extend(
+            found
+                .into_iter()
+                .filter_map(|span| clip_span(cache, span, lnum, end_lnum)),
+        );
+        complete.push([lnum, end_lnum]);
+    }
+    let (groups, cspans) = compact_spans(&spans);
+    send_reply(
+        out,
+        &Event::Highlights {
+            buf,
+            revision,
+            spans: None,
+            groups: Some(groups),
+            cspans: Some(cspans),
+            blocks: None,
+            cleared: Some(complete),
+            incomplete: (!incomplete.is_empty()).then_some(incomplete),
+            push: true,
+        },
+        server.binary_frames,
+    )
+}
+
+/// Block digests and changed line ranges of a delta highlight reply.
+type HighlightDelta = (BTreeMap<String, String>, Vec<[u32; 2]>);
+
+/// The delta blocks that cover lines `ls..=le`, as (first line, last line,
+/// key).
+///
+/// A block ends after a line whose text hashes into the lowest
+/// `1 / HIGHLIGHT_BLOCK_LINES` of the range (FNV's high bits are the well
+/// mixed ones), so boundaries follow the text rather than line
+/// numbers: inserting a line changes the block it lands in, and the blocks
+/// below keep their lines and their keys, a hash of those lines. Text without
+/// such a line for `MAX_HIGHLIGHT_BLOCK_LINES` lines is also cut at multiples
+/// of that. Either way a boundary depends only on nearby lines, so any range
+/// finds the same blocks. A key repeated within the range, for a block whose
+/// text recurs, gets an ordinal suffix.
+fn highlight_blocks(
+    text: &str,
+    line_index: &SparseLineIndex,
+    ls: u32,
+    le: u32,
+) -> Vec<(u32, u32, String)> {
+    let line_count = line_index.line_count as u32;
+    let lo = ls.saturating_sub(3 * MAX_HIGHLIGHT_BLOCK_LINES + 1).max(1);
+    let hi = le
+        .saturating_add(2 * MAX_HIGHLIGHT_BLOCK_LINES)
+        .min(line_count);
+    let region = line_range_from_index(line_index, text, lo, hi);
+    let lines: Vec<&str> = text[region]
+        .split_inclusive('\n')
+        .map(|line| line.strip_suffix('\n').unwrap_or(line))
+        .collect();
+    let line = |lnum: u32| lines.get((lnum - lo) as usize).copied().unwrap_or("");
+    let natural: Vec<bool> = (lo..=hi)
+        .map(|lnum| {
+            let mut hash = Fnv1a::new();
+            hash.str(line(lnum));
+            hash.0 < u64::MAX / u64::from(HIGHLIGHT_BLOCK_LINES)
+        })
+        .collect();
+    let ends = |lnum: u32| {
+        lnum >= line_count
+            || natural[(lnum - lo) as usize]
+            || (lnum.is_multiple_of(MAX_HIGHLIGHT_BLOCK_LINES)
+                && ((lnum + 1 - MAX_HIGHLIGHT_BLOCK_LINES).max(lo)..=lnum)
+                    .all(|before| !natural[(before - lo) as usize]))
+    };
+
+    let mut start = ls;
+    while start > 1 && !ends(start - 1) {
+        start -= 1;
+    }
+    let mut blocks = Vec::new();
+    let mut seen: HashMap<String, u32> = HashMap::new();
+    while start <= le {
+        let mut end = start;
+        while !ends(end) {
+            end += 1;
+        }
+        let mut hash = Fnv1a::new();
+        for lnum in start..=end {
+            hash.str(line(lnum));
+        }
+        let mut key = hash.finish();
+        let repeats = seen.entry(key.clone()).or_default();
+        if *repeats > 0 {
+            key = format!("{key}.{repeats}");
+        }
+        *repeats += 1;
+        blocks.push((start, end, key));
+        start = end + 1;
+    }
+    blocks
+}
+
+/// `run_highlight_cached`, answered as a delta against the blocks the client
+/// holds.
+///
+/// The range grows to whole blocks so that every block's digest covers all
+/// of its lines. A span crossing a block boundary is cut at it: a block's
+/// digest and payload then depend on that block's lines alone, and a client
+/// clearing one block leaves its neighbours' highlights intact. Digests count
+/// lines from the block's start, so a block that an edit above only moved
+/// is not resent: the client's highlights are expected to move with the text,
+/// as Vim's text properties do. A reply cut short by `max_spans` cannot vouch
+/// for its last blocks and goes out full.
+fn run_highlight_delta(
+    server: &mut Server,
+    buf: i64,
+    lang: &str,
+    lrange: Option<(u32, u32)>,
+    rainbow: bool,
+    max_spans: Option<usize>,
+    have: &HashMap<String, String>,
+) -> Result<(u64, Vec<Span>, Option<HighlightDelta>)> {
+    let cache = server.get_cache(buf, lang)?;
+    let line_count = cache.line_index.line_count as u32;
+    let (ls, le) = lrange.unwrap_or((1, line_count));
+    let le = le.min(line_count).max(1);
+    let ls = ls.clamp(1, le);
+    let blocks = highlight_blocks(&cache.text, &cache.line_index, ls, le);
+    let (first, last) = (blocks[0].0, blocks[blocks.len() - 1].1);
+    let (revision, spans) =
+        run_highlight_cached(server, buf, lang, Some((first, last)), rainbow, max_spans)?;
+    let limit = max_spans
+        .unwrap_or(MAX_HIGHLIGHT_SPANS)
+        .min(MAX_HIGHLIGHT_SPANS);
+    if spans.len() >= limit {
+        return Ok((revision, spans, None));
+    }
+
+    let cache = server.get_cache(buf, lang)?;
+    let block_of = |lnum: u32| blocks.partition_point(|&(_, end, _)| end < lnum);
+    let mut buckets = vec![Vec::new(); blocks.len()];
+    for span in spans {
+        let (lnum, end_lnum) = (span.lnum.max(first), span.end_lnum.min(last));
+        if lnum > end_lnum {
+            continue;
+        }
+        let (first_block, last_block) = (block_of(lnum), block_of(end_lnum));
+        for (&(start, end, _), bucket) in blocks[first_block..=last_block]
+            .iter()
+            .zip(&mut buckets[first_block..=last_block])
+        {
+            bucket.extend(clip_span(cache, span.clone(), start, end));
+        }
+    }
+
+    let mut digests = BTreeMap::new();
+    let mut cleared: Vec<[u32; 2]> = Vec::new();
+    let mut changed = Vec::new();
+    for ((start, end, key), mut pieces) in blocks.into_iter().zip(buckets) {
+        pieces.sort_by_key(|span| (span.lnum, span.col, span.end_lnum, span.end_col, span.group));
+        let digest = digest_spans(&pieces, start);
+        if have.get(&key) != Some(&digest) {
+            match cleared.last_mut() {
+                Some(range) if range[1] + 1 == start => range[1] = end,
+                _ => cleared.push([start, end]),
+            }
+            changed.extend(pieces);
+        }
+        digests.insert(key, digest);
+    }
+    Ok((revision, changed, Some((digests, cleared))))
+}
+
+// 复用缓存 Tree + bytes 做符号
+#[cfg(test)]
+fn run_symbols_cached(
+    server: &mut Server,
+    buf: i64,
+    lang: &str,
+    lrange: Option<(u32, u32)>,
+    max_items: Option<usize>,
+) -> Result<(u64, Vec<Symbol>)> {
+    run_symbols_cached_filtered(server, buf, lang, lrange, max_items, &[])
+}
+
+fn symbol_kind_allowed(kind: &str, kinds: &[String]) -> bool {
+    kinds.is_empty() || kinds.iter().any(|candidate| candidate == kind)
+}
+
+/// Dedup state and limits shared by the symbol passes over the host tree and
+/// each injected tree.
+struct SymbolCollector<'a> {
+    cancel: Option<&'a std::sync::atomic::AtomicBool>,
+    lrange: Option<(u32, u32)>,
+    byte_range: Option<ops::Range<usize>>,
+    kinds: &'a [String],
+    limit: usize,
+    seen: std::collections::HashSet<SymbolKey>,
+    seen_at: HashMap<(u32, u32), &'static str>,
+    symbols: Vec<Symbol>,
+}
+
+type SymbolKey = (
+    &'static str,
+    String,
+    u32,
+    u32,
+    Option<&'static str>,
+    Option<String>,
+    Option<u32>,
+    Option<u32>,
+);
+
+impl SymbolCollector<'_> {
+    /// Run one tree's symbol query. `lang` picks the container inference;
+    /// `injected` tags the results when the tree is not the host's.
+    fn collect(
+        &mut self,
+        lang: &str,
+        injected: Option<&'static str>,
+        query: &tree_sitter::Query,
+        predicates: &QueryPredicates,
+        root: tree_sitter::Node,
+        bytes: &[u8],
+    ) {
+        let mut cursor = tree_sitter::QueryCursor::new();
+        if let Some(range) = self.byte_range.clone() {
+            cursor.set_byte_range(range);
+        }
+        let mut stop = stop_when_cancelled(self.cancel);
+        let options = tree_sitter::QueryCursorOptions::new().progress_callback(&mut stop);
+        let mut it = cursor.captures_with_options(query, root, bytes, options);
+        while let Some((m, cap_ix)) = it.next() {
+            if self.symbols.len() >= self.limit {
+                break;
+            }
+            let cap = m.captures[*cap_ix];
+            let node = cap.node;
+            if node.start_byte() >= node.end_byte() || !predicates.accepts(m, bytes) {
+                continue;
+            }
+            let cname = query.capture_names()[cap.index as usize];
+            let mut kind = map_symbol_capture(cname);
+            if kind.is_empty() {
+                continue;
+            }
+
+            if lang == "rust" && kind == "function" && ancestor_kind(node, "impl_item").is_some() {
+                kind = "method";
+            }
+
+            if !symbol_kind_allowed(kind, self.kinds) {
+                continue;
+            }
+
+            let name = node_text(node, bytes);
+            let sp = node.start_position();
+            let lnum = sp.row as u32 + 1;
+            let col = sp.column as u32 + 1;
+            // Query 捕获的一般只是名称节点；向上找到真正的定义，范围才会覆盖函数体。
+            let def_end = definition_node(node, lang, kind).end_position();
+            let sym_end_lnum = def_end.row as u32 + 1;
+            let sym_end_col = def_end.column as u32 + 1;
+
+            if let Some((ls, le)) = self.lrange
+                && (lnum < ls || lnum > le)
+            {
+                continue;
+            }
+
+            // 容器信息（可选）
+            let mut ckind: Option<&'static str> = None;
+            let mut cname_opt: Option<String> = None;
+            let mut clnum: Option<u32> = None;
+            let mut ccol: Option<u32> = None;
+
+            // Rust 容器推断
+            if lang == "rust" {
+                match kind {
+                    "field" => {
+                        if let Some(vinfo) = variant_info(node, bytes) {
+                            ckind = Some("variant");
+                            cname_opt = Some(vinfo.0);
+                            clnum = Some(vinfo.1);
+                            ccol = Some(vinfo.2);
+                        } else if let Some(sinfo) = struct_info(node, bytes) {
+                            ckind = Some("struct");
+                            cname_opt = Some(sinfo.0);
+                            clnum = Some(sinfo.1);
+                            ccol = Some(sinfo.2);
+                        } else if let Some(minfo) = mod_info(node, bytes) {
+                            ckind = Some("namespace");
+                            cname_opt = Some(minfo.0);
+                            clnum = Some(minfo.1);
+                            ccol = Some(minfo.2);
+                        }
+                    }
+                    "variant" => {
+                        if let Some(einfo) = enum_info(node, bytes) {
+                            ckind = Some("enum");
+                            cname_opt = Some(einfo.0);
+                            clnum = Some(einfo.1);
+                            ccol = Some(einfo.2);
+                        }
+                    }
+                    "method" => {
+                        if let Some(tinfo) = impl_type_info(node, bytes) {
+                            ckind = Some("type");
+                            cname_opt = Some(tinfo.0);
+                            clnum = Some(tinfo.1);
+                            ccol = Some(tinfo.2);
+                        }
+                    }
+                    "function" => {
+                        if let Some(finfo) = outer_fn_info(node, bytes) {
+                            ckind = Some("function");
+                            cname_opt = Some(finfo.0);
+                            clnum = Some(finfo.1);
+                            ccol = Some(finfo.2);
+                        } else if let Some(minfo) = mod_info(node, bytes) {
+                            ckind = Some("namespace");
+                            cname_opt = Some(minfo.0);
+                            clnum = Some(minfo.1);
+                            ccol = Some(minfo.2);
+                        }
+                    }
+                    "const" => {
+                        if let Some(minfo) = mod_info(node, bytes) {
+                            ckind = Some("namespace");
+                            cname_opt = Some(minfo.0);
+                            clnum = Some(minfo.1);
+                            ccol = Some(minfo.2);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // JavaScript 容器推断：method → class
+            if lang == "javascript"
+                && kind == "method"
+                && let Some(cls) = ancestor_kind(node, "class_declaration")
+                && let Some(cls_name) = child_text_by_kind(cls, "identifier", bytes)
+                && let Some((ln, co)) = child_pos_by_kind(cls, "identifier")
+            {
+                ckind = Some("class");
+                cname_opt = Some(cls_name);
+                clnum = Some(ln);
+                ccol = Some(co);
+            }
+
+            // TypeScript/TSX 容器推断：method/field → class/interface，variant → enum
+            if lang == "typescript" || lang == "tsx" {
+                if kind == "method" || kind == "field" {
+                    for (ancestor, container_kind, name_kind) in [
+                        ("class_declaration", "class", "type_identifier"),
+                        ("abstract_class_declaration", "class", "type_identifier"),
+                        ("interface_declaration", "type", "type_identifier"),
+                    ] {
+                        if let Some(cls) = ancestor_kind(node, ancestor)
+                            && let Some(cls_name) = child_text_by_kind(cls, name_kind, bytes)
+                            && let Some((ln, co)) = child_pos_by_kind(cls, name_kind)
+                        {
+                            ckind = Some(container_kind);
+                            cname_opt = Some(cls_name);
+                            clnum = Some(ln);
+                            ccol = Some(co);
+                            break;
+                        }
+                    }
+                } else if kind == "variant"
+                    && let Some(en) = ancestor_kind(node, "enum_declaration")
+                    && let Some(enum_name) = child_text_by_kind(en, "identifier", bytes)
+                    && let Some((ln, co)) = child_pos_by_kind(en, "identifier")
+                {
+                    ckind = Some("enum");
+                    cname_opt = Some(enum_name);
+                    clnum = Some(ln);
+                    ccol = Some(co);
+                }
+            }
+
+            // JSON/YAML 容器推断：二级键 → 顶层键
+            if (lang == "json" || lang == "yaml") && kind == "field" {
+                let pair_kind = if lang == "json" {
+                    "pair"
+                } else {
+                    "block_mapping_pair"
+                };
+                let key_kind = if lang == "json" {
+                    "string_content"
+                } else {
+                    "string_scalar"
+                };
+                if let Some(own_pair) = ancestor_kind(node, pair_kind)
+                    && let Some(outer_pair) = ancestor_kind(own_pair, pair_kind)
+                    && let Some(key_node) = outer_pair
+                        .child_by_field_name("key")
+                        .and_then(|key| descendant_by_kind(key, key_kind))
+                {
+                    let sp = key_node.start_position();
+                    ckind = Some("property");
+                    cname_opt = Some(node_text(key_node, bytes));
+                    clnum = Some(sp.row as u32 + 1);
+                    ccol = Some(sp.column as u32 + 1);
+                }
+            }
+
+            // TOML 容器推断：pair → table
+            if lang == "toml"
+                && kind == "property"
+                && let Some(table) = ancestor_kind(node, "table")
+                    .or_else(|| ancestor_kind(node, "table_array_element"))
+            {
+                for key_kind in ["bare_key", "dotted_key", "quoted_key"] {
+                    if let Some(key_name) = child_text_by_kind(table, key_kind, bytes)
+                        && let Some((ln, co)) = child_pos_by_kind(table, key_kind)
+                    {
+                        ckind = Some("namespace");
+                        cname_opt = Some(key_name);
+                        clnum = Some(ln);
+                        ccol = Some(co);
+                        break;
+                    }
+                }
+            }
+
+            // Python 容器推断：method → class
+            if lang == "python"
+                && kind == "method"
+                && let Some(cls) = ancestor_kind(node, "class_definition")
+                && let Some(cls_name) = child_text_by_kind(cls, "identifier", bytes)
+                && let Some((ln, co)) = child_pos_by_kind(cls, "identifier")
+            {
+                ckind = Some("class");
+                cname_opt = Some(cls_name);
+                clnum = Some(ln);
+                ccol = Some(co);
+            }
+
+            // Go 容器推断：method → receiver type, field → struct
+            if lang == "go" {
+                if kind == "method" {
+                    // method_declaration 的 receiver 有 parameter_declaration → type_identifier
+                    if let Some(mdecl) = node.parent().filter(|p| p.kind() == "method_declaration")
+                    {
+                        let mut c = mdecl.walk();
+                        for ch in mdecl.children(&mut c) {
+                            if ch.kind() == "parameter_list" {
+                                let mut c2 = ch.walk();
+                                for pd in ch.children(&mut c2) {
+                                    if pd.kind() == "parameter_declaration"
+                                        && let Some(tname) =
+                                            child_text_by_kind(pd, "type_identifier", bytes)
+                                    {
+                                        let sp = pd.start_position();
+                                        ckind = Some("type");
+                                        cname_opt = Some(tname);
+                                        clnum = Some(sp.row as u32 + 1);
+                                        ccol = Some(sp.column as u32 + 1);
+                                    }
+                                }
+                                break;
+                            }
+                        }
+                    }
+                } else if kind == "field"
+                    && let Some(type_spec) = ancestor_kind(node, "type_spec")
+                    && let Some(type_name) = child_text_by_kind(type_spec, "type_identifier", bytes)
+                    && let Some((ln, co)) = child_pos_by_kind(type_spec, "type_identifier")
+                {
+                    ckind = Some("type");
+                    cname_opt = Some(type_name);
+                    clnum = Some(ln);
+                    ccol = Some(co);
+                }
+            }
+
+            if lang == "vim" {
+                if kind == "namespace" && name == "END" {
+                    continue;
+                }
+                if kind == "variable" {
+                    // 处于函数内的变量：标注容器为 function，交给插件的 hide_inner 逻辑过滤
+                    let mut cur = node;
+                    let mut in_func = false;
+                    while let Some(parent) = cur.parent() {
+                        let pk = parent.kind();
+                        // 兼容你的 Vim9 语法（def_function）
+                        if pk == "def_function"
+                            || pk == "function_definition"
+                            || pk == "vim9_function_definition"
+                        {
+                            in_func = true;
+                            break;
+                        }
+                        cur = parent;
+                    }
+                    if in_func {
+                        ckind = Some("function");
+                    }
+                }
+            }
+
+            // 同一位置的 function/method 去重规则
+            if let Some(&prev) = self.seen_at.get(&(lnum, col)) {
+                if prev == "method" && kind == "function" {
+                    continue;
+                }
+                if prev == "function" && kind == "method" {
+                    if let Some(pos) = self.symbols.iter().position(|s: &Symbol| {
+                        s.lnum == lnum && s.col == col && s.kind == "function" && s.name == name
+                    }) {
+                        self.symbols.remove(pos);
+                    }
+                    self.seen_at.insert((lnum, col), "method");
+                }
+            } else {
+                self.seen_at.insert((lnum, col), kind);
+            }
+
+            let key = (
+                kind,
+                name.clone(),
+                lnum,
+                col,
+                ckind,
+                cname_opt.clone(),
+                clnum,
+                ccol,
+            );
+            if self.seen.contains(&key) {
+                continue;
+            }
+            self.seen.insert(key);
+
+            self.symbols.push(Symbol {
+                name,
+                kind,
+                lnum,
+                col,
+                end_lnum: sym_end_lnum,
+                end_col: sym_end_col,
+                container_kind: ckind,
+                container_name: cname_opt,
+                container_lnum: clnum,
+                container_col: ccol,
+                lang: injected,
+            });
+        }
+    }
+}
+
+/// Give injected symbols that have no container of their own the symbol that
+/// encloses them from an outer tree: the `<script>` element around a function,
+/// or the markdown heading whose section holds a fenced block.
+///
+/// Sorts `symbols` into document order and sweeps them once, keeping the
+/// symbols already started on a stack; everything that has ended by the
+/// current position is popped, so what is left is what can still enclose it.
+fn nest_injected_symbols(symbols: &mut [Symbol], host_root: tree_sitter::Node) {
+    symbols.sort_by_key(|s| (s.lnum, s.col));
+    let start = |symbol: &Symbol| (symbol.lnum, symbol.col);
+    let end = |symbol: &Symbol| (symbol.end_lnum, symbol.end_col);
+    let mut nested = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    let mut next = 0;
+    for (index, symbol) in symbols.iter().enumerate() {
+        let at = start(symbol);
+        while open.last().is_some_and(|&top| end(&symbols[top]) < at) {
+            open.pop();
+        }
+        while next < symbols.len() && start(&symbols[next]) <= at {
+            open.push(next);
+            next += 1;
+        }
+        if symbol.lang.is_none() || symbol.container_kind.is_some() {
+            continue;
+        }
+        let enclosing = open
+            .iter()
+            .rev()
+            .copied()
+            .find(|&candidate| {
+                let candidate = &symbols[candidate];
+                candidate.lang != symbol.lang && at <= end(candidate)
+            })
+            .or_else(|| {
+                // 标题符号只覆盖标题那一行；围栏属于哪个标题要看它所在的 section。
+                let point = tree_sitter::Point::new(at.0 as usize - 1, at.1 as usize - 1);
+                let mut node = host_root.descendant_for_point_range(point, point);
+                while let Some(current) = node {
+                    if current.kind() == "section"
+                        && let Some(heading) = current.named_child(0)
+                    {
+                        let row = heading.start_position().row as u32 + 1;
+                        let first = symbols.partition_point(|candidate| candidate.lnum < row);
+                        if let Some(found) = (first..symbols.len())
+                            .take_while(|&candidate| symbols[candidate].lnum == row)
+                            .find(|&candidate| symbols[candidate].lang.is_none())
+                        {
+                            return Some(found);
+                        }
+                    }
+                    node = current.parent();
+                }
+                None
+            });
+        if let Some(container) = enclosing {
+            nested.push((index, container));
+        }
+    }
+    for (index, container) in nested {
+        let container = &symbols[container];
+        let (kind, name, lnum, col) = (
+            container.kind,
+            container.name.clone(),
+            container.lnum,
+            container.col,
+        );
+        let symbol = &mut symbols[index];
+        symbol.container_kind = Some(kind);
+        symbol.container_name = Some(name);
+        symbol.container_lnum = Some(lnum);
+        symbol.container_col = Some(col);
+    }
+}
+
+fn run_symbols_cached_filtered(
+    server: &mut Server,
+    buf: i64,
+    lang: &str,
+    lrange: Option<(u32, u32)>,
+    max_items: Option<usize>,
+    kinds: &[String],
+) -> Result<(u64, Vec<Symbol>)> {
+    server.ensure_queries(lang)?;
+    let injected_langs: Vec<&'static str> = server
+        .get_cache(buf, lang)?
+        .injections
+        .iter()
+        .map(|injected| injected.lang)
+        .collect();
+    for injected_lang in injected_langs {
+        // 注入语言没有可用查询时只是少了那部分符号，不影响宿主大纲。
+        server.ensure_queries(injected_lang).ok();
+    }
+    let cache = server.get_cache(buf, lang)?;
+    let bytes = cache.text.as_bytes();
+    let root = cache.tree.root_node();
+    let lang_queries = server.queries.get(&cache.lang).unwrap();
+
+    let limit = max_items.unwrap_or(MAX_SYMBOLS).min(MAX_SYMBOLS);
+    let mut collector = SymbolCollector {
+        cancel: server.cancel.as_deref(),
+        lrange,
+        byte_range: lrange
+            .map(|(ls, le)| line_range_from_index(&cache.line_index, &cache.text, ls, le)),
+        kinds,
+        limit,
+        seen: std::collections::HashSet::new(),
+        seen_at: HashMap::new(),
+        symbols: Vec::with_capacity(limit.min(4096)),
+    };
+
+    // 1) 先用查询收集符号；每棵注入树（markdown 围栏、HTML <script> 等）再跑一遍
+    // 自己语言的符号查询，结果标上注入语言。
+    collector.collect(
+        &cache.lang,
+        None,
+        &lang_queries.sym_query,
+        &lang_queries.sym_predicates,
+        root,
+        bytes,
+    );
+    for injected in &cache.injections {
+        if let Some(queries) = server.queries.get(injected.lang) {
+            collector.collect(
+                injected.lang,
+                Some(injected.lang),
+                &queries.sym_query,
+                &queries.sym_predicates,
+                injected.tree.root_node(),
+                bytes,
+            );
+        }
+    }
+    let SymbolCollector {
+        mut seen,
+        mut symbols,
+        ..
+    } = collector;
+    server.check_cancelled()?;
+
+    // 2) Vim9 grammar currently parses some valid `def`/`var` lines as generic
+    // Ex commands. A small line-oriented fallback keeps the core outline useful
+    // while the grammar evolves.
+    if cache.lang == "vim" && symbols.len() < limit {
+        for symbol in extract_vim_declarations(&cache.text, lrange, limit - symbols.len(), kinds) {
+            let key = (
+                symbol.kind,
+                symbol.name.clone(),
+                symbol.lnum,
+                symbol.col,
+                symbol.container_kind,
+                symbol.container_name.clone(),
+                symbol.container_lnum,
+                symbol.container_col,
+            );
+            if seen.insert(key) {
+                symbols.push(symbol);
+            }
+        }
+    }
+
+    // 3) 额外：Vim 语言从 command 节点中补充提取符号
+    if cache.lang == "vim" && symbols.len() < limit {
+        let mut stack = Vec::<tree_sitter::Node>::with_capacity(1024);
+        stack.push(root);
+        while let Some(n) = stack.pop() {
+            if symbols.len() >= limit {
+                break;
+            }
+            if n.kind() == "command" {
+                let cmd_name_text = {
+                    let mut cursor = n.walk();
+                    n.children(&mut cursor)
+                        .find(|c| c.kind() == "command_name")
+                        .map(|c| node_text(c, bytes))
+                };
+                if let Some(ref cmd) = cmd_name_text {
+                    let sp = n.start_position();
+                    let lnum = sp.row as u32 + 1;
+                    let col = sp.column as u32 + 1;
+
+                    if let Some((ls, le)) = lrange
+                        && (lnum < ls || lnum > le)
+                    {
+                        // 压入子节点继续遍历
+                        let mut child_cursor = n.walk();
+                        for ch in n.children(&mut child_cursor) {
+                            stack.push(ch);
+                        }
+                        continue;
+                    }
+
+                    let cmd_lower = cmd.trim().to_lowercase();
+                    let (sym_kind, sym_name) = match cmd_lower.as_str() {
+                        // 映射命令
+                        "nnoremap" | "vnoremap" | "inoremap" | "tnoremap" | "cnoremap"
+                        | "xnoremap" | "onoremap" | "snoremap" | "noremap" | "nmap" | "vmap"
+                        | "imap" | "tmap" | "cmap" | "xmap" | "omap" | "smap" | "map" => {
+                            // 从源码行中提取 lhs
+                            let line_text = {
+                                let start_byte = n.start_byte();
+                                let end_byte = n.end_byte();
+                                let s = &bytes[start_byte..end_byte];
+                                String::from_utf8_lossy(s).to_string()
+                            };
+                            // 解析：cmd [modifiers...] lhs rhs
+                            let mut parts = line_text.split_whitespace();
+                            let _cmd_part = parts.next(); // skip command name
+                            let mut lhs = String::new();
+                            for part in parts {
+                                let pl = part.to_lowercase();
+                                // 跳过独立修饰词
+                                if pl == "<silent>"
+                                    || pl == "<buffer>"
+                                    || pl == "<expr>"
+                                    || pl == "<nowait>"
+                                    || pl == "<unique>"
+                                    || pl == "<silent><expr>"
+                                {
+                                    continue;
+                                }
+                                // 第一个非修饰词就是 lhs
+                                // 但可能以修饰词为前缀：<silent><leader>gk → 去掉 <silent>
+                                let mut s = part.to_string();
+                                loop {
+                                    let sl = s.to_lowercase();
+                                    let prefix_length = [
+                                        "<silent>", "<buffer>", "<expr>", "<nowait>", "<unique>",
+                                        "<script>",
+                                    ]
+                                    .into_iter()
+                                    .find(|prefix| sl.starts_with(prefix))
+                                    .map(str::len);
+                                    let Some(prefix_length) = prefix_length else {
+                                        break;
+                                    };
+                                    s = s[prefix_length..].to_string();
+                                }
+                                if s.is_empty() {
+                                    continue;
+                                }
+                                lhs = s;
+                                break;
+                            }
+                            if lhs.is_empty() {
+                                (None, None)
+                            } else {
+                                (Some("mapping"), Some(format!("{} {}", cmd.trim(), lhs)))
+                            }
+                        }
+                        // Plug 插件
+                        "plug" => {
+                            let mut cursor = n.walk();
+                            let plug_name = n
+                                .children(&mut cursor)
+                                .find(|c| c.kind() == "safe_arg")
+                                .map(|c| {
+                                    let t = node_text(c, bytes).trim().to_string();
+                                    // 'user/repo' -> repo
+                                    let unquoted = t.trim_matches('\'').trim_matches('"');
+                                    if let Some(slash) = unquoted.rfind('/') {
+                                        unquoted[slash + 1..].to_string()
+                                    } else {
+                                        unquoted.to_string()
+                                    }
+                                });
+                            match plug_name {
+                                Some(name) => (Some("module"), Some(format!("Plug: {}", name))),
+                                None => (None, None),
+                            }
+                        }
+                        // Set 选项
+                        "set" | "setlocal" | "setglobal" => {
+                            let mut cursor = n.walk();
+                            let opts: Vec<String> = n
+                                .children(&mut cursor)
+                                .filter(|c| c.kind() == "safe_arg" || c.kind() == "raw_text")
+                                .map(|c| node_text(c, bytes).trim().to_string())
+                                .collect();
+                            let opts_str = opts.join(" ");
+                            if opts_str.is_empty() {
+                                (None, None)
+                            } else {
+                                (
+                                    Some("property"),
+                                    Some(format!("{} {}", cmd.trim(), opts_str)),
+                                )
+                            }
+                        }
+                        // Augroup
+                        "augroup" => {
+                            let mut cursor = n.walk();
+                            let group = n
+                                .children(&mut cursor)
+                                .find(|c| c.kind() == "safe_arg")
+                                .map(|c| node_text(c, bytes).trim().to_string());
+                            match group {
+                                Some(g) if g != "END" => {
+                                    (Some("namespace"), Some(format!("augroup {}", g)))
+                                }
+                                _ => (None, None),
+                            }
+                        }
+                        // Autocmd
+                        "autocmd" | "autocmd!" => {
+                            let mut cursor = n.walk();
                             let args: Vec<String> = n
                                 .children(&mut cursor)
                                 .filter(|c| c.kind() == "safe_arg")
@@ -2316,2263 +6100,5969 @@ fn run_symbols_cached_filtered(
                                 )
                             }
                         }
-                        _ => (None, None),
-                    };
-                    if let (Some(kind), Some(name)) = (sym_kind, sym_name)
-                        && symbol_kind_allowed(kind, kinds)
-                    {
-                        let key = (kind, name.clone(), lnum, col, None, None, None, None);
-                        if !seen.contains(&key) {
-                            seen.insert(key);
-                            let ep = n.end_position();
-                            symbols.push(Symbol {
-                                name,
-                                kind,
-                                lnum,
-                                col,
-                                end_lnum: ep.row as u32 + 1,
-                                end_col: ep.column as u32 + 1,
-                                container_kind: None,
-                                container_name: None,
-                                container_lnum: None,
-                                container_col: None,
-                            });
+                        _ => (None, None),
+                    };
+                    if let (Some(kind), Some(name)) = (sym_kind, sym_name)
+                        && symbol_kind_allowed(kind, kinds)
+                    {
+                        let key = (kind, name.clone(), lnum, col, None, None, None, None);
+                        if !seen.contains(&key) {
+                            seen.insert(key);
+                            let ep = n.end_position();
+                            symbols.push(Symbol {
+                                name,
+                                kind,
+                                lnum,
+                                col,
+                                end_lnum: ep.row as u32 + 1,
+                                end_col: ep.column as u32 + 1,
+                                container_kind: None,
+                                container_name: None,
+                                container_lnum: None,
+                                container_col: None,
+                                lang: None,
+                            });
+                        }
+                    }
+                }
+            }
+
+            // 压入子节点
+            let mut child_cursor = n.walk();
+            for ch in n.children(&mut child_cursor) {
+                stack.push(ch);
+            }
+        }
+    }
+
+    if cache.lang == "go" {
+        let type_positions: HashMap<String, (u32, u32)> = symbols
+            .iter()
+            .filter(|symbol| symbol.kind == "type")
+            .map(|symbol| (symbol.name.clone(), (symbol.lnum, symbol.col)))
+            .collect();
+        for symbol in &mut symbols {
+            if symbol.container_kind == Some("type")
+                && let Some(name) = &symbol.container_name
+                && let Some((line, column)) = type_positions.get(name)
+            {
+                symbol.container_lnum = Some(*line);
+                symbol.container_col = Some(*column);
+            }
+        }
+    }
+
+    if !cache.injections.is_empty() {
+        nest_injected_symbols(&mut symbols, root);
+    }
+
+    symbols.sort_by_key(|s| (s.lnum, s.col));
+    Ok((cache.revision, symbols))
+}
+
+/// Node kinds that produce folds per language. Plain string matching keeps
+/// this table cheap and tolerant: a kind absent from a grammar simply never
+/// matches.
+fn foldable_kinds(lang: &str) -> &'static [&'static str] {
+    match lang {
+        "rust" => &[
+            "function_item",
+            "impl_item",
+            "mod_item",
+            "struct_item",
+            "enum_item",
+            "trait_item",
+            "macro_definition",
+            "match_expression",
+            "block",
+        ],
+        "c" | "cpp" => &[
+            "function_definition",
+            "struct_specifier",
+            "enum_specifier",
+            "union_specifier",
+            "class_specifier",
+            "namespace_definition",
+            "compound_statement",
+        ],
+        "javascript" => &[
+            "function_declaration",
+            "function_expression",
+            "arrow_function",
+            "method_definition",
+            "class_declaration",
+            "object",
+            "statement_block",
+            "switch_statement",
+        ],
+        "typescript" | "tsx" => &[
+            "function_declaration",
+            "function_expression",
+            "arrow_function",
+            "method_definition",
+            "class_declaration",
+            "abstract_class_declaration",
+            "interface_declaration",
+            "enum_declaration",
+            "internal_module",
+            "object",
+            "statement_block",
+            "switch_statement",
+            "jsx_element",
+        ],
+        "python" => &[
+            "function_definition",
+            "class_definition",
+            "if_statement",
+            "for_statement",
+            "while_statement",
+            "try_statement",
+            "with_statement",
+            "match_statement",
+        ],
+        "go" => &[
+            "function_declaration",
+            "method_declaration",
+            "func_literal",
+            "type_declaration",
+            "block",
+        ],
+        "bash" => &[
+            "function_definition",
+            "if_statement",
+            "for_statement",
+            "while_statement",
+            "case_statement",
+            "compound_statement",
+        ],
+        "vim" => &["def_function", "function_definition"],
+        "json" => &["object", "array"],
+        "yaml" => &["block_mapping", "block_sequence"],
+        "toml" => &["table", "table_array_element"],
+        "julia" => &[
+            "function_definition",
+            "macro_definition",
+            "struct_definition",
+            "module_definition",
+            "if_statement",
+            "for_statement",
+            "while_statement",
+            "try_statement",
+            "let_statement",
+            "compound_statement",
+        ],
+        "haskell" => &[
+            "function",
+            "class",
+            "instance",
+            "data_type",
+            "newtype",
+            "do",
+            "case",
+        ],
+        _ => match dynamic_grammar(lang) {
+            Some(grammar) if grammar.folds.is_none() => GENERIC_FOLDABLE_KINDS,
+            _ => &[],
+        },
+    }
+}
+
+/// Fold kinds for a `load_grammar` grammar that ships no `folds.scm`: names
+/// that are a multi-line construct in every grammar spelling them this way.
+const GENERIC_FOLDABLE_KINDS: &[&str] = &[
+    "function_definition",
+    "function_declaration",
+    "method_definition",
+    "method_declaration",
+    "class_definition",
+    "class_declaration",
+    "if_statement",
+    "for_statement",
+    "while_statement",
+    "compound_statement",
+    "statement_block",
+    "object",
+    "array",
+];
+
+// 从缓存树上收集折叠区间；level 为折叠祖先数量 + 1。
+// 注入树（markdown 围栏、HTML <script> 等）按各自语言的规则折叠，层级叠加在
+// 外层树包住它的折叠之上。
+fn run_folds_cached(
+    server: &Server,
+    buf: i64,
+    lang: &str,
+    max_items: Option<usize>,
+) -> Result<(u64, Vec<Fold>)> {
+    let cache = server.get_cache(buf, lang)?;
+    let bytes = cache.text.as_bytes();
+    let limit = max_items.unwrap_or(MAX_FOLDS).min(MAX_FOLDS);
+    let mut trees = vec![(cache.lang.as_str(), cache.tree.root_node())];
+    for injected in &cache.injections {
+        trees.push((injected.lang, injected.tree.root_node()));
+    }
+    let mut folds: Vec<Fold> = Vec::new();
+    for (tree_lang, root) in trees {
+        let kinds = foldable_kinds(tree_lang);
+        let mut captured = std::collections::HashSet::new();
+        if let Some((query, predicates)) = server
+            .queries
+            .get(tree_lang)
+            .and_then(|queries| queries.folds_query.as_ref())
+        {
+            let mut cursor = tree_sitter::QueryCursor::new();
+            let mut stop = stop_when_cancelled(server.cancel.as_deref());
+            let options = tree_sitter::QueryCursorOptions::new().progress_callback(&mut stop);
+            let mut matches = cursor.matches_with_options(query, root, bytes, options);
+            while let Some(m) = matches.next() {
+                if predicates.accepts(m, bytes) {
+                    captured.extend(m.captures.iter().map(|cap| cap.node.id()));
+                }
+            }
+        }
+        let outer = folds.len();
+        // (node, level, enclosing fold range) —— 与父 fold 完全同界的嵌套节点合并，
+        // 否则 `fn f() { ... }` 会因 function_item 与 block 同界而叠出两层折叠。
+        let mut stack = vec![(root, 0_u32, None::<(u32, u32)>)];
+        while let Some((node, level, parent_range)) = stack.pop() {
+            if folds.len() >= limit || server.cancelled() {
+                break;
+            }
+            let sp = node.start_position();
+            let ep = node.end_position();
+            let range = (sp.row as u32 + 1, ep.row as u32 + 1);
+            let mut next_level = level;
+            let mut next_range = parent_range;
+            if (kinds.contains(&node.kind()) || captured.contains(&node.id()))
+                && ep.row > sp.row
+                && parent_range != Some(range)
+                && !folds[..outer]
+                    .iter()
+                    .any(|fold| (fold.lnum, fold.end_lnum) == range)
+            {
+                folds.push(Fold {
+                    lnum: range.0,
+                    end_lnum: range.1,
+                    level: level + 1,
+                });
+                next_level = level + 1;
+                next_range = Some(range);
+            }
+            let child_count = node.child_count().min(u32::MAX as usize);
+            for index in (0..child_count).rev() {
+                if let Some(child) = node.child(index as u32) {
+                    stack.push((child, next_level, next_range));
+                }
+            }
+        }
+        // 注入树自己的层级从 0 数起，再加上外层树里包住它的折叠数。
+        let (enclosing, own) = folds.split_at_mut(outer);
+        for fold in own {
+            fold.level += enclosing
+                .iter()
+                .filter(|host| host.lnum <= fold.lnum && fold.end_lnum <= host.end_lnum)
+                .count() as u32;
+        }
+    }
+    server.check_cancelled()?;
+    folds.sort_by_key(|fold| (fold.lnum, fold.level));
+    Ok((cache.revision, folds))
+}
+
+/// Collect the syntax errors of the host tree and of every injected tree.
+///
+/// Only subtrees that `has_error()` are entered, so a clean file costs one
+/// check on the root. An `ERROR` node is reported once and not descended into:
+/// whatever is broken inside it is the same mistake seen from closer up.
+fn run_diagnostics_cached(
+    server: &Server,
+    buf: i64,
+    lang: &str,
+    max_items: Option<usize>,
+) -> Result<(u64, Vec<Diagnostic>)> {
+    let cache = server.get_cache(buf, lang)?;
+    let limit = max_items.unwrap_or(MAX_DIAGNOSTICS).min(MAX_DIAGNOSTICS);
+    let mut diagnostics = Vec::new();
+    let mut roots = vec![(cache.tree.root_node(), None)];
+    for injected in &cache.injections {
+        roots.push((injected.tree.root_node(), Some(injected.lang)));
+    }
+    'roots: for (root, injected_lang) in roots {
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            if !node.has_error() {
+                continue;
+            }
+            let kind = if node.is_error() {
+                "error"
+            } else if node.is_missing() {
+                "missing"
+            } else {
+                let child_count = node.child_count().min(u32::MAX as usize);
+                for index in (0..child_count).rev() {
+                    if let Some(child) = node.child(index as u32) {
+                        stack.push(child);
+                    }
+                }
+                continue;
+            };
+            if diagnostics.len() >= MAX_DIAGNOSTICS {
+                break 'roots;
+            }
+            diagnostics.push(diagnostic_for(node, kind, &cache.text, injected_lang));
+        }
+    }
+    // Host errors are found before injected ones: cut only once the list is in
+    // document order, so `max_items` keeps the first errors of the file.
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.lnum, diagnostic.col));
+    diagnostics.truncate(limit);
+    Ok((cache.revision, diagnostics))
+}
+
+fn diagnostic_for(
+    node: tree_sitter::Node,
+    kind: &'static str,
+    text: &str,
+    injected_lang: Option<&'static str>,
+) -> Diagnostic {
+    let sp = node.start_position();
+    let ep = node.end_position();
+    // A missing node's kind is the token it stands for: `;` for punctuation,
+    // `identifier` for a named one. Quote the former as source text.
+    let message = if kind == "missing" {
+        if node.is_named() {
+            format!("missing {}", node.kind())
+        } else {
+            format!("missing `{}`", node.kind())
+        }
+    } else {
+        let raw = text.get(node.start_byte()..node.end_byte()).unwrap_or("");
+        let first_line = raw.trim().lines().next().unwrap_or("");
+        if first_line.is_empty() {
+            "syntax error".to_string()
+        } else if first_line.len() > MAX_DIAGNOSTIC_SNIPPET {
+            let mut cut = MAX_DIAGNOSTIC_SNIPPET;
+            while !first_line.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            format!("unexpected `{}…`", &first_line[..cut])
+        } else {
+            format!("unexpected `{first_line}`")
+        }
+    };
+    let mut parent = node.parent();
+    while let Some(candidate) = parent {
+        if candidate.is_named() && !candidate.is_error() {
+            break;
+        }
+        parent = candidate.parent();
+    }
+    Diagnostic {
+        lnum: sp.row as u32 + 1,
+        col: sp.column as u32 + 1,
+        end_lnum: ep.row as u32 + 1,
+        end_col: ep.column as u32 + 1,
+        kind,
+        message,
+        parent: parent.map(|node| node.kind()),
+        injected_lang,
+    }
+}
+
+fn extract_vim_declarations(
+    text: &str,
+    lrange: Option<(u32, u32)>,
+    limit: usize,
+    kinds: &[String],
+) -> Vec<Symbol> {
+    let mut symbols = Vec::<Symbol>::new();
+    // (name, line, column, index in symbols). Vim functions do not normally
+    // nest, but a stack makes malformed/in-progress edits behave predictably.
+    let mut functions = Vec::<(String, u32, u32, Option<usize>)>::new();
+
+    for (row, source_line) in text.lines().enumerate() {
+        let line_number = row as u32 + 1;
+        let leading = source_line.len() - source_line.trim_start().len();
+        let mut line = source_line.trim_start();
+        let mut declaration_offset = leading;
+        if line.is_empty() || line.starts_with('"') {
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("export")
+            && rest.starts_with(char::is_whitespace)
+        {
+            let trimmed = rest.trim_start();
+            declaration_offset += "export".len() + (rest.len() - trimmed.len());
+            line = trimmed;
+        }
+
+        if starts_with_vim_keyword(line, "enddef") || starts_with_vim_keyword(line, "endfunction") {
+            if let Some((_, _, _, Some(index))) = functions.pop()
+                && let Some(symbol) = symbols.get_mut(index)
+            {
+                symbol.end_lnum = line_number;
+                symbol.end_col = source_line.len() as u32 + 1;
+            }
+            continue;
+        }
+
+        let function_keyword = ["def", "function!", "function"]
+            .into_iter()
+            .find(|keyword| starts_with_vim_keyword(line, keyword));
+        if let Some(keyword) = function_keyword {
+            let rest = &line[keyword.len()..];
+            let name_part = rest.trim_start();
+            let spaces = rest.len() - name_part.len();
+            let name = name_part
+                .split(|character: char| character == '(' || character.is_whitespace())
+                .next()
+                .unwrap_or("")
+                .trim();
+            if !name.is_empty() {
+                let column = (declaration_offset + keyword.len() + spaces + 1) as u32;
+                let in_range =
+                    lrange.is_none_or(|(start, end)| line_number >= start && line_number <= end);
+                let index = if in_range
+                    && symbols.len() < limit
+                    && symbol_kind_allowed("function", kinds)
+                {
+                    let container = functions.last();
+                    symbols.push(Symbol {
+                        name: name.to_string(),
+                        kind: "function",
+                        lnum: line_number,
+                        col: column,
+                        end_lnum: line_number,
+                        end_col: source_line.len() as u32 + 1,
+                        container_kind: container.map(|_| "function"),
+                        container_name: container.map(|value| value.0.clone()),
+                        container_lnum: container.map(|value| value.1),
+                        container_col: container.map(|value| value.2),
+                        lang: None,
+                    });
+                    Some(symbols.len() - 1)
+                } else {
+                    None
+                };
+                functions.push((name.to_string(), line_number, column, index));
+            }
+            continue;
+        }
+
+        let variable_keyword = ["var", "const", "final", "let"]
+            .into_iter()
+            .find(|keyword| starts_with_vim_keyword(line, keyword));
+        if let Some(keyword) = variable_keyword {
+            let kind = if matches!(keyword, "const" | "final") {
+                "const"
+            } else {
+                "variable"
+            };
+            if !symbol_kind_allowed(kind, kinds)
+                || symbols.len() >= limit
+                || !lrange.is_none_or(|(start, end)| line_number >= start && line_number <= end)
+            {
+                continue;
+            }
+            let rest = &line[keyword.len()..];
+            let name_part = rest.trim_start();
+            let spaces = rest.len() - name_part.len();
+            let raw_name = name_part
+                .split(|character: char| character == '=' || character.is_whitespace())
+                .next()
+                .unwrap_or("");
+            let name = raw_name.trim_end_matches(':');
+            if name.is_empty() || name.starts_with('[') || name.starts_with('{') {
+                continue;
+            }
+            let container = functions.last();
+            symbols.push(Symbol {
+                name: name.to_string(),
+                kind,
+                lnum: line_number,
+                col: (declaration_offset + keyword.len() + spaces + 1) as u32,
+                end_lnum: line_number,
+                end_col: source_line.len() as u32 + 1,
+                container_kind: container.map(|_| "function"),
+                container_name: container.map(|value| value.0.clone()),
+                container_lnum: container.map(|value| value.1),
+                container_col: container.map(|value| value.2),
+                lang: None,
+            });
+        }
+    }
+
+    symbols
+}
+
+fn starts_with_vim_keyword(line: &str, keyword: &str) -> bool {
+    let Some(rest) = line.strip_prefix(keyword) else {
+        return false;
+    };
+    rest.is_empty() || rest.starts_with(char::is_whitespace)
+}
+
+/// Byte offset of a 1-based (line, byte column) pair, clamped into the line and
+/// snapped back to a UTF-8 boundary. Vim's col() counts bytes, so a cursor
+/// sitting on a continuation byte of a multi-byte character is impossible from
+/// Vim itself but trivially reachable from a script; a mid-character offset
+/// would make `descendant_for_byte_range` describe a neighbouring token.
+fn point_byte_offset(cache: &BufCache, lnum: u32, col: u32) -> usize {
+    let start = cache.line_index.line_start_byte(&cache.text, lnum.max(1));
+    let mut end = cache
+        .line_index
+        .line_start_byte(&cache.text, lnum.max(1).saturating_add(1))
+        .min(cache.text.len());
+    if end > start && cache.text.as_bytes()[end - 1] == b'\n' {
+        end -= 1;
+    }
+    let mut offset = start
+        .saturating_add(col.max(1).saturating_sub(1) as usize)
+        .min(end.max(start));
+    while offset > start && !cache.text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+fn inspect_node(node: tree_sitter::Node) -> InspectNode {
+    let sp = node.start_position();
+    let ep = node.end_position();
+    InspectNode {
+        kind: node.kind().to_string(),
+        named: node.is_named(),
+        lnum: sp.row as u32 + 1,
+        col: sp.column as u32 + 1,
+        end_lnum: ep.row as u32 + 1,
+        end_col: ep.column as u32 + 1,
+        field: None,
+    }
+}
+
+fn inspect_cached(
+    server: &mut Server,
+    buf: i64,
+    lang: &str,
+    lnum: u32,
+    col: u32,
+) -> Result<(u64, Vec<InspectCapture>, Vec<InspectNode>)> {
+    server.ensure_queries(lang)?;
+    let cache = server.get_cache(buf, lang)?;
+    let bytes = cache.text.as_bytes();
+    let root = cache.tree.root_node();
+    let offset = point_byte_offset(cache, lnum, col);
+    let lang_queries = server.queries.get(&cache.lang).unwrap();
+
+    // Node chain, innermost first. `field` is read from the parent because a
+    // node does not know which slot it fills.
+    let mut node_chain = Vec::new();
+    if let Some(innermost) = root.descendant_for_byte_range(offset, offset) {
+        let mut current = Some(innermost);
+        while let Some(node) = current {
+            let mut entry = inspect_node(node);
+            if let Some(parent) = node.parent() {
+                let mut cursor = parent.walk();
+                if cursor.goto_first_child() {
+                    loop {
+                        if cursor.node() == node {
+                            entry.field = cursor.field_name().map(str::to_string);
+                            break;
+                        }
+                        if !cursor.goto_next_sibling() {
+                            break;
                         }
                     }
                 }
             }
+            node_chain.push(entry);
+            if node_chain.len() >= MAX_AST_DEPTH {
+                break;
+            }
+            current = node.parent();
+        }
+    }
+
+    // Same passes as run_highlight_cached, so an injected grammar's captures
+    // show up here exactly when they show up on screen.
+    let passes = highlight_passes(server, cache, lang_queries);
+
+    let mut captures: Vec<InspectCapture> = Vec::new();
+    for (query, predicates, pass_root, injected_lang, covered) in passes {
+        let mut cursor = tree_sitter::QueryCursor::new();
+        cursor.set_byte_range(offset..offset.saturating_add(1));
+        let mut it = cursor.captures(query, pass_root, bytes);
+        while let Some((m, cap_ix)) = it.next() {
+            let cap = m.captures[*cap_ix];
+            let node = cap.node;
+            // set_byte_range only bounds which patterns are considered; a match
+            // may still report a capture that does not cover the point.
+            if node.start_byte() > offset
+                || node.end_byte() <= offset
+                || !predicates.accepts(m, bytes)
+            {
+                continue;
+            }
+            // The renderer drops these, so the report must too, or :TsHlInspect
+            // would name a group the screen does not show.
+            if covered_by_injection(covered, node) {
+                continue;
+            }
+            let (sp, ep) = predicates.capture_points(m.pattern_index, cap);
+            let name = query.capture_names()[cap.index as usize];
+            captures.push(InspectCapture {
+                capture: name.to_string(),
+                group: map_capture_to_group(name),
+                priority: pattern_priority(query, m.pattern_index, cap.index)
+                    .unwrap_or_else(|| capture_priority(name)),
+                lnum: sp.row as u32 + 1,
+                col: sp.column as u32 + 1,
+                end_lnum: ep.row as u32 + 1,
+                end_col: ep.column as u32 + 1,
+                applied: false,
+                injected_lang,
+            });
+        }
+    }
+
+    // Mark the winner per exact span with the rule run_highlight_cached applies:
+    // strictly greater priority replaces, so the first of a tie survives.
+    let mut winners = HashMap::<(u32, u32, u32, u32), usize>::new();
+    for index in 0..captures.len() {
+        if captures[index].group.is_empty() {
+            continue;
+        }
+        let key = (
+            captures[index].lnum,
+            captures[index].col,
+            captures[index].end_lnum,
+            captures[index].end_col,
+        );
+        match winners.get(&key) {
+            Some(&best) if captures[index].priority <= captures[best].priority => {}
+            _ => {
+                winners.insert(key, index);
+            }
+        }
+    }
+    for index in winners.into_values() {
+        captures[index].applied = true;
+    }
+    // Most specific first: the report's first line should be the answer.
+    captures.sort_by_key(|capture| std::cmp::Reverse(capture.priority));
+
+    Ok((cache.revision, captures, node_chain))
+}
+
+/// Grammar node kind -> text-object class, per language.
+///
+/// A table and not a name heuristic: `block` is a lexical block in Rust and a
+/// mapping entry in other grammars, `class` is a JavaScript class expression
+/// and a C++ storage specifier. A text object that matches the wrong node
+/// silently edits the wrong text, which is far worse than not answering.
+fn scope_table(lang: &str) -> &'static [(&'static str, &'static str)] {
+    match lang {
+        "rust" => &[
+            ("function_item", "function"),
+            ("function_signature_item", "function"),
+            ("closure_expression", "function"),
+            ("struct_item", "class"),
+            ("enum_item", "class"),
+            ("union_item", "class"),
+            ("trait_item", "class"),
+            ("impl_item", "class"),
+            ("mod_item", "class"),
+            ("block", "block"),
+            ("call_expression", "call"),
+            ("macro_invocation", "call"),
+            ("if_expression", "conditional"),
+            ("match_expression", "conditional"),
+            ("for_expression", "loop"),
+            ("while_expression", "loop"),
+            ("loop_expression", "loop"),
+        ],
+        "c" | "cpp" => &[
+            ("function_definition", "function"),
+            ("lambda_expression", "function"),
+            ("struct_specifier", "class"),
+            ("union_specifier", "class"),
+            ("enum_specifier", "class"),
+            ("class_specifier", "class"),
+            ("namespace_definition", "class"),
+            ("compound_statement", "block"),
+            ("call_expression", "call"),
+            ("if_statement", "conditional"),
+            ("switch_statement", "conditional"),
+            ("for_statement", "loop"),
+            ("for_range_loop", "loop"),
+            ("while_statement", "loop"),
+            ("do_statement", "loop"),
+        ],
+        // One table for the whole JavaScript family: the TypeScript-only kinds
+        // simply never appear in a JavaScript tree, so splitting it would only
+        // create two places to forget an entry.
+        "javascript" | "typescript" | "tsx" => &[
+            ("function_declaration", "function"),
+            ("function_expression", "function"),
+            ("generator_function", "function"),
+            ("generator_function_declaration", "function"),
+            ("arrow_function", "function"),
+            ("method_definition", "function"),
+            ("class_declaration", "class"),
+            ("class", "class"),
+            ("abstract_class_declaration", "class"),
+            ("interface_declaration", "class"),
+            ("enum_declaration", "class"),
+            ("type_alias_declaration", "class"),
+            ("internal_module", "class"),
+            ("statement_block", "block"),
+            ("call_expression", "call"),
+            ("new_expression", "call"),
+            ("if_statement", "conditional"),
+            ("switch_statement", "conditional"),
+            ("ternary_expression", "conditional"),
+            ("for_statement", "loop"),
+            ("for_in_statement", "loop"),
+            ("while_statement", "loop"),
+            ("do_statement", "loop"),
+        ],
+        "python" => &[
+            ("function_definition", "function"),
+            ("lambda", "function"),
+            ("class_definition", "class"),
+            ("block", "block"),
+            ("call", "call"),
+            ("if_statement", "conditional"),
+            ("match_statement", "conditional"),
+            ("for_statement", "loop"),
+            ("while_statement", "loop"),
+        ],
+        "go" => &[
+            ("function_declaration", "function"),
+            ("method_declaration", "function"),
+            ("func_literal", "function"),
+            ("type_declaration", "class"),
+            ("type_spec", "class"),
+            ("block", "block"),
+            ("call_expression", "call"),
+            ("if_statement", "conditional"),
+            ("expression_switch_statement", "conditional"),
+            ("type_switch_statement", "conditional"),
+            ("for_statement", "loop"),
+        ],
+        "bash" => &[
+            ("function_definition", "function"),
+            ("compound_statement", "block"),
+            ("command", "call"),
+            ("if_statement", "conditional"),
+            ("case_statement", "conditional"),
+            ("for_statement", "loop"),
+            ("while_statement", "loop"),
+        ],
+        "lua" => &[
+            ("function_declaration", "function"),
+            ("function_definition", "function"),
+            ("block", "block"),
+            ("function_call", "call"),
+            ("if_statement", "conditional"),
+            ("for_statement", "loop"),
+            ("while_statement", "loop"),
+            ("repeat_statement", "loop"),
+        ],
+        "vim" => &[
+            ("def_function", "function"),
+            ("function_definition", "function"),
+            ("if_statement", "conditional"),
+            ("for_statement", "loop"),
+            ("while_statement", "loop"),
+        ],
+        "julia" => &[
+            ("function_definition", "function"),
+            ("macro_definition", "function"),
+            ("struct_definition", "class"),
+            ("module_definition", "class"),
+            ("compound_statement", "block"),
+            ("call_expression", "call"),
+            ("if_statement", "conditional"),
+            ("try_statement", "conditional"),
+            ("for_statement", "loop"),
+            ("while_statement", "loop"),
+        ],
+        "haskell" => &[
+            ("function", "function"),
+            ("lambda", "function"),
+            ("class", "class"),
+            ("data_type", "class"),
+            ("newtype", "class"),
+            ("apply", "call"),
+            ("conditional", "conditional"),
+            ("case", "conditional"),
+            ("do", "block"),
+        ],
+        "css" => &[("rule_set", "class"), ("block", "block")],
+        _ if is_dynamic_grammar(lang) => GENERIC_SCOPE_TABLE,
+        _ => &[],
+    }
+}
+
+/// Text-object classes for a `load_grammar` grammar. Only kinds whose name
+/// means the same construct wherever tree-sitter grammars use it; `block` is
+/// left out for the reason given on `scope_table`.
+const GENERIC_SCOPE_TABLE: &[(&str, &str)] = &[
+    ("function_definition", "function"),
+    ("function_declaration", "function"),
+    ("method_definition", "function"),
+    ("method_declaration", "function"),
+    ("class_definition", "class"),
+    ("class_declaration", "class"),
+    ("if_statement", "conditional"),
+    ("for_statement", "loop"),
+    ("while_statement", "loop"),
+    ("call_expression", "call"),
+    ("compound_statement", "block"),
+    ("statement_block", "block"),
+];
+
+/// True for nodes whose named children are individually addressable as
+/// parameters or arguments.
+///
+/// Classifying by the container and not by a per-language list of parameter
+/// node kinds, because a JavaScript parameter is a bare `identifier` — a kind
+/// that means something else in every other position in the tree.
+fn is_parameter_container(kind: &str) -> bool {
+    matches!(
+        kind,
+        "parameters"
+            | "formal_parameters"
+            | "parameter_list"
+            | "lambda_parameters"
+            | "type_parameters"
+            | "type_parameter_list"
+            | "arguments"
+            | "argument_list"
+            | "type_arguments"
+    )
+}
+
+fn scope_kind(lang: &str, node: tree_sitter::Node) -> Option<&'static str> {
+    let kind = node.kind();
+    // Spelled the same in every bundled grammar, so it does not earn a row in
+    // seventeen tables.
+    if matches!(kind, "comment" | "line_comment" | "block_comment") {
+        return Some("comment");
+    }
+    if let Some((_, class)) = scope_table(lang).iter().find(|(k, _)| *k == kind) {
+        return Some(class);
+    }
+    // Reached only when the node has no class of its own, which is what keeps a
+    // closure argument answering to `af` rather than to `aa`.
+    if node.is_named()
+        && let Some(parent) = node.parent()
+        && is_parameter_container(parent.kind())
+    {
+        return Some("parameter");
+    }
+    None
+}
+
+/// Byte range of the "inner" half of a text object: the node's body with its
+/// delimiters and the whitespace they left behind removed.
+///
+/// `consequence` and `arguments` are listed beside `body` because a conditional
+/// and a call keep their interesting child in a differently named field, and
+/// falling back to the node itself is what makes a delimiter-only node such as
+/// a Rust `block` still produce a useful inner range.
+fn inner_byte_range(node: tree_sitter::Node, text: &str) -> (usize, usize) {
+    let target = ["body", "consequence", "arguments"]
+        .into_iter()
+        .find_map(|field| node.child_by_field_name(field))
+        .unwrap_or(node);
+    let mut start = target.start_byte();
+    let mut end = target.end_byte();
+    let count = target.child_count().min(u32::MAX as usize);
+    if count >= 2
+        && let (Some(first), Some(last)) = (target.child(0), target.child(count as u32 - 1))
+        && !first.is_named()
+        && !last.is_named()
+        && matches!(first.kind(), "{" | "(" | "[")
+        && matches!(last.kind(), "}" | ")" | "]")
+    {
+        start = first.end_byte();
+        end = last.start_byte();
+    }
+    // Without this, `dif` on a function body deletes the newline the opening
+    // brace sat on and leaves the closing brace hanging off the signature.
+    let bytes = text.as_bytes();
+    while start < end && bytes[start].is_ascii_whitespace() {
+        start += 1;
+    }
+    while end > start && bytes[end - 1].is_ascii_whitespace() {
+        end -= 1;
+    }
+    (start, end)
+}
+
+/// Outer range of a parameter or argument: the node plus the separator that
+/// would otherwise be left behind.
+///
+/// `daa` on the middle argument of `f(a, b, c)` has to leave `f(a, c)` and not
+/// `f(a, , c)`; on the last one there is no following comma, so the preceding
+/// one is taken instead.
+fn parameter_outer_byte_range(node: tree_sitter::Node, text: &str) -> (usize, usize) {
+    let start = node.start_byte();
+    let end = node.end_byte();
+    let mut sibling = node.next_sibling();
+    while let Some(next) = sibling {
+        if next.is_named() {
+            break;
+        }
+        if next.kind() == "," {
+            // Whitespace is not in the tree, so swallow the run after the comma
+            // by hand or `f(a, b)` loses `a,` and keeps its space.
+            let mut after = next.end_byte();
+            let bytes = text.as_bytes();
+            while after < text.len() && (bytes[after] == b' ' || bytes[after] == b'\t') {
+                after += 1;
+            }
+            return (start, after);
+        }
+        sibling = next.next_sibling();
+    }
+    let mut previous = node.prev_sibling();
+    while let Some(prior) = previous {
+        if prior.is_named() {
+            break;
+        }
+        if prior.kind() == "," {
+            return (prior.start_byte(), end);
+        }
+        previous = prior.prev_sibling();
+    }
+    (start, end)
+}
+
+fn scope_node(
+    node: tree_sitter::Node,
+    cache: &BufCache,
+    injected: Option<&'static str>,
+) -> ScopeNode {
+    let kind = scope_kind(injected.unwrap_or(&cache.lang), node);
+    let is_parameter = kind == Some("parameter");
+    // The common case is the node's own range, and the node already knows its
+    // points; only the parameter form has to be located in the text.
+    let (outer_sp, outer_ep) = if is_parameter {
+        let (start, end) = parameter_outer_byte_range(node, &cache.text);
+        (
+            cache.line_index.point_at_byte(&cache.text, start),
+            cache.line_index.point_at_byte(&cache.text, end),
+        )
+    } else {
+        (node.start_position(), node.end_position())
+    };
+    // A parameter has no body to reach into, so its inner half is the node
+    // itself — that is exactly the difference `daa` vs `dia` is asked to make.
+    let (inner_start, inner_end) = if is_parameter {
+        (node.start_byte(), node.end_byte())
+    } else {
+        inner_byte_range(node, &cache.text)
+    };
+    let inner_sp = cache.line_index.point_at_byte(&cache.text, inner_start);
+    let inner_ep = cache.line_index.point_at_byte(&cache.text, inner_end);
+    ScopeNode {
+        node: node.kind(),
+        kind,
+        lnum: outer_sp.row as u32 + 1,
+        col: outer_sp.column as u32 + 1,
+        end_lnum: outer_ep.row as u32 + 1,
+        end_col: outer_ep.column as u32 + 1,
+        inner_lnum: inner_sp.row as u32 + 1,
+        inner_col: inner_sp.column as u32 + 1,
+        inner_end_lnum: inner_ep.row as u32 + 1,
+        inner_end_col: inner_ep.column as u32 + 1,
+        lang: injected,
+    }
+}
+
+/// One `references` answer.
+#[derive(Debug)]
+struct ReferencesAnswer {
+    revision: u64,
+    name: String,
+    kind: Option<&'static str>,
+    references: Vec<Reference>,
+}
+
+/// One `definition` answer.
+#[derive(Debug)]
+struct DefinitionAnswer {
+    revision: u64,
+    name: String,
+    definition: Option<DefinitionSite>,
+}
+
+/// One `rename` answer.
+#[derive(Debug)]
+struct RenameAnswer {
+    revision: u64,
+    edits: Vec<RenameEdit>,
+}
+
+/// One `scope` answer.
+struct ScopeAnswer {
+    revision: u64,
+    /// 1-based `[lnum, col, end_lnum, end_col]` of the region in which every
+    /// point resolves to the same node and therefore to this same chain.
+    anchor: [u32; 4],
+    chain: Vec<ScopeNode>,
+}
+
+/// Byte range around `offset` in which `descendant_for_byte_range` keeps
+/// answering `node`.
+///
+/// This is *not* the node's own range: at a column of leading indentation the
+/// resolved node is the whole enclosing block, and a client that cached the
+/// block's range would keep serving that coarse chain after the cursor moved
+/// onto a statement inside it. `descendant_for_byte_range` returns the smallest
+/// node containing the point, so no child of `node` covers `offset` and the
+/// answer holds exactly across the gap between its neighbouring children.
+fn stable_byte_range(node: tree_sitter::Node, offset: usize) -> (usize, usize) {
+    let mut low = node.start_byte();
+    let mut high = node.end_byte();
+    let mut cursor = node.walk();
+    if cursor.goto_first_child() {
+        loop {
+            let child = cursor.node();
+            if child.end_byte() <= offset {
+                low = low.max(child.end_byte());
+            } else if child.start_byte() > offset {
+                high = high.min(child.start_byte());
+                break;
+            } else {
+                // A child covering the point should have been the descendant.
+                // Zero-width error/missing nodes can still land here; refuse to
+                // let the client cache anything rather than guess.
+                return (offset, offset);
+            }
+            if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+    (low, high)
+}
+
+/// Root, injected language and bounding range of one tree a scope chain walks.
+type ScopeTree<'a> = (
+    tree_sitter::Node<'a>,
+    Option<&'static str>,
+    Option<ops::Range<usize>>,
+);
+
+/// Trees the chain at `offset` runs through, innermost first: every injected
+/// tree with a range holding the point, deepest level first, then the host.
+/// Each injected tree comes with the included range its walk must stay in —
+/// one rust tree spans every fence of a document, and its root is no scope of
+/// the fence the cursor is in.
+fn scope_trees(cache: &BufCache, offset: usize) -> Vec<ScopeTree<'_>> {
+    let mut trees = Vec::new();
+    if cache
+        .injected_ranges
+        .iter()
+        .any(|range| range.start <= offset && offset < range.end)
+    {
+        let mut injected: Vec<_> = cache
+            .injections
+            .iter()
+            .filter_map(|injected| {
+                let range = injected
+                    .ranges
+                    .iter()
+                    .find(|range| range.start_byte <= offset && offset < range.end_byte)?;
+                Some((injected, range.start_byte..range.end_byte))
+            })
+            .collect();
+        injected.sort_by_key(|(injected, _)| std::cmp::Reverse(injected.depth));
+        for (injected, range) in injected {
+            trees.push((injected.tree.root_node(), Some(injected.lang), Some(range)));
+        }
+    }
+    trees.push((cache.tree.root_node(), None, None));
+    trees
+}
+
+fn scope_chain_cached(
+    server: &Server,
+    buf: i64,
+    lang: &str,
+    lnum: u32,
+    col: u32,
+) -> Result<ScopeAnswer> {
+    let cache = server.get_cache(buf, lang)?;
+    let offset = point_byte_offset(cache, lnum, col);
+    let trees = scope_trees(cache, offset);
+    let (innermost, _, bound) = &trees[0];
+    let resolved = innermost.descendant_for_byte_range(offset, offset);
+    let anchor = match resolved {
+        Some(node) => {
+            let (mut low, mut high) = stable_byte_range(node, offset);
+            // Between two statements of a fence the injected root answers, and
+            // its gap would run on into the host text around the fence.
+            if let Some(bound) = bound {
+                low = low.max(bound.start);
+                high = high.min(bound.end);
+            }
+            let start = cache.line_index.point_at_byte(&cache.text, low);
+            let end = cache.line_index.point_at_byte(&cache.text, high);
+            [
+                start.row as u32 + 1,
+                start.column as u32 + 1,
+                end.row as u32 + 1,
+                end.column as u32 + 1,
+            ]
+        }
+        // No node at all: an empty range, so a client can only ever treat the
+        // reply as immediately stale rather than caching it forever.
+        None => [lnum, col, lnum, col],
+    };
+    let mut chain: Vec<ScopeNode> = Vec::new();
+    'trees: for (root, injected, bound) in &trees {
+        let mut current = root.descendant_for_byte_range(offset, offset);
+        while let Some(node) = current {
+            if let Some(bound) = bound
+                && (node.start_byte() < bound.start || node.end_byte() > bound.end)
+            {
+                // Past the embedded code: the enclosing tree takes over.
+                break;
+            }
+            // Anonymous nodes are punctuation: never a text object, and never a
+            // step a user would recognise while growing a selection.
+            if node.is_named() {
+                chain.push(scope_node(node, cache, *injected));
+                if chain.len() >= MAX_SCOPE_CHAIN {
+                    break 'trees;
+                }
+            }
+            current = node.parent();
+        }
+    }
+    Ok(ScopeAnswer {
+        revision: cache.revision,
+        anchor,
+        chain,
+    })
+}
+
+/// Locals query for `lang`, or None where resolving names has not been taught.
+/// Kept out of `lang_info` because most filetypes here have no bindings worth
+/// resolving: JSON keys and CSS properties are not variables.
+fn locals_query_source(lang: &str) -> Option<&'static str> {
+    Some(match lang {
+        "rust" => queries::RUST_LOCALS_QUERY,
+        "javascript" => queries::JS_LOCALS_QUERY,
+        "typescript" | "tsx" => queries::TS_LOCALS_QUERY,
+        "python" => queries::PYTHON_LOCALS_QUERY,
+        "go" => queries::GO_LOCALS_QUERY,
+        "c" => queries::C_LOCALS_QUERY,
+        "cpp" => queries::CPP_LOCALS_QUERY,
+        _ => return dynamic_grammar(lang)?.locals,
+    })
+}
+
+/// Whether a second `let x` in the same scope starts a new binding. In Rust it
+/// does, and `let x = x + 1` reads the old one; elsewhere a repeated name in one
+/// scope is the same variable assigned again.
+fn locals_rebind_in_scope(lang: &str) -> bool {
+    lang == "rust"
+}
+
+/// Kind reported for a `@local.definition.<kind>` capture. Unknown suffixes and
+/// the bare `@local.definition` read as plain variables.
+fn local_definition_kind(capture: &str) -> &'static str {
+    match capture.strip_prefix("local.definition.").unwrap_or("var") {
+        "parameter" => "parameter",
+        "function" => "function",
+        "method" => "method",
+        "type" => "type",
+        "field" => "field",
+        "import" => "import",
+        "namespace" => "namespace",
+        "constant" => "constant",
+        "macro" => "macro",
+        _ => "var",
+    }
+}
+
+/// (parent kind, field) pairs whose identifier is the tail of a qualified path
+/// or a keyword name: `a::b`, `obj.attr`, `f(key=1)`. Such a name is looked up
+/// in something other than the lexical scope, so it never refers to a local.
+const QUALIFIED_NAME_FIELDS: &[(&str, &str)] = &[
+    ("scoped_identifier", "name"),
+    ("scoped_type_identifier", "name"),
+    ("qualified_identifier", "name"),
+    ("attribute", "attribute"),
+    ("keyword_argument", "name"),
+];
+
+fn is_qualified_name(node: tree_sitter::Node) -> bool {
+    let Some(parent) = node.parent() else {
+        return false;
+    };
+    QUALIFIED_NAME_FIELDS.iter().any(|(kind, field)| {
+        parent.kind() == *kind && parent.child_by_field_name(field) == Some(node)
+    })
+}
+
+struct LocalScope {
+    start: usize,
+    end: usize,
+    parent: Option<usize>,
+}
+
+struct LocalDefinition {
+    range: tree_sitter::Range,
+    kind: &'static str,
+    /// First byte at which a rebinding `var` is in effect: the end of the
+    /// statement that introduced it, so the initializer still sees the old one.
+    visible_from: usize,
+    /// Index of the definition that stands for the whole binding.
+    binding: usize,
+}
+
+struct LocalReference {
+    range: tree_sitter::Range,
+    binding: Option<usize>,
+}
+
+/// The definitions and references of one name, each resolved through the
+/// scopes of the locals query.
+struct LocalsIndex {
+    definitions: Vec<LocalDefinition>,
+    references: Vec<LocalReference>,
+}
+
+/// Run the locals query over the whole tree and resolve every occurrence of
+/// `name`. Scopes are collected in full; definitions and references of other
+/// names are dropped before resolution, which is where the time would go.
+fn build_locals_index(
+    lang: &str,
+    (query, predicates): &(tree_sitter::Query, QueryPredicates),
+    tree: &tree_sitter::Tree,
+    text: &str,
+    name: &str,
+) -> LocalsIndex {
+    use std::collections::HashSet;
+    let root = tree.root_node();
+    let bytes = text.as_bytes();
+    let mut scope_nodes: Vec<(usize, usize)> = vec![(0, text.len())];
+    let mut seen_scopes = HashSet::new();
+    let mut def_nodes: Vec<(tree_sitter::Node, &'static str, bool)> = Vec::new();
+    let mut def_ids: HashMap<usize, usize> = HashMap::new();
+    let mut ref_nodes: Vec<tree_sitter::Node> = Vec::new();
+    let mut seen_refs = HashSet::new();
+
+    let mut cursor = tree_sitter::QueryCursor::new();
+    let mut matches = cursor.matches(query, root, bytes);
+    while let Some(m) = matches.next() {
+        if !predicates.accepts(m, bytes) {
+            continue;
+        }
+        for cap in m.captures {
+            let node = cap.node;
+            let cname = query.capture_names()[cap.index as usize];
+            if cname == "local.scope" {
+                if seen_scopes.insert(node.id()) {
+                    scope_nodes.push((node.start_byte(), node.end_byte()));
+                }
+                continue;
+            }
+            if text.get(node.start_byte()..node.end_byte()) != Some(name) {
+                continue;
+            }
+            if cname == "local.reference" {
+                if seen_refs.insert(node.id()) {
+                    ref_nodes.push(node);
+                }
+            } else if cname.starts_with("local.definition") {
+                let hoist = query
+                    .property_settings(m.pattern_index)
+                    .iter()
+                    .any(|p| p.key.ends_with(".scope") && p.value.as_deref() == Some("parent"));
+                match def_ids.get(&node.id()) {
+                    Some(&index) => def_nodes[index].2 |= hoist,
+                    None => {
+                        def_ids.insert(node.id(), def_nodes.len());
+                        def_nodes.push((node, local_definition_kind(cname), hoist));
+                    }
+                }
+            }
+        }
+    }
+
+    // Nodes nest properly, so sorting outer-first by start lets one stack pass
+    // link every scope to its parent.
+    scope_nodes.sort_by_key(|&(start, end)| (start, std::cmp::Reverse(end)));
+    let mut scopes: Vec<LocalScope> = Vec::with_capacity(scope_nodes.len());
+    let mut stack: Vec<usize> = Vec::new();
+    for (start, end) in scope_nodes {
+        while let Some(&top) = stack.last() {
+            if scopes[top].end >= end && scopes[top].start <= start {
+                break;
+            }
+            stack.pop();
+        }
+        stack.push(scopes.len());
+        scopes.push(LocalScope {
+            start,
+            end,
+            parent: stack.iter().rev().nth(1).copied(),
+        });
+    }
+    let innermost = |start: usize, end: usize| -> usize {
+        let mut index = scopes
+            .partition_point(|s| s.start <= start)
+            .saturating_sub(1);
+        while scopes[index].end < end {
+            match scopes[index].parent {
+                Some(parent) => index = parent,
+                None => break,
+            }
+        }
+        index
+    };
+
+    let rebind = locals_rebind_in_scope(lang);
+    def_nodes.sort_by_key(|(node, _, _)| node.start_byte());
+    let mut definitions: Vec<LocalDefinition> = Vec::with_capacity(def_nodes.len());
+    let mut by_scope: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (node, kind, hoist) in &def_nodes {
+        let mut scope = innermost(node.start_byte(), node.end_byte());
+        if *hoist && let Some(parent) = scopes[scope].parent {
+            scope = parent;
+        }
+        let mut statement = *node;
+        while let Some(parent) = statement.parent() {
+            if parent.start_byte() <= scopes[scope].start && parent.end_byte() >= scopes[scope].end
+            {
+                break;
+            }
+            statement = parent;
+        }
+        let index = definitions.len();
+        let same_scope = by_scope.entry(scope).or_default();
+        let binding = match same_scope.first() {
+            Some(&first) if !rebind => definitions[first].binding,
+            _ => index,
+        };
+        same_scope.push(index);
+        definitions.push(LocalDefinition {
+            range: node.range(),
+            kind,
+            visible_from: statement.end_byte(),
+            binding,
+        });
+    }
+
+    let mut references = Vec::with_capacity(ref_nodes.len());
+    for node in ref_nodes {
+        if def_ids.contains_key(&node.id()) || is_qualified_name(node) {
+            continue;
+        }
+        let at = node.start_byte();
+        let mut scope = Some(innermost(at, node.end_byte()));
+        let mut binding = None;
+        while let Some(current) = scope {
+            if let Some(candidates) = by_scope.get(&current) {
+                binding = candidates
+                    .iter()
+                    .rev()
+                    .map(|&index| &definitions[index])
+                    .find(|def| !rebind || def.kind != "var" || at >= def.visible_from)
+                    .map(|def| def.binding);
+                if binding.is_some() {
+                    break;
+                }
+            }
+            scope = scopes[current].parent;
+        }
+        references.push(LocalReference {
+            range: node.range(),
+            binding,
+        });
+    }
+    references.sort_by_key(|reference| reference.range.start_byte);
+    LocalsIndex {
+        definitions,
+        references,
+    }
+}
+
+/// Leaf token under the cursor that could be a name; punctuation and
+/// zero-width nodes are not.
+fn name_node_at(cache: &BufCache, lnum: u32, col: u32) -> Option<tree_sitter::Node<'_>> {
+    let offset = point_byte_offset(cache, lnum, col);
+    cache
+        .tree
+        .root_node()
+        .named_descendant_for_byte_range(offset, offset)
+        .filter(|node| node.child_count() == 0 && node.start_byte() < node.end_byte())
+}
+
+/// Binding of the occurrence starting at `start`: `None` when no definition or
+/// reference starts there, `Some(None)` for a reference that resolves nowhere.
+fn locals_binding_at(index: &LocalsIndex, start: usize) -> Option<Option<usize>> {
+    if let Some(def) = index
+        .definitions
+        .iter()
+        .find(|def| def.range.start_byte == start)
+    {
+        return Some(Some(def.binding));
+    }
+    index
+        .references
+        .iter()
+        .find(|reference| reference.range.start_byte == start)
+        .map(|reference| reference.binding)
+}
+
+/// Definition/reference occurrence under the cursor, resolved to its binding.
+/// `Err` carries the reason there is nothing to resolve, worded for the user.
+fn locals_target<'a>(
+    server: &'a Server,
+    buf: i64,
+    lang: &str,
+    lnum: u32,
+    col: u32,
+) -> Result<(&'a BufCache, String, LocalsIndex, Option<usize>)> {
+    let cache = server.get_cache(buf, lang)?;
+    let query = server
+        .queries
+        .get(&cache.lang)
+        .and_then(|queries| queries.locals_query.as_ref())
+        .ok_or_else(|| anyhow!("no locals query for {lang}"))?;
+    let node = name_node_at(cache, lnum, col).ok_or_else(|| anyhow!("no name at {lnum}:{col}"))?;
+    let name = cache.text[node.start_byte()..node.end_byte()].to_string();
+    let index = build_locals_index(&cache.lang, query, &cache.tree, &cache.text, &name);
+    let binding = locals_binding_at(&index, node.start_byte())
+        .ok_or_else(|| anyhow!("no name at {lnum}:{col}"))?;
+    Ok((cache, name, index, binding))
+}
+
+/// Where the name under the cursor is bound. A local binding wins; a name the
+/// locals query cannot resolve — a global, a method called through a field, any
+/// name in a language without a locals query — falls back to the buffer's
+/// symbols of that name, top-level ones first.
+fn run_definition_cached(
+    server: &mut Server,
+    buf: i64,
+    lang: &str,
+    lnum: u32,
+    col: u32,
+) -> Result<DefinitionAnswer> {
+    let (revision, name, local) = {
+        let cache = server.get_cache(buf, lang)?;
+        let node =
+            name_node_at(cache, lnum, col).ok_or_else(|| anyhow!("no name at {lnum}:{col}"))?;
+        let name = cache.text[node.start_byte()..node.end_byte()].to_string();
+        let local = server
+            .queries
+            .get(&cache.lang)
+            .and_then(|queries| queries.locals_query.as_ref())
+            .and_then(|query| {
+                let index = build_locals_index(&cache.lang, query, &cache.tree, &cache.text, &name);
+                let def = &index.definitions[locals_binding_at(&index, node.start_byte())??];
+                Some(DefinitionSite {
+                    lnum: def.range.start_point.row as u32 + 1,
+                    col: def.range.start_point.column as u32 + 1,
+                    end_lnum: def.range.end_point.row as u32 + 1,
+                    end_col: def.range.end_point.column as u32 + 1,
+                    kind: def.kind,
+                    source: "local",
+                })
+            });
+        (cache.revision, name, local)
+    };
+    if local.is_some() {
+        return Ok(DefinitionAnswer {
+            revision,
+            name,
+            definition: local,
+        });
+    }
+    let (_, symbols) = run_symbols_cached_filtered(server, buf, lang, None, None, &[])?;
+    let definition = symbols
+        .iter()
+        .filter(|symbol| symbol.name == name)
+        .min_by_key(|symbol| symbol.container_kind.is_some())
+        .map(|symbol| DefinitionSite {
+            lnum: symbol.lnum,
+            col: symbol.col,
+            end_lnum: symbol.end_lnum,
+            end_col: symbol.end_col,
+            kind: symbol.kind,
+            source: "symbol",
+        });
+    Ok(DefinitionAnswer {
+        revision,
+        name,
+        definition,
+    })
+}
+
+/// Definitions (flagged true) and references of `binding`, in source order.
+/// `None` selects the references that resolve nowhere.
+fn binding_occurrences(
+    index: &LocalsIndex,
+    binding: Option<usize>,
+) -> Vec<(tree_sitter::Range, bool)> {
+    let mut occurrences: Vec<(tree_sitter::Range, bool)> = Vec::new();
+    if let Some(binding) = binding {
+        occurrences.extend(
+            index
+                .definitions
+                .iter()
+                .filter(|def| def.binding == binding)
+                .map(|def| (def.range, true)),
+        );
+    }
+    occurrences.extend(
+        index
+            .references
+            .iter()
+            .filter(|reference| reference.binding == binding)
+            .map(|reference| (reference.range, false)),
+    );
+    occurrences.sort_by_key(|(range, _)| range.start_byte);
+    occurrences
+}
+
+/// Every occurrence of the binding under the cursor. A name that resolves to
+/// no definition in this buffer (a global, a builtin, an import from a file the
+/// query cannot see) is matched against the other unresolved uses of it.
+fn run_references_cached(
+    server: &Server,
+    buf: i64,
+    lang: &str,
+    lnum: u32,
+    col: u32,
+    max_items: Option<usize>,
+) -> Result<ReferencesAnswer> {
+    let (cache, name, index, binding) = locals_target(server, buf, lang, lnum, col)?;
+    let limit = max_items.unwrap_or(MAX_REFERENCES).min(MAX_REFERENCES);
+    let mut occurrences = binding_occurrences(&index, binding);
+    occurrences.truncate(limit);
+    Ok(ReferencesAnswer {
+        revision: cache.revision,
+        name,
+        kind: binding.map(|binding| index.definitions[binding].kind),
+        references: occurrences
+            .into_iter()
+            .map(|(range, definition)| Reference {
+                lnum: range.start_point.row as u32 + 1,
+                col: range.start_point.column as u32 + 1,
+                end_lnum: range.end_point.row as u32 + 1,
+                end_col: range.end_point.column as u32 + 1,
+                definition,
+            })
+            .collect(),
+    })
+}
+
+/// Words `is_valid_identifier` refuses even though they are shaped like names,
+/// one string per language to keep the table readable.
+fn reserved_words(lang: &str) -> &'static str {
+    match lang {
+        "rust" => {
+            "as async await break const continue crate dyn else enum extern false fn for if \
+             impl in let loop match mod move mut pub ref return self Self static struct super \
+             trait true type unsafe use where while yield _"
+        }
+        "javascript" | "typescript" | "tsx" => {
+            "await break case catch class const continue debugger default delete do else enum \
+             export extends false finally for function if import in instanceof let new null \
+             return super switch this throw true try typeof var void while with yield"
+        }
+        "python" => {
+            "False None True and as assert async await break class continue def del elif else \
+             except finally for from global if import in is lambda nonlocal not or pass raise \
+             return try while with yield"
+        }
+        "go" => {
+            "break case chan const continue default defer else fallthrough for func go goto if \
+             import interface map package range return select struct switch type var"
+        }
+        "c" | "cpp" => {
+            "auto break case char class const continue default delete do double else enum \
+             extern float for goto if inline int long namespace new private protected public \
+             register return short signed sizeof static struct switch template this typedef \
+             union unsigned virtual void volatile while"
+        }
+        _ => "",
+    }
+}
+
+/// Whether `name` could be written where the renamed identifier stands. C and
+/// C++ take ASCII only; JavaScript adds `$`; everyone else takes Unicode
+/// letters, which is looser than the exact XID rules and strict enough to keep
+/// punctuation and whitespace out of the buffer.
+fn is_valid_identifier(lang: &str, name: &str) -> bool {
+    let ascii = matches!(lang, "c" | "cpp");
+    let dollar = matches!(lang, "javascript" | "typescript" | "tsx");
+    let start = |c: char| {
+        c == '_' || (dollar && c == '$') || (c.is_alphabetic() && (!ascii || c.is_ascii()))
+    };
+    let mut chars = name.chars();
+    chars.next().is_some_and(start)
+        && chars.all(|c| start(c) || (c.is_numeric() && (!ascii || c.is_ascii_digit())))
+        && !reserved_words(lang)
+            .split_ascii_whitespace()
+            .any(|word| word == name)
+}
+
+/// Edits that rename the binding under the cursor to `new_name`.
+///
+/// The result is checked, not trusted: the renamed text is parsed again and the
+/// binding must come out with exactly the occurrences that were edited. Fewer
+/// means a use now resolves to some other `new_name` in between; more means an
+/// existing `new_name` got captured or, in a language without rebinding, merged
+/// with the renamed one. Either way the client would silently change meaning,
+/// so the request is refused instead.
+fn run_rename_cached(
+    server: &mut Server,
+    buf: i64,
+    lang: &str,
+    lnum: u32,
+    col: u32,
+    new_name: &str,
+) -> Result<RenameAnswer> {
+    let (revision, old_name, starts, renamed) = {
+        let (cache, name, index, binding) = locals_target(server, buf, lang, lnum, col)?;
+        if !is_valid_identifier(&cache.lang, new_name) {
+            return Err(anyhow!("`{new_name}` is not a valid {lang} identifier"));
+        }
+        let Some(binding) = binding else {
+            return Err(anyhow!("`{name}` is not defined in this buffer"));
+        };
+        let occurrences = binding_occurrences(&index, Some(binding));
+        if occurrences.len() > MAX_REFERENCES {
+            return Err(anyhow!(
+                "`{name}` has more than {MAX_REFERENCES} occurrences"
+            ));
+        }
+        let root = cache.tree.root_node();
+        for (range, _) in &occurrences {
+            // `S { x }` and `{ x }` name a field and a variable at once; renaming
+            // the token would rename the field too.
+            if root
+                .descendant_for_byte_range(range.start_byte, range.end_byte)
+                .is_some_and(|node| {
+                    node.kind().starts_with("shorthand_")
+                        || node
+                            .parent()
+                            .is_some_and(|parent| parent.kind().starts_with("shorthand_"))
+                })
+            {
+                return Err(anyhow!(
+                    "`{name}` is also a field name at {}:{}",
+                    range.start_point.row + 1,
+                    range.start_point.column + 1
+                ));
+            }
+        }
+        let mut renamed = String::with_capacity(cache.text.len());
+        let mut copied = 0;
+        let mut starts = Vec::with_capacity(occurrences.len());
+        for (range, _) in &occurrences {
+            renamed.push_str(&cache.text[copied..range.start_byte]);
+            starts.push((range.start_point, renamed.len()));
+            renamed.push_str(new_name);
+            copied = range.end_byte;
+        }
+        renamed.push_str(&cache.text[copied..]);
+        (cache.revision, name, starts, renamed)
+    };
 
-            // 压入子节点
-            let mut child_cursor = n.walk();
-            for ch in n.children(&mut child_cursor) {
-                stack.push(ch);
+    if new_name != old_name {
+        let language = server.queries[lang].language.clone();
+        let tree = server
+            .parser_for(lang, language)?
+            .parse(&renamed, None)
+            .ok_or_else(|| anyhow!("parse failed"))?;
+        let query = server.queries[lang].locals_query.as_ref().unwrap();
+        let index = build_locals_index(lang, query, &tree, &renamed, new_name);
+        let after = locals_binding_at(&index, starts[0].1)
+            .flatten()
+            .map(|binding| binding_occurrences(&index, Some(binding)))
+            .unwrap_or_default();
+        if !after
+            .iter()
+            .map(|(range, _)| range.start_byte)
+            .eq(starts.iter().map(|(_, start)| *start))
+        {
+            return Err(anyhow!(
+                "renaming `{old_name}` to `{new_name}` would change what a name refers to"
+            ));
+        }
+    }
+
+    Ok(RenameAnswer {
+        revision,
+        edits: starts
+            .into_iter()
+            .map(|(point, _)| RenameEdit {
+                lnum: point.row as u32 + 1,
+                col: point.column as u32 + 1,
+                end_col: (point.column + old_name.len()) as u32 + 1,
+                new_text: new_name.to_string(),
+            })
+            .collect(),
+    })
+}
+
+/// A grammar loaded at run time by `load_grammar`.
+///
+/// Everything here lives until the process exits: every tree and query built
+/// from `language` points into the library's tables, so the library is never
+/// unloaded and the query sources are leaked alongside it. A name is loaded
+/// once; asking again for the same library reloads its queries from the
+/// `query_dir` given, or is a no-op without one.
+#[derive(Clone)]
+struct DynamicGrammar {
+    name: &'static str,
+    language: tree_sitter::Language,
+    library: String,
+    highlights: &'static str,
+    symbols: &'static str,
+    locals: Option<&'static str>,
+    indents: Option<&'static str>,
+    folds: Option<&'static str>,
+    injections: Option<&'static str>,
+}
+
+impl DynamicGrammar {
+    fn query_names(&self) -> Vec<&'static str> {
+        [
+            ("highlights", !self.highlights.is_empty()),
+            ("symbols", !self.symbols.is_empty()),
+            ("locals", self.locals.is_some()),
+            ("indents", self.indents.is_some()),
+            ("folds", self.folds.is_some()),
+            ("injections", self.injections.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, present)| present.then_some(name))
+        .collect()
+    }
+}
+
+/// Process-wide, like the bundled grammars it extends: `Server::lang_info`
+/// and the per-language query tables are free functions.
+fn dynamic_grammars() -> &'static std::sync::Mutex<HashMap<String, DynamicGrammar>> {
+    static GRAMMARS: std::sync::OnceLock<std::sync::Mutex<HashMap<String, DynamicGrammar>>> =
+        std::sync::OnceLock::new();
+    GRAMMARS.get_or_init(Default::default)
+}
+
+fn dynamic_grammar(name: &str) -> Option<DynamicGrammar> {
+    dynamic_grammars()
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(name)
+        .cloned()
+}
+
+fn is_dynamic_grammar(name: &str) -> bool {
+    dynamic_grammars()
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .contains_key(name)
+}
+
+/// Load `tree_sitter_<name>` from the shared library at `library` and the
+/// query files in `query_dir`, and register the grammar as language `name`.
+///
+/// Every failure is an error rather than a crash: a missing file or symbol,
+/// a grammar generated for an ABI this tree-sitter cannot read, and queries
+/// that do not compile against it are all refused before anything is
+/// registered. Loading an already loaded grammar again with a `query_dir`
+/// replaces its queries with that directory's; the caller drops the queries
+/// it compiled from the old ones (`Server::forget_queries`).
+fn load_grammar(name: &str, library: &str, query_dir: Option<&str>) -> Result<DynamicGrammar> {
+    if name.is_empty()
+        || !name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+    {
+        return Err(anyhow!(
+            "grammar name `{name}` must be lowercase letters, digits and `_`"
+        ));
+    }
+    if SUPPORTED_LANGUAGES.contains(&name) || name == "markdown_inline" {
+        return Err(anyhow!("`{name}` is a bundled grammar"));
+    }
+    if let Some(loaded) = dynamic_grammar(name) {
+        if loaded.library != library {
+            return Err(anyhow!(
+                "grammar `{name}` is already loaded from {}",
+                loaded.library
+            ));
+        }
+        let Some(dir) = query_dir else {
+            return Ok(loaded);
+        };
+        let sources = read_grammar_queries(&loaded.language, dir)?;
+        return Ok(register_grammar(
+            loaded.name,
+            loaded.language,
+            library,
+            sources,
+        ));
+    }
+
+    // SAFETY: loading runs the library's initialisers; the caller vouches for
+    // the file by naming it, exactly as with a Vim plugin's binary.
+    let lib = unsafe { libloading::Library::new(library) }
+        .map_err(|error| anyhow!("grammar `{name}`: {error}"))?;
+    let symbol = format!("tree_sitter_{name}");
+    // SAFETY: `tree_sitter_<name>` is the entry point tree-sitter generates for
+    // every grammar, taking nothing and returning its `TSLanguage` table.
+    let language = unsafe {
+        let entry = lib
+            .get::<unsafe extern "C" fn() -> *const ()>(symbol.as_bytes())
+            .map_err(|error| anyhow!("grammar `{name}`: {error}"))?;
+        tree_sitter::Language::new(tree_sitter_language::LanguageFn::from_raw(*entry))
+    };
+    let abi = language.abi_version();
+    if !(tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION..=tree_sitter::LANGUAGE_VERSION)
+        .contains(&abi)
+    {
+        return Err(anyhow!(
+            "{library} was generated for tree-sitter ABI {abi}; this daemon reads {}..={}",
+            tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION,
+            tree_sitter::LANGUAGE_VERSION
+        ));
+    }
+    let sources = match query_dir {
+        Some(dir) => read_grammar_queries(&language, dir)?,
+        None => HashMap::new(),
+    };
+
+    std::mem::forget(lib);
+    Ok(register_grammar(
+        Box::leak(name.to_string().into_boxed_str()),
+        language,
+        library,
+        sources,
+    ))
+}
+
+/// The query files in `dir`, each checked to compile against `language`.
+fn read_grammar_queries(
+    language: &tree_sitter::Language,
+    dir: &str,
+) -> Result<HashMap<&'static str, String>> {
+    let mut sources = HashMap::new();
+    for &query in QUERY_OVERRIDE_FILES {
+        let path = std::path::Path::new(dir).join(format!("{query}.scm"));
+        match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                compile_query(language, &text)
+                    .map_err(|error| anyhow!("{}: {error}", path.display()))?;
+                sources.insert(query, text);
             }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(anyhow!("{}: {error}", path.display())),
         }
     }
+    Ok(sources)
+}
 
-    if cache.lang == "go" {
-        let type_positions: HashMap<String, (u32, u32)> = symbols
+/// Leak `sources` and register the grammar under `name`, replacing whatever
+/// was registered there.
+fn register_grammar(
+    name: &'static str,
+    language: tree_sitter::Language,
+    library: &str,
+    mut sources: HashMap<&'static str, String>,
+) -> DynamicGrammar {
+    let mut leak = |query| {
+        sources
+            .remove(query)
+            .map(|source: String| -> &'static str { Box::leak(source.into_boxed_str()) })
+    };
+    let grammar = DynamicGrammar {
+        name,
+        language,
+        library: library.to_string(),
+        highlights: leak("highlights").unwrap_or_default(),
+        symbols: leak("symbols").unwrap_or_default(),
+        locals: leak("locals"),
+        indents: leak("indents"),
+        folds: leak("folds"),
+        injections: leak("injections"),
+    };
+    dynamic_grammars()
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(name.to_string(), grammar.clone());
+    grammar
+}
+
+/// Query files a `set_query_paths` directory may hold for each language.
+const QUERY_OVERRIDE_FILES: &[&str] = &[
+    "highlights",
+    "symbols",
+    "locals",
+    "indents",
+    "folds",
+    "injections",
+];
+
+/// Grammar a query for `name` compiles against; `markdown_inline` is the one
+/// name that is not a buffer language.
+fn query_language(name: &str) -> Result<tree_sitter::Language> {
+    if name == "markdown_inline" {
+        return Ok(tree_sitter_md::INLINE_LANGUAGE.into());
+    }
+    Ok(Server::lang_info(name)?.0)
+}
+
+/// The query compiled into the binary for `lang`, if it has one.
+fn bundled_query_source(lang: &str, query: &str) -> Option<&'static str> {
+    match (lang, query) {
+        ("markdown_inline", "highlights") => Some(queries::MD_INLINE_QUERY),
+        ("markdown_inline", _) => None,
+        (_, "highlights") => Server::lang_info(lang).ok().map(|(_, hl, _)| hl),
+        (_, "symbols") => Server::lang_info(lang).ok().map(|(_, _, sym)| sym),
+        (_, "locals") => locals_query_source(lang),
+        (_, "indents") => indents_query_source(lang),
+        (_, "folds") => dynamic_grammar(lang)?.folds,
+        (_, "injections") => injections_query_source(lang),
+        _ => None,
+    }
+}
+
+/// Injections query for `lang`: which of its nodes hold another language.
+fn injections_query_source(lang: &str) -> Option<&'static str> {
+    Some(match lang {
+        "markdown" => queries::MD_INJECTIONS_QUERY,
+        "html" => queries::HTML_INJECTIONS_QUERY,
+        "javascript" | "typescript" | "tsx" => queries::JS_INJECTIONS_QUERY,
+        "python" => queries::PYTHON_INJECTIONS_QUERY,
+        "rust" => queries::RUST_INJECTIONS_QUERY,
+        "yaml" => queries::YAML_INJECTIONS_QUERY,
+        _ => return dynamic_grammar(lang)?.injections,
+    })
+}
+
+/// Indents query for `lang`. None for Haskell, which keeps its hand-written
+/// `indent/haskell.vim`: the offside rule indents by the column of the token
+/// that opened a layout block, which `@indent.*` captures cannot express.
+fn indents_query_source(lang: &str) -> Option<&'static str> {
+    Some(match lang {
+        "rust" => queries::RUST_INDENTS_QUERY,
+        "javascript" => queries::JS_INDENTS_QUERY,
+        "typescript" => queries::TS_INDENTS_QUERY,
+        "tsx" => queries::TSX_INDENTS_QUERY,
+        "python" => queries::PYTHON_INDENTS_QUERY,
+        "go" => queries::GO_INDENTS_QUERY,
+        "c" => queries::C_INDENTS_QUERY,
+        "cpp" => queries::CPP_INDENTS_QUERY,
+        "bash" | "sh" => queries::BASH_INDENTS_QUERY,
+        "json" => queries::JSON_INDENTS_QUERY,
+        "yaml" => queries::YAML_INDENTS_QUERY,
+        "toml" => queries::TOML_INDENTS_QUERY,
+        "html" => queries::HTML_INDENTS_QUERY,
+        "css" => queries::CSS_INDENTS_QUERY,
+        "lua" => queries::LUA_INDENTS_QUERY,
+        "julia" => queries::JULIA_INDENTS_QUERY,
+        "markdown" => queries::MD_INDENTS_QUERY,
+        "vim" => queries::VIM_INDENTS_QUERY,
+        _ => return dynamic_grammar(lang)?.indents,
+    })
+}
+
+/// Node ids per `@indent.*` capture.
+///
+/// - `begin`: lines inside the node, after its first line, get one level more.
+///   Only a node spanning several lines counts, unless its pattern sets
+///   `indent.immediate` (a Python `def` whose block is still empty).
+/// - `end`: a blank line right after this node indents like the code around
+///   the node, not like its inside.
+/// - `branch`: the node sits at its parent's level: the parent's `begin` or
+///   `align` does not apply to it or to anything inside it (`}`, `else:`, a Go
+///   `case`). A clause that also indents its own body is `begin` as well.
+/// - `dedent`: lines inside the node, after its first line, get one level less.
+/// - `align`: lines inside the node line up with the first token after its
+///   opening delimiter when one follows on the same line, else indent like
+///   `begin`.
+/// - `ignore`: lines starting inside the node keep their indent (-1).
+#[derive(Default)]
+struct IndentCaptures {
+    begin: std::collections::HashSet<usize>,
+    immediate: std::collections::HashSet<usize>,
+    end: std::collections::HashSet<usize>,
+    branch: std::collections::HashSet<usize>,
+    dedent: std::collections::HashSet<usize>,
+    align: std::collections::HashSet<usize>,
+    ignore: std::collections::HashSet<usize>,
+}
+
+fn indent_captures(
+    (query, predicates): &(tree_sitter::Query, QueryPredicates),
+    root: tree_sitter::Node,
+    text: &str,
+    range: ops::Range<usize>,
+) -> IndentCaptures {
+    let mut captures = IndentCaptures::default();
+    let mut cursor = tree_sitter::QueryCursor::new();
+    cursor.set_byte_range(range);
+    let mut matches = cursor.matches(query, root, text.as_bytes());
+    while let Some(m) = matches.next() {
+        if !predicates.accepts(m, text.as_bytes()) {
+            continue;
+        }
+        let immediate = query
+            .property_settings(m.pattern_index)
             .iter()
-            .filter(|symbol| symbol.kind == "type")
-            .map(|symbol| (symbol.name.clone(), (symbol.lnum, symbol.col)))
-            .collect();
-        for symbol in &mut symbols {
-            if symbol.container_kind == Some("type")
-                && let Some(name) = &symbol.container_name
-                && let Some((line, column)) = type_positions.get(name)
-            {
-                symbol.container_lnum = Some(*line);
-                symbol.container_col = Some(*column);
-            }
+            .any(|property| &*property.key == "indent.immediate");
+        for cap in m.captures {
+            let id = cap.node.id();
+            let set = match query.capture_names()[cap.index as usize] {
+                "indent.begin" => {
+                    if immediate {
+                        captures.immediate.insert(id);
+                    }
+                    &mut captures.begin
+                }
+                "indent.end" => &mut captures.end,
+                "indent.branch" => &mut captures.branch,
+                "indent.dedent" => &mut captures.dedent,
+                "indent.align" => &mut captures.align,
+                "indent.ignore" => &mut captures.ignore,
+                _ => continue,
+            };
+            set.insert(id);
         }
     }
+    captures
+}
 
-    symbols.sort_by_key(|s| (s.lnum, s.col));
-    Ok((cache.revision, symbols))
+/// Byte range of line `lnum` without its line break.
+fn line_bounds(cache: &BufCache, lnum: u32) -> ops::Range<usize> {
+    let start = cache.line_index.line_start_byte(&cache.text, lnum);
+    let mut end = cache
+        .line_index
+        .line_start_byte(&cache.text, lnum.saturating_add(1))
+        .max(start);
+    let bytes = cache.text.as_bytes();
+    while end > start && matches!(bytes[end - 1], b'\n' | b'\r') {
+        end -= 1;
+    }
+    start..end
 }
 
-/// Node kinds that produce folds per language. Plain string matching keeps
-/// this table cheap and tolerant: a kind absent from a grammar simply never
-/// matches.
-fn foldable_kinds(lang: &str) -> &'static [&'static str] {
-    match lang {
-        "rust" => &[
-            "function_item",
-            "impl_item",
-            "mod_item",
-            "struct_item",
-            "enum_item",
-            "trait_item",
-            "macro_definition",
-            "match_expression",
-            "block",
-        ],
-        "c" | "cpp" => &[
-            "function_definition",
-            "struct_specifier",
-            "enum_specifier",
-            "union_specifier",
-            "class_specifier",
-            "namespace_definition",
-            "compound_statement",
-        ],
-        "javascript" => &[
-            "function_declaration",
-            "function_expression",
-            "arrow_function",
-            "method_definition",
-            "class_declaration",
-            "object",
-            "statement_block",
-            "switch_statement",
-        ],
-        "typescript" | "tsx" => &[
-            "function_declaration",
-            "function_expression",
-            "arrow_function",
-            "method_definition",
-            "class_declaration",
-            "abstract_class_declaration",
-            "interface_declaration",
-            "enum_declaration",
-            "internal_module",
-            "object",
-            "statement_block",
-            "switch_statement",
-            "jsx_element",
-        ],
-        "python" => &[
-            "function_definition",
-            "class_definition",
-            "if_statement",
-            "for_statement",
-            "while_statement",
-            "try_statement",
-            "with_statement",
-            "match_statement",
-        ],
-        "go" => &[
-            "function_declaration",
-            "method_declaration",
-            "func_literal",
-            "type_declaration",
-            "block",
-        ],
-        "bash" => &[
-            "function_definition",
-            "if_statement",
-            "for_statement",
-            "while_statement",
-            "case_statement",
-            "compound_statement",
-        ],
-        "vim" => &["def_function", "function_definition"],
-        "json" => &["object", "array"],
-        "yaml" => &["block_mapping", "block_sequence"],
-        "toml" => &["table", "table_array_element"],
-        "julia" => &[
-            "function_definition",
-            "macro_definition",
-            "struct_definition",
-            "module_definition",
-            "if_statement",
-            "for_statement",
-            "while_statement",
-            "try_statement",
-            "let_statement",
-            "compound_statement",
-        ],
-        "haskell" => &[
-            "function",
-            "class",
-            "instance",
-            "data_type",
-            "newtype",
-            "do",
-            "case",
-        ],
-        _ => &[],
+/// Screen width of `prefix` as leading indentation: tabs advance to the next
+/// multiple of `tabstop`, everything else counts one cell.
+fn display_width(prefix: &str, tabstop: u32) -> i32 {
+    prefix.chars().fold(0, |width, c| {
+        if c == '\t' {
+            width + tabstop as i32 - width % tabstop as i32
+        } else {
+            width + 1
+        }
+    })
+}
+
+/// Indent, in screen columns, that line `lnum` should have; -1 to keep the
+/// line as it is. Walks from the first token of the line (for a blank line:
+/// the last token of the previous non-blank one) up to the root, adding one
+/// `shiftwidth` per enclosing `begin` and counting at most one per source line,
+/// so `foo(bar(` on one line opens a single level.
+fn line_indent(
+    cache: &BufCache,
+    captures: &IndentCaptures,
+    lnum: u32,
+    shiftwidth: i32,
+    tabstop: u32,
+) -> i32 {
+    let text = &cache.text;
+    let root = cache.tree.root_node();
+    let bounds = line_bounds(cache, lnum);
+    let row = lnum.saturating_sub(1) as usize;
+    let first = text[bounds.clone()].find(|c: char| c != ' ' && c != '\t');
+    let blank = first.is_none();
+    let node = if let Some(col) = first {
+        let at = bounds.start + col;
+        let len = text[at..].chars().next().map_or(1, char::len_utf8);
+        root.descendant_for_byte_range(at, at + len)
+    } else {
+        let Some(prev) = (1..lnum)
+            .rev()
+            .find(|&l| !text[line_bounds(cache, l)].trim().is_empty())
+        else {
+            return 0;
+        };
+        let prev_bounds = line_bounds(cache, prev);
+        let prev_line = &text[prev_bounds.clone()];
+        let trimmed = prev_line.trim_end();
+        let len = trimmed.chars().next_back().map_or(1, char::len_utf8);
+        let at = prev_bounds.start + trimmed.len() - len;
+        let mut node = root.descendant_for_byte_range(at, at + len);
+        // A trailing comment says nothing about structure: use what precedes
+        // it on the line, or keep the previous line's indent.
+        if let Some(comment) = node.filter(|n| n.kind().contains("comment")) {
+            match comment
+                .prev_sibling()
+                .filter(|sibling| sibling.end_position().row + 1 == prev as usize)
+            {
+                Some(sibling) => node = Some(sibling),
+                None => {
+                    let leading = prev_line.len() - prev_line.trim_start().len();
+                    return display_width(&prev_line[..leading], tabstop);
+                }
+            }
+        }
+        // Right after a closed block the line belongs to what encloses it.
+        let mut closer = node;
+        while let Some(candidate) = closer {
+            if captures.end.contains(&candidate.id()) {
+                node = root.descendant_for_byte_range(bounds.start, bounds.start);
+                break;
+            }
+            closer = candidate
+                .parent()
+                .filter(|parent| parent.end_byte() == candidate.end_byte());
+        }
+        node
+    };
+    let Some(node) = node else {
+        return 0;
+    };
+
+    if !blank {
+        let mut ancestor = Some(node);
+        while let Some(candidate) = ancestor {
+            if candidate.start_position().row < row && captures.ignore.contains(&candidate.id()) {
+                return -1;
+            }
+            ancestor = candidate.parent();
+        }
+    }
+
+    let mut indent = 0;
+    let mut counted_rows = std::collections::HashSet::new();
+    let mut current = Some(node);
+    while let Some(n) = current {
+        current = n.parent();
+        let id = n.id();
+        let srow = n.start_position().row;
+        // The last token of the line above only closes things for that line.
+        if captures.branch.contains(&id)
+            && !(blank && n == node)
+            && let Some(parent) = current
+        {
+            counted_rows.insert(parent.start_position().row);
+        }
+        if counted_rows.contains(&srow) {
+            continue;
+        }
+        let spans =
+            srow != n.end_position().row || n.has_error() || captures.immediate.contains(&id);
+        if srow < row && captures.dedent.contains(&id) {
+            indent -= shiftwidth;
+            counted_rows.insert(srow);
+        } else if srow < row && spans && captures.begin.contains(&id) {
+            indent += shiftwidth;
+            counted_rows.insert(srow);
+        } else if srow < row && spans && captures.align.contains(&id) {
+            if let Some(open) = n.child(0)
+                && let Some(next) = open.next_sibling()
+                && next.start_position().row == open.end_position().row
+                && !next.kind().contains("comment")
+            {
+                // Aligned: an absolute column, whatever encloses the list.
+                let line_start = cache
+                    .line_index
+                    .line_start_byte(&cache.text, srow as u32 + 1);
+                return display_width(&text[line_start..next.start_byte()], tabstop);
+            }
+            indent += shiftwidth;
+            counted_rows.insert(srow);
+        } else if n.is_error() && srow < row {
+            // Half-typed code: a bracket the parser could not close still opens
+            // a level, or pressing Enter after `fn f() {` would not indent.
+            let mut open = Vec::new();
+            let mut cursor = n.walk();
+            for child in n.children(&mut cursor) {
+                match child.kind() {
+                    "{" | "(" | "[" => open.push(child.start_position().row),
+                    "}" | ")" | "]" => {
+                        open.pop();
+                    }
+                    _ => {}
+                }
+            }
+            for open_row in open {
+                if open_row < row && counted_rows.insert(open_row) {
+                    indent += shiftwidth;
+                }
+            }
+        }
     }
+    indent.max(0)
 }
 
-// 从缓存树上收集折叠区间；level 为折叠祖先数量 + 1。
-fn run_folds_cached(
+/// Indents for lines `lnum..=end_lnum`, computed from the cached tree.
+fn run_indent_cached(
     server: &Server,
     buf: i64,
     lang: &str,
-    max_items: Option<usize>,
-) -> Result<(u64, Vec<Fold>)> {
+    lnum: u32,
+    end_lnum: Option<u32>,
+    shiftwidth: u32,
+    tabstop: Option<u32>,
+) -> Result<(u64, Vec<i32>)> {
     let cache = server.get_cache(buf, lang)?;
-    let kinds = foldable_kinds(lang);
-    let limit = max_items.unwrap_or(MAX_FOLDS).min(MAX_FOLDS);
-    let mut folds: Vec<Fold> = Vec::new();
-    // (node, level, enclosing fold range) —— 与父 fold 完全同界的嵌套节点合并，
-    // 否则 `fn f() { ... }` 会因 function_item 与 block 同界而叠出两层折叠。
-    let mut stack = vec![(cache.tree.root_node(), 0_u32, None::<(u32, u32)>)];
-    while let Some((node, level, parent_range)) = stack.pop() {
-        if folds.len() >= limit {
+    let query = server
+        .queries
+        .get(&cache.lang)
+        .and_then(|queries| queries.indents_query.as_ref())
+        .ok_or_else(|| anyhow!("no indents query for {lang}"))?;
+    let first = lnum.max(1);
+    let line_count = cache.line_index.line_count as u32;
+    let last = end_lnum
+        .unwrap_or(first)
+        .max(first)
+        .min(line_count)
+        .min(first.saturating_add(MAX_INDENT_LINES as u32 - 1));
+    if first > last {
+        return Ok((cache.revision, Vec::new()));
+    }
+    let shiftwidth = shiftwidth.clamp(1, 64) as i32;
+    let tabstop = tabstop.unwrap_or(8).clamp(1, 64);
+    // A blank first line looks back at the previous non-blank one.
+    let mut from = first;
+    while from > 1 && cache.text[line_bounds(cache, from)].trim().is_empty() {
+        from -= 1;
+    }
+    let range = line_range_from_index(&cache.line_index, &cache.text, from, last);
+    let captures = indent_captures(query, cache.tree.root_node(), &cache.text, range);
+    let indents = (first..=last)
+        .map(|line| line_indent(cache, &captures, line, shiftwidth, tabstop))
+        .collect();
+    Ok((cache.revision, indents))
+}
+
+fn query_error_kind(kind: &tree_sitter::QueryErrorKind) -> &'static str {
+    match kind {
+        tree_sitter::QueryErrorKind::Syntax => "syntax",
+        tree_sitter::QueryErrorKind::NodeType => "node_type",
+        tree_sitter::QueryErrorKind::Field => "field",
+        tree_sitter::QueryErrorKind::Capture => "capture",
+        tree_sitter::QueryErrorKind::Predicate => "predicate",
+        tree_sitter::QueryErrorKind::Structure => "structure",
+        tree_sitter::QueryErrorKind::Language => "language",
+    }
+}
+
+/// Captures of `query` in document order, each with its byte range, at most
+/// `limit` of them; the flag says whether anything was cut off.
+fn collect_query_captures(
+    (query, predicates): &(tree_sitter::Query, QueryPredicates),
+    root: tree_sitter::Node,
+    text: &str,
+    range: Option<ops::Range<usize>>,
+    limit: usize,
+) -> (Vec<(QueryCapture, ops::Range<usize>)>, bool) {
+    let mut cursor = tree_sitter::QueryCursor::new();
+    cursor.set_match_limit(MAX_QUERY_IN_PROGRESS);
+    if let Some(range) = range.clone() {
+        cursor.set_byte_range(range);
+    }
+    let mut captures = Vec::new();
+    let mut truncated = false;
+    let mut it = cursor.captures(query, root, text.as_bytes());
+    while let Some((m, cap_ix)) = it.next() {
+        if captures.len() >= limit {
+            truncated = true;
             break;
         }
-        let sp = node.start_position();
-        let ep = node.end_position();
-        let range = (sp.row as u32 + 1, ep.row as u32 + 1);
-        let mut next_level = level;
-        let mut next_range = parent_range;
-        if kinds.contains(&node.kind()) && ep.row > sp.row && parent_range != Some(range) {
-            folds.push(Fold {
-                lnum: range.0,
-                end_lnum: range.1,
-                level: level + 1,
-            });
-            next_level = level + 1;
-            next_range = Some(range);
+        let cap = m.captures[*cap_ix];
+        if !predicates.accepts(m, text.as_bytes()) {
+            continue;
         }
-        let child_count = node.child_count().min(u32::MAX as usize);
-        for index in (0..child_count).rev() {
-            if let Some(child) = node.child(index as u32) {
-                stack.push((child, next_level, next_range));
-            }
+        let (sp, ep) = predicates.capture_points(m.pattern_index, cap);
+        // `#offset!` moves the text along with the position: `grep` prints what
+        // it reports, and a line range filters on where the capture ended up.
+        let anchor = (cap.node.start_byte(), cap.node.start_position());
+        let shifted = byte_at_point(text, anchor, sp)..byte_at_point(text, anchor, ep);
+        if let Some(range) = &range
+            && (shifted.end < range.start || shifted.start >= range.end)
+        {
+            continue;
         }
+        captures.push((
+            QueryCapture {
+                capture: query.capture_names()[cap.index as usize].to_string(),
+                pattern: m.pattern_index,
+                kind: cap.node.kind(),
+                lnum: sp.row as u32 + 1,
+                col: sp.column as u32 + 1,
+                end_lnum: ep.row as u32 + 1,
+                end_col: ep.column as u32 + 1,
+            },
+            shifted,
+        ));
     }
-    folds.sort_by_key(|fold| (fold.lnum, fold.level));
-    Ok((cache.revision, folds))
+    drop(it);
+    truncated |= cursor.did_exceed_match_limit();
+    (captures, truncated)
 }
 
-fn extract_vim_declarations(
-    text: &str,
+/// Compile `source` against the buffer's grammar and collect its captures
+/// over the cached tree, optionally only those meeting lines `lstart..=lend`.
+///
+/// A pattern that does not compile comes back as the `tree_sitter::QueryError`
+/// inside the error, so the caller can report where it broke. Predicates and
+/// `#offset!` apply as they do for highlights.
+fn run_query_cached(
+    server: &Server,
+    buf: i64,
+    lang: &str,
+    source: &str,
     lrange: Option<(u32, u32)>,
-    limit: usize,
-    kinds: &[String],
-) -> Vec<Symbol> {
-    let mut symbols = Vec::<Symbol>::new();
-    // (name, line, column, index in symbols). Vim functions do not normally
-    // nest, but a stack makes malformed/in-progress edits behave predictably.
-    let mut functions = Vec::<(String, u32, u32, Option<usize>)>::new();
+    max_items: Option<usize>,
+) -> Result<(u64, Vec<QueryCapture>, bool)> {
+    let cache = server.get_cache(buf, lang)?;
+    if source.len() > MAX_QUERY_SOURCE_BYTES {
+        return Err(anyhow!(
+            "query exceeds daemon limit of {MAX_QUERY_SOURCE_BYTES} bytes"
+        ));
+    }
+    let (language, _, _) = Server::lang_info(&cache.lang)?;
+    let query = compile_query(&language, source)?;
+    let limit = max_items
+        .unwrap_or(MAX_QUERY_CAPTURES)
+        .min(MAX_QUERY_CAPTURES);
+    let range =
+        lrange.map(|(ls, le)| line_range_from_index(&cache.line_index, &cache.text, ls, le));
+    let (captures, truncated) =
+        collect_query_captures(&query, cache.tree.root_node(), &cache.text, range, limit);
+    let captures = captures.into_iter().map(|(capture, _)| capture).collect();
+    Ok((cache.revision, captures, truncated))
+}
 
-    for (row, source_line) in text.lines().enumerate() {
-        let line_number = row as u32 + 1;
-        let leading = source_line.len() - source_line.trim_start().len();
-        let mut line = source_line.trim_start();
-        let mut declaration_offset = leading;
-        if line.is_empty() || line.starts_with('"') {
-            continue;
+fn dump_ast_cached(server: &mut Server, buf: i64, lang: &str) -> Result<(u64, Vec<String>)> {
+    let cache = server.get_cache(buf, lang)?;
+    let root = cache.tree.root_node();
+    let (lines, _) = format_ast(root, MAX_AST_NODES, MAX_AST_DEPTH, server.cancel.as_deref());
+    server.check_cancelled()?;
+    Ok((cache.revision, lines))
+}
+
+/// Format an AST while keeping both the result and pending traversal stack
+/// within `node_limit`. Returning the peak stack size lets regression tests
+/// verify that a very wide node cannot bypass the output budget. Stops early,
+/// with whatever it has, once `cancel` is set.
+fn format_ast(
+    root: tree_sitter::Node,
+    node_limit: usize,
+    depth_limit: usize,
+    cancel: Option<&std::sync::atomic::AtomicBool>,
+) -> (Vec<String>, usize) {
+    if node_limit == 0 {
+        return (Vec::new(), 0);
+    }
+    let mut lines = Vec::new();
+    let mut stack = vec![(root, 0_usize)];
+    let mut max_pending_nodes = stack.len();
+    let mut nodes_truncated = false;
+    let mut depth_truncated = false;
+    while let Some((node, depth)) = stack.pop() {
+        if lines.len() >= node_limit {
+            nodes_truncated = true;
+            break;
         }
-        if let Some(rest) = line.strip_prefix("export")
-            && rest.starts_with(char::is_whitespace)
-        {
-            let trimmed = rest.trim_start();
-            declaration_offset += "export".len() + (rest.len() - trimmed.len());
-            line = trimmed;
+        if cancel.is_some_and(|cancel| cancel.load(std::sync::atomic::Ordering::Relaxed)) {
+            break;
         }
+        let sp = node.start_position();
+        let ep = node.end_position();
+        let indent = (depth * 2).min(MAX_AST_INDENT);
+        let depth_marker = if depth * 2 > MAX_AST_INDENT {
+            "… "
+        } else {
+            ""
+        };
+        lines.push(format!(
+            "{:indent$}{}{} [{}:{} - {}:{}]",
+            "",
+            depth_marker,
+            node.kind(),
+            sp.row + 1,
+            sp.column + 1,
+            ep.row + 1,
+            ep.column + 1,
+            indent = indent
+        ));
 
-        if starts_with_vim_keyword(line, "enddef") || starts_with_vim_keyword(line, "endfunction") {
-            if let Some((_, _, _, Some(index))) = functions.pop()
-                && let Some(symbol) = symbols.get_mut(index)
-            {
-                symbol.end_lnum = line_number;
-                symbol.end_col = source_line.len() as u32 + 1;
-            }
+        if depth >= depth_limit {
+            depth_truncated = true;
             continue;
         }
 
-        let function_keyword = ["def", "function!", "function"]
-            .into_iter()
-            .find(|keyword| starts_with_vim_keyword(line, keyword));
-        if let Some(keyword) = function_keyword {
-            let rest = &line[keyword.len()..];
-            let name_part = rest.trim_start();
-            let spaces = rest.len() - name_part.len();
-            let name = name_part
-                .split(|character: char| character == '(' || character.is_whitespace())
-                .next()
-                .unwrap_or("")
-                .trim();
-            if !name.is_empty() {
-                let column = (declaration_offset + keyword.len() + spaces + 1) as u32;
-                let in_range =
-                    lrange.is_none_or(|(start, end)| line_number >= start && line_number <= end);
-                let index = if in_range
-                    && symbols.len() < limit
-                    && symbol_kind_allowed("function", kinds)
-                {
-                    let container = functions.last();
-                    symbols.push(Symbol {
-                        name: name.to_string(),
-                        kind: "function",
-                        lnum: line_number,
-                        col: column,
-                        end_lnum: line_number,
-                        end_col: source_line.len() as u32 + 1,
-                        container_kind: container.map(|_| "function"),
-                        container_name: container.map(|value| value.0.clone()),
-                        container_lnum: container.map(|value| value.1),
-                        container_col: container.map(|value| value.2),
-                    });
-                    Some(symbols.len() - 1)
-                } else {
-                    None
-                };
-                functions.push((name.to_string(), line_number, column, index));
+        // Pending siblings already consume part of the same hard node budget.
+        // Only enqueue children that can still be visited; never materialize an
+        // arbitrarily wide child list merely to discard it on the next loop.
+        let remaining_slots = node_limit.saturating_sub(lines.len().saturating_add(stack.len()));
+        let child_count = node.child_count().min(u32::MAX as usize);
+        let scheduled_children = child_count.min(remaining_slots);
+        if scheduled_children < child_count {
+            nodes_truncated = true;
+        }
+        for index in (0..scheduled_children).rev() {
+            if let Some(child) = node.child(index as u32) {
+                stack.push((child, depth + 1));
             }
-            continue;
         }
+        max_pending_nodes = max_pending_nodes.max(stack.len());
+    }
 
-        let variable_keyword = ["var", "const", "final", "let"]
-            .into_iter()
-            .find(|keyword| starts_with_vim_keyword(line, keyword));
-        if let Some(keyword) = variable_keyword {
-            let kind = if matches!(keyword, "const" | "final") {
-                "const"
-            } else {
-                "variable"
-            };
-            if !symbol_kind_allowed(kind, kinds)
-                || symbols.len() >= limit
-                || !lrange.is_none_or(|(start, end)| line_number >= start && line_number <= end)
-            {
-                continue;
+    if nodes_truncated || depth_truncated {
+        let marker = match (nodes_truncated, depth_truncated) {
+            (true, true) => {
+                format!("... AST truncated at {node_limit} nodes and depth {depth_limit} ...")
             }
-            let rest = &line[keyword.len()..];
-            let name_part = rest.trim_start();
-            let spaces = rest.len() - name_part.len();
-            let raw_name = name_part
-                .split(|character: char| character == '=' || character.is_whitespace())
-                .next()
-                .unwrap_or("");
-            let name = raw_name.trim_end_matches(':');
-            if name.is_empty() || name.starts_with('[') || name.starts_with('{') {
-                continue;
+            (true, false) => format!("... AST truncated at {node_limit} nodes ..."),
+            (false, true) => {
+                format!("... branches deeper than {depth_limit} nodes were truncated ...")
             }
-            let container = functions.last();
-            symbols.push(Symbol {
-                name: name.to_string(),
-                kind,
-                lnum: line_number,
-                col: (declaration_offset + keyword.len() + spaces + 1) as u32,
-                end_lnum: line_number,
-                end_col: source_line.len() as u32 + 1,
-                container_kind: container.map(|_| "function"),
-                container_name: container.map(|value| value.0.clone()),
-                container_lnum: container.map(|value| value.1),
-                container_col: container.map(|value| value.2),
-            });
+            (false, false) => unreachable!(),
+        };
+        if lines.len() < node_limit {
+            lines.push(marker);
+        } else if let Some(last) = lines.last_mut() {
+            *last = marker;
+        }
+    }
+
+    (lines, max_pending_nodes)
+}
+
+fn bracket_depth(node: tree_sitter::Node) -> u32 {
+    let mut depth: u32 = 0;
+    let mut cur = node.parent();
+    while let Some(p) = cur {
+        // Delimited grammar nodes expose their opening/closing token as an
+        // edge child. Checking two children keeps this O(tree depth), even
+        // when the source root itself has millions of children.
+        let last_child = p.child_count().saturating_sub(1).min(u32::MAX as usize) as u32;
+        let has_brackets = [p.child(0), p.child(last_child)]
+            .into_iter()
+            .flatten()
+            .any(|child| matches!(child.kind(), "(" | ")" | "{" | "}" | "[" | "]"));
+        if has_brackets {
+            depth += 1;
         }
+        cur = p.parent();
     }
+    depth
+}
 
-    symbols
+/// One general predicate or directive of a query pattern.
+///
+/// tree-sitter evaluates the text predicates itself (`#eq?`, `#match?`,
+/// `#any-of?` and their negations) and returns `#set!` as properties; anything
+/// else is handed back uninterpreted and would otherwise be ignored, so a
+/// pattern meant to be narrow would match everything.
+enum QueryPredicate {
+    /// `#lua-match?` / `#not-lua-match?`: a Lua pattern, translated once.
+    LuaMatch {
+        capture: u32,
+        regex: regex::Regex,
+        negate: bool,
+    },
+    /// `#has-parent?` / `#not-has-parent?`, `#has-ancestor?` /
+    /// `#not-has-ancestor?`: the capture's parent (or any ancestor) is one of
+    /// `kinds`.
+    HasKind {
+        capture: u32,
+        kinds: Vec<Box<str>>,
+        ancestor: bool,
+        negate: bool,
+    },
+    /// `#contains?` / `#not-contains?`: the capture's text contains any of
+    /// `needles`.
+    Contains {
+        capture: u32,
+        needles: Vec<Box<str>>,
+        negate: bool,
+    },
+    /// `#offset! @cap start_row start_col end_row end_col`: a directive that
+    /// moves the reported range of `capture`, not a filter.
+    Offset { capture: u32, delta: [i32; 4] },
 }
 
-fn starts_with_vim_keyword(line: &str, keyword: &str) -> bool {
-    let Some(rest) = line.strip_prefix(keyword) else {
-        return false;
-    };
-    rest.is_empty() || rest.starts_with(char::is_whitespace)
+/// The general predicates of every pattern of one query, checked when the
+/// query is compiled so that a misspelled predicate is an error rather than a
+/// pattern that silently matches too much.
+#[derive(Default)]
+struct QueryPredicates {
+    patterns: Vec<Vec<QueryPredicate>>,
 }
 
-/// Byte offset of a 1-based (line, byte column) pair, clamped into the line and
-/// snapped back to a UTF-8 boundary. Vim's col() counts bytes, so a cursor
-/// sitting on a continuation byte of a multi-byte character is impossible from
-/// Vim itself but trivially reachable from a script; a mid-character offset
-/// would make `descendant_for_byte_range` describe a neighbouring token.
-fn point_byte_offset(cache: &BufCache, lnum: u32, col: u32) -> usize {
-    let start = cache.line_index.line_start_byte(&cache.text, lnum.max(1));
-    let mut end = cache
-        .line_index
-        .line_start_byte(&cache.text, lnum.max(1).saturating_add(1))
-        .min(cache.text.len());
-    if end > start && cache.text.as_bytes()[end - 1] == b'\n' {
-        end -= 1;
-    }
-    let mut offset = start
-        .saturating_add(col.max(1).saturating_sub(1) as usize)
-        .min(end.max(start));
-    while offset > start && !cache.text.is_char_boundary(offset) {
-        offset -= 1;
+impl QueryPredicate {
+    fn parse(predicate: &tree_sitter::QueryPredicate) -> Result<Self, String> {
+        use tree_sitter::QueryPredicateArg as Arg;
+        let op = &*predicate.operator;
+        let (capture, rest) = match predicate.args.split_first() {
+            Some((Arg::Capture(capture), rest)) => (*capture, rest),
+            _ => return Err(format!("#{op} expects a capture first")),
+        };
+        let strings = rest
+            .iter()
+            .map(|arg| match arg {
+                Arg::String(value) => Ok(value.clone()),
+                Arg::Capture(_) => Err(format!("#{op} takes one capture")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let negate = op.starts_with("not-");
+        Ok(match op.trim_start_matches("not-") {
+            "lua-match?" => {
+                let [pattern] = strings.as_slice() else {
+                    return Err(format!("#{op} expects one pattern"));
+                };
+                let regex = lua_pattern_to_regex(pattern)
+                    .and_then(|regex| Ok(regex::Regex::new(&regex)?))
+                    .map_err(|error| format!("#{op} {pattern:?}: {error}"))?;
+                QueryPredicate::LuaMatch {
+                    capture,
+                    regex,
+                    negate,
+                }
+            }
+            "has-parent?" | "has-ancestor?" if !strings.is_empty() => QueryPredicate::HasKind {
+                capture,
+                kinds: strings,
+                ancestor: op.ends_with("has-ancestor?"),
+                negate,
+            },
+            "contains?" if !strings.is_empty() => QueryPredicate::Contains {
+                capture,
+                needles: strings,
+                negate,
+            },
+            "offset!" if !negate => {
+                let mut delta = [0; 4];
+                if strings.len() != 4 {
+                    return Err("#offset! expects four numbers".to_string());
+                }
+                for (slot, value) in delta.iter_mut().zip(&strings) {
+                    *slot = value
+                        .parse()
+                        .map_err(|_| format!("#offset! expects numbers, got {value:?}"))?;
+                }
+                QueryPredicate::Offset { capture, delta }
+            }
+            "has-parent?" | "has-ancestor?" | "contains?" => {
+                return Err(format!("#{op} expects at least one argument"));
+            }
+            _ => return Err(format!("unsupported predicate #{op}")),
+        })
     }
-    offset
 }
 
-fn inspect_node(node: tree_sitter::Node) -> InspectNode {
-    let sp = node.start_position();
-    let ep = node.end_position();
-    InspectNode {
-        kind: node.kind().to_string(),
-        named: node.is_named(),
-        lnum: sp.row as u32 + 1,
-        col: sp.column as u32 + 1,
-        end_lnum: ep.row as u32 + 1,
-        end_col: ep.column as u32 + 1,
-        field: None,
+impl QueryPredicates {
+    /// Parse the general predicates of `query`, compiled from `source`. A
+    /// predicate this daemon cannot evaluate, or a `#set! priority` outside
+    /// the `u8` scale, fails like a bad pattern would, pointing at it in
+    /// `source`.
+    fn new(query: &tree_sitter::Query, source: &str) -> Result<Self, tree_sitter::QueryError> {
+        let error_at = |pattern: usize, needle: &str, message: String| {
+            let start = query.start_byte_for_pattern(pattern).min(source.len());
+            let offset = source
+                .get(start..)
+                .and_then(|rest| rest.find(needle))
+                .map_or(start, |at| start + at);
+            let before = &source[..offset];
+            tree_sitter::QueryError {
+                row: before.matches('\n').count(),
+                column: offset - before.rfind('\n').map_or(0, |nl| nl + 1),
+                offset,
+                message,
+                kind: tree_sitter::QueryErrorKind::Predicate,
+            }
+        };
+        let mut patterns = Vec::with_capacity(query.pattern_count());
+        for pattern in 0..query.pattern_count() {
+            for property in query.property_settings(pattern) {
+                if &*property.key != "priority" {
+                    continue;
+                }
+                let value = property.value.as_deref().unwrap_or("");
+                if value.parse::<u8>().is_err() {
+                    return Err(error_at(
+                        pattern,
+                        "priority",
+                        format!("#set! priority expects a number from 0 to 255, got {value:?}"),
+                    ));
+                }
+            }
+            let mut predicates = Vec::new();
+            for predicate in query.general_predicates(pattern) {
+                let parsed = QueryPredicate::parse(predicate).map_err(|message| {
+                    error_at(pattern, &format!("#{}", predicate.operator), message)
+                })?;
+                predicates.push(parsed);
+            }
+            patterns.push(predicates);
+        }
+        Ok(QueryPredicates { patterns })
+    }
+
+    /// Whether match `m` satisfies its pattern's predicates. Like tree-sitter's
+    /// own text predicates, a quantified capture must satisfy them on every
+    /// node, and a capture that matched no node satisfies them trivially.
+    fn accepts(&self, m: &tree_sitter::QueryMatch, text: &[u8]) -> bool {
+        let Some(predicates) = self.patterns.get(m.pattern_index) else {
+            return true;
+        };
+        predicates.iter().all(|predicate| {
+            let (capture, negate) = match predicate {
+                QueryPredicate::LuaMatch {
+                    capture, negate, ..
+                }
+                | QueryPredicate::HasKind {
+                    capture, negate, ..
+                }
+                | QueryPredicate::Contains {
+                    capture, negate, ..
+                } => (*capture, *negate),
+                QueryPredicate::Offset { .. } => return true,
+            };
+            m.nodes_for_capture_index(capture).all(|node| {
+                let holds = match predicate {
+                    QueryPredicate::LuaMatch { regex, .. } => {
+                        regex.is_match(node.utf8_text(text).unwrap_or(""))
+                    }
+                    QueryPredicate::HasKind {
+                        kinds, ancestor, ..
+                    } => {
+                        let mut parent = node.parent();
+                        let mut found = false;
+                        while let Some(candidate) = parent {
+                            if kinds.iter().any(|kind| **kind == *candidate.kind()) {
+                                found = true;
+                                break;
+                            }
+                            parent = if *ancestor { candidate.parent() } else { None };
+                        }
+                        found
+                    }
+                    QueryPredicate::Contains { needles, .. } => {
+                        let haystack = node.utf8_text(text).unwrap_or("");
+                        needles.iter().any(|needle| haystack.contains(&**needle))
+                    }
+                    QueryPredicate::Offset { .. } => true,
+                };
+                holds != negate
+            })
+        })
+    }
+
+    /// Start and end of `capture` as reported: its node's, moved by an
+    /// `#offset!` of the pattern when there is one.
+    fn capture_points(
+        &self,
+        pattern: usize,
+        capture: tree_sitter::QueryCapture,
+    ) -> (tree_sitter::Point, tree_sitter::Point) {
+        let (start, end) = (capture.node.start_position(), capture.node.end_position());
+        let delta = self.patterns.get(pattern).and_then(|predicates| {
+            predicates.iter().find_map(|predicate| match predicate {
+                QueryPredicate::Offset { capture: c, delta } if *c == capture.index => Some(*delta),
+                _ => None,
+            })
+        });
+        let Some([r0, c0, r1, c1]) = delta else {
+            return (start, end);
+        };
+        let shift = |point: tree_sitter::Point, rows: i32, cols: i32| tree_sitter::Point {
+            row: point.row.saturating_add_signed(rows as isize),
+            column: point.column.saturating_add_signed(cols as isize),
+        };
+        let start = shift(start, r0, c0);
+        (start, shift(end, r1, c1).max(start))
     }
 }
 
-fn inspect_cached(
-    server: &mut Server,
-    buf: i64,
-    lang: &str,
-    lnum: u32,
-    col: u32,
-) -> Result<(u64, Vec<InspectCapture>, Vec<InspectNode>)> {
-    server.ensure_queries(lang)?;
-    let cache = server.get_cache(buf, lang)?;
-    let bytes = cache.text.as_bytes();
-    let root = cache.tree.root_node();
-    let offset = point_byte_offset(cache, lnum, col);
-    let lang_queries = server.queries.get(&cache.lang).unwrap();
+/// `#set! priority "N"` of a pattern, or of one of its captures with
+/// `#set! @cap priority "N"`. `N` is a `u8` on the scale of `capture_priority`,
+/// 0 to 255 with higher winning a tie on the same range; the name defaults
+/// stay within 0 to 8, so 9 and up outrank every undecorated capture.
+/// `QueryPredicates::new` has already rejected values outside the scale.
+fn pattern_priority(query: &tree_sitter::Query, pattern: usize, capture: u32) -> Option<u8> {
+    query
+        .property_settings(pattern)
+        .iter()
+        .filter(|property| &*property.key == "priority")
+        .filter(|property| property.capture_id.is_none_or(|id| id == capture as usize))
+        .find_map(|property| property.value.as_deref()?.parse().ok())
+}
 
-    // Node chain, innermost first. `field` is read from the parent because a
-    // node does not know which slot it fills.
-    let mut node_chain = Vec::new();
-    if let Some(innermost) = root.descendant_for_byte_range(offset, offset) {
-        let mut current = Some(innermost);
-        while let Some(node) = current {
-            let mut entry = inspect_node(node);
-            if let Some(parent) = node.parent() {
-                let mut cursor = parent.walk();
-                if cursor.goto_first_child() {
-                    loop {
-                        if cursor.node() == node {
-                            entry.field = cursor.field_name().map(str::to_string);
-                            break;
-                        }
-                        if !cursor.goto_next_sibling() {
-                            break;
+/// Compile `source` and the predicates it uses. Either failure comes back as a
+/// `tree_sitter::QueryError`, so callers can report where the source broke.
+fn compile_query(
+    language: &tree_sitter::Language,
+    source: &str,
+) -> Result<(tree_sitter::Query, QueryPredicates)> {
+    let query = tree_sitter::Query::new(language, source)?;
+    let predicates = QueryPredicates::new(&query, source)?;
+    Ok((query, predicates))
+}
+
+/// Translate a Lua pattern, as Neovim query files use in `#lua-match?`, into an
+/// equivalent regex. `%b` and `%f` have no regex counterpart and are refused.
+fn lua_pattern_to_regex(pattern: &str) -> Result<String> {
+    fn class(c: char) -> Option<&'static str> {
+        Some(match c {
+            'a' => "A-Za-z",
+            'c' => "\\x00-\\x1f\\x7f",
+            'd' => "0-9",
+            'g' => "!-~",
+            'l' => "a-z",
+            'p' => "!-/:-@\\[-`{-~",
+            's' => "\\t\\n\\x0b\\x0c\\r ",
+            'u' => "A-Z",
+            'w' => "A-Za-z0-9",
+            'x' => "0-9A-Fa-f",
+            _ => return None,
+        })
+    }
+    let mut out = String::with_capacity(pattern.len() * 2);
+    let mut chars = pattern.chars().peekable();
+    let mut in_set = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => {
+                let Some(next) = chars.next() else {
+                    return Err(anyhow!("lua pattern {pattern:?} ends with `%`"));
+                };
+                if next == 'b' || next == 'f' {
+                    return Err(anyhow!(
+                        "lua pattern {pattern:?}: `%{next}` is not supported"
+                    ));
+                }
+                match class(next.to_ascii_lowercase()) {
+                    Some(range) if in_set => {
+                        if next.is_ascii_uppercase() {
+                            return Err(anyhow!(
+                                "lua pattern {pattern:?}: `%{next}` inside a set is not supported"
+                            ));
                         }
+                        out.push_str(range);
+                    }
+                    Some(range) => {
+                        let negate = if next.is_ascii_uppercase() { "^" } else { "" };
+                        out.push_str(&format!("[{negate}{range}]"));
+                    }
+                    None if next.is_ascii_alphanumeric() => {
+                        return Err(anyhow!("lua pattern {pattern:?}: unknown class `%{next}`"));
                     }
+                    None => out.push_str(&regex::escape(&next.to_string())),
                 }
             }
-            node_chain.push(entry);
-            if node_chain.len() >= MAX_AST_DEPTH {
-                break;
+            '[' if !in_set => {
+                in_set = true;
+                out.push('[');
+                if chars.peek() == Some(&'^') {
+                    chars.next();
+                    out.push('^');
+                }
             }
-            current = node.parent();
+            ']' if in_set => {
+                in_set = false;
+                out.push(']');
+            }
+            '-' if in_set => out.push('-'),
+            '-' => out.push_str("*?"),
+            '.' | '*' | '+' | '?' | '(' | ')' if !in_set => out.push(c),
+            '^' if out.is_empty() => out.push('^'),
+            '$' if chars.peek().is_none() => out.push('$'),
+            '[' | ']' | '\\' | '^' | '&' | '~' if in_set => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    if in_set {
+        return Err(anyhow!("lua pattern {pattern:?} has an unclosed `[`"));
+    }
+    Ok(out)
+}
+
+/// Default priority of a capture by name, for patterns without a
+/// `#set! priority`. Finer tie-breaks (builtins at 5, dotted sub-groups at 9)
+/// live in the bundled queries as `#set!` settings.
+fn capture_priority(name: &str) -> u8 {
+    match name {
+        "variable" => 0,
+        "constant" | "number" | "boolean" | "string" => 2,
+        "keyword" | "operator" | "punctuation.delimiter" | "punctuation.bracket" => 3,
+        "property" | "field" | "function" | "method" | "type" | "namespace" => 8,
+        _ => 4,
+    }
+}
+
+fn map_capture_to_group(name: &str) -> &'static str {
+    match name {
+        "comment" => "TSComment",
+        "spell" => "TSComment",
+        "string" => "TSString",
+        "string.regex" => "TStringRegex",
+        "string.escape" => "TStringEscape",
+        "string.special" => "TStringSpecial",
+        "string.documentation" => "TSString",
+        "number" => "TSNumber",
+        "boolean" => "TSBoolean",
+        "null" => "TSConstant",
+
+        "keyword" => "TSKeyword",
+        "keyword.operator" => "TSKeywordOperator",
+        "operator" => "TSOperator",
+        "punctuation.delimiter" => "TSPunctDelimiter",
+        "punctuation.bracket" => "TSPunctBracket",
+        "punctuation.special" => "TSPunctDelimiter",
+
+        "variable" => "TSVariable",
+        "variable.parameter" => "TSVariableParameter",
+        "variable.builtin" => "TSVariableBuiltin",
+        "variable.member" => "TSProperty",
+        "constant" => "TSConstant",
+        "constant.builtin" => "TSConstBuiltin",
+
+        "property" => "TSProperty",
+        "field" => "TSField",
+
+        "function" => "TSFunction",
+        "method" => "TSMethod",
+        "function.builtin" => "TSFunctionBuiltin",
+        "function.macro" => "TSMacro",
+
+        "type" => "TSType",
+        "type.builtin" => "TSTypeBuiltin",
+        "namespace" => "TSNamespace",
+        "macro" => "TSMacro",
+        "attribute" => "TSAttribute",
+
+        "character" => "TSString",
+        "number.float" => "TSNumber",
+        "module" => "TSNamespace",
+        "constructor" => "TSType",
+
+        "text.title" => "TSTitle",
+        "text.literal" => "TSLiteral",
+        "text.emphasis" => "TSEmphasis",
+        "text.strong" => "TSStrong",
+        "text.strike" => "TSStrike",
+        "text.uri" => "TSURI",
+        "text.reference" => "TSLink",
+
+        name if name.starts_with("comment.") => "TSComment",
+        name if name.starts_with("string.special") => "TStringSpecial",
+        name if name.starts_with("function.") => "TSFunction",
+        name if name.starts_with("keyword.") => "TSKeyword",
+        name if name.starts_with("type.") => "TSType",
+        _ => "",
+    }
+}
+
+/// The LSP `SemanticTokensLegend` every `semantic_tokens` reply declares.
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+struct SemanticTokensLegend {
+    token_types: &'static [&'static str],
+    token_modifiers: &'static [&'static str],
+}
+
+const SEMANTIC_TOKENS_LEGEND: SemanticTokensLegend = SemanticTokensLegend {
+    token_types: &[
+        "namespace",
+        "type",
+        "parameter",
+        "variable",
+        "property",
+        "function",
+        "method",
+        "macro",
+        "keyword",
+        "comment",
+        "string",
+        "number",
+        "regexp",
+        "operator",
+        "decorator",
+    ],
+    token_modifiers: &["readonly", "defaultLibrary"],
+};
+
+/// LSP token type index and modifier bits for a highlight group, or None for
+/// the groups LSP has no type for: punctuation and markup.
+///
+/// Keyed by group rather than capture name because the group is what Vim
+/// paints; every capture `map_capture_to_group` folds into a group gets that
+/// group's token, so the two editors cannot drift apart.
+fn semantic_token_type(group: &str) -> Option<(u32, u32)> {
+    const READONLY: u32 = 1;
+    const DEFAULT_LIBRARY: u32 = 2;
+    let (token_type, modifiers) = match group {
+        "TSNamespace" => ("namespace", 0),
+        "TSType" => ("type", 0),
+        "TSTypeBuiltin" => ("type", DEFAULT_LIBRARY),
+        "TSVariableParameter" => ("parameter", 0),
+        "TSVariable" => ("variable", 0),
+        "TSVariableBuiltin" => ("variable", DEFAULT_LIBRARY),
+        "TSConstant" => ("variable", READONLY),
+        "TSConstBuiltin" => ("variable", READONLY | DEFAULT_LIBRARY),
+        "TSProperty" | "TSField" => ("property", 0),
+        "TSFunction" => ("function", 0),
+        "TSFunctionBuiltin" => ("function", DEFAULT_LIBRARY),
+        "TSMethod" => ("method", 0),
+        "TSMacro" => ("macro", 0),
+        "TSKeyword" | "TSKeywordOperator" | "TSBoolean" => ("keyword", 0),
+        "TSComment" => ("comment", 0),
+        "TSString" | "TStringEscape" | "TStringSpecial" | "TSLiteral" => ("string", 0),
+        "TStringRegex" => ("regexp", 0),
+        "TSNumber" => ("number", 0),
+        "TSOperator" => ("operator", 0),
+        "TSAttribute" => ("decorator", 0),
+        _ => return None,
+    };
+    let index = SEMANTIC_TOKENS_LEGEND
+        .token_types
+        .iter()
+        .position(|name| *name == token_type)?;
+    Some((index as u32, modifiers))
+}
+
+/// What an LSP position's `character` counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PositionEncoding {
+    Utf8,
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    fn parse(name: &str) -> Result<Self> {
+        match name {
+            "" | "utf-16" => Ok(Self::Utf16),
+            "utf-8" => Ok(Self::Utf8),
+            "utf-32" => Ok(Self::Utf32),
+            _ => Err(anyhow!("unsupported position encoding: {name}")),
         }
     }
 
-    // Same passes as run_highlight_cached, so an injected grammar's captures
-    // show up here exactly when they show up on screen.
-    let mut passes: Vec<(&tree_sitter::Query, tree_sitter::Node, Option<&'static str>)> =
-        vec![(&lang_queries.hl_query, root, None)];
-    for injected in &cache.injections {
-        if let Some(query) = server.injection_queries.get(injected.lang) {
-            passes.push((
-                &query.hl_query,
-                injected.tree.root_node(),
-                Some(injected.lang),
-            ));
+    fn name(self) -> &'static str {
+        match self {
+            Self::Utf8 => "utf-8",
+            Self::Utf16 => "utf-16",
+            Self::Utf32 => "utf-32",
         }
     }
 
-    let mut captures: Vec<InspectCapture> = Vec::new();
-    for (query, pass_root, injected_lang) in passes {
-        let mut cursor = tree_sitter::QueryCursor::new();
-        cursor.set_byte_range(offset..offset.saturating_add(1));
-        let mut it = cursor.captures(query, pass_root, bytes);
-        while let Some((m, cap_ix)) = it.next() {
-            let node = m.captures[*cap_ix].node;
-            // set_byte_range only bounds which patterns are considered; a match
-            // may still report a capture that does not cover the point.
-            if node.start_byte() > offset || node.end_byte() <= offset {
-                continue;
+    fn units(self, text: &str) -> u32 {
+        (match self {
+            Self::Utf8 => text.len(),
+            Self::Utf16 => text.encode_utf16().count(),
+            Self::Utf32 => text.chars().count(),
+        }) as u32
+    }
+
+    /// Byte offset into `line` of the position `character` units in, clamped
+    /// to the line's end as LSP asks.
+    fn byte_column(self, line: &str, character: u32) -> usize {
+        let mut units = 0;
+        for (index, ch) in line.char_indices() {
+            if units >= character {
+                return index;
             }
-            // The renderer drops these, so the report must too, or :TsHlInspect
-            // would name a group the screen does not show.
-            if injected_lang.is_none() && covered_by_injection(&cache.injected_ranges, node) {
-                continue;
+            units += match self {
+                Self::Utf8 => ch.len_utf8(),
+                Self::Utf16 => ch.len_utf16(),
+                Self::Utf32 => 1,
+            } as u32;
+        }
+        line.len()
+    }
+}
+
+fn run_semantic_tokens_cached(
+    server: &mut Server,
+    buf: i64,
+    lang: &str,
+    lrange: Option<(u32, u32)>,
+    encoding: &str,
+) -> Result<(u64, PositionEncoding, Vec<u32>, bool)> {
+    let encoding = PositionEncoding::parse(encoding)?;
+    let (revision, spans) = run_highlight_cached(server, buf, lang, lrange, false, None)?;
+    let cache = server.get_cache(buf, lang)?;
+    let lines = lrange.unwrap_or((1, cache.line_index.line_count as u32));
+    let truncated = spans.len() >= MAX_HIGHLIGHT_SPANS;
+    Ok((
+        revision,
+        encoding,
+        semantic_tokens(cache, &spans, lines, encoding),
+        truncated,
+    ))
+}
+
+/// Encode highlight spans over lines `ls..=le` as LSP semantic token data.
+///
+/// LSP tokens sit on one line and never overlap, so a multi-line span is cut
+/// per line, and where spans nest — an escape inside a string, an
+/// interpolation inside a template — the innermost one wins. Neighbouring
+/// pieces of the same token are joined again afterwards.
+fn semantic_tokens(
+    cache: &BufCache,
+    spans: &[Span],
+    (ls, le): (u32, u32),
+    encoding: PositionEncoding,
+) -> Vec<u32> {
+    let line_text = |lnum: u32| {
+        let range = line_range_from_index(&cache.line_index, &cache.text, lnum, lnum);
+        let text = &cache.text[range];
+        text.strip_suffix('\n').unwrap_or(text)
+    };
+    // Per line: (start byte, end byte, (type, modifiers)).
+    type Piece = (usize, usize, (u32, u32));
+    let mut pieces: BTreeMap<u32, Vec<Piece>> = BTreeMap::new();
+    for span in spans {
+        let Some((token_type, modifiers)) = semantic_token_type(span.group) else {
+            continue;
+        };
+        for lnum in span.lnum.max(ls)..=span.end_lnum.min(le) {
+            let start = if lnum == span.lnum {
+                span.col as usize - 1
+            } else {
+                0
+            };
+            let end = if lnum == span.end_lnum {
+                span.end_col as usize - 1
+            } else {
+                line_text(lnum).len()
+            };
+            if start < end {
+                pieces
+                    .entry(lnum)
+                    .or_default()
+                    .push((start, end, (token_type, modifiers)));
             }
-            let sp = node.start_position();
-            let ep = node.end_position();
-            let name = query.capture_names()[m.captures[*cap_ix].index as usize];
-            captures.push(InspectCapture {
-                capture: name.to_string(),
-                group: map_capture_to_group(name),
-                priority: capture_priority(name),
-                lnum: sp.row as u32 + 1,
-                col: sp.column as u32 + 1,
-                end_lnum: ep.row as u32 + 1,
-                end_col: ep.column as u32 + 1,
-                applied: false,
-                injected_lang,
-            });
         }
     }
 
-    // Mark the winner per exact span with the rule run_highlight_cached applies:
-    // strictly greater priority replaces, so the first of a tie survives.
-    let mut winners = HashMap::<(u32, u32, u32, u32), usize>::new();
-    for index in 0..captures.len() {
-        if captures[index].group.is_empty() {
-            continue;
+    let mut data = Vec::new();
+    let (mut prev_line, mut prev_start) = (0, 0);
+    for (lnum, line_pieces) in pieces {
+        let tokens = innermost_segments(line_pieces);
+        let text = line_text(lnum);
+        let (mut byte, mut unit) = (0, 0);
+        let mut units_at = |offset: usize| {
+            let offset = offset.min(text.len());
+            unit += text
+                .get(byte..offset)
+                .map_or(0, |piece| encoding.units(piece));
+            byte = offset;
+            unit
+        };
+        let line = lnum - 1;
+        for (start, end, (token_type, modifiers)) in tokens {
+            let start = units_at(start);
+            let end = units_at(end);
+            let delta_start = if line == prev_line {
+                start - prev_start
+            } else {
+                start
+            };
+            data.extend([
+                line - prev_line,
+                delta_start,
+                end - start,
+                token_type,
+                modifiers,
+            ]);
+            (prev_line, prev_start) = (line, start);
         }
-        let key = (
-            captures[index].lnum,
-            captures[index].col,
-            captures[index].end_lnum,
-            captures[index].end_col,
-        );
-        match winners.get(&key) {
-            Some(&best) if captures[index].priority <= captures[best].priority => {}
-            _ => {
-                winners.insert(key, index);
-            }
+    }
+    data
+}
+
+/// Flatten possibly nested `(start, end, value)` pieces into disjoint ones in
+/// offset order. Where pieces nest the innermost one wins, and neighbouring
+/// results with the same value are joined.
+fn innermost_segments<T: Copy + PartialEq>(
+    mut pieces: Vec<(usize, usize, T)>,
+) -> Vec<(usize, usize, T)> {
+    // Outer pieces first, so the stack below keeps the innermost on top.
+    pieces.sort_by_key(|&(start, end, _)| (start, std::cmp::Reverse(end)));
+    let mut bounds: Vec<usize> = pieces
+        .iter()
+        .flat_map(|&(start, end, _)| [start, end])
+        .collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+    let mut segments: Vec<(usize, usize, T)> = Vec::new();
+    let mut open: Vec<(usize, usize, T)> = Vec::new();
+    let mut next = pieces.into_iter().peekable();
+    for window in bounds.windows(2) {
+        let (from, to) = (window[0], window[1]);
+        while open.last().is_some_and(|piece| piece.1 <= from) {
+            open.pop();
+        }
+        while let Some(piece) = next.next_if(|piece| piece.0 == from) {
+            open.push(piece);
+        }
+        let Some(&(.., value)) = open.last() else {
+            continue;
+        };
+        match segments.last_mut() {
+            Some(last) if last.1 == from && last.2 == value => last.1 = to,
+            _ => segments.push((from, to, value)),
         }
     }
-    for index in winners.into_values() {
-        captures[index].applied = true;
+    segments
+}
+
+fn map_symbol_capture(name: &str) -> &'static str {
+    match name {
+        "symbol.function" => "function",
+        "symbol.method" => "method",
+        "symbol.type" => "type",
+        "symbol.struct" => "struct",
+        "symbol.enum" => "enum",
+        "symbol.class" => "class",
+        "symbol.namespace" => "namespace",
+        "symbol.variable" => "variable",
+        "symbol.const" => "const",
+        "symbol.macro" => "macro",
+        "symbol.property" => "property",
+        "symbol.field" => "field",
+        "symbol.variant" => "variant",
+        _ => "",
     }
-    // Most specific first: the report's first line should be the answer.
-    captures.sort_by_key(|capture| std::cmp::Reverse(capture.priority));
+}
 
-    Ok((cache.revision, captures, node_chain))
+#[allow(dead_code)]
+fn is_ident_char(b: u8) -> bool {
+    (b as char).is_ascii_alphanumeric() || b == b'_'
 }
 
-/// Grammar node kind -> text-object class, per language.
+fn node_text(node: tree_sitter::Node, bytes: &[u8]) -> String {
+    let s = &bytes[node.start_byte()..node.end_byte()];
+    String::from_utf8_lossy(s).to_string()
+}
+
+/// 收集树中指定 kind 的全部节点区间（不递归进匹配到的节点内部）。
+/// True when an injected grammar owns every byte of `node`.
 ///
-/// A table and not a name heuristic: `block` is a lexical block in Rust and a
-/// mapping entry in other grammars, `class` is a JavaScript class expression
-/// and a C++ storage specifier. A text object that matches the wrong node
-/// silently edits the wrong text, which is far worse than not answering.
-fn scope_table(lang: &str) -> &'static [(&'static str, &'static str)] {
-    match lang {
-        "rust" => &[
-            ("function_item", "function"),
-            ("function_signature_item", "function"),
-            ("closure_expression", "function"),
-            ("struct_item", "class"),
-            ("enum_item", "class"),
-            ("union_item", "class"),
-            ("trait_item", "class"),
-            ("impl_item", "class"),
-            ("mod_item", "class"),
-            ("block", "block"),
-            ("call_expression", "call"),
-            ("macro_invocation", "call"),
-            ("if_expression", "conditional"),
-            ("match_expression", "conditional"),
-            ("for_expression", "loop"),
-            ("while_expression", "loop"),
-            ("loop_expression", "loop"),
-        ],
-        "c" | "cpp" => &[
-            ("function_definition", "function"),
-            ("lambda_expression", "function"),
-            ("struct_specifier", "class"),
-            ("union_specifier", "class"),
-            ("enum_specifier", "class"),
-            ("class_specifier", "class"),
-            ("namespace_definition", "class"),
-            ("compound_statement", "block"),
-            ("call_expression", "call"),
-            ("if_statement", "conditional"),
-            ("switch_statement", "conditional"),
-            ("for_statement", "loop"),
-            ("for_range_loop", "loop"),
-            ("while_statement", "loop"),
-            ("do_statement", "loop"),
-        ],
-        // One table for the whole JavaScript family: the TypeScript-only kinds
-        // simply never appear in a JavaScript tree, so splitting it would only
-        // create two places to forget an entry.
-        "javascript" | "typescript" | "tsx" => &[
-            ("function_declaration", "function"),
-            ("function_expression", "function"),
-            ("generator_function", "function"),
-            ("generator_function_declaration", "function"),
-            ("arrow_function", "function"),
-            ("method_definition", "function"),
-            ("class_declaration", "class"),
-            ("class", "class"),
-            ("abstract_class_declaration", "class"),
-            ("interface_declaration", "class"),
-            ("enum_declaration", "class"),
-            ("type_alias_declaration", "class"),
-            ("internal_module", "class"),
-            ("statement_block", "block"),
-            ("call_expression", "call"),
-            ("new_expression", "call"),
-            ("if_statement", "conditional"),
-            ("switch_statement", "conditional"),
-            ("ternary_expression", "conditional"),
-            ("for_statement", "loop"),
-            ("for_in_statement", "loop"),
-            ("while_statement", "loop"),
-            ("do_statement", "loop"),
-        ],
-        "python" => &[
-            ("function_definition", "function"),
-            ("lambda", "function"),
-            ("class_definition", "class"),
-            ("block", "block"),
-            ("call", "call"),
-            ("if_statement", "conditional"),
-            ("match_statement", "conditional"),
-            ("for_statement", "loop"),
-            ("while_statement", "loop"),
-        ],
-        "go" => &[
-            ("function_declaration", "function"),
-            ("method_declaration", "function"),
-            ("func_literal", "function"),
-            ("type_declaration", "class"),
-            ("type_spec", "class"),
-            ("block", "block"),
-            ("call_expression", "call"),
-            ("if_statement", "conditional"),
-            ("expression_switch_statement", "conditional"),
-            ("type_switch_statement", "conditional"),
-            ("for_statement", "loop"),
-        ],
-        "bash" => &[
-            ("function_definition", "function"),
-            ("compound_statement", "block"),
-            ("command", "call"),
-            ("if_statement", "conditional"),
-            ("case_statement", "conditional"),
-            ("for_statement", "loop"),
-            ("while_statement", "loop"),
-        ],
-        "lua" => &[
-            ("function_declaration", "function"),
-            ("function_definition", "function"),
-            ("block", "block"),
-            ("function_call", "call"),
-            ("if_statement", "conditional"),
-            ("for_statement", "loop"),
-            ("while_statement", "loop"),
-            ("repeat_statement", "loop"),
-        ],
-        "vim" => &[
-            ("def_function", "function"),
-            ("function_definition", "function"),
-            ("if_statement", "conditional"),
-            ("for_statement", "loop"),
-            ("while_statement", "loop"),
-        ],
-        "julia" => &[
-            ("function_definition", "function"),
-            ("macro_definition", "function"),
-            ("struct_definition", "class"),
-            ("module_definition", "class"),
-            ("compound_statement", "block"),
-            ("call_expression", "call"),
-            ("if_statement", "conditional"),
-            ("try_statement", "conditional"),
-            ("for_statement", "loop"),
-            ("while_statement", "loop"),
-        ],
-        "haskell" => &[
-            ("function", "function"),
-            ("lambda", "function"),
-            ("class", "class"),
-            ("data_type", "class"),
-            ("newtype", "class"),
-            ("apply", "call"),
-            ("conditional", "conditional"),
-            ("case", "conditional"),
-            ("do", "block"),
-        ],
-        "css" => &[("rule_set", "class"), ("block", "block")],
-        _ => &[],
+/// The ranges are in document order, so a binary search finds the only
+/// candidate; a highlight pass calls this once per host capture.
+fn covered_by_injection(ranges: &[ops::Range<usize>], node: tree_sitter::Node) -> bool {
+    if ranges.is_empty() {
+        return false;
+    }
+    let start = node.start_byte();
+    let index = match ranges.binary_search_by_key(&start, |range| range.start) {
+        Ok(index) => index,
+        Err(0) => return false,
+        Err(index) => index - 1,
+    };
+    ranges[index].start <= start && node.end_byte() <= ranges[index].end
+}
+
+/// Map a fence info string to a bundled or loaded grammar, or `None`.
+///
+/// The aliases are the ones people actually write in fences; anything not
+/// resolving to a linked grammar is skipped rather than guessed at.
+fn injection_language_for_tag(tag: &str) -> Option<&'static str> {
+    let normalized = tag.trim().to_ascii_lowercase();
+    let name = match normalized.as_str() {
+        "rs" => "rust",
+        "js" | "jsx" | "mjs" | "cjs" | "node" => "javascript",
+        "ts" => "typescript",
+        "tsx" => "tsx",
+        "py" | "python3" => "python",
+        "sh" | "shell" | "zsh" | "console" => "bash",
+        "golang" => "go",
+        "c++" | "cxx" | "cc" | "hpp" | "hh" => "cpp",
+        "h" => "c",
+        "yml" => "yaml",
+        "vim9" | "viml" => "vim",
+        "md" => "markdown",
+        "jl" => "julia",
+        "hs" => "haskell",
+        other => other,
+    };
+    SUPPORTED_LANGUAGES
+        .iter()
+        .copied()
+        .find(|supported| *supported == name)
+        .or_else(|| dynamic_grammar(name).map(|grammar| grammar.name))
+}
+
+/// Grammar for an injection language name: `markdown_inline` or anything
+/// `injection_language_for_tag` resolves.
+fn injection_target(name: &str) -> Option<&'static str> {
+    match name {
+        "markdown_inline" => Some("markdown_inline"),
+        _ => injection_language_for_tag(name),
     }
 }
 
-/// True for nodes whose named children are individually addressable as
-/// parameters or arguments.
+/// `#set! injection.language "..."` of a pattern.
+fn fixed_injection_language(query: &tree_sitter::Query, pattern: usize) -> Option<&str> {
+    query
+        .property_settings(pattern)
+        .iter()
+        .find(|property| &*property.key == "injection.language")
+        .and_then(|property| property.value.as_deref())
+}
+
+/// Language named by an `@injection.language` node: a fence's info string, a
+/// `lang` attribute, a `# sql` comment. Comment leaders are skipped and only
+/// the first word counts, so ```` ```rust ignore ```` is rust.
+fn injection_language_at(node: tree_sitter::Node, text: &str) -> Option<&'static str> {
+    // tree-sitter-md wraps a fence's tag in a `language` node; older trees
+    // leave the whole info string bare.
+    let tag = descendant_by_kind(node, "language").unwrap_or(node);
+    let raw = text.get(tag.start_byte()..tag.end_byte())?;
+    let word = raw
+        .trim_start_matches(|c: char| !c.is_alphanumeric())
+        .split_whitespace()
+        .next()?;
+    injection_language_for_tag(word)
+}
+
+/// The parts of `range` that lie inside `within`, which is sorted and
+/// non-overlapping.
+fn clip_to_ranges(
+    range: tree_sitter::Range,
+    within: &[tree_sitter::Range],
+) -> impl Iterator<Item = tree_sitter::Range> + '_ {
+    let first = within.partition_point(|outer| outer.end_byte <= range.start_byte);
+    within[first..]
+        .iter()
+        .take_while(move |outer| outer.start_byte < range.end_byte)
+        .map(move |outer| {
+            let (start_byte, start_point) = if outer.start_byte > range.start_byte {
+                (outer.start_byte, outer.start_point)
+            } else {
+                (range.start_byte, range.start_point)
+            };
+            let (end_byte, end_point) = if outer.end_byte < range.end_byte {
+                (outer.end_byte, outer.end_point)
+            } else {
+                (range.end_byte, range.end_point)
+            };
+            tree_sitter::Range {
+                start_byte,
+                end_byte,
+                start_point,
+                end_point,
+            }
+        })
+}
+
+/// Byte offset of `point`, found by walking lines from `anchor`, a byte and
+/// point known to coincide. Columns past the end of a line are clamped to it.
+fn byte_at_point(
+    text: &str,
+    anchor: (usize, tree_sitter::Point),
+    point: tree_sitter::Point,
+) -> usize {
+    let (mut line_start, mut row) = (anchor.0 - anchor.1.column, anchor.1.row);
+    while row < point.row {
+        match text[line_start..].find('\n') {
+            Some(newline) => line_start += newline + 1,
+            None => break,
+        }
+        row += 1;
+    }
+    while row > point.row && line_start > 0 {
+        line_start = text[..line_start - 1]
+            .rfind('\n')
+            .map_or(0, |newline| newline + 1);
+        row -= 1;
+    }
+    let line_end = text[line_start..]
+        .find('\n')
+        .map_or(text.len(), |newline| line_start + newline);
+    (line_start + point.column).min(line_end)
+}
+
+/// Injected ranges of a tree per its injections query, in document order and
+/// non-overlapping, at most `limit` of them.
 ///
-/// Classifying by the container and not by a per-language list of parameter
-/// node kinds, because a JavaScript parameter is a bare `identifier` — a kind
-/// that means something else in every other position in the tree.
-fn is_parameter_container(kind: &str) -> bool {
-    matches!(
-        kind,
-        "parameters"
-            | "formal_parameters"
-            | "parameter_list"
-            | "lambda_parameters"
-            | "type_parameters"
-            | "type_parameter_list"
-            | "arguments"
-            | "argument_list"
-            | "type_arguments"
-    )
+/// A match inside another match's range is dropped: what is nested inside an
+/// injection is for the injected grammar's own query to find, one level down.
+/// For the very same range a later pattern wins, which lets a specific
+/// pattern follow a catch-all one.
+fn collect_injection_ranges(
+    (query, predicates): &(tree_sitter::Query, QueryPredicates),
+    root: tree_sitter::Node,
+    text: &str,
+    limit: usize,
+) -> Vec<(&'static str, tree_sitter::Range)> {
+    let bytes = text.as_bytes();
+    let content_index = query.capture_index_for_name("injection.content");
+    let language_index = query.capture_index_for_name("injection.language");
+    let mut found: Vec<(usize, &'static str, tree_sitter::Range)> = Vec::new();
+    let mut cursor = tree_sitter::QueryCursor::new();
+    let mut matches = cursor.matches(query, root, bytes);
+    while let Some(m) = matches.next() {
+        if found.len() >= limit {
+            break;
+        }
+        if !predicates.accepts(m, bytes) {
+            continue;
+        }
+        let target = match fixed_injection_language(query, m.pattern_index) {
+            Some(name) => injection_target(name),
+            None => language_index
+                .and_then(|index| m.nodes_for_capture_index(index).next())
+                .and_then(|node| injection_language_at(node, text)),
+        };
+        let Some(target) = target else {
+            continue;
+        };
+        for cap in m.captures {
+            if Some(cap.index) != content_index {
+                continue;
+            }
+            let node = cap.node;
+            let (start_point, end_point) = predicates.capture_points(m.pattern_index, *cap);
+            let anchor = (node.start_byte(), node.start_position());
+            let range = tree_sitter::Range {
+                start_byte: byte_at_point(text, anchor, start_point),
+                end_byte: byte_at_point(text, anchor, end_point),
+                start_point,
+                end_point,
+            };
+            if range.end_byte > range.start_byte {
+                found.push((m.pattern_index, target, range));
+            }
+        }
+    }
+    found.sort_by_key(|(pattern, _, range)| {
+        (
+            range.start_byte,
+            std::cmp::Reverse(range.end_byte),
+            std::cmp::Reverse(*pattern),
+        )
+    });
+    let mut ranges = Vec::with_capacity(found.len());
+    let mut covered_to = 0;
+    for (_, target, range) in found {
+        if range.start_byte < covered_to {
+            continue;
+        }
+        covered_to = range.end_byte;
+        ranges.push((target, range));
+    }
+    ranges
 }
 
-fn scope_kind(lang: &str, node: tree_sitter::Node) -> Option<&'static str> {
-    let kind = node.kind();
-    // Spelled the same in every bundled grammar, so it does not earn a row in
-    // seventeen tables.
-    if matches!(kind, "comment" | "line_comment" | "block_comment") {
-        return Some("comment");
+// ---- Rust-specific helpers (保持不变) ----
+fn ancestor_kind<'a>(mut node: tree_sitter::Node<'a>, want: &str) -> Option<tree_sitter::Node<'a>> {
+    while let Some(parent) = node.parent() {
+        if parent.kind() == want {
+            return Some(parent);
+        }
+        node = parent;
     }
-    if let Some((_, class)) = scope_table(lang).iter().find(|(k, _)| *k == kind) {
-        return Some(class);
+    None
+}
+
+fn definition_node<'a>(
+    node: tree_sitter::Node<'a>,
+    lang: &str,
+    kind: &str,
+) -> tree_sitter::Node<'a> {
+    let candidates: &[&str] = match (lang, kind) {
+        ("rust", "function" | "method") => &["function_item"],
+        ("rust", "struct") => &["struct_item"],
+        ("rust", "enum") => &["enum_item"],
+        ("rust", "type") => &["trait_item", "type_item"],
+        ("rust", "const") => &["const_item", "static_item"],
+        ("rust", "namespace") => &["mod_item"],
+        ("rust", "macro") => &["macro_definition"],
+        ("rust", "field") => &["field_declaration"],
+        ("rust", "variant") => &["enum_variant"],
+
+        ("c" | "cpp", "function" | "method") => {
+            &["function_definition", "field_declaration", "declaration"]
+        }
+        ("c" | "cpp", "class") => &["class_specifier"],
+        ("c" | "cpp", "struct") => &["struct_specifier", "type_definition"],
+        ("c" | "cpp", "enum") => &["enum_specifier"],
+        ("c" | "cpp", "namespace") => &["namespace_definition"],
+        ("c" | "cpp", "type") => &["alias_declaration", "type_definition"],
+        ("c" | "cpp", "variable") => &["declaration"],
+
+        ("javascript", "function") => &["function_declaration"],
+        ("javascript", "method") => &["method_definition"],
+        ("javascript", "class") => &["class_declaration"],
+        ("javascript", "variable") => &["variable_declarator", "variable_declaration"],
+
+        ("typescript" | "tsx", "function") => &["function_declaration", "function_signature"],
+        ("typescript" | "tsx", "method") => &[
+            "method_definition",
+            "method_signature",
+            "abstract_method_signature",
+        ],
+        ("typescript" | "tsx", "class") => &["class_declaration", "abstract_class_declaration"],
+        ("typescript" | "tsx", "type") => &["interface_declaration", "type_alias_declaration"],
+        ("typescript" | "tsx", "enum") => &["enum_declaration"],
+        ("typescript" | "tsx", "namespace") => &["internal_module", "module"],
+        ("typescript" | "tsx", "variable") => &["variable_declarator", "variable_declaration"],
+        ("typescript" | "tsx", "field") => &["public_field_definition", "property_signature"],
+        // 变体本身就是叶子；有赋值时也只取名字，避免退化到整个 enum_body。
+        ("typescript" | "tsx", "variant") => &["property_identifier"],
+
+        ("json", "property" | "field") => &["pair"],
+        ("yaml", "property" | "field") => &["block_mapping_pair"],
+        ("toml", "namespace") => &["table", "table_array_element"],
+        ("toml", "property") => &["pair"],
+
+        ("python", "function" | "method") => &["function_definition"],
+        ("python", "class") => &["class_definition"],
+        ("python", "variable") => &["assignment", "expression_statement"],
+        ("python", "type") => &["type_alias_statement"],
+
+        ("go", "function") => &["function_declaration"],
+        ("go", "method") => &["method_declaration"],
+        ("go", "type") => &["type_spec", "type_alias"],
+        ("go", "const") => &["const_spec", "const_declaration"],
+        ("go", "variable") => &["var_spec", "var_declaration"],
+        ("go", "field") => &["field_declaration"],
+
+        // 标签名只是起始标签的一部分；取整个元素，<script> 里的符号才有处可挂。
+        ("html", "function") => &["script_element", "style_element"],
+        ("html", "namespace" | "class") => &["element"],
+
+        ("bash", "function") => &["function_definition"],
+        ("bash", "variable") => &["variable_assignment"],
+
+        ("vim", "function") => &["def_function"],
+        ("vim", "variable" | "const") => &["let_statement", "const_statement"],
+        _ => &[],
+    };
+
+    let mut current = Some(node);
+    while let Some(candidate) = current {
+        if candidates.contains(&candidate.kind()) {
+            return candidate;
+        }
+        current = candidate.parent();
     }
-    // Reached only when the node has no class of its own, which is what keeps a
-    // closure argument answering to `af` rather than to `aa`.
-    if node.is_named()
-        && let Some(parent) = node.parent()
-        && is_parameter_container(parent.kind())
-    {
-        return Some("parameter");
+    node.parent().unwrap_or(node)
+}
+
+/// Depth-first search for the first descendant (including `node`) of `kind`.
+fn descendant_by_kind<'a>(
+    node: tree_sitter::Node<'a>,
+    kind: &str,
+) -> Option<tree_sitter::Node<'a>> {
+    if node.kind() == kind {
+        return Some(node);
+    }
+    let child_count = node.child_count().min(u32::MAX as usize);
+    for index in 0..child_count {
+        if let Some(child) = node.child(index as u32)
+            && let Some(found) = descendant_by_kind(child, kind)
+        {
+            return Some(found);
+        }
     }
     None
 }
 
-/// Byte range of the "inner" half of a text object: the node's body with its
-/// delimiters and the whitespace they left behind removed.
-///
-/// `consequence` and `arguments` are listed beside `body` because a conditional
-/// and a call keep their interesting child in a differently named field, and
-/// falling back to the node itself is what makes a delimiter-only node such as
-/// a Rust `block` still produce a useful inner range.
-fn inner_byte_range(node: tree_sitter::Node, text: &str) -> (usize, usize) {
-    let target = ["body", "consequence", "arguments"]
-        .into_iter()
-        .find_map(|field| node.child_by_field_name(field))
-        .unwrap_or(node);
-    let mut start = target.start_byte();
-    let mut end = target.end_byte();
-    let count = target.child_count().min(u32::MAX as usize);
-    if count >= 2
-        && let (Some(first), Some(last)) = (target.child(0), target.child(count as u32 - 1))
-        && !first.is_named()
-        && !last.is_named()
-        && matches!(first.kind(), "{" | "(" | "[")
-        && matches!(last.kind(), "}" | ")" | "]")
-    {
-        start = first.end_byte();
-        end = last.start_byte();
+fn child_text_by_kind(node: tree_sitter::Node, child_kind: &str, bytes: &[u8]) -> Option<String> {
+    let mut cursor = node.walk();
+    for ch in node.children(&mut cursor) {
+        if ch.kind() == child_kind {
+            return Some(node_text(ch, bytes));
+        }
     }
-    // Without this, `dif` on a function body deletes the newline the opening
-    // brace sat on and leaves the closing brace hanging off the signature.
-    let bytes = text.as_bytes();
-    while start < end && bytes[start].is_ascii_whitespace() {
-        start += 1;
+    None
+}
+fn child_pos_by_kind(node: tree_sitter::Node, child_kind: &str) -> Option<(u32, u32)> {
+    let mut cursor = node.walk();
+    for ch in node.children(&mut cursor) {
+        if ch.kind() == child_kind {
+            let sp = ch.start_position();
+            return Some((sp.row as u32 + 1, sp.column as u32 + 1));
+        }
     }
-    while end > start && bytes[end - 1].is_ascii_whitespace() {
-        end -= 1;
+    None
+}
+fn struct_info(node: tree_sitter::Node, bytes: &[u8]) -> Option<(String, u32, u32)> {
+    if let Some(st) = ancestor_kind(node, "struct_item")
+        && let Some(name) = child_text_by_kind(st, "type_identifier", bytes)
+        && let Some((ln, co)) = child_pos_by_kind(st, "type_identifier")
+    {
+        return Some((name, ln, co));
     }
-    (start, end)
+    None
+}
+fn enum_info(node: tree_sitter::Node, bytes: &[u8]) -> Option<(String, u32, u32)> {
+    if let Some(en) = ancestor_kind(node, "enum_item")
+        && let Some(name) = child_text_by_kind(en, "type_identifier", bytes)
+        && let Some((ln, co)) = child_pos_by_kind(en, "type_identifier")
+    {
+        return Some((name, ln, co));
+    }
+    None
 }
-
-/// Outer range of a parameter or argument: the node plus the separator that
-/// would otherwise be left behind.
-///
-/// `daa` on the middle argument of `f(a, b, c)` has to leave `f(a, c)` and not
-/// `f(a, , c)`; on the last one there is no following comma, so the preceding
-/// one is taken instead.
-fn parameter_outer_byte_range(node: tree_sitter::Node, text: &str) -> (usize, usize) {
-    let start = node.start_byte();
-    let end = node.end_byte();
-    let mut sibling = node.next_sibling();
-    while let Some(next) = sibling {
-        if next.is_named() {
-            break;
-        }
-        if next.kind() == "," {
-            // Whitespace is not in the tree, so swallow the run after the comma
-            // by hand or `f(a, b)` loses `a,` and keeps its space.
-            let mut after = next.end_byte();
-            let bytes = text.as_bytes();
-            while after < text.len() && (bytes[after] == b' ' || bytes[after] == b'\t') {
-                after += 1;
-            }
-            return (start, after);
+fn variant_info(node: tree_sitter::Node, bytes: &[u8]) -> Option<(String, u32, u32)> {
+    let mut cur = node;
+    while let Some(parent) = cur.parent() {
+        if parent.kind() == "enum_variant"
+            && let Some(name) = child_text_by_kind(parent, "identifier", bytes)
+            && let Some((ln, co)) = child_pos_by_kind(parent, "identifier")
+        {
+            return Some((name, ln, co));
         }
-        sibling = next.next_sibling();
+        cur = parent;
     }
-    let mut previous = node.prev_sibling();
-    while let Some(prior) = previous {
-        if prior.is_named() {
-            break;
+    None
+}
+fn impl_type_info(node: tree_sitter::Node, bytes: &[u8]) -> Option<(String, u32, u32)> {
+    if let Some(im) = ancestor_kind(node, "impl_item") {
+        let mut last: Option<(String, u32, u32)> = None;
+        let mut cursor = im.walk();
+        for ch in im.children(&mut cursor) {
+            if ch.kind() == "type_identifier" || ch.kind() == "identifier" {
+                let name = node_text(ch, bytes);
+                let sp = ch.start_position();
+                last = Some((name, sp.row as u32 + 1, sp.column as u32 + 1));
+            }
         }
-        if prior.kind() == "," {
-            return (prior.start_byte(), end);
+        if let Some(x) = last {
+            return Some(x);
         }
-        previous = prior.prev_sibling();
     }
-    (start, end)
+    None
 }
-
-fn scope_node(node: tree_sitter::Node, cache: &BufCache) -> ScopeNode {
-    let kind = scope_kind(&cache.lang, node);
-    let is_parameter = kind == Some("parameter");
-    // The common case is the node's own range, and the node already knows its
-    // points; only the parameter form has to be located in the text.
-    let (outer_sp, outer_ep) = if is_parameter {
-        let (start, end) = parameter_outer_byte_range(node, &cache.text);
-        (
-            cache.line_index.point_at_byte(&cache.text, start),
-            cache.line_index.point_at_byte(&cache.text, end),
-        )
-    } else {
-        (node.start_position(), node.end_position())
-    };
-    // A parameter has no body to reach into, so its inner half is the node
-    // itself — that is exactly the difference `daa` vs `dia` is asked to make.
-    let (inner_start, inner_end) = if is_parameter {
-        (node.start_byte(), node.end_byte())
-    } else {
-        inner_byte_range(node, &cache.text)
-    };
-    let inner_sp = cache.line_index.point_at_byte(&cache.text, inner_start);
-    let inner_ep = cache.line_index.point_at_byte(&cache.text, inner_end);
-    ScopeNode {
-        node: node.kind(),
-        kind,
-        lnum: outer_sp.row as u32 + 1,
-        col: outer_sp.column as u32 + 1,
-        end_lnum: outer_ep.row as u32 + 1,
-        end_col: outer_ep.column as u32 + 1,
-        inner_lnum: inner_sp.row as u32 + 1,
-        inner_col: inner_sp.column as u32 + 1,
-        inner_end_lnum: inner_ep.row as u32 + 1,
-        inner_end_col: inner_ep.column as u32 + 1,
+fn mod_info(node: tree_sitter::Node, bytes: &[u8]) -> Option<(String, u32, u32)> {
+    if let Some(md) = ancestor_kind(node, "mod_item")
+        && let Some(name) = child_text_by_kind(md, "identifier", bytes)
+        && let Some((ln, co)) = child_pos_by_kind(md, "identifier")
+    {
+        return Some((name, ln, co));
     }
+    None
 }
-
-/// One `scope` answer.
-struct ScopeAnswer {
-    revision: u64,
-    /// 1-based `[lnum, col, end_lnum, end_col]` of the region in which every
-    /// point resolves to the same node and therefore to this same chain.
-    anchor: [u32; 4],
-    chain: Vec<ScopeNode>,
-}
-
-/// Byte range around `offset` in which `descendant_for_byte_range` keeps
-/// answering `node`.
-///
-/// This is *not* the node's own range: at a column of leading indentation the
-/// resolved node is the whole enclosing block, and a client that cached the
-/// block's range would keep serving that coarse chain after the cursor moved
-/// onto a statement inside it. `descendant_for_byte_range` returns the smallest
-/// node containing the point, so no child of `node` covers `offset` and the
-/// answer holds exactly across the gap between its neighbouring children.
-fn stable_byte_range(node: tree_sitter::Node, offset: usize) -> (usize, usize) {
-    let mut low = node.start_byte();
-    let mut high = node.end_byte();
-    let mut cursor = node.walk();
-    if cursor.goto_first_child() {
-        loop {
-            let child = cursor.node();
-            if child.end_byte() <= offset {
-                low = low.max(child.end_byte());
-            } else if child.start_byte() > offset {
-                high = high.min(child.start_byte());
-                break;
-            } else {
-                // A child covering the point should have been the descendant.
-                // Zero-width error/missing nodes can still land here; refuse to
-                // let the client cache anything rather than guess.
-                return (offset, offset);
+fn outer_fn_info(node: tree_sitter::Node, bytes: &[u8]) -> Option<(String, u32, u32)> {
+    let mut cur = node;
+    let mut skipped_current = false;
+    while let Some(parent) = cur.parent() {
+        if parent.kind() == "function_item" {
+            if !skipped_current {
+                skipped_current = true;
+                cur = parent;
+                continue;
             }
-            if !cursor.goto_next_sibling() {
-                break;
+            if let Some(name) = child_text_by_kind(parent, "identifier", bytes)
+                && let Some((ln, co)) = child_pos_by_kind(parent, "identifier")
+            {
+                return Some((name, ln, co));
             }
         }
+        cur = parent;
     }
-    (low, high)
+    None
 }
-
-fn scope_chain_cached(
-    server: &Server,
-    buf: i64,
-    lang: &str,
-    lnum: u32,
-    col: u32,
-) -> Result<ScopeAnswer> {
-    let cache = server.get_cache(buf, lang)?;
-    let offset = point_byte_offset(cache, lnum, col);
-    let resolved = cache
-        .tree
-        .root_node()
-        .descendant_for_byte_range(offset, offset);
-    let anchor = match resolved {
-        Some(node) => {
-            let (low, high) = stable_byte_range(node, offset);
-            let start = cache.line_index.point_at_byte(&cache.text, low);
-            let end = cache.line_index.point_at_byte(&cache.text, high);
-            [
-                start.row as u32 + 1,
-                start.column as u32 + 1,
-                end.row as u32 + 1,
-                end.column as u32 + 1,
-            ]
-        }
-        // No node at all: an empty range, so a client can only ever treat the
-        // reply as immediately stale rather than caching it forever.
-        None => [lnum, col, lnum, col],
-    };
-    let mut chain: Vec<ScopeNode> = Vec::new();
-    let mut current = resolved;
-    while let Some(node) = current {
-        // Anonymous nodes are punctuation: never a text object, and never a
-        // step a user would recognise while growing a selection.
-        if node.is_named() {
-            chain.push(scope_node(node, cache));
-            if chain.len() >= MAX_SCOPE_CHAIN {
-                break;
+#[allow(dead_code)]
+fn has_ancestor_of(node: tree_sitter::Node, kinds: &[&str]) -> bool {
+    let mut cur = node;
+    while let Some(parent) = cur.parent() {
+        for &k in kinds {
+            if parent.kind() == k {
+                return true;
             }
         }
-        current = node.parent();
+        cur = parent;
     }
-    Ok(ScopeAnswer {
-        revision: cache.revision,
-        anchor,
-        chain,
-    })
-}
-
-fn dump_ast_cached(server: &mut Server, buf: i64, lang: &str) -> Result<(u64, Vec<String>)> {
-    let cache = server.get_cache(buf, lang)?;
-    let root = cache.tree.root_node();
-    let (lines, _) = format_ast(root, MAX_AST_NODES, MAX_AST_DEPTH);
-    Ok((cache.revision, lines))
+    false
 }
 
-/// Format an AST while keeping both the result and pending traversal stack
-/// within `node_limit`. Returning the peak stack size lets regression tests
-/// verify that a very wide node cannot bypass the output budget.
-fn format_ast(
-    root: tree_sitter::Node,
-    node_limit: usize,
-    depth_limit: usize,
-) -> (Vec<String>, usize) {
-    if node_limit == 0 {
-        return (Vec::new(), 0);
-    }
-    let mut lines = Vec::new();
-    let mut stack = vec![(root, 0_usize)];
-    let mut max_pending_nodes = stack.len();
-    let mut nodes_truncated = false;
-    let mut depth_truncated = false;
-    while let Some((node, depth)) = stack.pop() {
-        if lines.len() >= node_limit {
-            nodes_truncated = true;
-            break;
-        }
-        let sp = node.start_position();
-        let ep = node.end_position();
-        let indent = (depth * 2).min(MAX_AST_INDENT);
-        let depth_marker = if depth * 2 > MAX_AST_INDENT {
-            "… "
-        } else {
-            ""
-        };
-        lines.push(format!(
-            "{:indent$}{}{} [{}:{} - {}:{}]",
-            "",
-            depth_marker,
-            node.kind(),
-            sp.row + 1,
-            sp.column + 1,
-            ep.row + 1,
-            ep.column + 1,
-            indent = indent
-        ));
+// 如需拿到父函数名，可用这个辅助（可选）
+#[allow(dead_code)]
+fn vim_func_name(node: tree_sitter::Node, bytes: &[u8]) -> Option<String> {
+    let mut cur = node;
+    while let Some(parent) = cur.parent() {
+        if parent.kind() == "function_definition" || parent.kind() == "vim9_function_definition" {
+            // 找到声明节点，取里面的名字（identifier/scoped_identifier/field_expression）
+            let mut cursor = parent.walk();
+            for ch in parent.children(&mut cursor) {
+                if ch.kind() == "function_declaration" || ch.kind() == "vim9_function_declaration" {
+                    let mut c2 = ch.walk();
+                    for nm in ch.children(&mut c2) {
+                        match nm.kind() {
+                            "identifier" | "scoped_identifier" | "field_expression" => {
+                                return Some(node_text(nm, bytes));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        cur = parent;
+    }
+    None
+}
 
-        if depth >= depth_limit {
-            depth_truncated = true;
-            continue;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answer one request line inline, without a pool or batching.
+    fn handle_line(server: &mut Server, line: &str, out: &mut dyn Write) -> Result<()> {
+        match read_request(line) {
+            None => Ok(()),
+            Some(Ok(req)) => handle_request(server, req, out),
+            Some(Err(error)) => send(out, &error),
         }
+    }
 
-        // Pending siblings already consume part of the same hard node budget.
-        // Only enqueue children that can still be visited; never materialize an
-        // arbitrarily wide child list merely to discard it on the next loop.
-        let remaining_slots = node_limit.saturating_sub(lines.len().saturating_add(stack.len()));
-        let child_count = node.child_count().min(u32::MAX as usize);
-        let scheduled_children = child_count.min(remaining_slots);
-        if scheduled_children < child_count {
-            nodes_truncated = true;
+    #[test]
+    fn symbol_request_ids_are_additive_and_echoed_on_success_and_error() {
+        let legacy: Request =
+            serde_json::from_str(r#"{"type":"symbols","buf":3,"lang":"rust"}"#).unwrap();
+        match legacy {
+            Request::Symbols { request_id, .. } => assert_eq!(request_id, 0),
+            _ => panic!("wrong legacy request variant"),
         }
-        for index in (0..scheduled_children).rev() {
-            if let Some(child) = node.child(index as u32) {
-                stack.push((child, depth + 1));
-            }
+
+        let current: Request =
+            serde_json::from_str(r#"{"type":"symbols","buf":3,"lang":"rust","request_id":77}"#)
+                .unwrap();
+        match current {
+            Request::Symbols { request_id, .. } => assert_eq!(request_id, 77),
+            _ => panic!("wrong current request variant"),
         }
-        max_pending_nodes = max_pending_nodes.max(stack.len());
+
+        let success = serde_json::to_value(Event::Symbols {
+            buf: 3,
+            revision: 9,
+            request_id: 77,
+            symbols: Some(Vec::new()),
+            digest: digest_symbols(&[]),
+            unchanged: false,
+        })
+        .unwrap();
+        assert_eq!(success["request_id"], 77);
+
+        let error = serde_json::to_value(Event::Error {
+            message: "synthetic".into(),
+            buf: Some(3),
+            op: Some("symbols"),
+            request_id: Some(77),
+            location: None,
+        })
+        .unwrap();
+        assert_eq!(error["request_id"], 77);
     }
 
-    if nodes_truncated || depth_truncated {
-        let marker = match (nodes_truncated, depth_truncated) {
-            (true, true) => {
-                format!("... AST truncated at {node_limit} nodes and depth {depth_limit} ...")
-            }
-            (true, false) => format!("... AST truncated at {node_limit} nodes ..."),
-            (false, true) => {
-                format!("... branches deeper than {depth_limit} nodes were truncated ...")
-            }
-            (false, false) => unreachable!(),
-        };
-        if lines.len() < node_limit {
-            lines.push(marker);
-        } else if let Some(last) = lines.last_mut() {
-            *last = marker;
-        }
+    #[test]
+    fn byte_offsets_use_tree_sitter_byte_columns() {
+        let text = "αβ\nhello\n世界";
+        assert_eq!(byte_offset_to_point(text, 0), tree_sitter::Point::new(0, 0));
+        assert_eq!(byte_offset_to_point(text, 4), tree_sitter::Point::new(0, 4));
+        assert_eq!(byte_offset_to_point(text, 5), tree_sitter::Point::new(1, 0));
+        assert_eq!(
+            byte_offset_to_point(text, 10),
+            tree_sitter::Point::new(1, 5)
+        );
+        assert_eq!(
+            byte_offset_to_point(text, text.len()),
+            tree_sitter::Point::new(2, 6)
+        );
     }
 
-    (lines, max_pending_nodes)
-}
+    #[test]
+    fn input_edit_handles_insert_delete_and_unicode() {
+        let cases = [
+            ("fn main() {}", "fn main() { let x = 1; }"),
+            ("one\ntwo\nthree", "one\nthree"),
+            ("let café = 1;", "let 咖啡 = 2;"),
+            ("αβγ", "αxγ"),
+            ("", "hello\nworld"),
+        ];
 
-fn bracket_depth(node: tree_sitter::Node) -> u32 {
-    let mut depth: u32 = 0;
-    let mut cur = node.parent();
-    while let Some(p) = cur {
-        // Delimited grammar nodes expose their opening/closing token as an
-        // edge child. Checking two children keeps this O(tree depth), even
-        // when the source root itself has millions of children.
-        let last_child = p.child_count().saturating_sub(1).min(u32::MAX as usize) as u32;
-        let has_brackets = [p.child(0), p.child(last_child)]
-            .into_iter()
-            .flatten()
-            .any(|child| matches!(child.kind(), "(" | ")" | "{" | "}" | "[" | "]"));
-        if has_brackets {
-            depth += 1;
+        for (old, new) in cases {
+            let edit = compute_input_edit(old, new).expect("texts differ");
+            assert!(old.is_char_boundary(edit.start_byte));
+            assert!(old.is_char_boundary(edit.old_end_byte));
+            assert!(new.is_char_boundary(edit.new_end_byte));
+            assert_eq!(
+                edit.start_position,
+                byte_offset_to_point(old, edit.start_byte)
+            );
+            assert_eq!(
+                edit.old_end_position,
+                byte_offset_to_point(old, edit.old_end_byte)
+            );
+            assert_eq!(
+                edit.new_end_position,
+                byte_offset_to_point(new, edit.new_end_byte)
+            );
         }
-        cur = p.parent();
+        assert!(compute_input_edit("same", "same").is_none());
     }
-    depth
-}
 
-fn capture_priority(name: &str) -> u8 {
-    match name {
-        "variable" => 0,
-        "constant" | "number" | "boolean" | "string" => 2,
-        "keyword" | "operator" | "punctuation.delimiter" | "punctuation.bracket" => 3,
-        "variable.parameter" | "variable.builtin" | "constant.builtin" => 5,
-        "property" | "field" | "function" | "method" | "type" | "namespace" => 8,
-        name if name.contains('.') => 9,
-        _ => 4,
+    #[test]
+    fn incremental_parse_matches_full_parse() {
+        let old = "fn main() {\n    println!(\"hello\");\n}\n";
+        let new = "fn main() {\n    let café = 42;\n    println!(\"{café}\");\n}\n";
+        let language: tree_sitter::Language = tree_sitter_rust::LANGUAGE.into();
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(&language).unwrap();
+        let mut edited_tree = parser.parse(old, None).unwrap();
+        edited_tree.edit(&compute_input_edit(old, new).unwrap());
+        let incremental = parser.parse(new, Some(&edited_tree)).unwrap();
+        let full = parser.parse(new, None).unwrap();
+        assert_eq!(
+            incremental.root_node().to_sexp(),
+            full.root_node().to_sexp()
+        );
     }
-}
-
-fn map_capture_to_group(name: &str) -> &'static str {
-    match name {
-        "comment" => "TSComment",
-        "spell" => "TSComment",
-        "string" => "TSString",
-        "string.regex" => "TStringRegex",
-        "string.escape" => "TStringEscape",
-        "string.special" => "TStringSpecial",
-        "string.documentation" => "TSString",
-        "number" => "TSNumber",
-        "boolean" => "TSBoolean",
-        "null" => "TSConstant",
 
-        "keyword" => "TSKeyword",
-        "keyword.operator" => "TSKeywordOperator",
-        "operator" => "TSOperator",
-        "punctuation.delimiter" => "TSPunctDelimiter",
-        "punctuation.bracket" => "TSPunctBracket",
-        "punctuation.special" => "TSPunctDelimiter",
+    #[test]
+    fn line_ranges_are_clamped_and_utf8_safe() {
+        let text = "one\n二\nthree";
+        assert_eq!(line_range_to_byte_range(text, 1, 1), 0..4);
+        assert_eq!(line_range_to_byte_range(text, 2, 2), 4..8);
+        assert_eq!(line_range_to_byte_range(text, 2, 3), 4..text.len());
+        assert_eq!(
+            line_range_to_byte_range(text, 99, 100),
+            text.len()..text.len()
+        );
+        assert_eq!(line_range_to_byte_range(text, 3, 1), 8..text.len());
+    }
 
-        "variable" => "TSVariable",
-        "variable.parameter" => "TSVariableParameter",
-        "variable.builtin" => "TSVariableBuiltin",
-        "variable.member" => "TSProperty",
-        "constant" => "TSConstant",
-        "constant.builtin" => "TSConstBuiltin",
+    #[test]
+    fn sparse_line_index_stays_small_and_exact_for_newline_heavy_text() {
+        let newline_count = LINE_INDEX_STRIDE * 32 + 17;
+        let text = "\n".repeat(newline_count);
+        let index = SparseLineIndex::new(&text);
 
-        "property" => "TSProperty",
-        "field" => "TSField",
+        assert_eq!(index.line_count, newline_count + 1);
+        assert_eq!(
+            index.checkpoints.len(),
+            newline_count / LINE_INDEX_STRIDE + 1
+        );
+        let checkpoint_bytes = std::mem::size_of_val(index.checkpoints.as_ref());
+        assert_eq!(
+            checkpoint_bytes,
+            index.checkpoints.len() * std::mem::size_of::<usize>()
+        );
+        assert!(
+            checkpoint_bytes <= (text.len() / LINE_INDEX_STRIDE + 1) * std::mem::size_of::<usize>()
+        );
+        assert!(index.checkpoints.len() * 200 < index.line_count);
 
-        "function" => "TSFunction",
-        "method" => "TSMethod",
-        "function.builtin" => "TSFunctionBuiltin",
-        "function.macro" => "TSMacro",
+        for line in [
+            1,
+            2,
+            LINE_INDEX_STRIDE as u32,
+            LINE_INDEX_STRIDE as u32 + 1,
+            (LINE_INDEX_STRIDE * 17 + 93) as u32,
+            newline_count as u32 + 1,
+            newline_count as u32 + 2,
+        ] {
+            let expected = usize::try_from(line.saturating_sub(1))
+                .unwrap()
+                .min(text.len());
+            assert_eq!(index.line_start_byte(&text, line), expected, "line {line}");
+        }
+    }
 
-        "type" => "TSType",
-        "type.builtin" => "TSTypeBuiltin",
-        "namespace" => "TSNamespace",
-        "macro" => "TSMacro",
-        "attribute" => "TSAttribute",
+    #[test]
+    fn bounded_ast_dump_never_queues_all_children_of_a_wide_root() {
+        let source = (0..256)
+            .map(|index| format!("int item_{index};\n"))
+            .collect::<String>();
+        let language: tree_sitter::Language = tree_sitter_c::LANGUAGE.into();
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(&language).unwrap();
+        let tree = parser.parse(&source, None).unwrap();
+        let root = tree.root_node();
+        let node_limit = 32;
 
-        "character" => "TSString",
-        "number.float" => "TSNumber",
-        "module" => "TSNamespace",
-        "constructor" => "TSType",
+        assert!(root.child_count() > node_limit);
+        let (lines, max_pending_nodes) = format_ast(root, node_limit, MAX_AST_DEPTH, None);
+        assert!(lines.len() <= node_limit);
+        assert!(max_pending_nodes <= node_limit);
+        assert!(lines.last().unwrap().contains("truncated"));
+    }
 
-        "text.title" => "TSTitle",
-        "text.literal" => "TSLiteral",
-        "text.emphasis" => "TSEmphasis",
-        "text.strong" => "TSStrong",
-        "text.strike" => "TSStrike",
-        "text.uri" => "TSURI",
-        "text.reference" => "TSLink",
+    #[test]
+    fn all_language_queries_compile() {
+        let mut server = Server::new();
+        for lang in SUPPORTED_LANGUAGES {
+            server
+                .ensure_queries(lang)
+                .unwrap_or_else(|error| panic!("failed to compile {lang} queries: {error}"));
+            let queries = server.queries.get(*lang).unwrap();
+            for capture in queries.hl_query.capture_names() {
+                assert!(
+                    capture.starts_with('_') || !map_capture_to_group(capture).is_empty(),
+                    "{lang} has unmapped highlight capture @{capture}"
+                );
+            }
+            for capture in queries.sym_query.capture_names() {
+                assert!(
+                    !map_symbol_capture(capture).is_empty(),
+                    "{lang} has unmapped symbol capture @{capture}"
+                );
+            }
+            if let Some((locals, _)) = &queries.locals_query {
+                for capture in locals.capture_names() {
+                    assert!(
+                        matches!(
+                            *capture,
+                            "local.scope" | "local.reference" | "local.definition"
+                        ) || local_definition_kind(capture) != "var"
+                            || *capture == "local.definition.var",
+                        "{lang} has unknown locals capture @{capture}"
+                    );
+                }
+            }
+            if let Some((indents, _)) = &queries.indents_query {
+                for capture in indents.capture_names() {
+                    assert!(
+                        matches!(
+                            *capture,
+                            "indent.begin"
+                                | "indent.end"
+                                | "indent.branch"
+                                | "indent.dedent"
+                                | "indent.align"
+                                | "indent.ignore"
+                        ),
+                        "{lang} has unknown indents capture @{capture}"
+                    );
+                }
+            }
+        }
+        assert_eq!(server.queries.len(), SUPPORTED_LANGUAGES.len());
 
-        name if name.starts_with("comment.") => "TSComment",
-        name if name.starts_with("string.special") => "TStringSpecial",
-        name if name.starts_with("function.") => "TSFunction",
-        name if name.starts_with("keyword.") => "TSKeyword",
-        name if name.starts_with("type.") => "TSType",
-        _ => "",
+        // ensure_queries() compiles every fixed injection target too, so a
+        // broken injected query fails --self-test rather than silently leaving
+        // one construct uncoloured.
+        for (name, injection) in &server.injection_queries {
+            for capture in injection.hl_query.capture_names() {
+                assert!(
+                    !map_capture_to_group(capture).is_empty(),
+                    "injection {name} has unmapped highlight capture @{capture}"
+                );
+            }
+        }
+        for required in ["markdown_inline", "javascript", "css"] {
+            assert!(
+                server.injection_queries.contains_key(required),
+                "no compiled injection query for {required}: {:?}",
+                server.injection_queries.keys().collect::<Vec<_>>()
+            );
+        }
     }
-}
 
-fn map_symbol_capture(name: &str) -> &'static str {
-    match name {
-        "symbol.function" => "function",
-        "symbol.method" => "method",
-        "symbol.type" => "type",
-        "symbol.struct" => "struct",
-        "symbol.enum" => "enum",
-        "symbol.class" => "class",
-        "symbol.namespace" => "namespace",
-        "symbol.variable" => "variable",
-        "symbol.const" => "const",
-        "symbol.macro" => "macro",
-        "symbol.property" => "property",
-        "symbol.field" => "field",
-        "symbol.variant" => "variant",
-        _ => "",
-    }
-}
+    #[test]
+    fn markdown_highlights_symbols_and_inline_tree() {
+        let mut server = Server::new();
+        let source = "# Top\n\nSome *emphasis*, **strong**, `code span` and \
+                      [a link](https://example.com).\n\n## Second level\n\n\
+                      ```rust\nfn main() {}\n```\n\n- [x] done task\n\n\
+                      Setext Title\n============\n";
+        server
+            .set_text(9, "markdown", source.to_string(), 1)
+            .expect("markdown parse");
+        let injected: Vec<&str> = server
+            .cache
+            .get(&9)
+            .unwrap()
+            .injections
+            .iter()
+            .map(|injection| injection.lang)
+            .collect();
+        assert!(
+            injected.contains(&"markdown_inline"),
+            "inline tree must be built for markdown: {injected:?}"
+        );
+        assert!(
+            injected.contains(&"rust"),
+            "a ```rust fence must inject the rust grammar: {injected:?}"
+        );
 
-#[allow(dead_code)]
-fn is_ident_char(b: u8) -> bool {
-    (b as char).is_ascii_alphanumeric() || b == b'_'
-}
+        let (_, spans) =
+            run_highlight_cached(&mut server, 9, "markdown", None, false, None).unwrap();
+        let groups: Vec<&str> = spans.iter().map(|s| s.group).collect();
+        for expected in [
+            "TSTitle",    // headings (block)
+            "TSEmphasis", // *emphasis* (inline)
+            "TSStrong",   // **strong** (inline)
+            "TSLiteral",  // `code span` (the fence content is injected instead)
+            "TSLink",     // [a link]
+            "TSURI",      // (https://example.com)
+            "TSType",     // fence info string "rust"
+            "TSBoolean",  // task list marker
+        ] {
+            assert!(
+                groups.contains(&expected),
+                "missing {expected} in {groups:?}"
+            );
+        }
 
-fn node_text(node: tree_sitter::Node, bytes: &[u8]) -> String {
-    let s = &bytes[node.start_byte()..node.end_byte()];
-    String::from_utf8_lossy(s).to_string()
-}
+        let (_, symbols) = run_symbols_cached(&mut server, 9, "markdown", None, None).unwrap();
+        let names: Vec<(&str, &str)> = symbols.iter().map(|s| (s.kind, s.name.as_str())).collect();
+        assert!(names.contains(&("namespace", "Top")), "{names:?}");
+        assert!(names.contains(&("class", "Second level")), "{names:?}");
+        assert!(names.contains(&("namespace", "Setext Title")), "{names:?}");
 
-/// 收集树中指定 kind 的全部节点区间（不递归进匹配到的节点内部）。
-/// True when an injected grammar owns every byte of `node`.
-///
-/// The ranges are in document order, so a binary search finds the only
-/// candidate; a highlight pass calls this once per host capture.
-fn covered_by_injection(ranges: &[ops::Range<usize>], node: tree_sitter::Node) -> bool {
-    if ranges.is_empty() {
-        return false;
+        // Plain text without emphasis/links: the inline pass must not panic and
+        // the block-only path still renders. Also exercises the resync path.
+        server
+            .set_text(9, "markdown", "# Only heading\n".to_string(), 2)
+            .expect("markdown reparse");
+        let (_, spans) =
+            run_highlight_cached(&mut server, 9, "markdown", None, false, None).unwrap();
+        assert!(spans.iter().any(|s| s.group == "TSTitle"));
     }
-    let start = node.start_byte();
-    let index = match ranges.binary_search_by_key(&start, |range| range.start) {
-        Ok(index) => index,
-        Err(0) => return false,
-        Err(index) => index - 1,
-    };
-    ranges[index].start <= start && node.end_byte() <= ranges[index].end
-}
 
-/// Which grammar an injection rule hands its content to.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum InjectionTarget {
-    /// Always this grammar, whatever the content says.
-    Fixed(&'static str),
-    /// Read it off the fence's info string, e.g. ```` ```rust ````. Unknown or
-    /// absent languages are skipped, which is why an unlabelled fence keeps the
-    /// host's own `@text.literal`.
-    InfoString,
-}
-
-/// One "this node's content is another language" rule.
-struct InjectionRule {
-    /// Node kind that introduces the injection.
-    host: &'static str,
-    /// Named descendant holding the injected text; `None` injects the host node
-    /// itself, which is what markdown's `inline` needs.
-    content: Option<&'static str>,
-    target: InjectionTarget,
-}
-
-/// Injection rules for a host language.
-///
-/// Depth is capped at one by construction: `collect_injection_ranges` does not
-/// descend into a node it has already matched, so a fence inside a fence is
-/// left to the outer grammar and the cost of a sync stays linear.
-fn injection_rules(lang: &str) -> &'static [InjectionRule] {
-    match lang {
-        "markdown" => &[
-            // The block grammar leaves every span of prose as one opaque
-            // `inline` node; without this, emphasis and links are invisible.
-            InjectionRule {
-                host: "inline",
-                content: None,
-                target: InjectionTarget::Fixed("markdown_inline"),
-            },
-            InjectionRule {
-                host: "fenced_code_block",
-                content: Some("code_fence_content"),
-                target: InjectionTarget::InfoString,
-            },
-        ],
-        "html" => &[
-            InjectionRule {
-                host: "script_element",
-                content: Some("raw_text"),
-                target: InjectionTarget::Fixed("javascript"),
-            },
-            InjectionRule {
-                host: "style_element",
-                content: Some("raw_text"),
-                target: InjectionTarget::Fixed("css"),
-            },
-        ],
-        _ => &[],
-    }
-}
+    /// A fenced block is the first thing anyone notices about markdown
+    /// highlighting, and getting it half right is worse than not doing it: the
+    /// host query paints the whole fence `@text.literal`, so if that span
+    /// survived the injection the user would see one flat colour with tokens
+    /// fighting underneath it.
+    #[test]
+    fn a_labelled_fence_is_parsed_by_its_own_grammar_in_host_coordinates() {
+        let mut server = Server::new();
+        let source = "Prose with a héllo before it.\n\n```rust\nfn main() { let x = 1; }\n```\n\n\
+                      ```\nplain fence, no language\n```\n";
+        server
+            .set_text(11, "markdown", source.to_string(), 1)
+            .unwrap();
 
-/// Map a fence info string to a bundled grammar, or `None`.
-///
-/// The aliases are the ones people actually write in fences; anything not
-/// resolving to a linked grammar is skipped rather than guessed at.
-fn injection_language_for_tag(tag: &str) -> Option<&'static str> {
-    let normalized = tag.trim().to_ascii_lowercase();
-    let name = match normalized.as_str() {
-        "rs" => "rust",
-        "js" | "jsx" | "mjs" | "cjs" | "node" => "javascript",
-        "ts" => "typescript",
-        "tsx" => "tsx",
-        "py" | "python3" => "python",
-        "sh" | "shell" | "zsh" | "console" => "bash",
-        "golang" => "go",
-        "c++" | "cxx" | "hpp" => "cpp",
-        "h" => "c",
-        "yml" => "yaml",
-        "vim9" | "viml" => "vim",
-        "md" => "markdown",
-        "jl" => "julia",
-        "hs" => "haskell",
-        other => other,
-    };
-    SUPPORTED_LANGUAGES
-        .iter()
-        .copied()
-        .find(|supported| *supported == name)
-}
+        let (_, spans) =
+            run_highlight_cached(&mut server, 11, "markdown", None, false, None).unwrap();
+
+        // `fn` is on line 4, column 1 of the *host* document: set_included_ranges
+        // keeps injected positions in the host's coordinate space, and the
+        // multi-byte character above must not shift them.
+        let keyword = spans
+            .iter()
+            .find(|span| span.group == "TSKeyword" && span.lnum == 4)
+            .unwrap_or_else(|| panic!("rust fence produced no keyword: {spans:?}"));
+        assert_eq!((keyword.col, keyword.end_col), (1, 3));
+
+        // The host's flat literal over the injected fence is gone...
+        assert!(
+            !spans
+                .iter()
+                .any(|span| span.group == "TSLiteral" && span.lnum == 4),
+            "the host @text.literal survived on top of the injected fence: {spans:?}"
+        );
+        // ...but an unlabelled fence keeps it, because nothing replaced it.
+        assert!(
+            spans
+                .iter()
+                .any(|span| span.group == "TSLiteral" && span.lnum == 8),
+            "an unlabelled fence lost its literal highlighting: {spans:?}"
+        );
+
+        // :TsHlInspect has to agree with the screen, or it answers "why is this
+        // token this colour" with a colour that is not drawn.
+        let (_, captures, _) = inspect_cached(&mut server, 11, "markdown", 4, 1).unwrap();
+        assert!(
+            captures.iter().any(
+                |capture| capture.group == "TSKeyword" && capture.injected_lang == Some("rust")
+            ),
+            "inspect did not attribute the capture to the injected grammar: {captures:?}"
+        );
+        assert!(
+            captures.iter().all(|capture| capture.group != "TSLiteral"),
+            "inspect reported a host capture the renderer drops: {captures:?}"
+        );
+    }
 
-/// Read the language tag out of a `fenced_code_block`'s info string.
-fn fence_info_language(node: tree_sitter::Node, text: &str) -> Option<&'static str> {
-    let info = descendant_by_kind(node, "info_string")?;
-    // tree-sitter-md wraps the tag in a `language` node; older trees leave the
-    // whole info string bare, so fall back to its first word.
-    let tag = descendant_by_kind(info, "language").unwrap_or(info);
-    let raw = text.get(tag.start_byte()..tag.end_byte())?;
-    injection_language_for_tag(raw.split_whitespace().next().unwrap_or(""))
-}
+    #[test]
+    fn html_injects_javascript_and_css() {
+        let mut server = Server::new();
+        let source = "<html>\n<style>\nbody { color: red; }\n</style>\n\
+                      <script>\nfunction go() { return 1; }\n</script>\n</html>\n";
+        server.set_text(12, "html", source.to_string(), 1).unwrap();
+        let injected: Vec<&str> = server
+            .cache
+            .get(&12)
+            .unwrap()
+            .injections
+            .iter()
+            .map(|injection| injection.lang)
+            .collect();
+        assert!(injected.contains(&"css"), "{injected:?}");
+        assert!(injected.contains(&"javascript"), "{injected:?}");
 
-fn collect_injection_ranges(
-    node: tree_sitter::Node,
-    rules: &'static [InjectionRule],
-    text: &str,
-    out: &mut Vec<(&'static str, tree_sitter::Range)>,
-) {
-    if out.len() >= MAX_INJECTED_RANGES {
-        return;
+        let (_, spans) = run_highlight_cached(&mut server, 12, "html", None, false, None).unwrap();
+        assert!(
+            spans
+                .iter()
+                .any(|span| span.lnum == 6 && span.group == "TSKeyword"),
+            "<script> body was not highlighted as javascript: {spans:?}"
+        );
+        assert!(
+            spans.iter().any(|span| span.lnum == 3),
+            "<style> body produced no highlighting at all: {spans:?}"
+        );
     }
-    if let Some(rule) = rules.iter().find(|rule| rule.host == node.kind()) {
-        let target = match rule.target {
-            InjectionTarget::Fixed(name) => Some(name),
-            InjectionTarget::InfoString => fence_info_language(node, text),
+
+    /// Unknown fence tags are skipped rather than guessed at, and a fence inside
+    /// a fence starts a second level of injection, up to the configured depth.
+    #[test]
+    fn injections_skip_unknown_languages_and_nest_up_to_the_depth_limit() {
+        assert_eq!(injection_language_for_tag("rs"), Some("rust"));
+        assert_eq!(injection_language_for_tag("  Python3 "), Some("python"));
+        assert_eq!(injection_language_for_tag("brainfuck"), None);
+        assert_eq!(injection_language_for_tag(""), None);
+
+        let mut server = Server::new();
+        let source = "````markdown\n```rust\nfn inner() {}\n```\n````\n\n\
+                      ```html\n<script>let x = 1;</script>\n```\n\n```brainfuck\n+\n```\n";
+        let injected = |server: &mut Server| {
+            server
+                .set_text(13, "markdown", source.to_string(), 1)
+                .unwrap();
+            let mut injected: Vec<(usize, &str)> = server.cache[&13]
+                .injections
+                .iter()
+                .map(|injection| (injection.depth, injection.lang))
+                .collect();
+            injected.sort();
+            injected
         };
-        let content = match rule.content {
-            Some(kind) => descendant_by_kind(node, kind),
-            None => Some(node),
+        assert_eq!(
+            injected(&mut server),
+            [(1, "html"), (1, "markdown"), (2, "javascript"), (2, "rust")]
+        );
+
+        // The rust one level down wins over the markdown fence around it, and
+        // that fence's delimiters over the outer markdown's literal.
+        let (_, spans) =
+            run_highlight_cached(&mut server, 13, "markdown", Some((2, 4)), false, None).unwrap();
+        let on_line = |lnum| {
+            spans
+                .iter()
+                .filter(|span| span.lnum == lnum)
+                .map(|span| span.group)
+                .collect::<Vec<_>>()
         };
-        if let (Some(target), Some(content)) = (target, content)
-            && content.end_byte() > content.start_byte()
-        {
-            out.push((target, content.range()));
-        }
-        // Matched or not, this subtree belongs to the host rule: not descending
-        // is what caps injection depth at one.
-        return;
+        assert!(on_line(3).contains(&"TSKeyword"), "{spans:?}");
+        assert!(!on_line(3).contains(&"TSLiteral"), "{spans:?}");
+
+        server.injection_depth = 1;
+        server.set_text(13, "markdown", String::new(), 2).unwrap();
+        assert_eq!(injected(&mut server), [(1, "html"), (1, "markdown")]);
+        server.injection_depth = 0;
+        server.set_text(13, "markdown", String::new(), 2).unwrap();
+        assert!(injected(&mut server).is_empty());
     }
-    let mut cursor = node.walk();
-    for child in node.children(&mut cursor) {
-        collect_injection_ranges(child, rules, text, out);
+
+    #[test]
+    fn injections_queries_name_languages_by_property_capture_or_tag() {
+        let mut server = Server::new();
+        let injected = |server: &mut Server, buf, lang: &str, source: &str| {
+            server.set_text(buf, lang, source.to_string(), 1).unwrap();
+            let cache = &server.cache[&buf];
+            let mut langs: Vec<&str> = cache.injections.iter().map(|i| i.lang).collect();
+            langs.sort();
+            let ranges: Vec<&str> = cache
+                .injected_ranges
+                .iter()
+                .map(|range| &source[range.clone()])
+                .collect();
+            (
+                langs,
+                ranges.into_iter().map(str::to_string).collect::<Vec<_>>(),
+            )
+        };
+
+        // A `lang` attribute beats the plain <script>/<style> rule; one without
+        // a bundled grammar leaves it in place.
+        let (langs, ranges) = injected(
+            &mut server,
+            1,
+            "html",
+            "<script lang=\"ts\">let x: number = 1;</script>\n<style lang=\"scss\">a { color: red; }</style>\n",
+        );
+        assert_eq!(langs, ["css", "typescript"]);
+        assert_eq!(ranges, ["let x: number = 1;", "a { color: red; }"]);
+
+        // A tagged template injects its literal text, not the backticks and
+        // not what `${...}` substitutes.
+        let (langs, ranges) = injected(
+            &mut server,
+            2,
+            "javascript",
+            "const s = css`a { color: ${c}; }`;\nconst r = /a+/;\n",
+        );
+        assert_eq!(langs, ["css"]);
+        assert_eq!(ranges, ["a { color: ", "; }"]);
+
+        // ... and the `|` of a block scalar.
+        let (langs, ranges) = injected(
+            &mut server,
+            3,
+            "yaml",
+            "steps:\n  - run: |\n      make build\n      make test\n  - name: echo hi\n",
+        );
+        assert_eq!(langs, ["bash"]);
+        assert_eq!(ranges, ["\n      make build\n      make test"]);
+
+        let (langs, ranges) = injected(
+            &mut server,
+            4,
+            "python",
+            "# json\nDEFAULTS = '{\"a\": 1}'\n# not a language\nX = 'y'\n",
+        );
+        assert_eq!(langs, ["json"]);
+        assert_eq!(ranges, ["{\"a\": 1}"]);
+
+        let (langs, ranges) = injected(
+            &mut server,
+            5,
+            "rust",
+            "/// Adds `a`.\n/// # Examples\nfn add() {}\n// plain\n",
+        );
+        assert_eq!(langs, ["markdown", "markdown_inline"]);
+        assert_eq!(ranges.len(), 2, "{ranges:?}");
+        assert!(ranges[1].contains("# Examples"), "{ranges:?}");
+
+        let (langs, _) = injected(
+            &mut server,
+            6,
+            "markdown",
+            "---\ntitle: x\n---\n\n<div>hi</div>\n\n```py\nx = 1\n```\n",
+        );
+        assert_eq!(langs, ["html", "python", "yaml"]);
     }
-}
 
-// ---- Rust-specific helpers (保持不变) ----
-fn ancestor_kind<'a>(mut node: tree_sitter::Node<'a>, want: &str) -> Option<tree_sitter::Node<'a>> {
-    while let Some(parent) = node.parent() {
-        if parent.kind() == want {
-            return Some(parent);
-        }
-        node = parent;
+    #[test]
+    fn covered_by_injection_only_swallows_fully_contained_spans() {
+        let mut server = Server::new();
+        server
+            .set_text(14, "markdown", "```rust\nfn f() {}\n```\n".to_string(), 1)
+            .unwrap();
+        let cache = server.cache.get(&14).unwrap();
+        let ranges = &cache.injected_ranges;
+        assert_eq!(ranges.len(), 1, "{ranges:?}");
+
+        let block = cache
+            .tree
+            .root_node()
+            .child(0)
+            .expect("a fenced code block");
+        assert!(
+            !covered_by_injection(ranges, block),
+            "the fence itself must survive: its delimiters are not injected"
+        );
+        let content = descendant_by_kind(block, "code_fence_content").expect("fence content");
+        assert!(covered_by_injection(ranges, content));
     }
-    None
-}
 
-fn definition_node<'a>(
-    node: tree_sitter::Node<'a>,
-    lang: &str,
-    kind: &str,
-) -> tree_sitter::Node<'a> {
-    let candidates: &[&str] = match (lang, kind) {
-        ("rust", "function" | "method") => &["function_item"],
-        ("rust", "struct") => &["struct_item"],
-        ("rust", "enum") => &["enum_item"],
-        ("rust", "type") => &["trait_item", "type_item"],
-        ("rust", "const") => &["const_item", "static_item"],
-        ("rust", "namespace") => &["mod_item"],
-        ("rust", "macro") => &["macro_definition"],
-        ("rust", "field") => &["field_declaration"],
-        ("rust", "variant") => &["enum_variant"],
+    #[test]
+    fn server_reuses_trees_and_preserves_revisions() {
+        let mut server = Server::new();
+        assert_eq!(
+            server
+                .set_text(7, "rust", "fn first() {}".to_string(), 10)
+                .unwrap(),
+            ParseMode::Full
+        );
+        assert_eq!(
+            server
+                .set_text(7, "rust", "fn second() {}".to_string(), 11)
+                .unwrap(),
+            ParseMode::Incremental
+        );
+        assert_eq!(
+            server
+                .set_text(7, "rust", "fn second() {}".to_string(), 12)
+                .unwrap(),
+            ParseMode::Unchanged
+        );
 
-        ("c" | "cpp", "function" | "method") => {
-            &["function_definition", "field_declaration", "declaration"]
-        }
-        ("c" | "cpp", "class") => &["class_specifier"],
-        ("c" | "cpp", "struct") => &["struct_specifier", "type_definition"],
-        ("c" | "cpp", "enum") => &["enum_specifier"],
-        ("c" | "cpp", "namespace") => &["namespace_definition"],
-        ("c" | "cpp", "type") => &["alias_declaration", "type_definition"],
-        ("c" | "cpp", "variable") => &["declaration"],
+        let (revision, spans) =
+            run_highlight_cached(&mut server, 7, "rust", None, true, None).unwrap();
+        assert_eq!(revision, 12);
+        assert!(!spans.is_empty());
+        assert_eq!(server.full_parses, 1);
+        assert_eq!(server.incremental_parses, 1);
+        assert_eq!(server.unchanged_syncs, 1);
+    }
 
-        ("javascript", "function") => &["function_declaration"],
-        ("javascript", "method") => &["method_definition"],
-        ("javascript", "class") => &["class_declaration"],
-        ("javascript", "variable") => &["variable_declarator", "variable_declaration"],
+    #[test]
+    fn symbols_include_definition_ranges() {
+        let mut server = Server::new();
+        let source = "struct User { name: String }\nfn greet() {}\nimpl User { fn method(&self) {} }\nfn outer() { fn inner() {} }\n";
+        server.set_text(1, "rust", source.to_string(), 3).unwrap();
+        let (revision, symbols) = run_symbols_cached(&mut server, 1, "rust", None, None).unwrap();
+        assert_eq!(revision, 3);
+        assert!(symbols.iter().any(|symbol| symbol.name == "User"));
+        let greet = symbols
+            .iter()
+            .find(|symbol| symbol.name == "greet")
+            .unwrap();
+        assert_eq!(greet.kind, "function");
+        assert!(greet.container_kind.is_none());
+        let method = symbols
+            .iter()
+            .find(|symbol| symbol.name == "method")
+            .unwrap();
+        assert_eq!(method.kind, "method");
+        assert_eq!(method.container_name.as_deref(), Some("User"));
+        let inner = symbols
+            .iter()
+            .find(|symbol| symbol.name == "inner")
+            .unwrap();
+        assert_eq!(inner.container_kind, Some("function"));
+        assert_eq!(inner.container_name.as_deref(), Some("outer"));
+        assert!(symbols.iter().all(|symbol| symbol.end_lnum >= symbol.lnum));
+    }
 
-        ("typescript" | "tsx", "function") => &["function_declaration", "function_signature"],
-        ("typescript" | "tsx", "method") => &[
-            "method_definition",
-            "method_signature",
-            "abstract_method_signature",
-        ],
-        ("typescript" | "tsx", "class") => &["class_declaration", "abstract_class_declaration"],
-        ("typescript" | "tsx", "type") => &["interface_declaration", "type_alias_declaration"],
-        ("typescript" | "tsx", "enum") => &["enum_declaration"],
-        ("typescript" | "tsx", "namespace") => &["internal_module", "module"],
-        ("typescript" | "tsx", "variable") => &["variable_declarator", "variable_declaration"],
-        ("typescript" | "tsx", "field") => &["public_field_definition", "property_signature"],
-        // 变体本身就是叶子；有赋值时也只取名字，避免退化到整个 enum_body。
-        ("typescript" | "tsx", "variant") => &["property_identifier"],
+    #[test]
+    fn symbol_kind_filter_is_applied_before_result_limit() {
+        let mut server = Server::new();
+        let source = "const A: i32 = 1;\nconst B: i32 = 2;\nfn target() {}\n";
+        server
+            .set_text(42, "rust", source.to_string(), 1)
+            .expect("rust parse");
 
-        ("json", "property" | "field") => &["pair"],
-        ("yaml", "property" | "field") => &["block_mapping_pair"],
-        ("toml", "namespace") => &["table", "table_array_element"],
-        ("toml", "property") => &["pair"],
+        let (_, symbols) = run_symbols_cached_filtered(
+            &mut server,
+            42,
+            "rust",
+            None,
+            Some(1),
+            &["function".to_string()],
+        )
+        .expect("filtered symbols");
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].name, "target");
+        assert_eq!(symbols[0].kind, "function");
+    }
 
-        ("python", "function" | "method") => &["function_definition"],
-        ("python", "class") => &["class_definition"],
-        ("python", "variable") => &["assignment", "expression_statement"],
-        ("python", "type") => &["type_alias_statement"],
+    #[test]
+    fn rust_keywords_are_highlighted_as_keywords() {
+        let mut server = Server::new();
+        let source = "pub fn answer() -> i32 { let value = 42; return value; }\n";
+        server.set_text(1, "rust", source.to_string(), 1).unwrap();
+        let (_, spans) = run_highlight_cached(&mut server, 1, "rust", None, false, None).unwrap();
+        let keyword_count = spans
+            .iter()
+            .filter(|span| span.group == "TSKeyword")
+            .count();
+        assert!(keyword_count >= 4, "keyword spans: {spans:?}");
+        assert!(spans.iter().all(|span| span.depth.is_none()));
+    }
 
-        ("go", "function") => &["function_declaration"],
-        ("go", "method") => &["method_declaration"],
-        ("go", "type") => &["type_spec", "type_alias"],
-        ("go", "const") => &["const_spec", "const_declaration"],
-        ("go", "variable") => &["var_spec", "var_declaration"],
-        ("go", "field") => &["field_declaration"],
+    /// `:TsHlInspect` must answer "why is this token this colour, and which
+    /// group do I override" with the same facts the renderer used.
+    #[test]
+    fn inspect_reports_captures_groups_and_the_node_chain() {
+        let mut server = Server::new();
+        let source = "pub fn answer() -> i32 { 42 }\n";
+        server.set_text(1, "rust", source.to_string(), 7).unwrap();
+        // Column 5 is the 'f' of `fn`.
+        let (revision, captures, chain) = inspect_cached(&mut server, 1, "rust", 1, 5).unwrap();
+        assert_eq!(revision, 7);
 
-        ("bash", "function") => &["function_definition"],
-        ("bash", "variable") => &["variable_assignment"],
+        let keyword = captures
+            .iter()
+            .find(|capture| capture.capture == "keyword")
+            .unwrap_or_else(|| panic!("no @keyword capture: {captures:?}"));
+        assert_eq!(keyword.group, "TSKeyword");
+        assert_eq!(keyword.priority, capture_priority("keyword"));
+        assert_eq!((keyword.lnum, keyword.col), (1, 5));
+        assert_eq!((keyword.end_lnum, keyword.end_col), (1, 7));
+        assert!(keyword.applied, "the winning capture was not marked");
+        // Highest priority first, so the first line of the report is the answer.
+        assert_eq!(captures.first().map(|c| c.priority), Some(keyword.priority));
 
-        ("vim", "function") => &["def_function"],
-        ("vim", "variable" | "const") => &["let_statement", "const_statement"],
-        _ => &[],
-    };
+        // Innermost first, up to the root, with parent field names attached.
+        assert_eq!(chain.first().map(|node| node.kind.as_str()), Some("fn"));
+        assert_eq!(
+            chain.last().map(|node| node.kind.as_str()),
+            Some("source_file")
+        );
+        let function = chain
+            .iter()
+            .find(|node| node.kind == "function_item")
+            .unwrap_or_else(|| panic!("no enclosing function_item: {chain:?}"));
+        assert_eq!((function.lnum, function.end_lnum), (1, 1));
 
-    let mut current = Some(node);
-    while let Some(candidate) = current {
-        if candidates.contains(&candidate.kind()) {
-            return candidate;
-        }
-        current = candidate.parent();
+        // Column 8 is the function's name; a node that fills a named slot in
+        // its parent reports which slot, which is what makes the chain usable
+        // for writing a query.
+        let (_, name_captures, name_chain) = inspect_cached(&mut server, 1, "rust", 1, 8).unwrap();
+        assert_eq!(
+            name_chain.first().map(|node| node.field.as_deref()),
+            Some(Some("name")),
+            "innermost node did not report its parent field: {name_chain:?}"
+        );
+        assert!(
+            name_captures
+                .iter()
+                .any(|capture| capture.group == "TSFunction"),
+            "function name is not captured as a function: {name_captures:?}"
+        );
     }
-    node.parent().unwrap_or(node)
-}
 
-/// Depth-first search for the first descendant (including `node`) of `kind`.
-fn descendant_by_kind<'a>(
-    node: tree_sitter::Node<'a>,
-    kind: &str,
-) -> Option<tree_sitter::Node<'a>> {
-    if node.kind() == kind {
-        return Some(node);
-    }
-    let child_count = node.child_count().min(u32::MAX as usize);
-    for index in 0..child_count {
-        if let Some(child) = node.child(index as u32)
-            && let Some(found) = descendant_by_kind(child, kind)
-        {
-            return Some(found);
-        }
-    }
-    None
-}
+    /// A byte column landing inside a multi-byte character must not describe a
+    /// neighbouring token, and a column past the end of the line must clamp.
+    #[test]
+    fn inspect_clamps_columns_to_the_line_and_to_utf8_boundaries() {
+        let mut server = Server::new();
+        let source = "let s = \"héllo\";\nlet t = 1;\n";
+        server.set_text(1, "rust", source.to_string(), 1).unwrap();
+        // 'é' starts at byte column 11 and occupies columns 11-12.
+        let (_, mid_char, _) = inspect_cached(&mut server, 1, "rust", 1, 12).unwrap();
+        let (_, boundary, _) = inspect_cached(&mut server, 1, "rust", 1, 11).unwrap();
+        assert_eq!(
+            mid_char
+                .iter()
+                .map(|c| (c.capture.as_str(), c.col))
+                .collect::<Vec<_>>(),
+            boundary
+                .iter()
+                .map(|c| (c.capture.as_str(), c.col))
+                .collect::<Vec<_>>(),
+        );
 
-fn child_text_by_kind(node: tree_sitter::Node, child_kind: &str, bytes: &[u8]) -> Option<String> {
-    let mut cursor = node.walk();
-    for ch in node.children(&mut cursor) {
-        if ch.kind() == child_kind {
-            return Some(node_text(ch, bytes));
-        }
-    }
-    None
-}
-fn child_pos_by_kind(node: tree_sitter::Node, child_kind: &str) -> Option<(u32, u32)> {
-    let mut cursor = node.walk();
-    for ch in node.children(&mut cursor) {
-        if ch.kind() == child_kind {
-            let sp = ch.start_position();
-            return Some((sp.row as u32 + 1, sp.column as u32 + 1));
-        }
-    }
-    None
-}
-fn struct_info(node: tree_sitter::Node, bytes: &[u8]) -> Option<(String, u32, u32)> {
-    if let Some(st) = ancestor_kind(node, "struct_item")
-        && let Some(name) = child_text_by_kind(st, "type_identifier", bytes)
-        && let Some((ln, co)) = child_pos_by_kind(st, "type_identifier")
-    {
-        return Some((name, ln, co));
-    }
-    None
-}
-fn enum_info(node: tree_sitter::Node, bytes: &[u8]) -> Option<(String, u32, u32)> {
-    if let Some(en) = ancestor_kind(node, "enum_item")
-        && let Some(name) = child_text_by_kind(en, "type_identifier", bytes)
-        && let Some((ln, co)) = child_pos_by_kind(en, "type_identifier")
-    {
-        return Some((name, ln, co));
+        // Well past the end of line 1: still line 1, never line 2's content.
+        let (_, _, chain) = inspect_cached(&mut server, 1, "rust", 1, 9_000).unwrap();
+        assert!(
+            chain.iter().all(|node| node.lnum <= 1),
+            "clamped column escaped its line: {chain:?}"
+        );
     }
-    None
-}
-fn variant_info(node: tree_sitter::Node, bytes: &[u8]) -> Option<(String, u32, u32)> {
-    let mut cur = node;
-    while let Some(parent) = cur.parent() {
-        if parent.kind() == "enum_variant"
-            && let Some(name) = child_text_by_kind(parent, "identifier", bytes)
-            && let Some((ln, co)) = child_pos_by_kind(parent, "identifier")
-        {
-            return Some((name, ln, co));
+
+    /// `point_at_byte` is the only byte -> position conversion the text objects
+    /// have, and it is the one place a checkpoint off-by-one would put every
+    /// inner range on the wrong line without any other test noticing. Compare
+    /// it against a naive scan at every byte of a text that crosses several
+    /// checkpoint strides and carries multi-byte characters.
+    #[test]
+    fn point_at_byte_agrees_with_a_naive_scan_across_checkpoint_strides() {
+        let mut text = String::new();
+        for line in 0..(LINE_INDEX_STRIDE * 3 + 7) {
+            text.push_str(&format!("line {line} héllo\n"));
         }
-        cur = parent;
-    }
-    None
-}
-fn impl_type_info(node: tree_sitter::Node, bytes: &[u8]) -> Option<(String, u32, u32)> {
-    if let Some(im) = ancestor_kind(node, "impl_item") {
-        let mut last: Option<(String, u32, u32)> = None;
-        let mut cursor = im.walk();
-        for ch in im.children(&mut cursor) {
-            if ch.kind() == "type_identifier" || ch.kind() == "identifier" {
-                let name = node_text(ch, bytes);
-                let sp = ch.start_position();
-                last = Some((name, sp.row as u32 + 1, sp.column as u32 + 1));
+        text.push_str("no trailing newline");
+        let index = SparseLineIndex::new(&text);
+
+        let mut row = 0_usize;
+        let mut line_start = 0_usize;
+        for offset in 0..=text.len() {
+            let expected = tree_sitter::Point {
+                row,
+                column: offset - line_start,
+            };
+            assert_eq!(
+                index.point_at_byte(&text, offset),
+                expected,
+                "byte {offset} mapped to the wrong point"
+            );
+            if text.as_bytes().get(offset) == Some(&b'\n') {
+                row += 1;
+                line_start = offset + 1;
             }
         }
-        if let Some(x) = last {
-            return Some(x);
-        }
+        // Past the end clamps rather than panicking on the slice.
+        assert_eq!(
+            index.point_at_byte(&text, text.len() + 500),
+            index.point_at_byte(&text, text.len())
+        );
+    }
+
+    #[test]
+    fn scope_chain_separates_outer_and_inner_ranges() {
+        let mut server = Server::new();
+        let source = "fn outer(first: i32, second: i32) -> i32 {\n    \
+                      let total = first + second;\n    total\n}\n";
+        server.set_text(3, "rust", source.to_string(), 12).unwrap();
+
+        // Cursor on `total` inside the body.
+        let answer = scope_chain_cached(&server, 3, "rust", 2, 9).unwrap();
+        let (revision, chain) = (answer.revision, answer.chain);
+        assert_eq!(revision, 12);
+        assert_eq!(
+            chain.last().map(|node| node.node),
+            Some("source_file"),
+            "chain does not reach the root: {chain:?}"
+        );
+        let function = chain
+            .iter()
+            .find(|node| node.kind == Some("function"))
+            .unwrap_or_else(|| panic!("no enclosing function: {chain:?}"));
+        assert_eq!(function.node, "function_item");
+        // Outer spans the whole item; inner is the body with braces and the
+        // whitespace they left behind removed.
+        assert_eq!(
+            (
+                function.lnum,
+                function.col,
+                function.end_lnum,
+                function.end_col
+            ),
+            (1, 1, 4, 2)
+        );
+        assert_eq!(
+            (
+                function.inner_lnum,
+                function.inner_col,
+                function.inner_end_lnum,
+                function.inner_end_col
+            ),
+            (2, 5, 3, 10)
+        );
+
+        // A parameter's outer range swallows the separator that would otherwise
+        // be left behind; the last one takes the comma in front of it instead.
+        let at_second = scope_chain_cached(&server, 3, "rust", 1, 22).unwrap().chain;
+        let second = at_second
+            .iter()
+            .find(|node| node.kind == Some("parameter"))
+            .unwrap_or_else(|| panic!("no parameter at the cursor: {at_second:?}"));
+        assert_eq!(
+            (second.col, second.end_col),
+            (20, 33),
+            "last parameter did not take the preceding comma"
+        );
+        assert_eq!((second.inner_col, second.inner_end_col), (22, 33));
+
+        let at_first = scope_chain_cached(&server, 3, "rust", 1, 10).unwrap().chain;
+        let first = at_first
+            .iter()
+            .find(|node| node.kind == Some("parameter"))
+            .unwrap_or_else(|| panic!("no parameter at the cursor: {at_first:?}"));
+        assert_eq!(
+            (first.col, first.end_col),
+            (10, 22),
+            "parameter outer did not swallow the trailing comma and space"
+        );
+        assert_eq!((first.inner_col, first.inner_end_col), (10, 20));
     }
-    None
-}
-fn mod_info(node: tree_sitter::Node, bytes: &[u8]) -> Option<(String, u32, u32)> {
-    if let Some(md) = ancestor_kind(node, "mod_item")
-        && let Some(name) = child_text_by_kind(md, "identifier", bytes)
-        && let Some((ln, co)) = child_pos_by_kind(md, "identifier")
-    {
-        return Some((name, ln, co));
+
+    /// A node carries exactly one class, so a closure passed as an argument is
+    /// reachable with `af`/`if` and does not also masquerade as a parameter with
+    /// a contradictory outer range.
+    #[test]
+    fn a_closure_argument_is_classified_as_a_function_not_a_parameter() {
+        let mut server = Server::new();
+        let source = "fn main() {\n    let v = items.map(|x| x + 1);\n}\n";
+        server.set_text(4, "rust", source.to_string(), 1).unwrap();
+        // Column 27 is inside `|x| x + 1`.
+        let chain = scope_chain_cached(&server, 4, "rust", 2, 27).unwrap().chain;
+        let closure = chain
+            .iter()
+            .find(|node| node.node == "closure_expression")
+            .unwrap_or_else(|| panic!("no closure in the chain: {chain:?}"));
+        assert_eq!(closure.kind, Some("function"));
+        assert!(
+            chain
+                .iter()
+                .take_while(|node| node.node != "closure_expression")
+                .all(|node| node.kind != Some("parameter")),
+            "the closure argument was also reported as a parameter: {chain:?}"
+        );
     }
-    None
-}
-fn outer_fn_info(node: tree_sitter::Node, bytes: &[u8]) -> Option<(String, u32, u32)> {
-    let mut cur = node;
-    let mut skipped_current = false;
-    while let Some(parent) = cur.parent() {
-        if parent.kind() == "function_item" {
-            if !skipped_current {
-                skipped_current = true;
-                cur = parent;
-                continue;
-            }
-            if let Some(name) = child_text_by_kind(parent, "identifier", bytes)
-                && let Some((ln, co)) = child_pos_by_kind(parent, "identifier")
-            {
-                return Some((name, ln, co));
-            }
+
+    /// Incremental selection walks the chain outward, so it must be ordered
+    /// innermost-first and every step must contain the previous one.
+    #[test]
+    fn scope_chain_is_ordered_innermost_first_and_nests() {
+        let mut server = Server::new();
+        let source = "def f(a, b):\n    x = a + b\n    return x\n";
+        server.set_text(5, "python", source.to_string(), 1).unwrap();
+        let chain = scope_chain_cached(&server, 5, "python", 2, 9)
+            .unwrap()
+            .chain;
+        assert!(chain.len() >= 3, "chain is too short: {chain:?}");
+        for pair in chain.windows(2) {
+            let (inner, outer) = (&pair[0], &pair[1]);
+            assert!(
+                (outer.lnum, outer.col) <= (inner.lnum, inner.col)
+                    && (outer.end_lnum, outer.end_col) >= (inner.end_lnum, inner.end_col),
+                "chain step is not nested: {outer:?} does not contain {inner:?}"
+            );
         }
-        cur = parent;
+
+        // Python has no braces: the inner half of a function is its suite, and
+        // the delimiter-stripping must not eat anything it should not.
+        let function = chain
+            .iter()
+            .find(|node| node.kind == Some("function"))
+            .unwrap_or_else(|| panic!("no enclosing function: {chain:?}"));
+        assert_eq!(
+            (
+                function.inner_lnum,
+                function.inner_col,
+                function.inner_end_lnum,
+                function.inner_end_col
+            ),
+            (2, 5, 3, 13)
+        );
     }
-    None
-}
-#[allow(dead_code)]
-fn has_ancestor_of(node: tree_sitter::Node, kinds: &[&str]) -> bool {
-    let mut cur = node;
-    while let Some(parent) = cur.parent() {
-        for &k in kinds {
-            if parent.kind() == k {
-                return true;
+
+    /// The client caches one reply for the whole anchor range and asks again
+    /// only when the cursor leaves it, so the anchor has to be exactly the set
+    /// of columns that produce this chain. Were it ever too wide, a text object
+    /// would silently act on a chain belonging to a different token.
+    #[test]
+    fn every_column_inside_the_anchor_reproduces_the_same_chain() {
+        let mut server = Server::new();
+        let source = "fn main() {\n    let answer = compute(1, 2);\n}\n";
+        server.set_text(7, "rust", source.to_string(), 1).unwrap();
+
+        // Every (line, column) of the source, so the leading-indentation case —
+        // where the resolved node is the whole enclosing block and its own
+        // range would be far too wide an anchor — is covered by construction.
+        let lines: Vec<&str> = source.lines().collect();
+        let mut anchors_narrower_than_their_node = 0;
+        for (row, line) in lines.iter().enumerate() {
+            let lnum = row as u32 + 1;
+            for col in 1..=(line.len() as u32 + 1) {
+                let answer = scope_chain_cached(&server, 7, "rust", lnum, col).unwrap();
+                let shape: Vec<&str> = answer.chain.iter().map(|node| node.node).collect();
+                let [alnum, acol, aelnum, aecol] = answer.anchor;
+                if answer.chain.first().is_some_and(|node| {
+                    (node.lnum, node.col) < (alnum, acol)
+                        || (node.end_lnum, node.end_col) > (aelnum, aecol)
+                }) {
+                    anchors_narrower_than_their_node += 1;
+                }
+                // An anchor may end on the phantom line after the final newline.
+                for probe_lnum in alnum..=aelnum.min(lines.len() as u32) {
+                    let width = lines[probe_lnum as usize - 1].len() as u32;
+                    let first = if probe_lnum == alnum { acol } else { 1 };
+                    let last = if probe_lnum == aelnum {
+                        aecol - 1
+                    } else {
+                        width
+                    };
+                    for probe in first..=last.min(width) {
+                        let probed = scope_chain_cached(&server, 7, "rust", probe_lnum, probe)
+                            .unwrap()
+                            .chain;
+                        assert_eq!(
+                            probed.iter().map(|node| node.node).collect::<Vec<_>>(),
+                            shape,
+                            "{probe_lnum}:{probe} is inside the anchor \
+                             [{alnum}:{acol}-{aelnum}:{aecol}] reported for {lnum}:{col} \
+                             but produces a different chain"
+                        );
+                    }
+                }
             }
         }
-        cur = parent;
+        // The indentation columns are exactly the case a node-range anchor gets
+        // wrong, so the test is worthless if it never reached one.
+        assert!(
+            anchors_narrower_than_their_node > 0,
+            "no column resolved to a node wider than its anchor, so this test \
+             never exercised the case a node-range anchor would break on"
+        );
     }
-    false
-}
 
-// 如需拿到父函数名，可用这个辅助（可选）
-#[allow(dead_code)]
-fn vim_func_name(node: tree_sitter::Node, bytes: &[u8]) -> Option<String> {
-    let mut cur = node;
-    while let Some(parent) = cur.parent() {
-        if parent.kind() == "function_definition" || parent.kind() == "vim9_function_definition" {
-            // 找到声明节点，取里面的名字（identifier/scoped_identifier/field_expression）
-            let mut cursor = parent.walk();
-            for ch in parent.children(&mut cursor) {
-                if ch.kind() == "function_declaration" || ch.kind() == "vim9_function_declaration" {
-                    let mut c2 = ch.walk();
-                    for nm in ch.children(&mut c2) {
-                        match nm.kind() {
-                            "identifier" | "scoped_identifier" | "field_expression" => {
-                                return Some(node_text(nm, bytes));
-                            }
-                            _ => {}
-                        }
-                    }
-                }
+    /// Every bundled language must at least classify a comment, so that `ac`
+    /// on an unlisted grammar degrades to "no match here" and never to a match
+    /// on the wrong node.
+    #[test]
+    fn scope_tables_only_name_kinds_and_comments_are_universal() {
+        for lang in SUPPORTED_LANGUAGES {
+            for (node_kind, class) in scope_table(lang) {
+                assert!(
+                    matches!(
+                        *class,
+                        "function"
+                            | "class"
+                            | "parameter"
+                            | "block"
+                            | "call"
+                            | "comment"
+                            | "conditional"
+                            | "loop"
+                    ),
+                    "{lang}: {node_kind} maps to unknown text-object class {class}"
+                );
             }
         }
-        cur = parent;
+
+        let mut server = Server::new();
+        server
+            .set_text(6, "rust", "// a note\nfn f() {}\n".to_string(), 1)
+            .unwrap();
+        let chain = scope_chain_cached(&server, 6, "rust", 1, 4).unwrap().chain;
+        assert_eq!(
+            chain.first().map(|node| node.kind),
+            Some(Some("comment")),
+            "a comment is not a comment text object: {chain:?}"
+        );
     }
-    None
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn c_function_range_covers_its_body() {
+        let mut server = Server::new();
+        let source = "int answer(void) {\n  return 42;\n}\n";
+        server.set_text(1, "c", source.to_string(), 1).unwrap();
+        let (_, symbols) = run_symbols_cached(&mut server, 1, "c", None, None).unwrap();
+        let function = symbols
+            .iter()
+            .find(|symbol| symbol.name == "answer")
+            .unwrap();
+        assert_eq!(function.kind, "function");
+        assert_eq!(function.end_lnum, 3);
+    }
+
+    #[test]
+    fn visible_range_includes_multiline_tokens_starting_above_it() {
+        let mut server = Server::new();
+        let source = "text = '''first\nsecond\nthird'''\nprint(text)\n";
+        server.set_text(1, "python", source.to_string(), 1).unwrap();
+        let (_, spans) =
+            run_highlight_cached(&mut server, 1, "python", Some((2, 2)), false, None).unwrap();
+        assert!(
+            spans
+                .iter()
+                .any(|span| span.group == "TSString" && span.lnum == 1 && span.end_lnum == 3),
+            "multiline spans: {spans:?}"
+        );
+    }
 
     #[test]
-    fn symbol_request_ids_are_additive_and_echoed_on_success_and_error() {
-        let legacy: Request =
-            serde_json::from_str(r#"{"type":"symbols","buf":3,"lang":"rust"}"#).unwrap();
-        match legacy {
-            Request::Symbols { request_id, .. } => assert_eq!(request_id, 0),
-            _ => panic!("wrong legacy request variant"),
-        }
+    fn vim9_declarations_are_available_when_grammar_uses_generic_commands() {
+        let mut server = Server::new();
+        let source = "vim9script\nexport def Greet(name: string)\n  var message = name\n  return message\nenddef\nconst VERSION = 2\n";
+        server.set_text(1, "vim", source.to_string(), 1).unwrap();
+        let (_, symbols) = run_symbols_cached(&mut server, 1, "vim", None, None).unwrap();
+        let function = symbols
+            .iter()
+            .find(|symbol| symbol.name == "Greet")
+            .unwrap();
+        assert_eq!(function.kind, "function");
+        assert_eq!(function.end_lnum, 5);
+        let local = symbols
+            .iter()
+            .find(|symbol| symbol.name == "message")
+            .unwrap();
+        assert_eq!(local.container_name.as_deref(), Some("Greet"));
+        let version = symbols
+            .iter()
+            .find(|symbol| symbol.name == "VERSION")
+            .unwrap();
+        assert_eq!(version.kind, "const");
+        assert!(version.container_kind.is_none());
+    }
 
-        let current: Request =
-            serde_json::from_str(r#"{"type":"symbols","buf":3,"lang":"rust","request_id":77}"#)
-                .unwrap();
-        match current {
-            Request::Symbols { request_id, .. } => assert_eq!(request_id, 77),
-            _ => panic!("wrong current request variant"),
+    #[test]
+    fn javascript_lexical_declarations_are_symbols() {
+        let mut server = Server::new();
+        let source = "const first = 1; let second = 2; var third = 3;\n";
+        server
+            .set_text(1, "javascript", source.to_string(), 1)
+            .unwrap();
+        let (_, symbols) = run_symbols_cached(&mut server, 1, "javascript", None, None).unwrap();
+        for expected in ["first", "second", "third"] {
+            assert!(
+                symbols.iter().any(|symbol| symbol.name == expected),
+                "missing {expected}: {symbols:?}"
+            );
         }
-
-        let success = serde_json::to_value(Event::Symbols {
-            buf: 3,
-            revision: 9,
-            request_id: 77,
-            symbols: Some(Vec::new()),
-            digest: digest_symbols(&[]),
-            unchanged: false,
-        })
-        .unwrap();
-        assert_eq!(success["request_id"], 77);
-
-        let error = serde_json::to_value(Event::Error {
-            message: "synthetic".into(),
-            buf: Some(3),
-            op: Some("symbols"),
-            request_id: Some(77),
-        })
-        .unwrap();
-        assert_eq!(error["request_id"], 77);
     }
 
     #[test]
-    fn byte_offsets_use_tree_sitter_byte_columns() {
-        let text = "αβ\nhello\n世界";
-        assert_eq!(byte_offset_to_point(text, 0), tree_sitter::Point::new(0, 0));
-        assert_eq!(byte_offset_to_point(text, 4), tree_sitter::Point::new(0, 4));
-        assert_eq!(byte_offset_to_point(text, 5), tree_sitter::Point::new(1, 0));
-        assert_eq!(
-            byte_offset_to_point(text, 10),
-            tree_sitter::Point::new(1, 5)
-        );
-        assert_eq!(
-            byte_offset_to_point(text, text.len()),
-            tree_sitter::Point::new(2, 6)
-        );
+    fn go_methods_and_fields_share_the_real_type_container() {
+        let mut server = Server::new();
+        let source = "package main\ntype User struct { Name string }\nfunc (u User) Greet() {}\n";
+        server.set_text(1, "go", source.to_string(), 1).unwrap();
+        let (_, symbols) = run_symbols_cached(&mut server, 1, "go", None, None).unwrap();
+        let user = symbols.iter().find(|symbol| symbol.name == "User").unwrap();
+        for name in ["Name", "Greet"] {
+            let child = symbols.iter().find(|symbol| symbol.name == name).unwrap();
+            assert_eq!(child.container_name.as_deref(), Some("User"));
+            assert_eq!(child.container_lnum, Some(user.lnum));
+            assert_eq!(child.container_col, Some(user.col));
+        }
     }
 
     #[test]
-    fn input_edit_handles_insert_delete_and_unicode() {
+    fn every_supported_language_has_semantic_smoke_coverage() {
         let cases = [
-            ("fn main() {}", "fn main() { let x = 1; }"),
-            ("one\ntwo\nthree", "one\nthree"),
-            ("let café = 1;", "let 咖啡 = 2;"),
-            ("αβγ", "αxγ"),
-            ("", "hello\nworld"),
+            ("rust", "fn rust_fn() {}\n", "rust_fn"),
+            (
+                "javascript",
+                "function javascriptFn() { return 1; }\n",
+                "javascriptFn",
+            ),
+            (
+                "typescript",
+                "interface Shape { area(): number }\nfunction tsFn(x: number): number { return x; }\n",
+                "tsFn",
+            ),
+            (
+                "tsx",
+                "function TsxComponent() { return <div className=\"x\">hi</div>; }\n",
+                "TsxComponent",
+            ),
+            ("c", "int c_fn(void) { return 0; }\n", "c_fn"),
+            ("cpp", "class Widget { public: int value; };\n", "Widget"),
+            ("python", "def python_fn():\n    return 1\n", "python_fn"),
+            ("go", "package main\nfunc goFn() {}\n", "goFn"),
+            ("bash", "bash_fn() { echo ok; }\n", "bash_fn"),
+            ("vim", "vim9script\ndef VimFn()\nenddef\n", "VimFn"),
+            ("json", "{\"top_key\": {\"nested\": 1}}\n", "top_key"),
+            ("yaml", "top_key:\n  nested: 1\n", "top_key"),
+            ("toml", "[section]\nkey = \"value\"\n", "section"),
+            (
+                "julia",
+                "module Demo\nstruct Point\n  x::Float64\nend\nfunction area(p::Point)\n  p.x\nend\nend\n",
+                "area",
+            ),
+            ("haskell", "module Demo where\narea x = x * x\n", "area"),
         ];
-
-        for (old, new) in cases {
-            let edit = compute_input_edit(old, new).expect("texts differ");
-            assert!(old.is_char_boundary(edit.start_byte));
-            assert!(old.is_char_boundary(edit.old_end_byte));
-            assert!(new.is_char_boundary(edit.new_end_byte));
-            assert_eq!(
-                edit.start_position,
-                byte_offset_to_point(old, edit.start_byte)
-            );
-            assert_eq!(
-                edit.old_end_position,
-                byte_offset_to_point(old, edit.old_end_byte)
-            );
-            assert_eq!(
-                edit.new_end_position,
-                byte_offset_to_point(new, edit.new_end_byte)
+        let mut server = Server::new();
+        for (index, (lang, source, expected_symbol)) in cases.into_iter().enumerate() {
+            let buffer = index as i64 + 1;
+            server
+                .set_text(buffer, lang, source.to_string(), 1)
+                .unwrap();
+            let (_, highlights) =
+                run_highlight_cached(&mut server, buffer, lang, None, false, None).unwrap();
+            assert!(!highlights.is_empty(), "no {lang} highlights");
+            let (_, symbols) = run_symbols_cached(&mut server, buffer, lang, None, None).unwrap();
+            assert!(
+                symbols.iter().any(|symbol| symbol.name == expected_symbol),
+                "missing {lang} symbol {expected_symbol}: {symbols:?}"
             );
         }
-        assert!(compute_input_edit("same", "same").is_none());
     }
 
-    #[test]
-    fn incremental_parse_matches_full_parse() {
-        let old = "fn main() {\n    println!(\"hello\");\n}\n";
-        let new = "fn main() {\n    let café = 42;\n    println!(\"{café}\");\n}\n";
-        let language: tree_sitter::Language = tree_sitter_rust::LANGUAGE.into();
-        let mut parser = tree_sitter::Parser::new();
-        parser.set_language(&language).unwrap();
-        let mut edited_tree = parser.parse(old, None).unwrap();
-        edited_tree.edit(&compute_input_edit(old, new).unwrap());
-        let incremental = parser.parse(new, Some(&edited_tree)).unwrap();
-        let full = parser.parse(new, None).unwrap();
-        assert_eq!(
-            incremental.root_node().to_sexp(),
-            full.root_node().to_sexp()
-        );
+    fn splice(
+        lstart: u32,
+        old_lend: u32,
+        lines: &[&str],
+        line_count: u64,
+        eol: bool,
+    ) -> LineSplice {
+        LineSplice {
+            lstart,
+            old_lend,
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+            line_count,
+            eol,
+        }
     }
 
     #[test]
-    fn line_ranges_are_clamped_and_utf8_safe() {
-        let text = "one\n二\nthree";
-        assert_eq!(line_range_to_byte_range(text, 1, 1), 0..4);
-        assert_eq!(line_range_to_byte_range(text, 2, 2), 4..8);
-        assert_eq!(line_range_to_byte_range(text, 2, 3), 4..text.len());
+    fn edit_lines_replaces_inserts_and_deletes() {
+        let mut server = Server::new();
+        server
+            .set_text(
+                1,
+                "rust",
+                "fn one() {}\nfn two() {}\nfn three() {}\n".to_string(),
+                1,
+            )
+            .unwrap();
+
+        // 替换中间一行
+        let mode = server
+            .edit_lines(1, "rust", 2, splice(2, 3, &["fn changed() {}"], 3, true))
+            .unwrap();
+        assert_eq!(mode, ParseMode::Incremental);
         assert_eq!(
-            line_range_to_byte_range(text, 99, 100),
-            text.len()..text.len()
+            &*server.cache.get(&1).unwrap().text,
+            "fn one() {}\nfn changed() {}\nfn three() {}\n"
         );
-        assert_eq!(line_range_to_byte_range(text, 3, 1), 8..text.len());
-    }
 
-    #[test]
-    fn sparse_line_index_stays_small_and_exact_for_newline_heavy_text() {
-        let newline_count = LINE_INDEX_STRIDE * 32 + 17;
-        let text = "\n".repeat(newline_count);
-        let index = SparseLineIndex::new(&text);
+        // 在开头插入一行
+        server
+            .edit_lines(1, "rust", 3, splice(1, 1, &["fn zero() {}"], 4, true))
+            .unwrap();
+        assert_eq!(
+            &*server.cache.get(&1).unwrap().text,
+            "fn zero() {}\nfn one() {}\nfn changed() {}\nfn three() {}\n"
+        );
 
-        assert_eq!(index.line_count, newline_count + 1);
+        // 删除最后两行
+        server
+            .edit_lines(1, "rust", 4, splice(3, 5, &[], 2, true))
+            .unwrap();
         assert_eq!(
-            index.checkpoints.len(),
-            newline_count / LINE_INDEX_STRIDE + 1
+            &*server.cache.get(&1).unwrap().text,
+            "fn zero() {}\nfn one() {}\n"
         );
-        let checkpoint_bytes = std::mem::size_of_val(index.checkpoints.as_ref());
+
+        // 语法树与全量解析一致，revision 精确更新
+        let (revision, symbols) = run_symbols_cached(&mut server, 1, "rust", None, None).unwrap();
+        assert_eq!(revision, 4);
+        let names: Vec<&str> = symbols.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["zero", "one"]);
+    }
+
+    #[test]
+    fn edit_lines_respects_missing_trailing_newline() {
+        let mut server = Server::new();
+        server
+            .set_text(1, "rust", "fn a() {}\nfn b() {}".to_string(), 1)
+            .unwrap();
+        server
+            .edit_lines(1, "rust", 2, splice(2, 3, &[], 1, false))
+            .unwrap();
+        assert_eq!(&*server.cache.get(&1).unwrap().text, "fn a() {}");
+        server
+            .edit_lines(1, "rust", 3, splice(2, 2, &["fn c() {}"], 2, true))
+            .unwrap();
         assert_eq!(
-            checkpoint_bytes,
-            index.checkpoints.len() * std::mem::size_of::<usize>()
-        );
-        assert!(
-            checkpoint_bytes <= (text.len() / LINE_INDEX_STRIDE + 1) * std::mem::size_of::<usize>()
+            &*server.cache.get(&1).unwrap().text,
+            "fn a() {}\nfn c() {}\n"
         );
-        assert!(index.checkpoints.len() * 200 < index.line_count);
-
-        for line in [
-            1,
-            2,
-            LINE_INDEX_STRIDE as u32,
-            LINE_INDEX_STRIDE as u32 + 1,
-            (LINE_INDEX_STRIDE * 17 + 93) as u32,
-            newline_count as u32 + 1,
-            newline_count as u32 + 2,
-        ] {
-            let expected = usize::try_from(line.saturating_sub(1))
-                .unwrap()
-                .min(text.len());
-            assert_eq!(index.line_start_byte(&text, line), expected, "line {line}");
-        }
     }
 
     #[test]
-    fn bounded_ast_dump_never_queues_all_children_of_a_wide_root() {
-        let source = (0..256)
-            .map(|index| format!("int item_{index};\n"))
-            .collect::<String>();
-        let language: tree_sitter::Language = tree_sitter_c::LANGUAGE.into();
-        let mut parser = tree_sitter::Parser::new();
-        parser.set_language(&language).unwrap();
-        let tree = parser.parse(&source, None).unwrap();
-        let root = tree.root_node();
-        let node_limit = 32;
+    fn edit_lines_mismatch_drops_the_cache() {
+        let mut server = Server::new();
+        server
+            .set_text(1, "rust", "fn a() {}\n".to_string(), 1)
+            .unwrap();
+        let error = server
+            .edit_lines(1, "rust", 2, splice(1, 2, &["fn b() {}"], 99, true))
+            .unwrap_err();
+        assert!(error.to_string().contains("edit_lines mismatch"));
+        assert!(!server.cache.contains_key(&1));
 
-        assert!(root.child_count() > node_limit);
-        let (lines, max_pending_nodes) = format_ast(root, node_limit, MAX_AST_DEPTH);
-        assert!(lines.len() <= node_limit);
-        assert!(max_pending_nodes <= node_limit);
-        assert!(lines.last().unwrap().contains("truncated"));
+        // 未缓存 buffer 的 edit_lines 必须请求全量重同步
+        let error = server
+            .edit_lines(2, "rust", 1, splice(1, 1, &["fn x() {}"], 1, true))
+            .unwrap_err();
+        assert!(error.to_string().contains("buffer not cached"));
     }
 
     #[test]
-    fn all_language_queries_compile() {
+    fn folds_are_nested_and_merge_identical_ranges() {
         let mut server = Server::new();
-        for lang in SUPPORTED_LANGUAGES {
-            server
-                .ensure_queries(lang)
-                .unwrap_or_else(|error| panic!("failed to compile {lang} queries: {error}"));
-            let queries = server.queries.get(*lang).unwrap();
-            for capture in queries.hl_query.capture_names() {
-                assert!(
-                    capture.starts_with('_') || !map_capture_to_group(capture).is_empty(),
-                    "{lang} has unmapped highlight capture @{capture}"
-                );
-            }
-            for capture in queries.sym_query.capture_names() {
-                assert!(
-                    !map_symbol_capture(capture).is_empty(),
-                    "{lang} has unmapped symbol capture @{capture}"
-                );
-            }
-            for pattern in 0..queries.hl_query.pattern_count() {
-                assert!(
-                    queries.hl_query.general_predicates(pattern).is_empty(),
-                    "{lang} highlight query pattern {pattern} has an unhandled general predicate"
-                );
-            }
-            for pattern in 0..queries.sym_query.pattern_count() {
-                assert!(
-                    queries.sym_query.general_predicates(pattern).is_empty(),
-                    "{lang} symbol query pattern {pattern} has an unhandled general predicate"
-                );
-            }
-        }
-        assert_eq!(server.queries.len(), SUPPORTED_LANGUAGES.len());
-
-        // ensure_queries() compiles every fixed injection target too, so a
-        // broken injected query fails --self-test rather than silently leaving
-        // one construct uncoloured.
-        for (name, injection) in &server.injection_queries {
-            for capture in injection.hl_query.capture_names() {
-                assert!(
-                    !map_capture_to_group(capture).is_empty(),
-                    "injection {name} has unmapped highlight capture @{capture}"
-                );
-            }
-            for pattern in 0..injection.hl_query.pattern_count() {
-                assert!(
-                    injection.hl_query.general_predicates(pattern).is_empty(),
-                    "injection {name} pattern {pattern} has an unhandled general predicate"
-                );
-            }
-        }
-        for required in ["markdown_inline", "javascript", "css"] {
-            assert!(
-                server.injection_queries.contains_key(required),
-                "no compiled injection query for {required}: {:?}",
-                server.injection_queries.keys().collect::<Vec<_>>()
-            );
-        }
+        let source = "fn outer() {\n    match 1 {\n        _ => {}\n    }\n}\nfn flat() {}\n";
+        server.set_text(1, "rust", source.to_string(), 7).unwrap();
+        let (revision, folds) = run_folds_cached(&server, 1, "rust", None).unwrap();
+        assert_eq!(revision, 7);
+        // function_item 与其同界 block 合并为一个 level-1 折叠；match 嵌套其中。
+        let outer = folds.iter().find(|fold| fold.lnum == 1).unwrap();
+        assert_eq!((outer.end_lnum, outer.level), (5, 1));
+        let inner = folds.iter().find(|fold| fold.lnum == 2).unwrap();
+        assert_eq!((inner.end_lnum, inner.level), (4, 2));
+        // 单行函数不产生折叠
+        assert!(!folds.iter().any(|fold| fold.lnum == 6));
+        assert_eq!(folds.len(), 2);
     }
 
     #[test]
-    fn markdown_highlights_symbols_and_inline_tree() {
+    fn diagnostics_report_missing_and_unexpected_tokens() {
         let mut server = Server::new();
-        let source = "# Top\n\nSome *emphasis*, **strong**, `code span` and \
-                      [a link](https://example.com).\n\n## Second level\n\n\
-                      ```rust\nfn main() {}\n```\n\n- [x] done task\n\n\
-                      Setext Title\n============\n";
         server
-            .set_text(9, "markdown", source.to_string(), 1)
-            .expect("markdown parse");
-        let injected: Vec<&str> = server
-            .cache
-            .get(&9)
-            .unwrap()
-            .injections
+            .set_text(1, "rust", "fn main() {\n    let x = 1;\n}\n".to_string(), 1)
+            .unwrap();
+        let (_, clean) = run_diagnostics_cached(&server, 1, "rust", None).unwrap();
+        assert!(clean.is_empty(), "a clean file has diagnostics: {clean:?}");
+
+        server
+            .set_text(1, "rust", "fn main() {\n    let x = 1\n}\n".to_string(), 2)
+            .unwrap();
+        let (revision, diagnostics) = run_diagnostics_cached(&server, 1, "rust", None).unwrap();
+        assert_eq!(revision, 2);
+        let missing = diagnostics
             .iter()
-            .map(|injection| injection.lang)
-            .collect();
-        assert!(
-            injected.contains(&"markdown_inline"),
-            "inline tree must be built for markdown: {injected:?}"
-        );
-        assert!(
-            injected.contains(&"rust"),
-            "a ```rust fence must inject the rust grammar: {injected:?}"
+            .find(|diagnostic| diagnostic.kind == "missing")
+            .unwrap_or_else(|| panic!("no missing node: {diagnostics:?}"));
+        assert_eq!(missing.message, "missing `;`");
+        assert_eq!(missing.parent, Some("let_declaration"));
+        // Zero-width, right after the `1` the semicolon should follow.
+        assert_eq!(
+            (missing.lnum, missing.col, missing.end_lnum, missing.end_col),
+            (2, 14, 2, 14)
         );
 
-        let (_, spans) =
-            run_highlight_cached(&mut server, 9, "markdown", None, false, None).unwrap();
-        let groups: Vec<&str> = spans.iter().map(|s| s.group).collect();
-        for expected in [
-            "TSTitle",    // headings (block)
-            "TSEmphasis", // *emphasis* (inline)
-            "TSStrong",   // **strong** (inline)
-            "TSLiteral",  // `code span` (the fence content is injected instead)
-            "TSLink",     // [a link]
-            "TSURI",      // (https://example.com)
-            "TSType",     // fence info string "rust"
-            "TSBoolean",  // task list marker
-        ] {
-            assert!(
-                groups.contains(&expected),
-                "missing {expected} in {groups:?}"
-            );
-        }
-
-        let (_, symbols) = run_symbols_cached(&mut server, 9, "markdown", None, None).unwrap();
-        let names: Vec<(&str, &str)> = symbols.iter().map(|s| (s.kind, s.name.as_str())).collect();
-        assert!(names.contains(&("namespace", "Top")), "{names:?}");
-        assert!(names.contains(&("class", "Second level")), "{names:?}");
-        assert!(names.contains(&("namespace", "Setext Title")), "{names:?}");
-
-        // Plain text without emphasis/links: the inline pass must not panic and
-        // the block-only path still renders. Also exercises the resync path.
         server
-            .set_text(9, "markdown", "# Only heading\n".to_string(), 2)
-            .expect("markdown reparse");
-        let (_, spans) =
-            run_highlight_cached(&mut server, 9, "markdown", None, false, None).unwrap();
-        assert!(spans.iter().any(|s| s.group == "TSTitle"));
+            .set_text(
+                1,
+                "rust",
+                "fn main() {\n    let x = 1;\n    ) }\n".to_string(),
+                3,
+            )
+            .unwrap();
+        let (_, diagnostics) = run_diagnostics_cached(&server, 1, "rust", None).unwrap();
+        let error = diagnostics
+            .iter()
+            .find(|diagnostic| diagnostic.kind == "error")
+            .unwrap_or_else(|| panic!("no error node: {diagnostics:?}"));
+        assert_eq!(error.message, "unexpected `)`");
+        assert_eq!((error.lnum, error.col), (3, 5));
     }
 
-    /// A fenced block is the first thing anyone notices about markdown
-    /// highlighting, and getting it half right is worse than not doing it: the
-    /// host query paints the whole fence `@text.literal`, so if that span
-    /// survived the injection the user would see one flat colour with tokens
-    /// fighting underneath it.
     #[test]
-    fn a_labelled_fence_is_parsed_by_its_own_grammar_in_host_coordinates() {
+    fn diagnostics_cover_injected_trees_and_are_bounded() {
         let mut server = Server::new();
-        let source = "Prose with a héllo before it.\n\n```rust\nfn main() { let x = 1; }\n```\n\n\
-                      ```\nplain fence, no language\n```\n";
+        let source = "# Notes\n\n```rust\nfn f() { let y = 2 }\n```\n";
         server
-            .set_text(11, "markdown", source.to_string(), 1)
+            .set_text(2, "markdown", source.to_string(), 1)
             .unwrap();
-
-        let (_, spans) =
-            run_highlight_cached(&mut server, 11, "markdown", None, false, None).unwrap();
-
-        // `fn` is on line 4, column 1 of the *host* document: set_included_ranges
-        // keeps injected positions in the host's coordinate space, and the
-        // multi-byte character above must not shift them.
-        let keyword = spans
+        let (_, diagnostics) = run_diagnostics_cached(&server, 2, "markdown", None).unwrap();
+        let in_fence = diagnostics
             .iter()
-            .find(|span| span.group == "TSKeyword" && span.lnum == 4)
-            .unwrap_or_else(|| panic!("rust fence produced no keyword: {spans:?}"));
-        assert_eq!((keyword.col, keyword.end_col), (1, 3));
-
-        // The host's flat literal over the injected fence is gone...
+            .find(|diagnostic| diagnostic.injected_lang == Some("rust"))
+            .unwrap_or_else(|| panic!("the broken fence reported nothing: {diagnostics:?}"));
+        // Host coordinates, like every injected range.
+        assert_eq!(in_fence.lnum, 4);
+
+        let source = "int f() { return 1 }\n".repeat(20);
+        server.set_text(3, "c", source, 1).unwrap();
+        let (_, all) = run_diagnostics_cached(&server, 3, "c", None).unwrap();
+        assert!(all.len() >= 20, "{all:?}");
+        let (_, bounded) = run_diagnostics_cached(&server, 3, "c", Some(5)).unwrap();
+        assert_eq!(bounded.len(), 5);
         assert!(
-            !spans
-                .iter()
-                .any(|span| span.group == "TSLiteral" && span.lnum == 4),
-            "the host @text.literal survived on top of the injected fence: {spans:?}"
+            bounded
+                .windows(2)
+                .all(|pair| (pair[0].lnum, pair[0].col) <= (pair[1].lnum, pair[1].col))
         );
-        // ...but an unlabelled fence keeps it, because nothing replaced it.
-        assert!(
-            spans
-                .iter()
-                .any(|span| span.group == "TSLiteral" && span.lnum == 8),
-            "an unlabelled fence lost its literal highlighting: {spans:?}"
+        assert_ne!(digest_diagnostics(&all), digest_diagnostics(&bounded));
+
+        // The host tree is walked first, but the bound keeps the earliest error
+        // of the file, here the one inside `<script>`.
+        let source = "<script>let = ;</script>\n<div>\n</div>\n<p a=\"\n";
+        server.set_text(4, "html", source.to_string(), 1).unwrap();
+        let (_, first) = run_diagnostics_cached(&server, 4, "html", Some(1)).unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(
+            (first[0].lnum, first[0].injected_lang),
+            (1, Some("javascript"))
         );
+    }
 
-        // :TsHlInspect has to agree with the screen, or it answers "why is this
-        // token this colour" with a colour that is not drawn.
-        let (_, captures, _) = inspect_cached(&mut server, 11, "markdown", 4, 1).unwrap();
-        assert!(
-            captures.iter().any(
-                |capture| capture.group == "TSKeyword" && capture.injected_lang == Some("rust")
-            ),
-            "inspect did not attribute the capture to the injected grammar: {captures:?}"
-        );
-        assert!(
-            captures.iter().all(|capture| capture.group != "TSLiteral"),
-            "inspect reported a host capture the renderer drops: {captures:?}"
-        );
+    #[test]
+    fn locals_languages_match_the_linked_locals_queries() {
+        for lang in SUPPORTED_LANGUAGES {
+            assert_eq!(
+                locals_query_source(lang).is_some(),
+                LOCALS_LANGUAGES.contains(lang),
+                "{lang}"
+            );
+        }
+    }
+
+    fn reference_points(answer: &ReferencesAnswer) -> Vec<(u32, u32, bool)> {
+        answer
+            .references
+            .iter()
+            .map(|reference| (reference.lnum, reference.col, reference.definition))
+            .collect()
     }
 
     #[test]
-    fn html_injects_javascript_and_css() {
+    fn references_follow_rust_scopes_and_shadowing() {
         let mut server = Server::new();
-        let source = "<html>\n<style>\nbody { color: red; }\n</style>\n\
-                      <script>\nfunction go() { return 1; }\n</script>\n</html>\n";
-        server.set_text(12, "html", source.to_string(), 1).unwrap();
-        let injected: Vec<&str> = server
-            .cache
-            .get(&12)
-            .unwrap()
-            .injections
-            .iter()
-            .map(|injection| injection.lang)
-            .collect();
-        assert!(injected.contains(&"css"), "{injected:?}");
-        assert!(injected.contains(&"javascript"), "{injected:?}");
+        let src = "fn add(a: i32) -> i32 {\n    let x = a;\n    let x = x + 1;\n    let f = |a| a * x;\n    f(x)\n}\nfn main() { let a = add(2); }\n";
+        server.set_text(1, "rust", src.to_string(), 4).unwrap();
+
+        // `let x = x + 1` reads the first `x` and binds the second.
+        let first = run_references_cached(&server, 1, "rust", 2, 9, None).unwrap();
+        assert_eq!(first.revision, 4);
+        assert_eq!((first.name.as_str(), first.kind), ("x", Some("var")));
+        assert_eq!(reference_points(&first), [(2, 9, true), (3, 13, false)]);
+        let second = run_references_cached(&server, 1, "rust", 5, 7, None).unwrap();
+        assert_eq!(
+            reference_points(&second),
+            [(3, 9, true), (4, 21, false), (5, 7, false)]
+        );
+
+        // The closure parameter shadows the function's, and `main` has its own.
+        let param = run_references_cached(&server, 1, "rust", 1, 8, None).unwrap();
+        assert_eq!(param.kind, Some("parameter"));
+        assert_eq!(reference_points(&param), [(1, 8, true), (2, 13, false)]);
+        let closure = run_references_cached(&server, 1, "rust", 4, 17, None).unwrap();
+        assert_eq!(reference_points(&closure), [(4, 14, true), (4, 17, false)]);
+
+        // Item names live in the enclosing scope, so a sibling can call them.
+        let function = run_references_cached(&server, 1, "rust", 7, 22, None).unwrap();
+        assert_eq!(function.kind, Some("function"));
+        assert_eq!(reference_points(&function), [(1, 4, true), (7, 21, false)]);
+
+        let bounded = run_references_cached(&server, 1, "rust", 5, 7, Some(1)).unwrap();
+        assert_eq!(bounded.references.len(), 1);
+    }
 
-        let (_, spans) = run_highlight_cached(&mut server, 12, "html", None, false, None).unwrap();
-        assert!(
-            spans
-                .iter()
-                .any(|span| span.lnum == 6 && span.group == "TSKeyword"),
-            "<script> body was not highlighted as javascript: {spans:?}"
+    #[test]
+    fn references_merge_rebinding_and_report_unresolved_names() {
+        let mut server = Server::new();
+        let src = "import os\ndef f(x):\n    total = 0\n    for x in range(3):\n        total = total + x\n    return total, os.path, len(x)\ndef g():\n    return total\n";
+        server.set_text(1, "python", src.to_string(), 1).unwrap();
+
+        // Python assigns; it does not declare. Both `total =` are one variable.
+        let total = run_references_cached(&server, 1, "python", 3, 5, None).unwrap();
+        assert_eq!(total.kind, Some("var"));
+        assert_eq!(
+            reference_points(&total),
+            [(3, 5, true), (5, 9, true), (5, 17, false), (6, 12, false)]
         );
-        assert!(
-            spans.iter().any(|span| span.lnum == 3),
-            "<style> body produced no highlighting at all: {spans:?}"
+        let loop_var = run_references_cached(&server, 1, "python", 6, 32, None).unwrap();
+        assert_eq!(loop_var.kind, Some("parameter"));
+        assert_eq!(
+            reference_points(&loop_var),
+            [(2, 7, true), (4, 9, true), (5, 25, false), (6, 32, false)]
         );
+
+        // `g` sees no `total`: only the unresolved uses of the name come back.
+        let unresolved = run_references_cached(&server, 1, "python", 8, 12, None).unwrap();
+        assert_eq!(unresolved.kind, None);
+        assert_eq!(reference_points(&unresolved), [(8, 12, false)]);
+
+        let import = run_references_cached(&server, 1, "python", 6, 19, None).unwrap();
+        assert_eq!(import.kind, Some("import"));
+        assert_eq!(reference_points(&import), [(1, 8, true), (6, 19, false)]);
+        // An attribute is looked up on the object, never in a scope.
+        let error = run_references_cached(&server, 1, "python", 6, 22, None).unwrap_err();
+        assert_eq!(error.to_string(), "no name at 6:22");
     }
 
-    /// Unknown fence tags are skipped rather than guessed at, and a fence inside
-    /// a fence must not start a second level of injection.
     #[test]
-    fn injections_skip_unknown_languages_and_do_not_nest() {
-        assert_eq!(injection_language_for_tag("rs"), Some("rust"));
-        assert_eq!(injection_language_for_tag("  Python3 "), Some("python"));
-        assert_eq!(injection_language_for_tag("brainfuck"), None);
-        assert_eq!(injection_language_for_tag(""), None);
-
+    fn references_resolve_through_block_scopes_in_c_family_languages() {
         let mut server = Server::new();
-        let source = "````markdown\n```rust\nfn inner() {}\n```\n````\n";
         server
-            .set_text(13, "markdown", source.to_string(), 1)
+            .set_text(
+                1,
+                "javascript",
+                "let v = 1;\nfunction h(v) { return v; }\nconsole.log(h(v));\n".to_string(),
+                1,
+            )
             .unwrap();
-        let injected: Vec<&str> = server
-            .cache
-            .get(&13)
-            .unwrap()
-            .injections
-            .iter()
-            .map(|injection| injection.lang)
-            .collect();
-        assert!(
-            injected.contains(&"markdown"),
-            "the outer fence did not inject markdown: {injected:?}"
+        let outer = run_references_cached(&server, 1, "javascript", 3, 15, None).unwrap();
+        assert_eq!(reference_points(&outer), [(1, 5, true), (3, 15, false)]);
+        let function = run_references_cached(&server, 1, "javascript", 3, 13, None).unwrap();
+        assert_eq!(reference_points(&function), [(2, 10, true), (3, 13, false)]);
+
+        server
+            .set_text(
+                2,
+                "c",
+                "int n = 0;\nint sq(int n) { return n * n; }\nint main(void) { return sq(n); }\n"
+                    .to_string(),
+                1,
+            )
+            .unwrap();
+        let param = run_references_cached(&server, 2, "c", 2, 24, None).unwrap();
+        assert_eq!(
+            reference_points(&param),
+            [(2, 12, true), (2, 24, false), (2, 28, false)]
         );
-        assert!(
-            !injected.contains(&"rust"),
-            "an injected fence started a second level of injection: {injected:?}"
+        let global = run_references_cached(&server, 2, "c", 3, 28, None).unwrap();
+        assert_eq!(reference_points(&global), [(1, 5, true), (3, 28, false)]);
+
+        server
+            .set_text(
+                3,
+                "go",
+                "package p\nfunc g() int {\n\ta := 1\n\tif a > 0 {\n\t\ta := 2\n\t\t_ = a\n\t}\n\treturn a\n}\n"
+                    .to_string(),
+                1,
+            )
+            .unwrap();
+        let outer = run_references_cached(&server, 3, "go", 8, 9, None).unwrap();
+        assert_eq!(
+            reference_points(&outer),
+            [(3, 2, true), (4, 5, false), (8, 9, false)]
         );
+
+        server
+            .set_text(4, "json", "{\"a\": 1}\n".to_string(), 1)
+            .unwrap();
+        let error = run_references_cached(&server, 4, "json", 1, 2, None).unwrap_err();
+        assert_eq!(error.to_string(), "no locals query for json");
     }
 
     #[test]
-    fn covered_by_injection_only_swallows_fully_contained_spans() {
+    fn definition_prefers_local_bindings_then_symbols() {
         let mut server = Server::new();
+        let src = "fn add(a: i32) -> i32 {\n    let x = a;\n    let x = x + 1;\n    x\n}\nstruct S;\nimpl S {\n    fn go(&self) {}\n}\nfn main() { let s = S; s.go(); add(1); }\n";
+        server.set_text(1, "rust", src.to_string(), 3).unwrap();
+
+        let site = |answer: &DefinitionAnswer| {
+            answer
+                .definition
+                .as_ref()
+                .map(|site| (site.lnum, site.col, site.end_col, site.kind, site.source))
+        };
+        let shadowed = run_definition_cached(&mut server, 1, "rust", 4, 5).unwrap();
+        assert_eq!(shadowed.revision, 3);
+        assert_eq!(shadowed.name, "x");
+        assert_eq!(site(&shadowed), Some((3, 9, 10, "var", "local")));
+        let param = run_definition_cached(&mut server, 1, "rust", 2, 13).unwrap();
+        assert_eq!(site(&param), Some((1, 8, 9, "parameter", "local")));
+        let function = run_definition_cached(&mut server, 1, "rust", 10, 32).unwrap();
+        assert_eq!(site(&function), Some((1, 4, 7, "function", "local")));
+
+        // A method reached through a field is no local; the symbol list knows it.
+        let method = run_definition_cached(&mut server, 1, "rust", 10, 26).unwrap();
+        assert_eq!(method.name, "go");
+        assert_eq!(site(&method), Some((8, 8, 20, "method", "symbol")));
+
+        // Languages without a locals query still get the symbol fallback.
         server
-            .set_text(14, "markdown", "```rust\nfn f() {}\n```\n".to_string(), 1)
+            .set_text(2, "bash", "greet() { echo hi; }\ngreet\n".to_string(), 1)
             .unwrap();
-        let cache = server.cache.get(&14).unwrap();
-        let ranges = &cache.injected_ranges;
-        assert_eq!(ranges.len(), 1, "{ranges:?}");
+        let shell = run_definition_cached(&mut server, 2, "bash", 2, 2).unwrap();
+        assert_eq!(site(&shell).map(|site| (site.0, site.1)), Some((1, 1)));
 
-        let block = cache
-            .tree
-            .root_node()
-            .child(0)
-            .expect("a fenced code block");
-        assert!(
-            !covered_by_injection(ranges, block),
-            "the fence itself must survive: its delimiters are not injected"
-        );
-        let content = descendant_by_kind(block, "code_fence_content").expect("fence content");
-        assert!(covered_by_injection(ranges, content));
+        server
+            .set_text(3, "python", "print(y)\n".to_string(), 1)
+            .unwrap();
+        let nowhere = run_definition_cached(&mut server, 3, "python", 1, 7).unwrap();
+        assert_eq!(nowhere.name, "y");
+        assert!(nowhere.definition.is_none());
     }
 
     #[test]
-    fn server_reuses_trees_and_preserves_revisions() {
+    fn rename_returns_scope_correct_edits_and_refuses_conflicts() {
         let mut server = Server::new();
+        let src = "fn add(a: i32) -> i32 {\n    let x = a;\n    let x = x + 1;\n    let f = |a| a * x;\n    f(x)\n}\n";
+        server.set_text(1, "rust", src.to_string(), 7).unwrap();
+
+        let answer = run_rename_cached(&mut server, 1, "rust", 5, 7, "sum").unwrap();
+        assert_eq!(answer.revision, 7);
+        let edits: Vec<_> = answer
+            .edits
+            .iter()
+            .map(|edit| (edit.lnum, edit.col, edit.end_col, edit.new_text.as_str()))
+            .collect();
         assert_eq!(
-            server
-                .set_text(7, "rust", "fn first() {}".to_string(), 10)
-                .unwrap(),
-            ParseMode::Full
+            edits,
+            [(3, 9, 10, "sum"), (4, 21, 22, "sum"), (5, 7, 8, "sum")]
         );
+        // The cache is untouched: the client applies the edits and re-syncs.
+        assert_eq!(&*server.get_cache(1, "rust").unwrap().text, src);
+
+        // Shadowing an outer name is fine when no use changes meaning.
+        let outer = run_rename_cached(&mut server, 1, "rust", 2, 9, "a").unwrap();
+        assert_eq!(outer.edits.len(), 2);
+        // Inside the closure `a` is the closure's parameter, so `x` cannot become `a`.
+        let error = run_rename_cached(&mut server, 1, "rust", 5, 7, "a").unwrap_err();
         assert_eq!(
-            server
-                .set_text(7, "rust", "fn second() {}".to_string(), 11)
-                .unwrap(),
-            ParseMode::Incremental
+            error.to_string(),
+            "renaming `x` to `a` would change what a name refers to"
         );
+        for bad in ["fn", "1x", "a-b", ""] {
+            let error = run_rename_cached(&mut server, 1, "rust", 5, 7, bad).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("`{bad}` is not a valid rust identifier")
+            );
+        }
+
+        server
+            .set_text(
+                2,
+                "rust",
+                "fn main() {\n    let v = 1;\n    let s = S { v };\n}\n".to_string(),
+                1,
+            )
+            .unwrap();
+        let error = run_rename_cached(&mut server, 2, "rust", 2, 9, "w").unwrap_err();
+        assert_eq!(error.to_string(), "`v` is also a field name at 3:17");
+
+        // An existing use of the new name would be captured by the renamed one.
+        let py = "def f(x):\n    total = 0\n    return total + len(x)\nprint(f(1))\n";
+        server.set_text(3, "python", py.to_string(), 1).unwrap();
+        let error = run_rename_cached(&mut server, 3, "python", 2, 5, "len").unwrap_err();
         assert_eq!(
-            server
-                .set_text(7, "rust", "fn second() {}".to_string(), 12)
-                .unwrap(),
-            ParseMode::Unchanged
+            error.to_string(),
+            "renaming `total` to `len` would change what a name refers to"
         );
+        let error = run_rename_cached(&mut server, 3, "python", 4, 1, "echo").unwrap_err();
+        assert_eq!(error.to_string(), "`print` is not defined in this buffer");
+        assert!(run_rename_cached(&mut server, 3, "python", 2, 5, "acc").is_ok());
+    }
 
-        let (revision, spans) =
-            run_highlight_cached(&mut server, 7, "rust", None, true, None).unwrap();
-        assert_eq!(revision, 12);
-        assert!(!spans.is_empty());
-        assert_eq!(server.full_parses, 1);
-        assert_eq!(server.incremental_parses, 1);
-        assert_eq!(server.unchanged_syncs, 1);
+    fn indents(server: &Server, buf: i64, lang: &str, src: &str, shiftwidth: u32) -> Vec<i32> {
+        let lines = src.lines().count() as u32;
+        let (_, indents) =
+            run_indent_cached(server, buf, lang, 1, Some(lines), shiftwidth, None).unwrap();
+        indents
     }
 
     #[test]
-    fn symbols_include_definition_ranges() {
+    fn indent_follows_blocks_branches_and_alignment() {
         let mut server = Server::new();
-        let source = "struct User { name: String }\nfn greet() {}\nimpl User { fn method(&self) {} }\nfn outer() { fn inner() {} }\n";
-        server.set_text(1, "rust", source.to_string(), 3).unwrap();
-        let (revision, symbols) = run_symbols_cached(&mut server, 1, "rust", None, None).unwrap();
-        assert_eq!(revision, 3);
-        assert!(symbols.iter().any(|symbol| symbol.name == "User"));
-        let greet = symbols
-            .iter()
-            .find(|symbol| symbol.name == "greet")
-            .unwrap();
-        assert_eq!(greet.kind, "function");
-        assert!(greet.container_kind.is_none());
-        let method = symbols
-            .iter()
-            .find(|symbol| symbol.name == "method")
-            .unwrap();
-        assert_eq!(method.kind, "method");
-        assert_eq!(method.container_name.as_deref(), Some("User"));
-        let inner = symbols
-            .iter()
-            .find(|symbol| symbol.name == "inner")
+        let rust = "fn f(a: i32,\n     b: i32) -> i32 {\n    if a > b {\n        a\n    } else {\n\n        b\n    }\n}\nlet s = r\"x\n  y\";\n";
+        server.set_text(1, "rust", rust.to_string(), 3).unwrap();
+        assert_eq!(
+            indents(&server, 1, "rust", rust, 4),
+            [0, 4, 4, 8, 4, 8, 8, 4, 0, 0, -1]
+        );
+        let (revision, one) = run_indent_cached(&server, 1, "rust", 4, None, 2, None).unwrap();
+        assert_eq!((revision, one), (3, vec![4]));
+
+        // Enter right after an unclosed `{` still indents.
+        let open = "fn g() {\n\n";
+        server.set_text(2, "rust", open.to_string(), 1).unwrap();
+        assert_eq!(indents(&server, 2, "rust", open, 4), [0, 4]);
+
+        let python = "def f(x,\n      y):\n    if x:\n        return 1\n    else:\n        return 2\n\ndef g():\n\n";
+        server.set_text(3, "python", python.to_string(), 1).unwrap();
+        assert_eq!(
+            indents(&server, 3, "python", python, 4),
+            [0, 6, 4, 8, 4, 8, 8, 0, 4]
+        );
+
+        let go = "func f(x int) {\n\tswitch x {\n\tcase 1:\n\t\treturn\n\tdefault:\n\t}\n}\n";
+        server.set_text(4, "go", go.to_string(), 1).unwrap();
+        assert_eq!(indents(&server, 4, "go", go, 8), [0, 8, 8, 16, 8, 8, 0]);
+
+        let vim = "vim9script\ndef F(a: number)\n  var l = [\n    a,\n  ]\n\nenddef\n";
+        server.set_text(5, "vim", vim.to_string(), 1).unwrap();
+        assert_eq!(indents(&server, 5, "vim", vim, 2), [0, 0, 2, 4, 2, 2, 0]);
+
+        server
+            .set_text(6, "haskell", "main = pure ()\n".to_string(), 1)
             .unwrap();
-        assert_eq!(inner.container_kind, Some("function"));
-        assert_eq!(inner.container_name.as_deref(), Some("outer"));
-        assert!(symbols.iter().all(|symbol| symbol.end_lnum >= symbol.lnum));
+        let error = run_indent_cached(&server, 6, "haskell", 1, None, 4, None).unwrap_err();
+        assert_eq!(error.to_string(), "no indents query for haskell");
     }
 
     #[test]
-    fn symbol_kind_filter_is_applied_before_result_limit() {
+    fn query_request_returns_captures_and_locates_compile_errors() {
         let mut server = Server::new();
-        let source = "const A: i32 = 1;\nconst B: i32 = 2;\nfn target() {}\n";
-        server
-            .set_text(42, "rust", source.to_string(), 1)
-            .expect("rust parse");
+        let src = "fn main() {\n    a.unwrap();\n}\nimpl S {\n    fn f(&self) {\n        self.x.unwrap();\n        b.unwrap();\n    }\n}\n";
+        server.set_text(1, "rust", src.to_string(), 4).unwrap();
+        let unwraps_in_impl = "(impl_item body: (declaration_list (function_item body: (block (expression_statement (call_expression function: (field_expression field: (field_identifier) @method (#eq? @method \"unwrap\"))) @call)))))";
 
-        let (_, symbols) = run_symbols_cached_filtered(
-            &mut server,
-            42,
+        let (revision, captures, truncated) =
+            run_query_cached(&server, 1, "rust", unwraps_in_impl, None, None).unwrap();
+        assert_eq!(revision, 4);
+        assert!(!truncated);
+        let found: Vec<_> = captures
+            .iter()
+            .map(|c| {
+                (
+                    c.capture.as_str(),
+                    c.kind,
+                    c.lnum,
+                    c.col,
+                    c.end_lnum,
+                    c.end_col,
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("call", "call_expression", 6, 9, 6, 24),
+                ("method", "field_identifier", 6, 16, 6, 22),
+                ("call", "call_expression", 7, 9, 7, 19),
+                ("method", "field_identifier", 7, 11, 7, 17),
+            ]
+        );
+
+        let (_, captures, _) =
+            run_query_cached(&server, 1, "rust", unwraps_in_impl, Some((7, 7)), None).unwrap();
+        assert!(captures.iter().all(|c| c.lnum == 7));
+        // A line range filters on where `#offset!` moved the capture to.
+        let moved_down = "((function_item) @f (#offset! @f 1 0 1 0))";
+        let (_, captures, _) =
+            run_query_cached(&server, 1, "rust", moved_down, Some((1, 1)), None).unwrap();
+        assert!(captures.is_empty(), "{captures:?}");
+        let (_, captures, _) =
+            run_query_cached(&server, 1, "rust", moved_down, Some((2, 2)), None).unwrap();
+        assert_eq!(captures.len(), 1);
+        assert_eq!((captures[0].lnum, captures[0].end_lnum), (2, 4));
+        let (_, captures, truncated) =
+            run_query_cached(&server, 1, "rust", "(identifier) @id", None, Some(2)).unwrap();
+        assert_eq!(captures.len(), 2);
+        assert!(truncated);
+
+        let error = run_query_cached(
+            &server,
+            1,
             "rust",
+            "(block\n  (no_such_node) @x)",
+            None,
             None,
-            Some(1),
-            &["function".to_string()],
         )
-        .expect("filtered symbols");
-        assert_eq!(symbols.len(), 1);
-        assert_eq!(symbols[0].name, "target");
-        assert_eq!(symbols[0].kind, "function");
+        .unwrap_err();
+        let error = error.downcast_ref::<tree_sitter::QueryError>().unwrap();
+        assert_eq!(query_error_kind(&error.kind), "node_type");
+        assert_eq!((error.offset, error.row, error.column), (10, 1, 3));
     }
 
     #[test]
-    fn rust_keywords_are_highlighted_as_keywords() {
-        let mut server = Server::new();
-        let source = "pub fn answer() -> i32 { let value = 42; return value; }\n";
-        server.set_text(1, "rust", source.to_string(), 1).unwrap();
-        let (_, spans) = run_highlight_cached(&mut server, 1, "rust", None, false, None).unwrap();
-        let keyword_count = spans
-            .iter()
-            .filter(|span| span.group == "TSKeyword")
-            .count();
-        assert!(keyword_count >= 4, "keyword spans: {spans:?}");
-        assert!(spans.iter().all(|span| span.depth.is_none()));
+    fn grep_walks_files_of_the_language_and_prints_captures() {
+        let root = std::env::temp_dir().join(format!("ts-hl-grep-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::write(root.join("src/a.rs"), "fn main() {\n    x.unwrap();\n}\n").unwrap();
+        std::fs::write(root.join("src/b.py"), "x.unwrap()\n").unwrap();
+        std::fs::write(root.join(".git/c.rs"), "fn c() { y.unwrap(); }\n").unwrap();
+        std::fs::write(root.join("notes.txt"), "fn d() { z.unwrap(); }\n").unwrap();
+
+        let args: Vec<String> = [
+            "--lang",
+            "rs",
+            "(call_expression function: (field_expression field: (field_identifier) @m (#eq? @m \"unwrap\"))) @call",
+            root.to_str().unwrap(),
+            root.join("notes.txt").to_str().unwrap(),
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let opts = parse_grep_args(&args).unwrap();
+        assert_eq!(opts.lang, "rust");
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        assert!(run_grep(&opts, &mut out, &mut err).unwrap());
+        let a = root.join("src/a.rs");
+        let notes = root.join("notes.txt");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "{a}:2:5:call:x.unwrap()\n{a}:2:7:m:unwrap\n{notes}:1:10:call:z.unwrap()\n{notes}:1:12:m:unwrap\n",
+                a = a.display(),
+                notes = notes.display()
+            )
+        );
+        assert!(err.is_empty());
+
+        let json = GrepOptions {
+            paths: vec![a.clone()],
+            json: true,
+            ..opts
+        };
+        let mut out = Vec::new();
+        run_grep(&json, &mut out, &mut err).unwrap();
+        let first: serde_json::Value =
+            serde_json::from_str(String::from_utf8(out).unwrap().lines().next().unwrap()).unwrap();
+        assert_eq!(first["capture"], "call");
+        assert_eq!(first["lnum"], 2);
+        assert_eq!(first["text"], "x.unwrap()");
+
+        // `#offset!` moves the printed text along with the position.
+        let shifted = GrepOptions {
+            lang: "rust",
+            query: "((call_expression) @call (#offset! @call 0 2 0 0))".to_string(),
+            paths: vec![a.clone()],
+            json: false,
+        };
+        let mut out = Vec::new();
+        run_grep(&shifted, &mut out, &mut err).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("{}:2:7:call:unwrap()\n", a.display())
+        );
+
+        let bad = GrepOptions {
+            query: "(call_expression".to_string(),
+            ..json
+        };
+        let error = run_grep(&bad, &mut Vec::new(), &mut err).unwrap_err();
+        assert!(
+            error.to_string().starts_with("Query error at 1:"),
+            "{error}"
+        );
+        assert!(parse_grep_args(&["(x)".to_string(), "a".to_string()]).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    /// `:TsHlInspect` must answer "why is this token this colour, and which
-    /// group do I override" with the same facts the renderer used.
     #[test]
-    fn inspect_reports_captures_groups_and_the_node_chain() {
-        let mut server = Server::new();
-        let source = "pub fn answer() -> i32 { 42 }\n";
-        server.set_text(1, "rust", source.to_string(), 7).unwrap();
-        // Column 5 is the 'f' of `fn`.
-        let (revision, captures, chain) = inspect_cached(&mut server, 1, "rust", 1, 5).unwrap();
-        assert_eq!(revision, 7);
+    fn highlight_renders_files_as_ansi_html_and_json() {
+        let args = |list: &[&str]| list.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let opts = parse_highlight_args(&args(&["src/main.rs"])).unwrap();
+        assert_eq!(opts.lang, None);
+        assert_eq!(opts.format, HighlightFormat::Ansi);
+        let opts = parse_highlight_args(&args(&["-l", "py", "--format", "html", "-"])).unwrap();
+        assert_eq!(
+            (opts.lang, opts.path, opts.format),
+            (Some("python"), None, HighlightFormat::Html)
+        );
+        assert!(parse_highlight_args(&args(&["notes.txt"])).is_err());
+        assert!(parse_highlight_args(&args(&["--format", "pdf", "a.rs"])).is_err());
+        assert!(parse_highlight_args(&args(&["a.rs", "b.rs"])).is_err());
+
+        let source = "fn f() -> u8 { g((1)) }\n// a < b\n";
+        let render = |format| {
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            render_highlights(
+                "rust",
+                source.to_string(),
+                "f.rs",
+                format,
+                &mut out,
+                &mut err,
+            )
+            .unwrap();
+            assert!(err.is_empty());
+            String::from_utf8(out).unwrap()
+        };
 
-        let keyword = captures
-            .iter()
-            .find(|capture| capture.capture == "keyword")
-            .unwrap_or_else(|| panic!("no @keyword capture: {captures:?}"));
-        assert_eq!(keyword.group, "TSKeyword");
-        assert_eq!(keyword.priority, capture_priority("keyword"));
-        assert_eq!((keyword.lnum, keyword.col), (1, 5));
-        assert_eq!((keyword.end_lnum, keyword.end_col), (1, 7));
-        assert!(keyword.applied, "the winning capture was not marked");
-        // Highest priority first, so the first line of the report is the answer.
-        assert_eq!(captures.first().map(|c| c.priority), Some(keyword.priority));
+        let ansi = render(HighlightFormat::Ansi);
+        assert!(ansi.starts_with("\x1b[38;5;176mfn\x1b[0m "), "{ansi:?}");
+        assert!(ansi.contains("\x1b[38;5;75mg\x1b[0m"), "{ansi:?}");
+        assert!(ansi.contains("\x1b[38;5;173m1\x1b[0m"), "{ansi:?}");
+        // Nested brackets take successive rainbow colours: `{` is the first.
+        assert!(ansi.contains("\x1b[38;5;168m{\x1b[0m"), "{ansi:?}");
+        assert!(
+            ansi.contains("\x1b[38;5;180m(\x1b[0m\x1b[38;5;75m(\x1b[0m"),
+            "{ansi:?}"
+        );
+        assert!(
+            ansi.ends_with("\x1b[3;38;5;242m// a < b\x1b[0m\n"),
+            "{ansi:?}"
+        );
+        let plain: String = {
+            let mut plain = String::new();
+            let mut rest = ansi.as_str();
+            while let Some(at) = rest.find('\x1b') {
+                plain.push_str(&rest[..at]);
+                rest = &rest[at + rest[at..].find('m').unwrap() + 1..];
+            }
+            plain + rest
+        };
+        assert_eq!(plain, source);
 
-        // Innermost first, up to the root, with parent field names attached.
-        assert_eq!(chain.first().map(|node| node.kind.as_str()), Some("fn"));
-        assert_eq!(
-            chain.last().map(|node| node.kind.as_str()),
-            Some("source_file")
+        let html = render(HighlightFormat::Html);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>f.rs</title>"));
+        assert!(html.contains(".TSKeyword { color: #c678dd }"));
+        assert!(
+            html.contains(
+                "<span class=\"TSKeyword\">fn</span> <span class=\"TSFunction\">f</span>"
+            ),
+            "{html}"
+        );
+        assert!(
+            html.contains("<span class=\"TSComment\">// a &lt; b</span>"),
+            "{html}"
         );
-        let function = chain
-            .iter()
-            .find(|node| node.kind == "function_item")
-            .unwrap_or_else(|| panic!("no enclosing function_item: {chain:?}"));
-        assert_eq!((function.lnum, function.end_lnum), (1, 1));
 
-        // Column 8 is the function's name; a node that fills a named slot in
-        // its parent reports which slot, which is what makes the chain usable
-        // for writing a query.
-        let (_, name_captures, name_chain) = inspect_cached(&mut server, 1, "rust", 1, 8).unwrap();
-        assert_eq!(
-            name_chain.first().map(|node| node.field.as_deref()),
-            Some(Some("name")),
-            "innermost node did not report its parent field: {name_chain:?}"
+        let json: Vec<serde_json::Value> =
+            serde_json::from_str(&render(HighlightFormat::Json)).unwrap();
+        assert!(
+            json.iter()
+                .any(|span| span["group"] == "TSComment" && span["lnum"] == 2)
         );
         assert!(
-            name_captures
-                .iter()
-                .any(|capture| capture.group == "TSFunction"),
-            "function name is not captured as a function: {name_captures:?}"
+            json.iter()
+                .any(|span| span["group"] == "TSPunctBracket" && span["depth"] == 2)
+        );
+
+        // Past the span cap the rest is written plain, and stderr says so.
+        let numbers = format!("[{}0]\n", "0,".repeat(MAX_HIGHLIGHT_SPANS));
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        render_highlights(
+            "json",
+            numbers.clone(),
+            "big.json",
+            HighlightFormat::Ansi,
+            &mut out,
+            &mut err,
+        )
+        .unwrap();
+        assert!(out.ends_with(b"0,0]\n"));
+        assert_eq!(
+            String::from_utf8(err).unwrap(),
+            format!("big.json: stopped after {MAX_HIGHLIGHT_SPANS} highlight spans\n")
         );
     }
 
-    /// A byte column landing inside a multi-byte character must not describe a
-    /// neighbouring token, and a column past the end of the line must clamp.
     #[test]
-    fn inspect_clamps_columns_to_the_line_and_to_utf8_boundaries() {
+    fn query_overrides_extend_or_replace_and_report_broken_files() {
+        let dir = std::env::temp_dir().join(format!("ts-hl-queries-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for lang in ["rust", "python", "json"] {
+            std::fs::create_dir_all(dir.join(lang)).unwrap();
+        }
+        std::fs::write(
+            dir.join("rust/highlights.scm"),
+            "; extends\n((identifier) @keyword (#eq? @keyword \"unless\"))\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("json/symbols.scm"),
+            "(pair key: (string) @name) @definition.field\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("python/highlights.scm"),
+            "; extends\n\n  (no_such_node) @x\n",
+        )
+        .unwrap();
+
         let mut server = Server::new();
-        let source = "let s = \"héllo\";\nlet t = 1;\n";
-        server.set_text(1, "rust", source.to_string(), 1).unwrap();
-        // 'é' starts at byte column 11 and occupies columns 11-12.
-        let (_, mid_char, _) = inspect_cached(&mut server, 1, "rust", 1, 12).unwrap();
-        let (_, boundary, _) = inspect_cached(&mut server, 1, "rust", 1, 11).unwrap();
+        server
+            .set_text(1, "rust", "fn f() { unless }\n".to_string(), 1)
+            .unwrap();
+        let bundled = server.queries["rust"].hl_query.pattern_count();
+        let (overrides, errors) = server.set_query_paths(&[dir.to_string_lossy().into_owned()]);
+        let applied: Vec<_> = overrides
+            .iter()
+            .map(|o| (o.lang, o.query, o.mode))
+            .collect();
         assert_eq!(
-            mid_char
-                .iter()
-                .map(|c| (c.capture.as_str(), c.col))
-                .collect::<Vec<_>>(),
-            boundary
-                .iter()
-                .map(|c| (c.capture.as_str(), c.col))
-                .collect::<Vec<_>>(),
+            applied,
+            [
+                ("rust", "highlights", "extend"),
+                ("json", "symbols", "replace")
+            ]
         );
-
-        // Well past the end of line 1: still line 1, never line 2's content.
-        let (_, _, chain) = inspect_cached(&mut server, 1, "rust", 1, 9_000).unwrap();
+        // Already-loaded languages are recompiled in place.
+        assert_eq!(server.queries["rust"].hl_query.pattern_count(), bundled + 1);
+        let (_, spans) = run_highlight_cached(&mut server, 1, "rust", None, false, None).unwrap();
         assert!(
-            chain.iter().all(|node| node.lnum <= 1),
-            "clamped column escaped its line: {chain:?}"
+            spans
+                .iter()
+                .any(|span| span.col == 10 && span.group == "TSKeyword")
         );
+
+        assert_eq!(errors.len(), 1);
+        let (error, location) = &errors[0];
+        assert!(error.to_string().contains("python"), "{error}");
+        let location = location.as_ref().unwrap();
+        assert!(location.path.ends_with("highlights.scm"));
+        assert_eq!((location.row, location.column), (3, 4));
+        // The broken file is skipped: python still compiles with the bundled query.
+        server
+            .set_text(2, "python", "x = 1\n".to_string(), 1)
+            .unwrap();
+
+        let (overrides, errors) = server.set_query_paths(&[]);
+        assert!(overrides.is_empty() && errors.is_empty());
+        assert_eq!(server.queries["rust"].hl_query.pattern_count(), bundled);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    /// `point_at_byte` is the only byte -> position conversion the text objects
-    /// have, and it is the one place a checkpoint off-by-one would put every
-    /// inner range on the wrong line without any other test noticing. Compare
-    /// it against a naive scan at every byte of a text that crosses several
-    /// checkpoint strides and carries multi-byte characters.
     #[test]
-    fn point_at_byte_agrees_with_a_naive_scan_across_checkpoint_strides() {
-        let mut text = String::new();
-        for line in 0..(LINE_INDEX_STRIDE * 3 + 7) {
-            text.push_str(&format!("line {line} héllo\n"));
+    fn load_grammar_refuses_bad_names_and_unloadable_libraries() {
+        for (name, expected) in [
+            (
+                "Zig",
+                "grammar name `Zig` must be lowercase letters, digits and `_`",
+            ),
+            (
+                "",
+                "grammar name `` must be lowercase letters, digits and `_`",
+            ),
+            ("rust", "`rust` is a bundled grammar"),
+            ("markdown_inline", "`markdown_inline` is a bundled grammar"),
+        ] {
+            let error = load_grammar(name, "/nonexistent/libtree-sitter-x.so", None)
+                .err()
+                .unwrap();
+            assert_eq!(error.to_string(), expected);
         }
-        text.push_str("no trailing newline");
-        let index = SparseLineIndex::new(&text);
+        let error = load_grammar("zig", "/nonexistent/libtree-sitter-zig.so", None)
+            .err()
+            .unwrap();
+        assert!(error.to_string().starts_with("grammar `zig`: "), "{error}");
+        // Nothing was registered, so the language stays unknown.
+        assert!(dynamic_grammar("zig").is_none());
+        let mut server = Server::new();
+        let error = server
+            .set_text(1, "zig", "const x = 1;\n".to_string(), 1)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "unsupported language: zig");
+    }
 
-        let mut row = 0_usize;
-        let mut line_start = 0_usize;
-        for offset in 0..=text.len() {
-            let expected = tree_sitter::Point {
-                row,
-                column: offset - line_start,
-            };
-            assert_eq!(
-                index.point_at_byte(&text, offset),
-                expected,
-                "byte {offset} mapped to the wrong point"
-            );
-            if text.as_bytes().get(offset) == Some(&b'\n') {
-                row += 1;
-                line_start = offset + 1;
-            }
+    #[test]
+    #[cfg(unix)]
+    fn load_grammar_registers_a_library_and_falls_back_to_generic_tables() {
+        // A stand-in grammar library whose entry point returns whatever table
+        // it was handed: here the bundled Python grammar.
+        let dir = std::env::temp_dir().join(format!("ts-hl-grammar-{}", std::process::id()));
+        let queries = dir.join("queries");
+        std::fs::create_dir_all(&queries).unwrap();
+        let source = dir.join("shim.c");
+        std::fs::write(
+            &source,
+            "static const void *language;\n\
+             void set_language(const void *l) { language = l; }\n\
+             const void *tree_sitter_shim(void) { return language; }\n",
+        )
+        .unwrap();
+        let library = dir.join("libtree-sitter-shim.so");
+        let compiler = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+        let Ok(status) = std::process::Command::new(&compiler)
+            .args(["-shared", "-fPIC", "-o"])
+            .arg(&library)
+            .arg(&source)
+            .status()
+        else {
+            // Building the shim needs a C compiler; a host without one has
+            // nothing to test here rather than a failure.
+            eprintln!("skipped: no C compiler `{compiler}` to build the shim grammar");
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        };
+        assert!(status.success());
+        // SAFETY: the library was just built from the source above; its
+        // `set_language` stores the pointer and nothing else.
+        unsafe {
+            let shim = libloading::Library::new(&library).unwrap();
+            let set_language = shim
+                .get::<unsafe extern "C" fn(*const ())>(b"set_language")
+                .unwrap();
+            set_language(tree_sitter_python::LANGUAGE.into_raw()());
+            // Left loaded: `load_grammar` opens the same handle and needs the
+            // stored pointer.
+            std::mem::forget(shim);
         }
-        // Past the end clamps rather than panicking on the slice.
+        std::fs::write(queries.join("highlights.scm"), "(identifier) @variable\n").unwrap();
+
+        let library = library.to_str().unwrap();
+        let grammar = load_grammar("shim", library, queries.to_str()).unwrap();
+        assert_eq!(grammar.name, "shim");
+        assert_eq!(grammar.query_names(), ["highlights"]);
+        // The same library again without a query directory is a no-op; another
+        // library is refused.
+        assert!(load_grammar("shim", library, None).is_ok());
+        let error = load_grammar("shim", "/nonexistent/other.so", None)
+            .err()
+            .unwrap();
         assert_eq!(
-            index.point_at_byte(&text, text.len() + 500),
-            index.point_at_byte(&text, text.len())
+            error.to_string(),
+            format!("grammar `shim` is already loaded from {library}")
         );
+
+        let mut server = Server::new();
+        let text = "class A:\n    def f(self):\n        if self:\n            return g(\n                1)\n";
+        server.set_text(1, "shim", text.to_string(), 1).unwrap();
+        // No folds.scm: the generic kinds fold the class, the method and the `if`.
+        let (_, folds) = run_folds_cached(&server, 1, "shim", None).unwrap();
+        let folds: Vec<_> = folds
+            .iter()
+            .map(|fold| (fold.lnum, fold.end_lnum, fold.level))
+            .collect();
+        assert_eq!(folds, [(1, 5, 1), (2, 5, 2), (3, 5, 3)]);
+
+        // Loading it again from another query directory swaps the queries,
+        // and the ones this server already compiled give way to them.
+        server.ensure_queries("shim").unwrap();
+        let reloaded = dir.join("reloaded");
+        std::fs::create_dir_all(&reloaded).unwrap();
+        std::fs::write(reloaded.join("folds.scm"), "(return_statement) @fold\n").unwrap();
+        let mut out = Vec::new();
+        let line = serde_json::json!({
+            "type": "load_grammar",
+            "name": "shim",
+            "library": library,
+            "query_dir": reloaded,
+        });
+        handle_line(&mut server, &line.to_string(), &mut out).unwrap();
+        let reply: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(reply["type"], "grammar_loaded", "{reply}");
+        assert_eq!(reply["queries"], serde_json::json!(["folds"]));
+        server.ensure_queries("shim").unwrap();
+        // A folds.scm replaces the generic kinds: only the `return` folds now.
+        let (_, folds) = run_folds_cached(&server, 1, "shim", None).unwrap();
+        let folds: Vec<_> = folds
+            .iter()
+            .map(|fold| (fold.lnum, fold.end_lnum, fold.level))
+            .collect();
+        assert_eq!(folds, [(4, 5, 1)]);
+        let cache = server.get_cache(1, "shim").unwrap();
+        let call = cache
+            .tree
+            .root_node()
+            .named_descendant_for_point_range(
+                tree_sitter::Point::new(3, 19),
+                tree_sitter::Point::new(3, 19),
+            )
+            .unwrap();
+        let kinds: Vec<_> = std::iter::successors(Some(call), |node| node.parent())
+            .filter_map(|node| scope_kind("shim", node))
+            .collect();
+        assert_eq!(kinds, ["conditional", "function", "class"]);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn scope_chain_separates_outer_and_inner_ranges() {
+    fn query_predicates_filter_matches_offset_captures_and_set_priority() {
+        assert_eq!(
+            lua_pattern_to_regex("^[A-Z][A-Z%d_]*$").unwrap(),
+            "^[A-Z][A-Z0-9_]*$"
+        );
+        assert_eq!(lua_pattern_to_regex("%a+%.-$").unwrap(), "[A-Za-z]+\\.*?$");
+        assert!(lua_pattern_to_regex("%b()").is_err());
+
         let mut server = Server::new();
-        let source = "fn outer(first: i32, second: i32) -> i32 {\n    \
-                      let total = first + second;\n    total\n}\n";
-        server.set_text(3, "rust", source.to_string(), 12).unwrap();
+        let text = "const MAX_LEN: usize = 1;\nfn f(n: usize) { let x = MAX_LEN + n; }\n";
+        server.set_text(1, "rust", text.to_string(), 1).unwrap();
+        let (_, spans) = run_highlight_cached(&mut server, 1, "rust", None, false, None).unwrap();
+        let group_at = |spans: &[Span], lnum, col| {
+            spans
+                .iter()
+                .find(|span| span.lnum == lnum && span.col == col)
+                .map(|span| span.group)
+        };
+        // `#lua-match?` in the bundled query: SCREAMING_CASE is a constant.
+        assert_eq!(group_at(&spans, 1, 7), Some("TSConstant"));
+        assert_eq!(group_at(&spans, 2, 26), Some("TSConstant"));
+        assert_eq!(group_at(&spans, 2, 22), Some("TSVariable"));
+
+        let positions = |source: &str| {
+            let (_, captures, _) =
+                run_query_cached(&server, 1, "rust", source, None, None).unwrap();
+            captures
+                .iter()
+                .map(|capture| (capture.lnum, capture.col, capture.end_col))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            positions("((identifier) @id (#not-has-parent? @id let_declaration parameter))"),
+            [(1, 7, 14), (2, 4, 5), (2, 26, 33), (2, 36, 37)]
+        );
+        assert_eq!(
+            positions("((identifier) @id (#has-ancestor? @id block))"),
+            [(2, 22, 23), (2, 26, 33), (2, 36, 37)]
+        );
+        assert_eq!(
+            positions("((identifier) @id (#contains? @id \"LEN\"))"),
+            [(1, 7, 14), (2, 26, 33)]
+        );
+        assert_eq!(
+            positions("((identifier) @id (#eq? @id \"MAX_LEN\") (#offset! @id 0 4 0 0))"),
+            [(1, 11, 14), (2, 30, 33)]
+        );
 
-        // Cursor on `total` inside the body.
-        let answer = scope_chain_cached(&server, 3, "rust", 2, 9).unwrap();
-        let (revision, chain) = (answer.revision, answer.chain);
-        assert_eq!(revision, 12);
+        let error = run_query_cached(
+            &server,
+            1,
+            "rust",
+            "((identifier) @id (#frob? @id))",
+            None,
+            None,
+        )
+        .unwrap_err();
+        let error = error.downcast_ref::<tree_sitter::QueryError>().unwrap();
+        assert_eq!(error.kind, tree_sitter::QueryErrorKind::Predicate);
+        assert_eq!((error.row, error.column), (0, 19));
+        assert!(error.message.contains("#frob?"), "{}", error.message);
+
+        let error = run_query_cached(
+            &server,
+            1,
+            "rust",
+            "((identifier) @id (#set! priority \"300\"))",
+            None,
+            None,
+        )
+        .unwrap_err();
+        let error = error.downcast_ref::<tree_sitter::QueryError>().unwrap();
+        assert_eq!(error.kind, tree_sitter::QueryErrorKind::Predicate);
+        assert_eq!((error.row, error.column), (0, 25));
+        assert!(error.message.contains("\"300\""), "{}", error.message);
+
+        // The bundled queries carry the builtin tie-breaks as `#set!` settings.
+        let (_, captures, _) = inspect_cached(&mut server, 1, "rust", 1, 16).unwrap();
         assert_eq!(
-            chain.last().map(|node| node.node),
-            Some("source_file"),
-            "chain does not reach the root: {chain:?}"
+            (captures[0].capture.as_str(), captures[0].priority),
+            ("type.builtin", 9)
         );
-        let function = chain
-            .iter()
-            .find(|node| node.kind == Some("function"))
-            .unwrap_or_else(|| panic!("no enclosing function: {chain:?}"));
-        assert_eq!(function.node, "function_item");
-        // Outer spans the whole item; inner is the body with braces and the
-        // whitespace they left behind removed.
+
+        // `#set! priority` outranks the capture-name heuristic.
+        let dir = std::env::temp_dir().join(format!("ts-hl-predicates-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("rust")).unwrap();
+        std::fs::write(
+            dir.join("rust/highlights.scm"),
+            "; extends\n((identifier) @variable (#eq? @variable \"MAX_LEN\") (#set! priority \"9\"))\n",
+        )
+        .unwrap();
+        let (_, errors) = server.set_query_paths(&[dir.to_string_lossy().into_owned()]);
+        assert!(errors.is_empty());
+        let (_, spans) = run_highlight_cached(&mut server, 1, "rust", None, false, None).unwrap();
+        assert_eq!(group_at(&spans, 1, 7), Some("TSVariable"));
+        let (_, captures, _) = inspect_cached(&mut server, 1, "rust", 1, 7).unwrap();
         assert_eq!(
             (
-                function.lnum,
-                function.col,
-                function.end_lnum,
-                function.end_col
+                captures[0].capture.as_str(),
+                captures[0].priority,
+                captures[0].applied
             ),
-            (1, 1, 4, 2)
+            ("variable", 9, true)
         );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn injected_trees_are_reused_while_their_ranges_only_shift() {
+        let document = |name: &str| {
+            format!("# Title\n\n```rust\nfn {name}() {{}}\n```\n\n```rust\nfn other() {{}}\n```\n")
+        };
+        let mut server = Server::new();
+        server.set_text(1, "markdown", document("a"), 1).unwrap();
+        // One rust tree for both fences, one markdown_inline tree for the title.
         assert_eq!(
             (
-                function.inner_lnum,
-                function.inner_col,
-                function.inner_end_lnum,
-                function.inner_end_col
+                server.injected_full_parses,
+                server.injected_incremental_parses
             ),
-            (2, 5, 3, 10)
+            (2, 0)
         );
 
-        // A parameter's outer range swallows the separator that would otherwise
-        // be left behind; the last one takes the comma in front of it instead.
-        let at_second = scope_chain_cached(&server, 3, "rust", 1, 22).unwrap().chain;
-        let second = at_second
-            .iter()
-            .find(|node| node.kind == Some("parameter"))
-            .unwrap_or_else(|| panic!("no parameter at the cursor: {at_second:?}"));
+        server.set_text(1, "markdown", document("abc"), 2).unwrap();
         assert_eq!(
-            (second.col, second.end_col),
-            (20, 33),
-            "last parameter did not take the preceding comma"
+            (
+                server.injected_full_parses,
+                server.injected_incremental_parses
+            ),
+            (2, 2)
         );
-        assert_eq!((second.inner_col, second.inner_end_col), (22, 33));
-
-        let at_first = scope_chain_cached(&server, 3, "rust", 1, 10).unwrap().chain;
-        let first = at_first
-            .iter()
-            .find(|node| node.kind == Some("parameter"))
-            .unwrap_or_else(|| panic!("no parameter at the cursor: {at_first:?}"));
+        let (_, reused) =
+            run_highlight_cached(&mut server, 1, "markdown", None, false, None).unwrap();
+        let mut fresh = Server::new();
+        fresh.set_text(1, "markdown", document("abc"), 1).unwrap();
+        let (_, expected) =
+            run_highlight_cached(&mut fresh, 1, "markdown", None, false, None).unwrap();
+        assert_eq!(format!("{reused:?}"), format!("{expected:?}"));
+
+        // A third fence is a range the old rust tree never saw.
+        server
+            .set_text(
+                1,
+                "markdown",
+                document("abc") + "```rust\nfn third() {}\n```\n",
+                3,
+            )
+            .unwrap();
         assert_eq!(
-            (first.col, first.end_col),
-            (10, 22),
-            "parameter outer did not swallow the trailing comma and space"
+            (
+                server.injected_full_parses,
+                server.injected_incremental_parses
+            ),
+            (3, 3)
         );
-        assert_eq!((first.inner_col, first.inner_end_col), (10, 20));
     }
 
-    /// A node carries exactly one class, so a closure passed as an argument is
-    /// reachable with `af`/`if` and does not also masquerade as a parameter with
-    /// a contradictory outer range.
     #[test]
-    fn a_closure_argument_is_classified_as_a_function_not_a_parameter() {
+    fn injected_symbols_and_folds_nest_under_their_host() {
         let mut server = Server::new();
-        let source = "fn main() {\n    let v = items.map(|x| x + 1);\n}\n";
-        server.set_text(4, "rust", source.to_string(), 1).unwrap();
-        // Column 27 is inside `|x| x + 1`.
-        let chain = scope_chain_cached(&server, 4, "rust", 2, 27).unwrap().chain;
-        let closure = chain
+        let source = "# Intro\n\n## Usage\n\n```rust\nfn main() {\n    run();\n}\n```\n";
+        server
+            .set_text(1, "markdown", source.to_string(), 1)
+            .unwrap();
+        let (_, symbols) =
+            run_symbols_cached_filtered(&mut server, 1, "markdown", None, None, &[]).unwrap();
+        let main = symbols
             .iter()
-            .find(|node| node.node == "closure_expression")
-            .unwrap_or_else(|| panic!("no closure in the chain: {chain:?}"));
-        assert_eq!(closure.kind, Some("function"));
+            .find(|symbol| symbol.name == "main")
+            .expect("fenced function in the outline");
+        assert_eq!(
+            (
+                main.lang,
+                main.kind,
+                main.lnum,
+                main.container_name.as_deref(),
+                main.container_lnum
+            ),
+            (Some("rust"), "function", 6, Some("Usage"), Some(3))
+        );
         assert!(
-            chain
+            symbols
                 .iter()
-                .take_while(|node| node.node != "closure_expression")
-                .all(|node| node.kind != Some("parameter")),
-            "the closure argument was also reported as a parameter: {chain:?}"
+                .filter(|symbol| symbol.kind != "function")
+                .all(|symbol| symbol.lang.is_none())
+        );
+
+        // The function folds on its own, one level inside whatever the host
+        // folds around the fence.
+        let (_, folds) = run_folds_cached(&server, 1, "markdown", None).unwrap();
+        let function = folds
+            .iter()
+            .find(|fold| (fold.lnum, fold.end_lnum) == (6, 8))
+            .expect("fold for the fenced function");
+        let enclosing = folds
+            .iter()
+            .filter(|fold| fold.lnum <= 6 && 8 <= fold.end_lnum && fold != &function)
+            .count() as u32;
+        assert_eq!(function.level, enclosing + 1);
+
+        let html = "<html>\n<script>\nfunction greet() {\n  return 1;\n}\n</script>\n</html>\n";
+        server.set_text(2, "html", html.to_string(), 1).unwrap();
+        let (_, symbols) =
+            run_symbols_cached_filtered(&mut server, 2, "html", None, None, &[]).unwrap();
+        let greet = symbols
+            .iter()
+            .find(|symbol| symbol.name == "greet")
+            .expect("script function in the outline");
+        assert_eq!(
+            (greet.lang, greet.container_name.as_deref()),
+            (Some("javascript"), Some("script"))
+        );
+        let (_, folds) = run_folds_cached(&server, 2, "html", None).unwrap();
+        assert!(
+            folds
+                .iter()
+                .any(|fold| (fold.lnum, fold.end_lnum) == (3, 5))
         );
     }
 
-    /// Incremental selection walks the chain outward, so it must be ordered
-    /// innermost-first and every step must contain the previous one.
     #[test]
-    fn scope_chain_is_ordered_innermost_first_and_nests() {
+    fn scope_chain_enters_injected_code_and_continues_into_the_host() {
         let mut server = Server::new();
-        let source = "def f(a, b):\n    x = a + b\n    return x\n";
-        server.set_text(5, "python", source.to_string(), 1).unwrap();
-        let chain = scope_chain_cached(&server, 5, "python", 2, 9)
-            .unwrap()
-            .chain;
-        assert!(chain.len() >= 3, "chain is too short: {chain:?}");
+        let source =
+            "# Notes\n\n```rust\nfn main() {\n    run(1);\n}\n```\n\n```rust\nfn other() {}\n```\n";
+        server
+            .set_text(1, "markdown", source.to_string(), 1)
+            .unwrap();
+        let answer = scope_chain_cached(&server, 1, "markdown", 5, 9).unwrap();
+        let chain = &answer.chain;
+        let function = chain
+            .iter()
+            .find(|node| node.kind == Some("function"))
+            .unwrap_or_else(|| panic!("no function around the point: {chain:?}"));
+        assert_eq!(
+            (
+                function.node,
+                function.lang,
+                function.lnum,
+                function.end_lnum
+            ),
+            ("function_item", Some("rust"), 4, 6)
+        );
+        assert_eq!((function.inner_lnum, function.inner_end_lnum), (5, 5));
+        // The rust tree's root spans both fences and is skipped; the walk goes
+        // on through the fence and the section of the host instead.
+        assert!(
+            chain.iter().all(|node| node.node != "source_file"),
+            "{chain:?}"
+        );
+        let host = chain
+            .iter()
+            .position(|node| node.lang.is_none())
+            .expect("host nodes after the injected ones");
+        assert!(chain[host..].iter().all(|node| node.lang.is_none()));
+        assert!(
+            chain[host..]
+                .iter()
+                .any(|node| node.node == "fenced_code_block")
+        );
         for pair in chain.windows(2) {
             let (inner, outer) = (&pair[0], &pair[1]);
             assert!(
@@ -4581,370 +12071,1119 @@ mod tests {
                 "chain step is not nested: {outer:?} does not contain {inner:?}"
             );
         }
+        assert!(
+            answer.anchor[0] >= 3 && answer.anchor[2] <= 7,
+            "{:?}",
+            answer.anchor
+        );
 
-        // Python has no braces: the inner half of a function is its suite, and
-        // the delimiter-stripping must not eat anything it should not.
-        let function = chain
-            .iter()
-            .find(|node| node.kind == Some("function"))
-            .unwrap_or_else(|| panic!("no enclosing function: {chain:?}"));
+        let html = "<script>\nconst f = (a, b) => a + b;\n</script>\n";
+        server.set_text(2, "html", html.to_string(), 1).unwrap();
+        let chain = scope_chain_cached(&server, 2, "html", 2, 12).unwrap().chain;
         assert_eq!(
-            (
-                function.inner_lnum,
-                function.inner_col,
-                function.inner_end_lnum,
-                function.inner_end_col
-            ),
-            (2, 5, 3, 13)
+            chain.first().map(|node| (node.kind, node.lang)),
+            Some((Some("parameter"), Some("javascript")))
         );
+        assert!(chain.iter().any(|node| node.node == "script_element"));
     }
 
-    /// The client caches one reply for the whole anchor range and asks again
-    /// only when the cursor leaves it, so the anchor has to be exactly the set
-    /// of columns that produce this chain. Were it ever too wide, a text object
-    /// would silently act on a chain belonging to a different token.
+    #[cfg(unix)]
     #[test]
-    fn every_column_inside_the_anchor_reproduces_the_same_chain() {
-        let mut server = Server::new();
-        let source = "fn main() {\n    let answer = compute(1, 2);\n}\n";
-        server.set_text(7, "rust", source.to_string(), 1).unwrap();
-
-        // Every (line, column) of the source, so the leading-indentation case —
-        // where the resolved node is the whole enclosing block and its own
-        // range would be far too wide an anchor — is covered by construction.
-        let lines: Vec<&str> = source.lines().collect();
-        let mut anchors_narrower_than_their_node = 0;
-        for (row, line) in lines.iter().enumerate() {
-            let lnum = row as u32 + 1;
-            for col in 1..=(line.len() as u32 + 1) {
-                let answer = scope_chain_cached(&server, 7, "rust", lnum, col).unwrap();
-                let shape: Vec<&str> = answer.chain.iter().map(|node| node.node).collect();
-                let [alnum, acol, aelnum, aecol] = answer.anchor;
-                if answer.chain.first().is_some_and(|node| {
-                    (node.lnum, node.col) < (alnum, acol)
-                        || (node.end_lnum, node.end_col) > (aelnum, aecol)
-                }) {
-                    anchors_narrower_than_their_node += 1;
-                }
-                // An anchor may end on the phantom line after the final newline.
-                for probe_lnum in alnum..=aelnum.min(lines.len() as u32) {
-                    let width = lines[probe_lnum as usize - 1].len() as u32;
-                    let first = if probe_lnum == alnum { acol } else { 1 };
-                    let last = if probe_lnum == aelnum {
-                        aecol - 1
-                    } else {
-                        width
-                    };
-                    for probe in first..=last.min(width) {
-                        let probed = scope_chain_cached(&server, 7, "rust", probe_lnum, probe)
-                            .unwrap()
-                            .chain;
-                        assert_eq!(
-                            probed.iter().map(|node| node.node).collect::<Vec<_>>(),
-                            shape,
-                            "{probe_lnum}:{probe} is inside the anchor \
-                             [{alnum}:{acol}-{aelnum}:{aecol}] reported for {lnum}:{col} \
-                             but produces a different chain"
-                        );
-                    }
+    fn listen_connections_share_the_daemon_but_not_buffer_numbers() {
+        use std::os::unix::net::UnixStream;
+        let path = std::env::temp_dir().join(format!("ts-hl-listen-{}.sock", std::process::id()));
+        // A socket nobody answers on is what a crashed daemon leaves behind.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let opts = ListenOptions {
+            path: path.clone(),
+            idle_timeout: None,
+        };
+        std::thread::spawn(move || listen(&opts));
+        let connect = || {
+            for _ in 0..100 {
+                if let Ok(stream) = UnixStream::connect(&path) {
+                    return stream;
                 }
+                std::thread::sleep(std::time::Duration::from_millis(20));
             }
-        }
-        // The indentation columns are exactly the case a node-range anchor gets
-        // wrong, so the test is worthless if it never reached one.
+            panic!("daemon never listened on {}", path.display());
+        };
+        let request = |stream: &mut UnixStream,
+                       lines: &mut std::io::Lines<BufReader<UnixStream>>,
+                       json: &str| {
+            stream.write_all(json.as_bytes()).unwrap();
+            stream.write_all(b"\n").unwrap();
+            serde_json::from_str::<serde_json::Value>(&lines.next().unwrap().unwrap()).unwrap()
+        };
+        let mut first = connect();
+        let mut first_lines = BufReader::new(first.try_clone().unwrap()).lines();
+        let mut second = connect();
+        let mut second_lines = BufReader::new(second.try_clone().unwrap()).lines();
+
+        let hello = request(&mut first, &mut first_lines, r#"{"type":"hello"}"#);
         assert!(
-            anchors_narrower_than_their_node > 0,
-            "no column resolved to a node wider than its anchor, so this test \
-             never exercised the case a node-range anchor would break on"
+            hello["capabilities"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|c| c == "listen")
+        );
+        // Both editors call their buffer 1.
+        request(
+            &mut first,
+            &mut first_lines,
+            r#"{"type":"set_text","buf":1,"lang":"rust","text":"fn alpha() {}\n","revision":1}"#,
+        );
+        request(
+            &mut second,
+            &mut second_lines,
+            r#"{"type":"set_text","buf":1,"lang":"rust","text":"fn beta() {}\n","revision":1}"#,
         );
+        let symbols = |stream: &mut UnixStream,
+                       lines: &mut std::io::Lines<BufReader<UnixStream>>| {
+            let reply = request(
+                stream,
+                lines,
+                r#"{"type":"symbols","buf":1,"lang":"rust","request_id":1}"#,
+            );
+            reply["symbols"][0]["name"].as_str().unwrap().to_string()
+        };
+        assert_eq!(symbols(&mut first, &mut first_lines), "alpha");
+        assert_eq!(symbols(&mut second, &mut second_lines), "beta");
+        // Connections are served by the pool like stdin: `cancel` is taken
+        // (and, naming nothing in flight, answered with nothing).
+        for capability in ["cancel", "supersede"] {
+            assert!(
+                hello["capabilities"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(|c| c == capability)
+            );
+        }
+        first
+            .write_all(b"{\"type\":\"cancel\",\"request_id\":99}\n")
+            .unwrap();
+        assert_eq!(symbols(&mut first, &mut first_lines), "alpha");
+
+        // A second daemon on the same path is refused, not a silent takeover.
+        let again = ListenOptions {
+            path: path.clone(),
+            idle_timeout: None,
+        };
+        assert!(listen(&again).is_err());
+        let _ = std::fs::remove_file(&path);
     }
 
-    /// Every bundled language must at least classify a comment, so that `ac`
-    /// on an unlisted grammar degrades to "no match here" and never to a match
-    /// on the wrong node.
     #[test]
-    fn scope_tables_only_name_kinds_and_comments_are_universal() {
-        for lang in SUPPORTED_LANGUAGES {
-            for (node_kind, class) in scope_table(lang) {
-                assert!(
-                    matches!(
-                        *class,
-                        "function"
-                            | "class"
-                            | "parameter"
-                            | "block"
-                            | "call"
-                            | "comment"
-                            | "conditional"
-                            | "loop"
-                    ),
-                    "{lang}: {node_kind} maps to unknown text-object class {class}"
-                );
-            }
+    fn listen_sessions_keep_their_settings_and_share_the_cache_budget() {
+        let dir = std::env::temp_dir().join(format!("ts-hl-sessions-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("rust")).unwrap();
+        std::fs::write(
+            dir.join("rust/highlights.scm"),
+            "; extends\n((identifier) @keyword (#eq? @keyword \"unless\"))\n",
+        )
+        .unwrap();
+        fn request(server: &mut Server, session: u64, json: String) -> serde_json::Value {
+            let shared = server.enter_session(session);
+            let mut out = Vec::new();
+            handle_line(server, &json, &mut out).unwrap();
+            server.leave_session(session, shared);
+            let reply = String::from_utf8(out).unwrap();
+            serde_json::from_str(reply.lines().last().unwrap()).unwrap()
         }
-
         let mut server = Server::new();
-        server
-            .set_text(6, "rust", "// a note\nfn f() {}\n".to_string(), 1)
-            .unwrap();
-        let chain = scope_chain_cached(&server, 6, "rust", 1, 4).unwrap().chain;
-        assert_eq!(
-            chain.first().map(|node| node.kind),
-            Some(Some("comment")),
-            "a comment is not a comment text object: {chain:?}"
+        let sync = |buf: i64| {
+            format!(
+                r#"{{"type":"set_text","buf":{buf},"lang":"rust","text":"fn f() {{ unless }}\n","revision":1}}"#
+            )
+        };
+        let keywords = |reply: serde_json::Value| {
+            reply["spans"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|span| span["group"] == "TSKeyword")
+                .count()
+        };
+        let highlight = r#"{"type":"highlight","buf":1,"lang":"rust"}"#.to_string();
+
+        request(&mut server, 1, sync(1));
+        request(&mut server, 2, sync(1));
+        let paths = serde_json::json!({"type": "set_query_paths", "paths": [dir]}).to_string();
+        request(&mut server, 1, paths);
+        let depth = request(
+            &mut server,
+            1,
+            r#"{"type":"set_injection_depth","depth":0}"#.to_string(),
+        );
+        assert_eq!(depth["depth"], 0);
+        // `fn` everywhere, `unless` only where the override was loaded.
+        assert_eq!(keywords(request(&mut server, 1, highlight.clone())), 2);
+        assert_eq!(keywords(request(&mut server, 2, highlight.clone())), 1);
+        assert_eq!(server.injection_depth, DEFAULT_INJECTION_DEPTH);
+        assert!(server.query_overrides.is_empty());
+        // Clearing the overrides puts the connection back on the shared queries.
+        request(
+            &mut server,
+            1,
+            r#"{"type":"set_query_paths","paths":[]}"#.to_string(),
         );
+        assert!(server.sessions[&1].queries.is_none());
+        assert_eq!(keywords(request(&mut server, 1, highlight)), 1);
+
+        // The buffer cap holds across connections.
+        for buf in 2..=MAX_CACHED_BUFFERS as i64 {
+            request(&mut server, 1, sync(buf));
+        }
+        // Counting the other connection's buffer, one of the first's went.
+        assert_eq!(server.all_cached().count(), MAX_CACHED_BUFFERS);
+        assert_eq!(server.cache_evictions, 1);
+        assert_eq!(server.sessions[&2].cache.len(), 1);
+        request(&mut server, 2, sync(2));
+        let status = request(&mut server, 2, r#"{"type":"status"}"#.to_string());
+        assert_eq!(status["cached_buffers"], MAX_CACHED_BUFFERS);
+        // The second's buffers push out the first's, which holds far more.
+        assert_eq!(server.cache_evictions, 2);
+        assert_eq!(server.sessions[&2].cache.len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Collects what the pool writes, for the test to read back afterwards.
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
     }
 
     #[test]
-    fn c_function_range_covers_its_body() {
+    fn pooled_requests_answer_from_snapshots_and_can_be_cancelled() {
+        let written = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let out: SharedOut = std::sync::Arc::new(std::sync::Mutex::new(Box::new(SharedBuffer(
+            std::sync::Arc::clone(&written),
+        ))));
+        let pool = WorkerPool::new(1, std::sync::Arc::clone(&out));
         let mut server = Server::new();
-        let source = "int answer(void) {\n  return 42;\n}\n";
-        server.set_text(1, "c", source.to_string(), 1).unwrap();
-        let (_, symbols) = run_symbols_cached(&mut server, 1, "c", None, None).unwrap();
-        let function = symbols
-            .iter()
-            .find(|symbol| symbol.name == "answer")
+        let batch = |requests: &[&str]| {
+            std::iter::once(requests.iter().map(|line| line.to_string()).collect())
+        };
+        serve_batches(
+            &mut server,
+            &pool,
+            batch(&[
+                r#"{"type":"set_text","buf":1,"lang":"rust","text":"fn f(a: u8) {}\n","revision":3}"#,
+            ]),
+        )
+        .unwrap();
+        // A snapshot shares the buffer rather than copying its text.
+        let snapshot = server.snapshot(1, "rust").unwrap();
+        assert!(std::sync::Arc::ptr_eq(
+            &snapshot.cache[&1],
+            &server.cache[&1]
+        ));
+        drop(snapshot);
+        {
+            // With the single worker stuck writing the highlight reply, the
+            // scope request is still queued when its cancel arrives.
+            let _stalled = out.lock().unwrap();
+            serve_batches(
+                &mut server,
+                &pool,
+                batch(&[
+                    r#"{"type":"highlight","buf":1,"lang":"rust","request_id":1}"#,
+                    r#"{"type":"scope","buf":1,"lang":"rust","lnum":1,"col":6,"request_id":2}"#,
+                    r#"{"type":"cancel","request_id":2}"#,
+                    r#"{"type":"cancel","request_id":99}"#,
+                    r#"{"type":"inspect","buf":1,"lang":"rust","lnum":1,"col":4,"request_id":3}"#,
+                ]),
+            )
             .unwrap();
-        assert_eq!(function.kind, "function");
-        assert_eq!(function.end_lnum, 3);
+            // The same question in a later batch overtakes the queued one.
+            serve_batches(
+                &mut server,
+                &pool,
+                batch(&[
+                    r#"{"type":"inspect","buf":1,"lang":"rust","lnum":1,"col":4,"request_id":4}"#,
+                ]),
+            )
+            .unwrap();
+        }
+        // An uncached buffer is not worth a snapshot: answered inline.
+        serve_batches(
+            &mut server,
+            &pool,
+            batch(&[r#"{"type":"highlight","buf":7,"lang":"rust"}"#]),
+        )
+        .unwrap();
+        let dropped = std::sync::Arc::clone(&pool.superseded);
+        pool.finish();
+
+        let written = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+        let replies: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 6, "{written}");
+        assert_eq!(replies[0]["op"], "set_text");
+        let reply = |op: &str| {
+            replies
+                .iter()
+                .find(|reply| reply["type"] == op || reply["op"] == op)
+                .unwrap_or_else(|| panic!("no {op} reply: {written}"))
+        };
+        assert_eq!(reply("highlights")["revision"], 3);
+        let cancelled = reply("scope");
+        assert_eq!(
+            (
+                cancelled["message"].as_str(),
+                cancelled["request_id"].as_u64()
+            ),
+            (Some("cancelled"), Some(2))
+        );
+        assert!(
+            reply("highlight")["message"]
+                .as_str()
+                .unwrap()
+                .contains("not cached")
+        );
+        let inspects: Vec<_> = replies
+            .iter()
+            .filter(|reply| reply["type"] == "inspect" || reply["op"] == "inspect")
+            .map(|reply| (reply["type"].as_str(), reply["message"].as_str()))
+            .collect();
+        assert_eq!(
+            inspects,
+            [(Some("error"), Some("superseded")), (Some("inspect"), None)],
+            "{written}"
+        );
+        // Counted once, whether the next request already moved it to the
+        // server or not.
+        assert_eq!(
+            server.superseded_requests + dropped.load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
     }
 
     #[test]
-    fn visible_range_includes_multiline_tokens_starting_above_it() {
+    fn cancel_stops_a_pooled_request_that_is_already_running() {
+        let text: String = (0..5_000)
+            .map(|n| format!("fn f{n}(a: u32) -> u32 {{ let x = a + {n}; x * 2 }}\n"))
+            .collect();
         let mut server = Server::new();
-        let source = "text = '''first\nsecond\nthird'''\nprint(text)\n";
-        server.set_text(1, "python", source.to_string(), 1).unwrap();
-        let (_, spans) =
-            run_highlight_cached(&mut server, 1, "python", Some((2, 2)), false, None).unwrap();
-        assert!(
-            spans
-                .iter()
-                .any(|span| span.group == "TSString" && span.lnum == 1 && span.end_lnum == 3),
-            "multiline spans: {spans:?}"
+        server.set_text(1, "rust", text, 1).unwrap();
+        // A snapshot whose flag is set gives up inside its loops.
+        let mut snapshot = server.snapshot(1, "rust").unwrap();
+        snapshot.cancel = Some(CancelFlag::new(true.into()));
+        let errors = [
+            run_highlight_cached(&mut snapshot, 1, "rust", None, false, None).map(|_| ()),
+            run_symbols_cached(&mut snapshot, 1, "rust", None, None).map(|_| ()),
+            run_folds_cached(&snapshot, 1, "rust", None).map(|_| ()),
+            dump_ast_cached(&mut snapshot, 1, "rust").map(|_| ()),
+        ];
+        for error in errors {
+            assert_eq!(error.unwrap_err().to_string(), "cancelled");
+        }
+
+        let written = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let out: SharedOut = std::sync::Arc::new(std::sync::Mutex::new(Box::new(SharedBuffer(
+            std::sync::Arc::clone(&written),
+        ))));
+        let pool = WorkerPool::new(1, out);
+        let highlight = |request_id: u32| {
+            std::iter::once(vec![format!(
+                r#"{{"type":"highlight","buf":1,"lang":"rust","request_id":{request_id}}}"#
+            )])
+        };
+        let started = |pool: &WorkerPool| {
+            while !pool.newest.lock().unwrap().is_empty() {
+                std::thread::yield_now();
+            }
+            std::time::Instant::now()
+        };
+        // Once uncancelled, for the time a whole highlight takes.
+        serve_batches(&mut server, &pool, highlight(1)).unwrap();
+        let full = started(&pool);
+        while !written.lock().unwrap().ends_with(b"\n") {
+            std::thread::yield_now();
+        }
+        let full = full.elapsed();
+        written.lock().unwrap().clear();
+        // Then cancelled once it is running.
+        serve_batches(&mut server, &pool, highlight(2)).unwrap();
+        let cancelled = started(&pool);
+        serve_batches(
+            &mut server,
+            &pool,
+            std::iter::once(vec![r#"{"type":"cancel","request_id":2}"#.to_string()]),
+        )
+        .unwrap();
+        pool.finish();
+        let cancelled = cancelled.elapsed();
+
+        let reply: serde_json::Value = serde_json::from_slice(&written.lock().unwrap()).unwrap();
+        assert_eq!(
+            (reply["message"].as_str(), reply["request_id"].as_u64()),
+            (Some("cancelled"), Some(2))
         );
+        assert!(cancelled < full / 2, "{cancelled:?} vs {full:?}");
     }
 
     #[test]
-    fn vim9_declarations_are_available_when_grammar_uses_generic_commands() {
+    fn newer_read_only_requests_in_a_batch_supersede_older_ones() {
+        let written = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let out: SharedOut = std::sync::Arc::new(std::sync::Mutex::new(Box::new(SharedBuffer(
+            std::sync::Arc::clone(&written),
+        ))));
+        let pool = WorkerPool::new(2, out);
         let mut server = Server::new();
-        let source = "vim9script\nexport def Greet(name: string)\n  var message = name\n  return message\nenddef\nconst VERSION = 2\n";
-        server.set_text(1, "vim", source.to_string(), 1).unwrap();
-        let (_, symbols) = run_symbols_cached(&mut server, 1, "vim", None, None).unwrap();
-        let function = symbols
+        let sync = |revision: u32| {
+            format!(
+                r#"{{"type":"set_text","buf":1,"lang":"rust","text":"fn v{revision}() {{}}\n","revision":{revision}}}"#
+            )
+        };
+        let highlight = r#"{"type":"highlight","buf":1,"lang":"rust"}"#.to_string();
+        let symbols = |request_id: u32, kinds: &str| {
+            format!(
+                r#"{{"type":"symbols","buf":1,"lang":"rust","request_id":{request_id},"kinds":{kinds}}}"#
+            )
+        };
+        let batch = vec![
+            sync(10),
+            highlight.clone(),
+            sync(11),
+            highlight.clone(),
+            sync(12),
+            highlight,
+            symbols(5, "[]"),
+            symbols(6, "[]"),
+            // Another consumer's question, not a newer copy of the last one.
+            symbols(7, r#"["function"]"#),
+            // Two windows on one buffer: different ranges, both answered.
+            r#"{"type":"highlight","buf":1,"lang":"rust","lstart":1,"lend":50,"request_id":8}"#
+                .to_string(),
+            r#"{"type":"highlight","buf":1,"lang":"rust","lstart":200,"lend":250,"request_id":9}"#
+                .to_string(),
+            r#"{"type":"scope","buf":1,"lang":"rust","lnum":1,"col":1,"request_id":10}"#
+                .to_string(),
+            r#"{"type":"scope","buf":1,"lang":"rust","lnum":1,"col":4,"request_id":11}"#
+                .to_string(),
+            r#"{"type":"status"}"#.to_string(),
+        ];
+        serve_batches(&mut server, &pool, std::iter::once(batch)).unwrap();
+        pool.finish();
+
+        let written = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+        let replies: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let superseded: Vec<_> = replies
             .iter()
-            .find(|symbol| symbol.name == "Greet")
-            .unwrap();
-        assert_eq!(function.kind, "function");
-        assert_eq!(function.end_lnum, 5);
-        let local = symbols
+            .filter(|reply| reply["message"] == "superseded")
+            .map(|reply| (reply["op"].as_str().unwrap(), reply["request_id"].as_u64()))
+            .collect();
+        assert_eq!(
+            superseded,
+            [
+                ("highlight", None),
+                ("highlight", None),
+                ("symbols", Some(5))
+            ],
+            "{written}"
+        );
+        let highlights: Vec<_> = replies
             .iter()
-            .find(|symbol| symbol.name == "message")
-            .unwrap();
-        assert_eq!(local.container_name.as_deref(), Some("Greet"));
-        let version = symbols
+            .filter(|reply| reply["type"] == "highlights")
+            .map(|reply| reply["revision"].as_u64())
+            .collect();
+        // The whole buffer once, then each window's range.
+        assert_eq!(highlights, [Some(12); 3], "{written}");
+        let scopes = replies
             .iter()
-            .find(|symbol| symbol.name == "VERSION")
+            .filter(|reply| reply["type"] == "scope")
+            .count();
+        assert_eq!(scopes, 2, "{written}");
+        let mut answered: Vec<_> = replies
+            .iter()
+            .filter(|reply| reply["type"] == "symbols")
+            .map(|reply| reply["request_id"].as_u64().unwrap())
+            .collect();
+        answered.sort();
+        assert_eq!(answered, [6, 7]);
+        let status = replies
+            .iter()
+            .find(|reply| reply["type"] == "status")
             .unwrap();
-        assert_eq!(version.kind, "const");
-        assert!(version.container_kind.is_none());
+        assert_eq!(status["superseded_requests"], 3);
+    }
+
+    #[test]
+    fn binary_frames_carry_the_same_rows_as_compact_json() {
+        fn decode(bytes: &[u8]) -> (serde_json::Value, Vec<u32>, usize) {
+            let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+            assert_eq!(bytes[0], 0);
+            let length = word(1) as usize;
+            let header_len = word(5) as usize;
+            let header: serde_json::Value =
+                serde_json::from_slice(&bytes[9..9 + header_len]).unwrap();
+            let words = bytes[9 + header_len..5 + length]
+                .chunks(4)
+                .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect();
+            (header, words, 5 + length)
+        }
+        let source = "mod outer {\n    fn alpha() {\n        let x = 1;\n    }\n}\n";
+        let set_text = serde_json::json!({
+            "type": "set_text", "buf": 1, "lang": "rust", "text": source, "revision": 1,
+        })
+        .to_string();
+        let requests = [
+            r#"{"type":"highlight","buf":1,"lang":"rust","compact":true}"#,
+            r#"{"type":"symbols","buf":1,"lang":"rust"}"#,
+            r#"{"type":"folds","buf":1,"lang":"rust"}"#,
+        ];
+        let run = |hello: &str| {
+            let mut server = Server::new();
+            let mut out = Vec::new();
+            handle_line(&mut server, hello, &mut out).unwrap();
+            let hello_len = out.len();
+            handle_line(&mut server, &set_text, &mut out).unwrap();
+            for request in requests {
+                handle_line(&mut server, request, &mut out).unwrap();
+            }
+            let hello: serde_json::Value = serde_json::from_slice(&out[..hello_len]).unwrap();
+            (hello, out[hello_len..].to_vec())
+        };
+
+        let (hello, json) = run(r#"{"type":"hello"}"#);
+        assert!(hello.get("framing").is_none());
+        assert_eq!(
+            hello["locals_languages"],
+            serde_json::json!(LOCALS_LANGUAGES)
+        );
+        let json: Vec<serde_json::Value> = String::from_utf8(json)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        let (hello, binary) = run(r#"{"type":"hello","framing":"binary"}"#);
+        assert_eq!(hello["framing"], "binary");
+        // The set_text acknowledgement stays a JSON line.
+        let ok_len = binary.iter().position(|&byte| byte == b'\n').unwrap() + 1;
+        let mut rest = &binary[ok_len..];
+        let mut frames = Vec::new();
+        while !rest.is_empty() {
+            let (header, words, used) = decode(rest);
+            frames.push((header, words));
+            rest = &rest[used..];
+        }
+        assert_eq!(frames.len(), 3);
+
+        let (header, words) = &frames[0];
+        assert_eq!(header["type"], "highlights");
+        assert_eq!(header["stride"], 6);
+        assert_eq!(header["groups"], json[1]["groups"]);
+        let cspans: Vec<u32> = json[1]["cspans"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|row| row.as_array().unwrap().iter())
+            .map(|value| value.as_u64().unwrap() as u32)
+            .collect();
+        assert!(!cspans.is_empty());
+        assert_eq!(words, &cspans);
+        assert_eq!(header["rows"], cspans.len() / 6);
+
+        let (header, words) = &frames[1];
+        assert_eq!(header["type"], "symbols");
+        let strings = header["strings"].as_array().unwrap();
+        let expected = json[2]["symbols"].as_array().unwrap();
+        assert_eq!(header["rows"], expected.len());
+        for (row, symbol) in words.chunks(11).zip(expected) {
+            assert_eq!(strings[row[0] as usize], symbol["name"]);
+            assert_eq!(strings[row[1] as usize], symbol["kind"]);
+            assert_eq!(row[2], symbol["lnum"]);
+            assert_eq!(row[4], symbol["end_lnum"]);
+            match symbol["container_name"].as_str() {
+                Some(name) => assert_eq!(strings[row[7] as usize - 1], name),
+                None => assert_eq!(row[7], 0),
+            }
+        }
+
+        let (header, words) = &frames[2];
+        assert_eq!(header["type"], "folds");
+        let folds: Vec<u32> = json[3]["folds"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|fold| [&fold["lnum"], &fold["end_lnum"], &fold["level"]])
+            .map(|value| value.as_u64().unwrap() as u32)
+            .collect();
+        assert!(!folds.is_empty());
+        assert_eq!(words, &folds);
     }
 
     #[test]
-    fn javascript_lexical_declarations_are_symbols() {
+    fn delta_highlights_resend_only_the_blocks_that_changed() {
+        let mut lines: Vec<String> = (1..=300)
+            .map(|n| format!("fn f{n}() -> u32 {{ {n} }}"))
+            .collect();
+        // A comment long enough to cross block boundaries.
+        lines[99] = "/* one".to_string();
+        lines[199] = "two */".to_string();
         let mut server = Server::new();
-        let source = "const first = 1; let second = 2; var third = 3;\n";
-        server
-            .set_text(1, "javascript", source.to_string(), 1)
-            .unwrap();
-        let (_, symbols) = run_symbols_cached(&mut server, 1, "javascript", None, None).unwrap();
-        for expected in ["first", "second", "third"] {
-            assert!(
-                symbols.iter().any(|symbol| symbol.name == expected),
-                "missing {expected}: {symbols:?}"
+        let sync = |server: &mut Server, lines: &[String], revision: u64| {
+            server
+                .set_text(1, "rust", lines.join("\n") + "\n", revision)
+                .unwrap();
+        };
+        sync(&mut server, &lines, 1);
+        let highlight =
+            |server: &mut Server, range: Option<(u32, u32)>, have: &serde_json::Value| {
+                let mut request = serde_json::json!({
+                    "type": "highlight", "buf": 1, "lang": "rust", "have_blocks": have,
+                });
+                if let Some((lstart, lend)) = range {
+                    request["lstart"] = lstart.into();
+                    request["lend"] = lend.into();
+                }
+                let mut out = Vec::new();
+                handle_line(server, &request.to_string(), &mut out).unwrap();
+                serde_json::from_slice::<serde_json::Value>(&out).unwrap()
+            };
+        let spans_in =
+            |reply: &serde_json::Value, start: u32, end: u32| -> Vec<serde_json::Value> {
+                reply["spans"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .filter(|span| {
+                        span["lnum"].as_u64().unwrap() >= u64::from(start)
+                            && span["end_lnum"].as_u64().unwrap() <= u64::from(end)
+                    })
+                    .cloned()
+                    .collect()
+            };
+        let ranges = |server: &Server| -> Vec<(u32, u32)> {
+            let cache = server.get_cache(1, "rust").unwrap();
+            let line_count = cache.line_index.line_count as u32;
+            highlight_blocks(&cache.text, &cache.line_index, 1, line_count)
+                .into_iter()
+                .map(|(start, end, _)| (start, end))
+                .collect()
+        };
+        let block_at = |ranges: &[(u32, u32)], lnum: u32| {
+            *ranges
+                .iter()
+                .find(|&&(start, end)| start <= lnum && lnum <= end)
+                .unwrap()
+        };
+
+        let full = highlight(&mut server, None, &serde_json::json!({}));
+        assert_eq!(full["cleared"], serde_json::json!([[1, 301]]));
+        let blocks = full["blocks"].clone();
+        let before = ranges(&server);
+        assert!(before.len() > 2, "{before:?}");
+        assert_eq!(blocks.as_object().unwrap().len(), before.len());
+        for span in full["spans"].as_array().unwrap() {
+            let lnum = |key: &str| span[key].as_u64().unwrap() as u32;
+            assert_eq!(
+                block_at(&before, lnum("lnum")),
+                block_at(&before, lnum("end_lnum")),
+                "{span}"
             );
         }
+        // The comment is cut at the block boundaries, not dropped.
+        let comment: Vec<(u32, u32)> = full["spans"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|span| span["group"] == "TSComment")
+            .map(|span| {
+                (
+                    span["lnum"].as_u64().unwrap() as u32,
+                    span["end_lnum"].as_u64().unwrap() as u32,
+                )
+            })
+            .collect();
+        assert!(comment.len() > 1, "{comment:?}");
+        assert_eq!((comment[0].0, comment[comment.len() - 1].1), (100, 200));
+        for pair in comment.windows(2) {
+            assert_eq!(pair[0].1 + 1, pair[1].0);
+            assert_eq!(block_at(&before, pair[0].1).1, pair[0].1);
+        }
+
+        let again = highlight(&mut server, None, &blocks);
+        assert_eq!(again["cleared"], serde_json::json!([]), "{again}");
+        assert_eq!(again["spans"], serde_json::json!([]));
+        assert_eq!(again["blocks"], blocks);
+
+        lines[249] = "fn f250() -> &'static str { \"changed\" }".to_string();
+        sync(&mut server, &lines, 2);
+        let edited = block_at(&ranges(&server), 250);
+        let delta = highlight(&mut server, None, &blocks);
+        assert_eq!(delta["cleared"], serde_json::json!([[edited.0, edited.1]]));
+        let fresh = highlight(&mut server, None, &serde_json::json!({}));
+        assert_eq!(
+            delta["spans"].as_array().unwrap(),
+            &spans_in(&fresh, edited.0, edited.1)
+        );
+        assert_eq!(delta["blocks"], fresh["blocks"]);
+
+        // An inserted line resends its own block; the ones below only moved.
+        let blocks = fresh["blocks"].clone();
+        lines.insert(20, "fn inserted() {}".to_string());
+        sync(&mut server, &lines, 3);
+        let after = ranges(&server);
+        let edited = block_at(&after, 21);
+        let delta = highlight(&mut server, None, &blocks);
+        assert_eq!(delta["cleared"], serde_json::json!([[edited.0, edited.1]]));
+        let fresh = highlight(&mut server, None, &serde_json::json!({}));
+        assert_eq!(
+            delta["spans"].as_array().unwrap(),
+            &spans_in(&fresh, edited.0, edited.1)
+        );
+        let kept = delta["blocks"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .filter(|(key, digest)| blocks.get(key.as_str()) == Some(digest))
+            .count();
+        assert_eq!(kept, after.len() - 1);
+
+        // A viewport request grows to the blocks it touches.
+        let (start, end) = (block_at(&after, 70).0, block_at(&after, 80).1);
+        let viewport = highlight(&mut server, Some((70, 80)), &serde_json::json!({}));
+        assert_eq!(viewport["cleared"], serde_json::json!([[start, end]]));
+        assert_eq!(
+            viewport["spans"].as_array().unwrap(),
+            &spans_in(&fresh, start, end)
+        );
+        // Without `have_blocks` the reply is the full one it always was.
+        let mut out = Vec::new();
+        handle_line(
+            &mut server,
+            r#"{"type":"highlight","buf":1,"lang":"rust"}"#,
+            &mut out,
+        )
+        .unwrap();
+        let plain: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert!(plain.get("cleared").is_none() && plain.get("blocks").is_none());
     }
 
     #[test]
-    fn go_methods_and_fields_share_the_real_type_container() {
-        let mut server = Server::new();
-        let source = "package main\ntype User struct { Name string }\nfunc (u User) Greet() {}\n";
-        server.set_text(1, "go", source.to_string(), 1).unwrap();
-        let (_, symbols) = run_symbols_cached(&mut server, 1, "go", None, None).unwrap();
-        let user = symbols.iter().find(|symbol| symbol.name == "User").unwrap();
-        for name in ["Name", "Greet"] {
-            let child = symbols.iter().find(|symbol| symbol.name == name).unwrap();
-            assert_eq!(child.container_name.as_deref(), Some("User"));
-            assert_eq!(child.container_lnum, Some(user.lnum));
-            assert_eq!(child.container_col, Some(user.col));
+    fn highlight_blocks_follow_the_text_and_stay_bounded() {
+        // The same blocks whichever range asks.
+        let text: String = (1..=3000).map(|n| format!("let x{n} = {n};\n")).collect();
+        let index = SparseLineIndex::new(&text);
+        let whole = highlight_blocks(&text, &index, 1, 3001);
+        for (start, end, key) in &whole {
+            let part = highlight_blocks(&text, &index, *start + (end - start) / 2, *end);
+            assert_eq!(part, [(*start, *end, key.clone())]);
         }
+        // Identical lines offer no boundary of their own.
+        let text = "x\n".repeat(3000);
+        let index = SparseLineIndex::new(&text);
+        let blocks: Vec<_> = highlight_blocks(&text, &index, 1, 3001)
+            .into_iter()
+            .map(|(start, end, _)| (start, end))
+            .collect();
+        assert_eq!(blocks, [(1, 1024), (1025, 2048), (2049, 3001)]);
     }
 
     #[test]
-    fn every_supported_language_has_semantic_smoke_coverage() {
-        let cases = [
-            ("rust", "fn rust_fn() {}\n", "rust_fn"),
-            (
-                "javascript",
-                "function javascriptFn() { return 1; }\n",
-                "javascriptFn",
-            ),
-            (
-                "typescript",
-                "interface Shape { area(): number }\nfunction tsFn(x: number): number { return x; }\n",
-                "tsFn",
-            ),
-            (
-                "tsx",
-                "function TsxComponent() { return <div className=\"x\">hi</div>; }\n",
-                "TsxComponent",
-            ),
-            ("c", "int c_fn(void) { return 0; }\n", "c_fn"),
-            ("cpp", "class Widget { public: int value; };\n", "Widget"),
-            ("python", "def python_fn():\n    return 1\n", "python_fn"),
-            ("go", "package main\nfunc goFn() {}\n", "goFn"),
-            ("bash", "bash_fn() { echo ok; }\n", "bash_fn"),
-            ("vim", "vim9script\ndef VimFn()\nenddef\n", "VimFn"),
-            ("json", "{\"top_key\": {\"nested\": 1}}\n", "top_key"),
-            ("yaml", "top_key:\n  nested: 1\n", "top_key"),
-            ("toml", "[section]\nkey = \"value\"\n", "section"),
-            (
-                "julia",
-                "module Demo\nstruct Point\n  x::Float64\nend\nfunction area(p::Point)\n  p.x\nend\nend\n",
-                "area",
-            ),
-            ("haskell", "module Demo where\narea x = x * x\n", "area"),
-        ];
+    fn sync_replies_report_the_lines_whose_syntax_changed() {
         let mut server = Server::new();
-        for (index, (lang, source, expected_symbol)) in cases.into_iter().enumerate() {
-            let buffer = index as i64 + 1;
-            server
-                .set_text(buffer, lang, source.to_string(), 1)
-                .unwrap();
-            let (_, highlights) =
-                run_highlight_cached(&mut server, buffer, lang, None, false, None).unwrap();
-            assert!(!highlights.is_empty(), "no {lang} highlights");
-            let (_, symbols) = run_symbols_cached(&mut server, buffer, lang, None, None).unwrap();
-            assert!(
-                symbols.iter().any(|symbol| symbol.name == expected_symbol),
-                "missing {lang} symbol {expected_symbol}: {symbols:?}"
-            );
-        }
-    }
-
-    fn splice(
-        lstart: u32,
-        old_lend: u32,
-        lines: &[&str],
-        line_count: u64,
-        eol: bool,
-    ) -> LineSplice {
-        LineSplice {
-            lstart,
-            old_lend,
-            lines: lines.iter().map(|line| line.to_string()).collect(),
-            line_count,
-            eol,
-        }
+        let mut sync = |lang: &str, text: &str, revision: u64| {
+            let request = serde_json::json!({
+                "type": "set_text", "buf": 1, "lang": lang, "text": text, "revision": revision,
+            });
+            let mut out = Vec::new();
+            handle_line(&mut server, &request.to_string(), &mut out).unwrap();
+            let reply: serde_json::Value = serde_json::from_slice(&out).unwrap();
+            assert_eq!(reply["type"], "ok", "{reply}");
+            reply["changed"].clone()
+        };
+        let mut lines: Vec<String> = (1..=10).map(|n| format!("fn f{n}() {{}}")).collect();
+        let text = |lines: &[String]| lines.join("\n") + "\n";
+
+        // A first sync has no previous tree: everything is new.
+        assert_eq!(sync("rust", &text(&lines), 1), serde_json::json!([[1, 11]]));
+        assert_eq!(sync("rust", &text(&lines), 2), serde_json::json!([]));
+        lines[4] = "fn renamed() {}".to_string();
+        assert_eq!(sync("rust", &text(&lines), 3), serde_json::json!([[5, 5]]));
+        // An unterminated block comment swallows every line after the edit.
+        lines[1] = "/* fn f2() {}".to_string();
+        let changed = sync("rust", &text(&lines), 4);
+        assert_eq!(changed[0][0], 2, "{changed}");
+        assert!(changed[0][1].as_u64().unwrap() >= 10, "{changed}");
+
+        // Retargeting a fence reparses its body in another grammar, though the
+        // markdown tree only sees the info string change.
+        let fence =
+            |info: &str| format!("# Title\n\n```{info}\nlet x = 1;\nlet y = 2;\n```\n\ntail\n");
+        sync("markdown", &fence("rust"), 5);
+        let changed = sync("markdown", &fence("python"), 6);
+        let covered = |line: u64| {
+            changed.as_array().unwrap().iter().any(|range| {
+                range[0].as_u64().unwrap() <= line && line <= range[1].as_u64().unwrap()
+            })
+        };
+        assert!(covered(3) && covered(4) && covered(5), "{changed}");
+        assert!(!covered(1) && !covered(8), "{changed}");
     }
 
     #[test]
-    fn edit_lines_replaces_inserts_and_deletes() {
+    fn subscribed_windows_get_highlights_pushed_after_each_sync() {
         let mut server = Server::new();
-        server
-            .set_text(
-                1,
-                "rust",
-                "fn one() {}\nfn two() {}\nfn three() {}\n".to_string(),
-                1,
-            )
-            .unwrap();
+        let mut request = |request: serde_json::Value| -> Vec<serde_json::Value> {
+            let mut out = Vec::new();
+            handle_line(&mut server, &request.to_string(), &mut out).unwrap();
+            String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+        let mut lines: Vec<String> = (1..=100).map(|n| format!("fn f{n}() {{}}")).collect();
+        let sync = |lines: &[String], revision: u64| {
+            serde_json::json!({
+                "type": "set_text", "buf": 1, "lang": "rust",
+                "text": lines.join("\n") + "\n", "revision": revision,
+            })
+        };
+        let pushed = |replies: &[serde_json::Value]| -> Option<serde_json::Value> {
+            assert_eq!(replies[0]["type"], "ok", "{replies:?}");
+            let push = replies.get(1)?;
+            assert_eq!(push["type"], "highlights");
+            assert_eq!(push["push"], true);
+            let cleared = push["cleared"].as_array().unwrap();
+            for row in push["cspans"].as_array().unwrap() {
+                let (lnum, end_lnum) = (&row[0], &row[2]);
+                assert!(
+                    cleared
+                        .iter()
+                        .any(|range| range[0].as_u64() <= lnum.as_u64()
+                            && end_lnum.as_u64() <= range[1].as_u64()),
+                    "{row} outside {cleared:?}"
+                );
+            }
+            Some(push["cleared"].clone())
+        };
 
-        // 替换中间一行
-        let mode = server
-            .edit_lines(1, "rust", 2, splice(2, 3, &["fn changed() {}"], 3, true))
-            .unwrap();
-        assert_eq!(mode, ParseMode::Incremental);
+        let subscribe = serde_json::json!({
+            "type": "subscribe", "buf": 1, "lstart": 1, "lend": 10, "margin": 2,
+        });
+        assert_eq!(pushed(&request(subscribe)), None);
+        let replies = request(sync(&lines, 1));
+        assert_eq!(pushed(&replies), Some(serde_json::json!([[1, 12]])));
+        assert!(!replies[1]["cspans"].as_array().unwrap().is_empty());
+
+        lines[49] = "fn far_away() {}".to_string();
+        assert_eq!(pushed(&request(sync(&lines, 2))), None);
+        lines[4] = "fn near() {}".to_string();
         assert_eq!(
-            server.cache.get(&1).unwrap().text,
-            "fn one() {}\nfn changed() {}\nfn three() {}\n"
+            pushed(&request(sync(&lines, 3))),
+            Some(serde_json::json!([[5, 5]]))
         );
 
-        // 在开头插入一行
-        server
-            .edit_lines(1, "rust", 3, splice(1, 1, &["fn zero() {}"], 4, true))
-            .unwrap();
+        let viewport = |lstart: u32, lend: u32| serde_json::json!({"type": "viewport", "buf": 1, "lstart": lstart, "lend": lend});
         assert_eq!(
-            server.cache.get(&1).unwrap().text,
-            "fn zero() {}\nfn one() {}\nfn changed() {}\nfn three() {}\n"
+            pushed(&request(viewport(20, 30))),
+            Some(serde_json::json!([[18, 32]]))
         );
-
-        // 删除最后两行
-        server
-            .edit_lines(1, "rust", 4, splice(3, 5, &[], 2, true))
-            .unwrap();
         assert_eq!(
-            server.cache.get(&1).unwrap().text,
-            "fn zero() {}\nfn one() {}\n"
+            pushed(&request(viewport(25, 35))),
+            Some(serde_json::json!([[33, 37]]))
+        );
+        // A comment opened above the window still repaints all of it.
+        lines[18] = "/* fn f19() {}".to_string();
+        assert_eq!(
+            pushed(&request(sync(&lines, 4))),
+            Some(serde_json::json!([[23, 37]]))
         );
 
-        // 语法树与全量解析一致，revision 精确更新
-        let (revision, symbols) = run_symbols_cached(&mut server, 1, "rust", None, None).unwrap();
-        assert_eq!(revision, 4);
-        let names: Vec<&str> = symbols.iter().map(|s| s.name.as_str()).collect();
-        assert_eq!(names, ["zero", "one"]);
+        request(serde_json::json!({"type": "unsubscribe", "buf": 1}));
+        lines[29] = "fn unwatched() {}".to_string();
+        assert_eq!(pushed(&request(sync(&lines, 5))), None);
+        let replies = request(viewport(1, 10));
+        assert_eq!(replies[0]["message"], "buffer not subscribed: 1");
     }
 
     #[test]
-    fn edit_lines_respects_missing_trailing_newline() {
+    fn pushes_cut_short_by_max_spans_leave_the_rest_uncleared() {
         let mut server = Server::new();
-        server
-            .set_text(1, "rust", "fn a() {}\nfn b() {}".to_string(), 1)
-            .unwrap();
-        server
-            .edit_lines(1, "rust", 2, splice(2, 3, &[], 1, false))
-            .unwrap();
-        assert_eq!(server.cache.get(&1).unwrap().text, "fn a() {}");
-        server
-            .edit_lines(1, "rust", 3, splice(2, 2, &["fn c() {}"], 2, true))
-            .unwrap();
-        assert_eq!(server.cache.get(&1).unwrap().text, "fn a() {}\nfn c() {}\n");
+        let mut push = |request: serde_json::Value| -> serde_json::Value {
+            let mut out = Vec::new();
+            handle_line(&mut server, &request.to_string(), &mut out).unwrap();
+            let replies: Vec<serde_json::Value> = String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            replies.last().unwrap().clone()
+        };
+        let mut lines: Vec<String> = (1..=40).map(|n| format!("fn f{n}() {{}}")).collect();
+        let sync = |lines: &[String], revision: u64| {
+            serde_json::json!({
+                "type": "set_text", "buf": 1, "lang": "rust",
+                "text": lines.join("\n") + "\n", "revision": revision,
+            })
+        };
+        push(serde_json::json!({
+            "type": "subscribe", "buf": 1, "lstart": 1, "lend": 20, "max_spans": 8,
+        }));
+
+        // Twenty lines of spans do not fit in eight: nothing is cleared.
+        let first = push(sync(&lines, 1));
+        assert_eq!(first["cleared"], serde_json::json!([]));
+        assert_eq!(first["incomplete"], serde_json::json!([[1, 20]]));
+        assert!(first["cspans"].as_array().unwrap().is_empty());
+
+        // One changed line fits; two far apart are one range that does not.
+        lines[2] = "fn three() {}".to_string();
+        let second = push(sync(&lines, 2));
+        assert_eq!(second["cleared"], serde_json::json!([[3, 3]]), "{second}");
+        assert!(second.get("incomplete").is_none());
+        let rows = second["cspans"].as_array().unwrap();
+        assert!(!rows.is_empty());
+        assert!(
+            rows.iter().all(|row| row[0] == 3 && row[2] == 3),
+            "{second}"
+        );
+
+        lines[4] = "fn five() {}".to_string();
+        lines[14] = "fn fifteen() {}".to_string();
+        let third = push(sync(&lines, 3));
+        assert_eq!(third["cleared"], serde_json::json!([]));
+        assert_eq!(third["incomplete"], serde_json::json!([[5, 15]]));
     }
 
     #[test]
-    fn edit_lines_mismatch_drops_the_cache() {
+    fn semantic_tokens_follow_the_highlight_groups_in_lsp_form() {
+        let source = "/* one\n two */\nfn add(a: u32) -> u32 {\n    let s = \"é\\n\";\n    a\n}\n";
         let mut server = Server::new();
-        server
-            .set_text(1, "rust", "fn a() {}\n".to_string(), 1)
-            .unwrap();
-        let error = server
-            .edit_lines(1, "rust", 2, splice(1, 2, &["fn b() {}"], 99, true))
-            .unwrap_err();
-        assert!(error.to_string().contains("edit_lines mismatch"));
-        assert!(!server.cache.contains_key(&1));
+        server.set_text(1, "rust", source.to_string(), 1).unwrap();
+        let tokens = |server: &mut Server, extra: serde_json::Value| {
+            let mut request =
+                serde_json::json!({"type": "semantic_tokens", "buf": 1, "lang": "rust"});
+            request
+                .as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            let mut out = Vec::new();
+            handle_line(server, &request.to_string(), &mut out).unwrap();
+            let reply: serde_json::Value = serde_json::from_slice(&out).unwrap();
+            assert_eq!(reply["type"], "semantic_tokens", "{reply}");
+            let types = reply["legend"]["tokenTypes"].as_array().unwrap().clone();
+            let data: Vec<u64> = reply["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|value| value.as_u64().unwrap())
+                .collect();
+            // Undo the delta encoding: (line, start, length, type, modifiers).
+            let (mut line, mut start) = (0, 0);
+            data.chunks(5)
+                .map(|token| {
+                    if token[0] > 0 {
+                        (line, start) = (line + token[0], token[1]);
+                    } else {
+                        start += token[1];
+                    }
+                    let name = types[token[3] as usize].as_str().unwrap().to_string();
+                    (line, start, token[2], name, token[4])
+                })
+                .collect::<Vec<_>>()
+        };
 
-        // 未缓存 buffer 的 edit_lines 必须请求全量重同步
-        let error = server
-            .edit_lines(2, "rust", 1, splice(1, 1, &["fn x() {}"], 1, true))
-            .unwrap_err();
-        assert!(error.to_string().contains("buffer not cached"));
+        let full = tokens(&mut server, serde_json::json!({}));
+        let find = |line: u64, start: u64| {
+            full.iter()
+                .find(|token| token.0 == line && token.1 == start)
+                .cloned()
+                .unwrap_or_else(|| panic!("no token at {line}:{start} in {full:?}"))
+        };
+        // The block comment is cut into one token per line.
+        assert_eq!(find(0, 0), (0, 0, 6, "comment".to_string(), 0));
+        assert_eq!(find(1, 0), (1, 0, 7, "comment".to_string(), 0));
+        assert_eq!(find(2, 0).3, "keyword");
+        assert_eq!(find(2, 3), (2, 3, 3, "function".to_string(), 0));
+        assert_eq!(find(2, 7).3, "parameter");
+        // `u32` is a builtin type: modifier bit 1 is defaultLibrary.
+        assert_eq!(find(2, 10), (2, 10, 3, "type".to_string(), 2));
+        // The escape nests inside the string and both are strings: one token,
+        // five UTF-16 units for six bytes.
+        assert_eq!(find(3, 12), (3, 12, 5, "string".to_string(), 0));
+        for pair in full.windows(2) {
+            let (before, after) = (&pair[0], &pair[1]);
+            assert!(
+                before.0 < after.0 || before.1 + before.2 <= after.1,
+                "{before:?} overlaps {after:?}"
+            );
+        }
+
+        let utf8 = tokens(&mut server, serde_json::json!({"encoding": "utf-8"}));
+        assert!(
+            utf8.contains(&(3, 12, 6, "string".to_string(), 0)),
+            "{utf8:?}"
+        );
+        // The range variant still counts from the document start.
+        let range = tokens(&mut server, serde_json::json!({"lstart": 4, "lend": 4}));
+        assert!(!range.is_empty());
+        assert!(range.iter().all(|token| token.0 == 3), "{range:?}");
+        assert_eq!(
+            range,
+            full.iter()
+                .filter(|token| token.0 == 3)
+                .cloned()
+                .collect::<Vec<_>>()
+        );
+
+        let mut out = Vec::new();
+        handle_line(
+            &mut server,
+            r#"{"type":"semantic_tokens","buf":1,"lang":"rust","encoding":"utf-7","request_id":9}"#,
+            &mut out,
+        )
+        .unwrap();
+        let error: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(error["message"], "unsupported position encoding: utf-7");
+        assert_eq!(error["request_id"], 9);
+
+        let (.., truncated) =
+            run_semantic_tokens_cached(&mut server, 1, "rust", None, "utf-16").unwrap();
+        assert!(!truncated);
+        let numbers = format!("[{}0]\n", "0,".repeat(MAX_HIGHLIGHT_SPANS));
+        server.set_text(2, "json", numbers, 1).unwrap();
+        let (.., truncated) =
+            run_semantic_tokens_cached(&mut server, 2, "json", None, "utf-16").unwrap();
+        assert!(truncated);
     }
 
     #[test]
-    fn folds_are_nested_and_merge_identical_ranges() {
-        let mut server = Server::new();
-        let source = "fn outer() {\n    match 1 {\n        _ => {}\n    }\n}\nfn flat() {}\n";
-        server.set_text(1, "rust", source.to_string(), 7).unwrap();
-        let (revision, folds) = run_folds_cached(&server, 1, "rust", None).unwrap();
-        assert_eq!(revision, 7);
-        // function_item 与其同界 block 合并为一个 level-1 折叠；match 嵌套其中。
-        let outer = folds.iter().find(|fold| fold.lnum == 1).unwrap();
-        assert_eq!((outer.end_lnum, outer.level), (5, 1));
-        let inner = folds.iter().find(|fold| fold.lnum == 2).unwrap();
-        assert_eq!((inner.end_lnum, inner.level), (4, 2));
-        // 单行函数不产生折叠
-        assert!(!folds.iter().any(|fold| fold.lnum == 6));
-        assert_eq!(folds.len(), 2);
+    fn lsp_mode_answers_the_editor_features_from_the_same_trees() {
+        let uri = "file:///tmp/lib.rs";
+        let messages = [
+            serde_json::json!({"jsonrpc": "2.0", "id": 0, "method": "shutdown"}),
+            serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {
+                "capabilities": {"general": {"positionEncodings": ["utf-16"]}},
+            }}),
+            serde_json::json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+            serde_json::json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+                "textDocument": {
+                    "uri": uri, "languageId": "rust", "version": 1,
+                    "text": "mod outer {\n    fn inner(é: u32) {\n        let x = (1;\n    }\n}\n",
+                },
+            }}),
+            serde_json::json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/documentSymbol",
+                "params": {"textDocument": {"uri": uri}}}),
+            serde_json::json!({"jsonrpc": "2.0", "id": 3, "method": "textDocument/foldingRange",
+                "params": {"textDocument": {"uri": uri}}}),
+            // Close the parenthesis: the parse error goes away.
+            serde_json::json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+                "textDocument": {"uri": uri, "version": 2},
+                "contentChanges": [{
+                    "range": {"start": {"line": 2, "character": 18}, "end": {"line": 2, "character": 18}},
+                    "text": ")",
+                }],
+            }}),
+            serde_json::json!({"jsonrpc": "2.0", "id": 4, "method": "textDocument/selectionRange",
+                "params": {"textDocument": {"uri": uri}, "positions": [{"line": 2, "character": 12}]}}),
+            serde_json::json!({"jsonrpc": "2.0", "id": 5, "method": "textDocument/semanticTokens/full",
+                "params": {"textDocument": {"uri": uri}}}),
+            serde_json::json!({"jsonrpc": "2.0", "id": 6, "method": "workspace/symbol", "params": {}}),
+            serde_json::json!({"jsonrpc": "2.0", "id": 7, "method": "shutdown"}),
+            serde_json::json!({"jsonrpc": "2.0", "method": "exit"}),
+        ];
+        let mut input = Vec::new();
+        for message in &messages {
+            let body = message.to_string();
+            input.extend(format!("Content-Length: {}\r\n\r\n{body}", body.len()).bytes());
+        }
+        let mut out = Vec::new();
+        let code = serve_lsp(&mut std::io::Cursor::new(input), &mut out).unwrap();
+        assert_eq!(code, 0);
+
+        let mut replies = Vec::new();
+        let mut reader = std::io::Cursor::new(out);
+        while let Some(body) = read_lsp_frame(&mut reader).unwrap() {
+            replies.push(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+        }
+        let reply = |id: u64| {
+            replies
+                .iter()
+                .find(|reply| reply["id"] == id)
+                .unwrap_or_else(|| panic!("no reply {id} in {replies:?}"))
+        };
+        let published: Vec<_> = replies
+            .iter()
+            .filter(|reply| reply["method"] == "textDocument/publishDiagnostics")
+            .map(|reply| &reply["params"])
+            .collect();
+
+        assert_eq!(reply(0)["error"]["code"], -32002);
+        let capabilities = &reply(1)["result"]["capabilities"];
+        assert_eq!(capabilities["positionEncoding"], "utf-16");
+        assert_eq!(
+            capabilities["semanticTokensProvider"]["legend"]["tokenModifiers"],
+            serde_json::json!(["readonly", "defaultLibrary"])
+        );
+
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0]["version"], 1);
+        assert_eq!(published[0]["diagnostics"][0]["range"]["start"]["line"], 2);
+        assert_eq!(published[1]["diagnostics"], serde_json::json!([]));
+
+        let symbols = &reply(2)["result"];
+        assert_eq!(symbols.as_array().unwrap().len(), 1, "{symbols}");
+        assert_eq!(symbols[0]["name"], "outer");
+        assert_eq!(symbols[0]["kind"], 3);
+        let inner = &symbols[0]["children"][0];
+        assert_eq!(inner["name"], "inner");
+        assert_eq!(inner["kind"], 12);
+        assert_eq!(
+            inner["selectionRange"],
+            serde_json::json!({"start": {"line": 1, "character": 7}, "end": {"line": 1, "character": 12}})
+        );
+
+        let folds = reply(3)["result"].as_array().unwrap();
+        assert!(
+            folds.contains(&serde_json::json!({"startLine": 0, "endLine": 4})),
+            "{folds:?}"
+        );
+
+        // `x`, then outwards through the `let` and the body to the file.
+        let mut selection = &reply(4)["result"][0];
+        let mut starts = Vec::new();
+        while !selection.is_null() {
+            starts.push(selection["range"]["start"].clone());
+            selection = &selection["parent"];
+        }
+        assert_eq!(
+            starts[0],
+            serde_json::json!({"line": 2, "character": 12}),
+            "{starts:?}"
+        );
+        assert_eq!(
+            starts.last().unwrap(),
+            &serde_json::json!({"line": 0, "character": 0})
+        );
+        assert!(starts.len() >= 4, "{starts:?}");
+
+        let data = reply(5)["result"]["data"].as_array().unwrap();
+        assert!(!data.is_empty() && data.len() % 5 == 0);
+        assert_eq!(reply(6)["error"]["code"], -32601);
+        assert_eq!(reply(7)["result"], serde_json::Value::Null);
     }
 
     #[test]
@@ -5088,6 +13327,10 @@ mod tests {
             spans: None,
             groups: Some(groups),
             cspans: Some(rows),
+            blocks: None,
+            cleared: None,
+            incomplete: None,
+            push: false,
         })
         .unwrap();
         assert!(compact.get("spans").is_none());
@@ -5098,6 +13341,10 @@ mod tests {
             spans: Some(spans),
             groups: None,
             cspans: None,
+            blocks: None,
+            cleared: None,
+            incomplete: None,
+            push: false,
         })
         .unwrap();
         assert!(legacy.get("cspans").is_none());
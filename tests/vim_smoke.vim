@@ -363,6 +363,38 @@ call simpletreesitter#FoldsToggle()
 call assert_equal('manual', &l:foldmethod, 'fold settings were not restored')
 call assert_equal(0, g:simpletreesitter_folds)
 
+" Opt-in 'indentexpr': answered synchronously by the daemon's indent request,
+" including for a line typed since the last sync, and falling back to the
+" buffer's previous 'indentexpr' when the daemon cannot answer in time.
+function! SimpleTreeSitterTestIndent() abort
+  return 7
+endfunction
+let g:simpletreesitter_indentexpr = 1
+enew
+setlocal shiftwidth=4 expandtab indentexpr=SimpleTreeSitterTestIndent()
+setfiletype rust
+let s:indent_buf = bufnr()
+call simpletreesitter#OnBufEvent(s:indent_buf)
+sleep 300m
+call assert_equal('simpletreesitter#IndentExpr(v:lnum)', &l:indentexpr,
+      \ 'g:simpletreesitter_indentexpr did not install the indentexpr')
+call setline(1, ['fn indent_me() {', 'let a = 1;', 'if a {', 'a;', '}', '}'])
+normal! gg=G
+call assert_equal(['fn indent_me() {', '    let a = 1;', '    if a {', '        a;', '    }', '}'],
+      \ getline(1, '$'), '= did not reindent from the syntax tree')
+let g:simpletreesitter_indent_timeout = 0
+call assert_equal(7, simpletreesitter#IndentExpr(2),
+      \ 'a timed-out indent did not fall back to the previous indentexpr')
+let g:simpletreesitter_indent_timeout = 50
+call assert_equal({}, s:State().s_indent_wait, 'an abandoned indent request stayed pending')
+call simpletreesitter#Disable()
+call assert_equal('SimpleTreeSitterTestIndent()', &l:indentexpr,
+      \ 'disabling did not restore the previous indentexpr')
+let g:simpletreesitter_indentexpr = 0
+call simpletreesitter#Enable()
+execute 'bwipeout!' s:indent_buf
+sleep 300m
+
 " :TsHlSymbols fills the location list.
 call simpletreesitter#SymbolsToLoclist()
 sleep 300m